@@ -4,8 +4,10 @@ extern crate log;
 use async_std::io;
 use futures::channel::oneshot;
 use sql_proxy::{
+    context::Context,
     packet::{DatabaseType, Packet},
-    packet_handler::PacketHandler,
+    packet_handler::{Action, PacketHandler},
+    sql::StatementKind,
 };
 use std::collections::HashMap;
 
@@ -24,7 +26,7 @@ impl CounterHandler {
 // Just forward the packet
 #[async_trait::async_trait]
 impl PacketHandler for CounterHandler {
-    async fn handle_request(&mut self, p: &Packet) -> Packet {
+    async fn handle_request(&mut self, context: &Context, p: &Packet) -> Action {
         // Print out the packet
         //debug!("[{}]", String::from_utf8_lossy(&p.bytes));
         debug!(
@@ -33,29 +35,30 @@ impl PacketHandler for CounterHandler {
             p.get_size()
         );
 
-        match p.get_query() {
-            Ok(sql) => {
-                info!("SQL: {}", sql);
-                let tokens: Vec<&str> = sql.split(' ').collect();
-                let command = tokens[0].to_lowercase();
-                let count = self.count_map.entry(command).or_insert(0);
-                *count += 1;
+        match context.parse_query(p) {
+            Ok(statements) => {
+                for statement in statements {
+                    info!("SQL: {}", statement);
+                    let command = StatementKind::of(&statement).as_str().to_string();
+                    let count = self.count_map.entry(command).or_insert(0);
+                    *count += 1;
+                }
                 println!("{:?}", self.count_map);
             }
             Err(e) => debug!("{:?} packet: {}", p.get_packet_type(), e),
         };
 
-        p.clone()
+        Action::Forward(p.clone())
     }
 
-    async fn handle_response(&mut self, p: &Packet) -> Packet {
+    async fn handle_response(&mut self, _context: &Context, p: &Packet) -> Action {
         debug!(
             "c<=s: {:?} packet: {} bytes",
             p.get_packet_type(),
             p.get_size()
         );
 
-        p.clone()
+        Action::Forward(p.clone())
     }
 }
 
@@ -4,8 +4,9 @@ extern crate log;
 use async_std::io;
 use futures::channel::oneshot;
 use sql_proxy::{
+    context::Context,
     packet::{DatabaseType, Packet},
-    packet_handler::PacketHandler,
+    packet_handler::{Action, PacketHandler},
 };
 
 struct PassthroughHandler {}
@@ -13,22 +14,22 @@ struct PassthroughHandler {}
 // Just forward the packet
 #[async_trait::async_trait]
 impl PacketHandler for PassthroughHandler {
-    async fn handle_request(&mut self, p: &Packet) -> Packet {
+    async fn handle_request(&mut self, _context: &Context, p: &Packet) -> Action {
         debug!(
             "c=>s: {:?} packet: {} bytes",
             p.get_packet_type(),
             p.get_size()
         );
-        p.clone()
+        Action::Forward(p.clone())
     }
 
-    async fn handle_response(&mut self, p: &Packet) -> Packet {
+    async fn handle_response(&mut self, _context: &Context, p: &Packet) -> Action {
         debug!(
             "c<=s: {:?} packet: {} bytes",
             p.get_packet_type(),
             p.get_size()
         );
-        p.clone()
+        Action::Forward(p.clone())
     }
 }
 
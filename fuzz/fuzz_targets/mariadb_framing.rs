@@ -0,0 +1,25 @@
+//! Framing of a MariaDB stream, as read by a pipe: plain packets, and compressed ones
+#![no_main]
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+
+use sql_proxy::{
+    mariadb::compress::{Compression, CompressionMode, Side},
+    packet::{DatabaseType, WriteQueue},
+    pipe::get_packet,
+};
+
+fuzz_target!(|data: &[u8]| {
+    let mut packet_buf = BytesMut::from(data);
+    while let Ok(Some(packet)) = get_packet(DatabaseType::MariaDB, &mut packet_buf) {
+        let _ = packet.get_sequence_id();
+        let _ = packet.get_packet_type();
+        let _ = packet.get_query_bytes();
+        packet.write_mariadb_fragments(0, &mut WriteQueue::new());
+    }
+
+    let mut compression = Compression::new(CompressionMode::Passthrough);
+    let mut compressed_buf = BytesMut::from(data);
+    let mut packet_buf = BytesMut::new();
+    let _ = compression.read(Side::Client, &mut compressed_buf, &mut packet_buf);
+});
@@ -0,0 +1,32 @@
+//! Type detection of MariaDB packets, from the bytes alone and from the connection state.
+//! The first byte of each packet chooses the direction it travels in.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use sql_proxy::{
+    context::Context,
+    packet::{DatabaseType, Packet, WriteQueue},
+    packet_handler::Direction,
+};
+
+fuzz_target!(|data: &[u8]| {
+    let mut context = Context::new("fuzz".to_string(), DatabaseType::MariaDB);
+    for chunk in data.split(|b| *b == 0xa5) {
+        let (direction, bytes) = match chunk.split_first() {
+            Some((d, bytes)) if d & 1 == 0 => (Direction::Forward, bytes),
+            Some((_, bytes)) => (Direction::Backward, bytes),
+            None => continue,
+        };
+        let packet = Packet::new(DatabaseType::MariaDB, bytes.to_vec());
+        let _ = packet.get_packet_type();
+        let _ = packet.get_sequence_id();
+        let _ = packet.get_payload();
+
+        let mut labeled = packet.clone();
+        context.decode(&direction, &mut labeled);
+        let _ = context.get_query(&labeled);
+        let _ = context.get_mariadb().decode_row(&labeled);
+        let _ = context.get_mariadb().get_execute(&labeled);
+        context.encode(&direction, &labeled, &labeled, &mut WriteQueue::new());
+    }
+});
@@ -0,0 +1,15 @@
+//! Framing of a PostgresSQL stream, as read by a pipe
+#![no_main]
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+
+use sql_proxy::{packet::DatabaseType, pipe::get_packet};
+
+fuzz_target!(|data: &[u8]| {
+    let mut packet_buf = BytesMut::from(data);
+    while let Ok(Some(packet)) = get_packet(DatabaseType::PostgresSQL, &mut packet_buf) {
+        let _ = packet.get_payload();
+        let _ = packet.get_packet_type();
+        let _ = packet.get_query_bytes();
+    }
+});
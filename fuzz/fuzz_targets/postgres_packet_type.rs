@@ -0,0 +1,33 @@
+//! Type detection of PostgresSQL messages, from the bytes alone and from the connection
+//! state. The first byte of each message chooses the direction it travels in.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use sql_proxy::{
+    context::Context,
+    packet::{DatabaseType, Packet},
+    packet_handler::Direction,
+    postgres::message::{BackendMessage, FrontendMessage},
+};
+
+fuzz_target!(|data: &[u8]| {
+    let mut context = Context::new("fuzz".to_string(), DatabaseType::PostgresSQL);
+    for chunk in data.split(|b| *b == 0xa5) {
+        let (direction, bytes) = match chunk.split_first() {
+            Some((d, bytes)) if d & 1 == 0 => (Direction::Forward, bytes),
+            Some((_, bytes)) => (Direction::Backward, bytes),
+            None => continue,
+        };
+        let packet = Packet::new(DatabaseType::PostgresSQL, bytes.to_vec());
+        let _ = packet.get_packet_type();
+        let _ = packet.get_payload();
+        let _ = FrontendMessage::parse(&packet);
+        let _ = BackendMessage::parse(&packet);
+
+        let mut labeled = packet.clone();
+        context.decode(&direction, &mut labeled);
+        let _ = context.get_query(&labeled);
+        let _ = context.get_postgres().decode_row(&labeled);
+        let _ = context.get_postgres().get_execute(&labeled);
+    }
+});
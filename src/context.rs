@@ -0,0 +1,806 @@
+use bytes::{Buf, BytesMut};
+use encoding_rs::Encoding;
+use std::{io::Error, path::PathBuf};
+
+use crate::{
+    fingerprint::Fingerprint,
+    mariadb::{
+        self,
+        compress::{Compression, CompressionMode, Side},
+        decoder::Phase,
+        handshake::CapabilityFlags,
+        infile::{self, LocalInfile, LocalInfileDecision, LocalInfilePolicy},
+    },
+    packet::{DatabaseType, Packet, PacketType, QueryText, WriteQueue},
+    packet_handler::{Action, Direction},
+    postgres,
+    session::SessionState,
+    sql::{self, Statement, Syntax},
+    transaction::TransactionState,
+};
+
+/// State of a single client connection, shared by its forward and backward pipes
+#[derive(Debug)]
+pub struct Context {
+    client_addr: String,
+    db_type: DatabaseType,
+    mariadb: mariadb::decoder::Decoder,
+    postgres: postgres::decoder::Decoder,
+    /// MariaDB sequence id seen by the server minus the one seen by the client. It changes
+    /// when a handler's packet needs a different number of physical packets than the one
+    /// it replaces, and is reset by each new command.
+    sequence_offset: u8,
+    /// MariaDB compressed protocol state of both sides
+    compression: Compression,
+    session: SessionState,
+    transaction: TransactionState,
+    local_infile_policy: LocalInfilePolicy,
+    /// The last MariaDB LOCAL INFILE request of the current command
+    local_infile: Option<LocalInfile>,
+    /// The client already got an error for a refused LOCAL INFILE request, so the server's
+    /// answer to the empty file is not forwarded
+    drop_local_infile_answer: bool,
+}
+
+impl Context {
+    pub fn new(client_addr: String, db_type: DatabaseType) -> Context {
+        Context {
+            client_addr,
+            db_type,
+            mariadb: mariadb::decoder::Decoder::new(),
+            postgres: postgres::decoder::Decoder::new(),
+            sequence_offset: 0,
+            compression: Compression::default(),
+            session: SessionState::new(),
+            transaction: TransactionState::new(),
+            local_infile_policy: LocalInfilePolicy::default(),
+            local_infile: None,
+            drop_local_infile_answer: false,
+        }
+    }
+
+    /// Choose which sides of a MariaDB connection use the compressed protocol. Must be set
+    /// before the handshake.
+    pub fn set_compression_mode(&mut self, mode: CompressionMode) {
+        self.compression = Compression::new(mode);
+    }
+
+    /// MariaDB compressed protocol state
+    pub fn get_compression(&self) -> &Compression {
+        &self.compression
+    }
+
+    /// Choose what to do with the LOCAL INFILE requests of a MariaDB server
+    pub fn set_local_infile_policy(&mut self, policy: LocalInfilePolicy) {
+        self.local_infile_policy = policy;
+    }
+
+    pub fn get_local_infile_policy(&self) -> &LocalInfilePolicy {
+        &self.local_infile_policy
+    }
+
+    /// The LOCAL INFILE request of the current MariaDB command, with the policy's decision
+    /// and the number of file bytes sent. The file itself goes through `handle_request` as
+    /// LocalInfileData packets, whether the client sends it or the proxy replies it.
+    pub fn get_local_infile(&self) -> Option<&LocalInfile> {
+        self.local_infile.as_ref()
+    }
+
+    pub fn get_client_addr(&self) -> &str {
+        &self.client_addr
+    }
+
+    pub fn get_db_type(&self) -> DatabaseType {
+        self.db_type
+    }
+
+    /// Connection phase tracking for MariaDB connections
+    pub fn get_mariadb(&self) -> &mariadb::decoder::Decoder {
+        &self.mariadb
+    }
+
+    /// Message labeling state for PostgresSQL connections
+    pub fn get_postgres(&self) -> &postgres::decoder::Decoder {
+        &self.postgres
+    }
+
+    /// The user, current database and session variables of the connection
+    pub fn get_session(&self) -> &SessionState {
+        &self.session
+    }
+
+    /// Whether the connection is in a transaction, as last reported by the server
+    pub fn get_transaction(&self) -> &TransactionState {
+        &self.transaction
+    }
+
+    /// Character set of the SQL text sent by the client
+    pub fn get_client_encoding(&self) -> &'static Encoding {
+        match self.db_type {
+            DatabaseType::MariaDB => self.mariadb.get_client_encoding(),
+            DatabaseType::PostgresSQL => self.postgres.get_client_encoding(),
+        }
+    }
+
+    /// SQL text of a query packet decoded in the client's character set. Bytes invalid in
+    /// that character set are replaced, and reported with `QueryText::lossy`.
+    pub fn get_query(&self, packet: &Packet) -> Result<QueryText, Error> {
+        packet.decode_query(self.get_client_encoding())
+    }
+
+    /// Fingerprint of the SQL of a query packet, or of the prepared statement run by a
+    /// MariaDB COM_STMT_EXECUTE or PostgresSQL Execute
+    pub fn fingerprint_query(&self, packet: &Packet) -> Result<Fingerprint, Error> {
+        let sql = match (self.db_type, packet.get_packet_type()) {
+            (DatabaseType::MariaDB, Ok(PacketType::ComStmtExecute)) => {
+                self.mariadb.get_execute(packet)?.query.clone()
+            }
+            (DatabaseType::PostgresSQL, Ok(PacketType::Execute)) => {
+                self.postgres.get_execute(packet)?.query.clone()
+            }
+            _ => self.get_query(packet)?.text,
+        };
+        Ok(Fingerprint::new(self.get_syntax(), &sql))
+    }
+
+    /// How the SQL of the connection is read, which depends on its session
+    pub fn get_syntax(&self) -> Syntax {
+        Syntax::of_session(self.db_type, &self.session)
+    }
+
+    /// SQL of each statement of a query packet, which can hold a batch of them
+    pub fn split_query(&self, packet: &Packet) -> Result<Vec<String>, Error> {
+        let query = self.get_query(packet)?;
+        Ok(sql::split(self.get_syntax(), &query.text)
+            .into_iter()
+            .map(str::to_string)
+            .collect())
+    }
+
+    /// Syntax tree of the statements of a query packet, parsed in the dialect of the database
+    pub fn parse_query(&self, packet: &Packet) -> Result<Vec<Statement>, Error> {
+        sql::parse(self.db_type, &self.get_query(packet)?.text)
+    }
+
+    /// Update the connection state from a packet read by a pipe, and label the packet with
+    /// its type
+    pub fn decode(&mut self, direction: &Direction, packet: &mut Packet) {
+        match self.db_type {
+            DatabaseType::MariaDB => {
+                if *direction == Direction::Forward && packet.get_sequence_id().ok() == Some(0) {
+                    self.sequence_offset = 0;
+                    self.compression.next_command();
+                    self.local_infile = None;
+                }
+                let authenticating = matches!(
+                    self.mariadb.get_phase(),
+                    Phase::HandshakeResponse | Phase::AuthSwitch
+                );
+                let packet_type = match direction {
+                    Direction::Forward => self.mariadb.decode_request(packet),
+                    Direction::Backward => self.mariadb.decode_response(packet),
+                };
+                if authenticating && packet_type == PacketType::ComOk {
+                    self.compression.authenticated();
+                }
+                packet.set_packet_type(packet_type);
+                self.update_local_infile(packet);
+                self.session
+                    .update_mariadb(direction, &self.mariadb, packet);
+                if *direction == Direction::Backward {
+                    self.transaction.update_mariadb(&self.mariadb, packet);
+                }
+            }
+            DatabaseType::PostgresSQL => {
+                let packet_type = match direction {
+                    Direction::Forward => self.postgres.decode_request(packet),
+                    Direction::Backward => self.postgres.decode_response(packet),
+                };
+                match packet_type {
+                    Ok(packet_type) => packet.set_packet_type(packet_type),
+                    Err(e) => debug!("Unable to label PostgresSQL message: {}", e),
+                }
+                self.session
+                    .update_postgres(direction, &self.postgres, packet);
+                if *direction == Direction::Backward {
+                    self.transaction.update_postgres(packet);
+                }
+            }
+        }
+    }
+
+    fn update_local_infile(&mut self, packet: &Packet) {
+        match packet.get_packet_type() {
+            Ok(PacketType::LocalInfileRequest) => match infile::LocalInfileRequest::parse(packet) {
+                Ok(request) => {
+                    let decision = self.local_infile_policy.decide(&request.filename);
+                    self.local_infile = Some(LocalInfile {
+                        filename: request.filename,
+                        decision,
+                        bytes: 0,
+                    });
+                }
+                Err(e) => warn!("Unable to parse LOCAL INFILE request: {}", e),
+            },
+            Ok(PacketType::LocalInfileData) => {
+                if let Some(local_infile) = self.local_infile.as_mut() {
+                    local_infile.bytes += packet.get_payload().map_or(0, |p| p.len() as u64);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The file the proxy sends for a MariaDB LOCAL INFILE request, with its maximum size,
+    /// when the policy serves it. The pipe reads it without holding the context, and gives
+    /// it to `reply_local_infile`.
+    pub fn get_served_local_infile(&self, request: &Packet) -> Option<(PathBuf, u64)> {
+        if request.get_packet_type().ok() != Some(PacketType::LocalInfileRequest) {
+            return None;
+        }
+        match &self.local_infile.as_ref()?.decision {
+            LocalInfileDecision::Serve { path, max_size } => Some((path.clone(), *max_size)),
+            _ => None,
+        }
+    }
+
+    /// Answer a MariaDB LOCAL INFILE request at the proxy when the policy refuses it or
+    /// serves the file, whose `contents` were read as told by `get_served_local_infile`.
+    /// Returns None when the request is to be forwarded to the client.
+    ///
+    /// A served file is replied to the server without the client seeing the request. A
+    /// refused request, or a served file that could not be read, is replaced by an error
+    /// for the client, and an empty file is replied to the server, whose answer to it is
+    /// then dropped.
+    pub fn reply_local_infile(
+        &mut self,
+        request: &Packet,
+        contents: Option<Result<Vec<u8>, Error>>,
+    ) -> Option<Action> {
+        if request.get_packet_type().ok() != Some(PacketType::LocalInfileRequest) {
+            return None;
+        }
+        let sequence_id = request.get_sequence_id().ok()?;
+        let local_infile = self.local_infile.as_mut()?;
+        let contents = match (&local_infile.decision, contents) {
+            (LocalInfileDecision::Forward, _) => return None,
+            (LocalInfileDecision::Deny(message), _) => Err(message.clone()),
+            (LocalInfileDecision::Serve { .. }, Some(Ok(contents))) => Ok(contents),
+            (LocalInfileDecision::Serve { .. }, Some(Err(e))) => Err(format!(
+                "Unable to read '{}' for LOAD DATA LOCAL INFILE at the proxy: {}",
+                local_infile.filename, e
+            )),
+            (LocalInfileDecision::Serve { .. }, None) => Err(format!(
+                "'{}' was not read for LOAD DATA LOCAL INFILE at the proxy",
+                local_infile.filename
+            )),
+        };
+        let next_sequence_id = sequence_id.wrapping_add(1);
+        match contents {
+            Ok(contents) => Some(Action::Reply(infile::data_packets(
+                next_sequence_id,
+                &contents,
+            ))),
+            Err(message) => {
+                debug!("Refusing LOCAL INFILE request: {}", message);
+                local_infile.decision = LocalInfileDecision::Deny(message.clone());
+                self.drop_local_infile_answer = true;
+                Some(Action::ReplyAndForward(
+                    infile::data_packets(next_sequence_id, &[]),
+                    infile::deny_packet(sequence_id, &message),
+                ))
+            }
+        }
+    }
+
+    /// Append bytes read by a pipe to `packet_buf`, without copying them when they directly
+    /// follow its content in memory. They are decompressed first when the pipe's source uses
+    /// the MariaDB compressed protocol, in which case incomplete compressed packets are kept
+    /// in `compressed_buf`.
+    pub fn read(
+        &mut self,
+        direction: &Direction,
+        bytes: BytesMut,
+        compressed_buf: &mut BytesMut,
+        packet_buf: &mut BytesMut,
+    ) -> Result<(), Error> {
+        let source = Side::source(direction);
+        if self.db_type != DatabaseType::MariaDB || !self.compression.is_active(source) {
+            packet_buf.unsplit(bytes);
+            return Ok(());
+        }
+        compressed_buf.unsplit(bytes);
+        self.compression.read(source, compressed_buf, packet_buf)
+    }
+
+    /// Queue a packet returned by a handler for `received` in `buf`, as sent on the wire.
+    /// See `encode_all`.
+    pub fn encode(
+        &mut self,
+        direction: &Direction,
+        received: &Packet,
+        packet: &Packet,
+        buf: &mut WriteQueue,
+    ) {
+        self.encode_all(direction, received, std::slice::from_ref(packet), buf);
+    }
+
+    /// Queue the packets sent on for `received` in `buf`, as sent on the wire.
+    /// Packets that need no changes are queued without copying them.
+    /// The rest of a PostgresSQL COPY aborted by a handler is dropped.
+    /// MariaDB payloads of 16MB and over are split again, and packets are numbered from the
+    /// sequence id of `received`, shifted so both peers keep seeing consecutive ids when the
+    /// number of physical packets changed.
+    /// The server's answer to the empty file sent for a refused LOCAL INFILE request is dropped.
+    /// The handshake is adjusted to the compression mode, and packets are compressed when the
+    /// pipe's sink uses the compressed protocol.
+    pub fn encode_all(
+        &mut self,
+        direction: &Direction,
+        received: &Packet,
+        packets: &[Packet],
+        buf: &mut WriteQueue,
+    ) {
+        if self.db_type != DatabaseType::MariaDB {
+            for packet in packets {
+                if self.postgres.should_send(direction, received, packet) {
+                    buf.push(packet.bytes.clone());
+                }
+            }
+            return;
+        }
+        if *direction == Direction::Backward
+            && self.drop_local_infile_answer
+            && matches!(
+                received.get_packet_type(),
+                Ok(PacketType::ComOk) | Ok(PacketType::ComErr)
+            )
+        {
+            self.drop_local_infile_answer = false;
+            return;
+        }
+        let rewritten: Option<Vec<Packet>> = match received.get_packet_type() {
+            Ok(PacketType::Handshake) => {
+                let server_capabilities = self
+                    .mariadb
+                    .get_handshake()
+                    .map(|h| h.capabilities)
+                    .unwrap_or_else(CapabilityFlags::empty);
+                Some(
+                    packets
+                        .iter()
+                        .map(|packet| {
+                            self.compression
+                                .rewrite_handshake(server_capabilities, packet)
+                                .unwrap_or_else(|| packet.clone())
+                        })
+                        .collect(),
+                )
+            }
+            Ok(PacketType::HandshakeResponse) => Some(
+                packets
+                    .iter()
+                    .map(|packet| {
+                        self.compression
+                            .rewrite_handshake_response(packet)
+                            .unwrap_or_else(|| packet.clone())
+                    })
+                    .collect(),
+            ),
+            _ => None,
+        };
+        let packets = rewritten.as_deref().unwrap_or(packets);
+        let sink = Side::sink(direction);
+        if self.compression.is_active(sink) {
+            let mut plain = WriteQueue::new();
+            self.encode_mariadb(direction, received, packets, &mut plain);
+            let mut compressed = Vec::new();
+            self.compression
+                .write(sink, &plain.to_bytes(), &mut compressed);
+            buf.push(compressed.into());
+        } else {
+            self.encode_mariadb(direction, received, packets, buf);
+        }
+        if *direction == Direction::Backward {
+            self.compression.start();
+        }
+    }
+
+    /// Give MariaDB sequence ids to the packets a handler replied to the source of
+    /// `direction` for `received`, once the packets sent on for it were encoded. They are
+    /// numbered as if the peer on the sink side sent them, so `encode_replies` gives them the
+    /// ids the source expects.
+    pub fn number_replies(
+        &self,
+        direction: &Direction,
+        received: &Packet,
+        replies: Vec<Packet>,
+    ) -> Vec<Packet> {
+        let sequence_id = match received.get_sequence_id() {
+            Ok(sequence_id) if self.db_type == DatabaseType::MariaDB => sequence_id,
+            _ => return replies,
+        };
+        let mut sequence_id = match direction {
+            Direction::Forward => sequence_id.wrapping_add(self.sequence_offset),
+            Direction::Backward => sequence_id.wrapping_sub(self.sequence_offset),
+        }
+        .wrapping_add(received.get_fragment_count() as u8);
+        replies
+            .into_iter()
+            .map(|reply| match reply.get_payload() {
+                Ok(payload) => {
+                    let numbered = Packet::new_mariadb(sequence_id, payload);
+                    sequence_id = sequence_id.wrapping_add(reply.get_fragment_count() as u8);
+                    numbered
+                }
+                Err(_) => reply,
+            })
+            .collect()
+    }
+
+    /// Queue in `buf` the packets sent back to the source of `direction`, for each of the
+    /// replies numbered by `number_replies` the packets a handler let through. The peer on
+    /// the sink side never sees them.
+    pub fn encode_replies(
+        &mut self,
+        direction: &Direction,
+        replies: &[(Packet, Vec<Packet>)],
+        buf: &mut WriteQueue,
+    ) {
+        let mut fragments: u8 = 0;
+        for (reply, packets) in replies {
+            self.encode_all(&direction.reverse(), reply, packets, buf);
+            fragments = fragments.wrapping_add(reply.get_fragment_count() as u8);
+        }
+        if self.db_type == DatabaseType::MariaDB {
+            self.sequence_offset = match direction {
+                Direction::Forward => self.sequence_offset.wrapping_sub(fragments),
+                Direction::Backward => self.sequence_offset.wrapping_add(fragments),
+            };
+        }
+    }
+
+    fn encode_mariadb(
+        &mut self,
+        direction: &Direction,
+        received: &Packet,
+        packets: &[Packet],
+        buf: &mut WriteQueue,
+    ) {
+        let sequence_id = match received.get_sequence_id() {
+            Ok(sequence_id) => sequence_id,
+            Err(_) => {
+                for packet in packets {
+                    buf.push(packet.bytes.clone());
+                }
+                return;
+            }
+        };
+        let mut sequence_id = match direction {
+            Direction::Forward => sequence_id.wrapping_add(self.sequence_offset),
+            Direction::Backward => sequence_id.wrapping_sub(self.sequence_offset),
+        };
+        let mut sent: u8 = 0;
+        for packet in packets {
+            let fragments = packet.write_mariadb_fragments(sequence_id, buf) as u8;
+            sequence_id = sequence_id.wrapping_add(fragments);
+            sent = sent.wrapping_add(fragments);
+        }
+        let added = sent.wrapping_sub(received.get_fragment_count() as u8);
+        self.sequence_offset = match direction {
+            Direction::Forward => self.sequence_offset.wrapping_add(added),
+            Direction::Backward => self.sequence_offset.wrapping_sub(added),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mariadb::handshake::{HandshakeResponse41, HandshakeV10, StatusFlags};
+    use crate::packet::MARIADB_MAX_PAYLOAD;
+
+    #[test]
+    fn shifts_sequence_ids_when_fragment_count_changes() {
+        let mut context = Context::new("127.0.0.1:1234".to_string(), DatabaseType::MariaDB);
+        let mut large = vec![0x03];
+        large.resize(MARIADB_MAX_PAYLOAD + 1, b'x');
+        let mut query = Packet::new_mariadb(0, &large);
+        context.decode(&Direction::Forward, &mut query);
+
+        // The handler shrinks a two packet query into one
+        let mut buf = WriteQueue::new();
+        let rewritten = Packet::new_mariadb(0, b"\x03SELECT 1");
+        context.encode(&Direction::Forward, &query, &rewritten, &mut buf);
+        assert_eq!(buf.to_bytes(), rewritten.bytes);
+
+        // The server answers with sequence id 1, the client expects 2
+        let mut ok = Packet::new_mariadb(1, &[0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00]);
+        context.decode(&Direction::Backward, &mut ok);
+        let mut buf = WriteQueue::new();
+        context.encode(&Direction::Backward, &ok, &ok, &mut buf);
+        assert_eq!(buf.to_bytes()[3], 2);
+
+        // The next command starts over
+        let mut ping = Packet::new_mariadb(0, &[0x0e]);
+        context.decode(&Direction::Forward, &mut ping);
+        let mut buf = WriteQueue::new();
+        context.encode(&Direction::Forward, &ping, &ping, &mut buf);
+        assert_eq!(buf.to_bytes(), ping.bytes);
+    }
+
+    #[test]
+    fn terminates_compression_at_the_proxy() {
+        let mut context = Context::new("127.0.0.1:1234".to_string(), DatabaseType::MariaDB);
+        context.set_compression_mode(CompressionMode::Server);
+        let capabilities = CapabilityFlags::CLIENT_PROTOCOL_41
+            | CapabilityFlags::CLIENT_SECURE_CONNECTION
+            | CapabilityFlags::CLIENT_PLUGIN_AUTH;
+        let mut handshake = HandshakeV10 {
+            protocol_version: 10,
+            server_version: "10.5.8-MariaDB".to_string(),
+            connection_id: 1,
+            auth_plugin_data: vec![1; 20],
+            capabilities: capabilities | CapabilityFlags::CLIENT_COMPRESS,
+            charset: 45,
+            status: StatusFlags::SERVER_STATUS_AUTOCOMMIT,
+            auth_plugin_name: Some("mysql_native_password".to_string()),
+        }
+        .to_packet();
+        context.decode(&Direction::Backward, &mut handshake);
+        let mut buf = WriteQueue::new();
+        context.encode(&Direction::Backward, &handshake, &handshake, &mut buf);
+        let sent =
+            HandshakeV10::parse(&Packet::new(DatabaseType::MariaDB, buf.to_bytes())).unwrap();
+        assert_eq!(sent.capabilities, capabilities);
+
+        let mut response = HandshakeResponse41 {
+            capabilities,
+            max_packet_size: 16_777_216,
+            charset: 45,
+            username: "root".to_string(),
+            auth_response: vec![2; 20],
+            database: None,
+            auth_plugin_name: Some("mysql_native_password".to_string()),
+            connect_attrs: Vec::new(),
+        }
+        .to_packet(1);
+        context.decode(&Direction::Forward, &mut response);
+        let mut buf = WriteQueue::new();
+        context.encode(&Direction::Forward, &response, &response, &mut buf);
+        let sent = HandshakeResponse41::parse(&Packet::new(DatabaseType::MariaDB, buf.to_bytes()))
+            .unwrap();
+        assert!(sent.capabilities.contains(CapabilityFlags::CLIENT_COMPRESS));
+
+        // The OK ending authentication is not compressed
+        let mut ok = Packet::new_mariadb(2, &[0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00]);
+        context.decode(&Direction::Backward, &mut ok);
+        let mut buf = WriteQueue::new();
+        context.encode(&Direction::Backward, &ok, &ok, &mut buf);
+        assert_eq!(buf.to_bytes(), ok.bytes);
+        assert!(!context.get_compression().is_active(Side::Client));
+        assert!(context.get_compression().is_active(Side::Server));
+
+        // Only the server side is compressed from now on
+        let mut packet_buf = BytesMut::new();
+        let ping = Packet::new_mariadb(0, &[0x0e]);
+        context
+            .read(
+                &Direction::Forward,
+                BytesMut::from(&ping.bytes[..]),
+                &mut BytesMut::new(),
+                &mut packet_buf,
+            )
+            .unwrap();
+        assert_eq!(packet_buf, ping.bytes);
+        let mut ping = Packet::new(DatabaseType::MariaDB, packet_buf.freeze());
+        context.decode(&Direction::Forward, &mut ping);
+        let mut wire = WriteQueue::new();
+        context.encode(&Direction::Forward, &ping, &ping, &mut wire);
+        assert_eq!(wire.to_bytes()[..7], [5, 0, 0, 0, 0, 0, 0]);
+
+        let ok = Packet::new_mariadb(1, &[0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00]);
+        let mut compressed = Compression::new(CompressionMode::Passthrough);
+        let mut wire = Vec::new();
+        compressed.write(Side::Client, &ok.bytes, &mut wire);
+        wire[3] = 1;
+        let mut packet_buf = BytesMut::new();
+        context
+            .read(
+                &Direction::Backward,
+                BytesMut::from(&wire[..]),
+                &mut BytesMut::new(),
+                &mut packet_buf,
+            )
+            .unwrap();
+        assert_eq!(packet_buf, ok.bytes);
+        let mut buf = WriteQueue::new();
+        context.encode(&Direction::Backward, &ok, &ok, &mut buf);
+        assert_eq!(buf.to_bytes(), ok.bytes);
+    }
+
+    fn authenticate(context: &mut Context) {
+        let capabilities = CapabilityFlags::CLIENT_PROTOCOL_41
+            | CapabilityFlags::CLIENT_SECURE_CONNECTION
+            | CapabilityFlags::CLIENT_LOCAL_FILES;
+        let mut handshake = HandshakeV10 {
+            protocol_version: 10,
+            server_version: "10.5.8-MariaDB".to_string(),
+            connection_id: 1,
+            auth_plugin_data: vec![1; 20],
+            capabilities,
+            charset: 45,
+            status: StatusFlags::SERVER_STATUS_AUTOCOMMIT,
+            auth_plugin_name: None,
+        }
+        .to_packet();
+        context.decode(&Direction::Backward, &mut handshake);
+        let mut response = HandshakeResponse41 {
+            capabilities,
+            max_packet_size: 16_777_216,
+            charset: 45,
+            username: "root".to_string(),
+            auth_response: vec![2; 20],
+            database: None,
+            auth_plugin_name: None,
+            connect_attrs: Vec::new(),
+        }
+        .to_packet(1);
+        context.decode(&Direction::Forward, &mut response);
+        let mut ok = Packet::new_mariadb(2, &[0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00]);
+        context.decode(&Direction::Backward, &mut ok);
+    }
+
+    /// Carry out an action like a pipe whose handler lets replies through. Returns the
+    /// bytes sent to the sink, and the ones sent back to the source.
+    fn perform(
+        context: &mut Context,
+        direction: Direction,
+        received: &Packet,
+        action: Action,
+    ) -> (Vec<u8>, Vec<u8>) {
+        let (packets, replies) = action.split();
+        let mut sink = WriteQueue::new();
+        context.encode_all(&direction, received, &packets, &mut sink);
+        let mut handled = Vec::new();
+        for mut reply in context.number_replies(&direction, received, replies) {
+            context.decode(&direction.reverse(), &mut reply);
+            handled.push((reply.clone(), vec![reply]));
+        }
+        let mut source = WriteQueue::new();
+        context.encode_replies(&direction, &handled, &mut source);
+        (sink.to_bytes().to_vec(), source.to_bytes().to_vec())
+    }
+
+    #[test]
+    fn replies_with_consecutive_sequence_ids() {
+        let mut context = Context::new("127.0.0.1:1234".to_string(), DatabaseType::MariaDB);
+        authenticate(&mut context);
+        let ok = |sequence_id| {
+            Packet::new_mariadb(sequence_id, &[0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00])
+        };
+
+        // A query answered by the proxy
+        let mut query = Packet::new_mariadb(0, b"\x03SELECT 1");
+        context.decode(&Direction::Forward, &mut query);
+        let (to_server, to_client) = perform(
+            &mut context,
+            Direction::Forward,
+            &query,
+            Action::Reply(vec![ok(0)]),
+        );
+        assert!(to_server.is_empty());
+        assert_eq!(to_client, ok(1).bytes);
+
+        // A reply to the client ahead of the server's answer
+        let mut query = Packet::new_mariadb(0, b"\x03SELECT 2");
+        context.decode(&Direction::Forward, &mut query);
+        let (to_server, to_client) = perform(
+            &mut context,
+            Direction::Forward,
+            &query,
+            Action::ReplyAndForward(vec![ok(0)], query.clone()),
+        );
+        assert_eq!(to_server, query.bytes);
+        assert_eq!(to_client, ok(1).bytes);
+        let mut answer = ok(1);
+        context.decode(&Direction::Backward, &mut answer);
+        let (to_client, _) = perform(
+            &mut context,
+            Direction::Backward,
+            &answer,
+            Action::Forward(answer.clone()),
+        );
+        assert_eq!(to_client, ok(2).bytes);
+
+        // A query split in two, with the second answer dropped
+        let mut query = Packet::new_mariadb(0, b"\x03SELECT 3; SELECT 4");
+        context.decode(&Direction::Forward, &mut query);
+        let first = Packet::new_mariadb(0, b"\x03SELECT 3");
+        let (to_server, _) = perform(
+            &mut context,
+            Direction::Forward,
+            &query,
+            Action::Replace(vec![first.clone()]),
+        );
+        assert_eq!(to_server, first.bytes);
+        let mut answer = ok(1);
+        context.decode(&Direction::Backward, &mut answer);
+        let (to_client, _) = perform(&mut context, Direction::Backward, &answer, Action::Drop);
+        assert!(to_client.is_empty());
+    }
+
+    #[test]
+    fn splits_queries_in_the_session_syntax() {
+        let mut context = Context::new("127.0.0.1:1234".to_string(), DatabaseType::MariaDB);
+        authenticate(&mut context);
+        let batch = Packet::new_mariadb(0, b"\x03SELECT 'a\\'; DROP TABLE users; SELECT '");
+        assert_eq!(context.split_query(&batch).unwrap().len(), 1);
+
+        let mut set = Packet::new_mariadb(0, b"\x03SET sql_mode = 'ANSI,NO_BACKSLASH_ESCAPES'");
+        context.decode(&Direction::Forward, &mut set);
+        let mut ok = Packet::new_mariadb(1, &[0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00]);
+        context.decode(&Direction::Backward, &mut ok);
+        assert!(!context.get_syntax().backslash_escapes);
+        assert_eq!(
+            context.split_query(&batch).unwrap(),
+            vec!["SELECT 'a\\'", "DROP TABLE users", "SELECT '"]
+        );
+    }
+
+    #[test]
+    fn answers_local_infile_requests_at_the_proxy() {
+        let mut context = Context::new("127.0.0.1:1234".to_string(), DatabaseType::MariaDB);
+        authenticate(&mut context);
+        context.set_local_infile_policy(LocalInfilePolicy::Deny);
+        let mut query =
+            Packet::new_mariadb(0, b"\x03LOAD DATA LOCAL INFILE '/etc/passwd' INTO TABLE t");
+        context.decode(&Direction::Forward, &mut query);
+        let mut request = Packet::new_mariadb(1, b"\xfb/etc/passwd");
+        context.decode(&Direction::Backward, &mut request);
+        assert!(context.get_served_local_infile(&request).is_none());
+        let action = context.reply_local_infile(&request, None).unwrap();
+        let (to_client, to_server) = perform(&mut context, Direction::Backward, &request, action);
+        assert_eq!(to_server, Packet::new_mariadb(2, &[]).bytes);
+        assert_eq!(&to_client[3..5], &[1, 0xff]);
+
+        // The server's answer to the empty file is not forwarded to the client
+        let mut ok = Packet::new_mariadb(3, &[0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00]);
+        context.decode(&Direction::Backward, &mut ok);
+        let mut buf = WriteQueue::new();
+        context.encode(&Direction::Backward, &ok, &ok, &mut buf);
+        assert!(buf.is_empty());
+
+        let directory = std::env::temp_dir().join(format!("infile-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("a.csv"), b"1,2\n").unwrap();
+        context.set_local_infile_policy(LocalInfilePolicy::Serve {
+            directory: directory.clone(),
+            max_size: 4,
+        });
+        let mut query = Packet::new_mariadb(0, b"\x03LOAD DATA LOCAL INFILE 'a.csv' INTO TABLE t");
+        context.decode(&Direction::Forward, &mut query);
+        let mut request = Packet::new_mariadb(1, b"\xfba.csv");
+        context.decode(&Direction::Backward, &mut request);
+        let (path, max_size) = context.get_served_local_infile(&request).unwrap();
+        assert_eq!(max_size, 4);
+        let contents = std::fs::read(path);
+        std::fs::remove_dir_all(&directory).unwrap();
+        let action = context
+            .reply_local_infile(&request, Some(contents))
+            .unwrap();
+        let (to_client, to_server) = perform(&mut context, Direction::Backward, &request, action);
+        assert!(to_client.is_empty());
+        let mut file = Packet::new_mariadb(2, b"1,2\n").bytes.to_vec();
+        file.extend_from_slice(&Packet::new_mariadb(3, &[]).bytes);
+        assert_eq!(to_server, file);
+        assert_eq!(context.get_local_infile().unwrap().bytes, 4);
+
+        // The client sees the server's answer in place of the request
+        let mut ok = Packet::new_mariadb(4, &[0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00]);
+        context.decode(&Direction::Backward, &mut ok);
+        let mut buf = WriteQueue::new();
+        context.encode(&Direction::Backward, &ok, &ok, &mut buf);
+        assert_eq!(buf.to_bytes()[3], 1);
+    }
+}
@@ -0,0 +1,273 @@
+//! Query fingerprints: SQL normalized to its shape, with literals replaced by placeholders,
+//! and a stable 64-bit digest of it to aggregate, allowlist or cache queries by shape
+
+use std::fmt;
+
+use crate::sql::{self, Syntax, TokenKind};
+
+/// The shape of a query. Literals and parameters become `?`, IN lists of them become
+/// `(...)`, comments are removed, unquoted words are lowercased and tokens are separated by
+/// single spaces. A query and the same query prepared with parameters share a fingerprint.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Fingerprint {
+    pub normalized: String,
+    /// FNV-1a hash of `normalized`, which does not change between runs or versions
+    pub digest: u64,
+}
+
+impl Fingerprint {
+    pub fn new<S: Into<Syntax>>(syntax: S, sql: &str) -> Fingerprint {
+        let normalized = normalize(syntax, sql);
+        Fingerprint {
+            digest: digest(&normalized),
+            normalized,
+        }
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}", self.digest)
+    }
+}
+
+/// Normalize SQL text, see `Fingerprint`
+pub fn normalize<S: Into<Syntax>>(syntax: S, sql: &str) -> String {
+    let tokens = collapse_lists(tokenize(syntax.into(), sql));
+    let mut normalized = String::with_capacity(sql.len());
+    let mut last: Option<&Token> = None;
+    for token in &tokens {
+        let text = token.text();
+        let glued = last.is_none_or(|last| matches!(last.text(), "(" | "[" | "." | "::"))
+            || matches!(text, ")" | "]" | "," | "." | "::" | ";");
+        if !glued {
+            normalized.push(' ');
+        }
+        normalized.push_str(text);
+        last = Some(token);
+    }
+    normalized
+}
+
+/// 64-bit FNV-1a hash
+pub fn digest(normalized: &str) -> u64 {
+    normalized
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+        })
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    /// Keyword or unquoted identifier, lowercased
+    Word(String),
+    /// Quoted identifier, with its quotes
+    Quoted(String),
+    /// Literal or parameter
+    Value,
+    /// Collapsed list of values
+    List,
+    Punct(String),
+}
+
+impl Token {
+    fn text(&self) -> &str {
+        match self {
+            Token::Word(text) | Token::Quoted(text) | Token::Punct(text) => text,
+            Token::Value => "?",
+            Token::List => "...",
+        }
+    }
+}
+
+fn tokenize(syntax: Syntax, sql: &str) -> Vec<Token> {
+    let mut tokens: Vec<Token> = Vec::new();
+    // End of the last token, to glue operator characters written together
+    let mut last_end = None;
+    let mut sql_tokens = sql::tokenize(syntax, sql).into_iter().peekable();
+    while let Some(token) = sql_tokens.next() {
+        let adjacent = last_end == Some(token.start);
+        last_end = Some(token.end());
+        match token.kind {
+            TokenKind::Word => tokens.push(Token::Word(token.text.to_lowercase())),
+            TokenKind::Identifier => tokens.push(Token::Quoted(token.text.to_string())),
+            TokenKind::String | TokenKind::Parameter => tokens.push(Token::Value),
+            TokenKind::Number => {
+                // A sign directly before a number is part of the literal, unless it follows
+                // an operand
+                if let Some(Token::Punct(sign)) = tokens.last() {
+                    if (sign == "-" || sign == "+") && unary(&tokens[..tokens.len() - 1]) {
+                        tokens.pop();
+                    }
+                }
+                tokens.push(Token::Value);
+            }
+            TokenKind::Symbol if token.text == ":" => match tokens.last_mut() {
+                Some(Token::Punct(colon)) if adjacent && colon == ":" => colon.push(':'),
+                _ => tokens.push(Token::Punct(":".to_string())),
+            },
+            TokenKind::Symbol if is_operator(token.text) => {
+                let mut operator = token.text.to_string();
+                while let Some(next) = sql_tokens.next_if(|next| {
+                    next.kind == TokenKind::Symbol
+                        && is_operator(next.text)
+                        && next.start == last_end.unwrap_or_default()
+                }) {
+                    operator.push_str(next.text);
+                    last_end = Some(next.end());
+                }
+                // As in PostgresSQL, an operator only ends with a sign when it has characters
+                // no standard operator uses, so `=-1` compares with a negative number
+                let mut signs = String::new();
+                if !operator.contains(['~', '!', '@', '#', '%', '^', '&', '|']) {
+                    while operator.len() > 1 && operator.ends_with(['+', '-']) {
+                        signs.insert(0, operator.pop().unwrap_or_default());
+                    }
+                }
+                tokens.push(Token::Punct(operator));
+                tokens.extend(signs.chars().map(|sign| Token::Punct(sign.to_string())));
+            }
+            TokenKind::Symbol => tokens.push(Token::Punct(token.text.to_string())),
+        }
+    }
+    // Trailing semicolons do not change the query
+    while tokens.last().is_some_and(|token| token.text() == ";") {
+        tokens.pop();
+    }
+    tokens
+}
+
+/// Whether a sign after these tokens applies to the next operand alone
+fn unary(before: &[Token]) -> bool {
+    match before.last() {
+        None => true,
+        Some(Token::Punct(punct)) => punct != ")" && punct != "]",
+        Some(Token::Word(word)) => matches!(
+            word.as_str(),
+            "select"
+                | "where"
+                | "and"
+                | "or"
+                | "not"
+                | "when"
+                | "then"
+                | "else"
+                | "by"
+                | "limit"
+                | "offset"
+                | "values"
+                | "set"
+                | "between"
+                | "return"
+                | "in"
+        ),
+        Some(_) => false,
+    }
+}
+
+/// Replace IN lists of values with a single `...`, so their length does not matter
+fn collapse_lists(tokens: Vec<Token>) -> Vec<Token> {
+    let mut collapsed = Vec::with_capacity(tokens.len());
+    for token in tokens {
+        let closes = token.text() == ")";
+        collapsed.push(token);
+        if closes {
+            collapse_list_end(&mut collapsed);
+        }
+    }
+    collapsed
+}
+
+/// Collapse the list ending the tokens when it is `in (?, ?, …)`
+fn collapse_list_end(tokens: &mut Vec<Token>) {
+    // Index of the ")"
+    let mut start = tokens.len() - 1;
+    loop {
+        if start < 2 || tokens[start - 1] != Token::Value {
+            return;
+        }
+        start -= 2;
+        match tokens[start].text() {
+            "," => {}
+            "(" => break,
+            _ => return,
+        }
+    }
+    if start > 0 && tokens[start - 1] == Token::Word("in".to_string()) {
+        tokens.truncate(start);
+        tokens.push(Token::Punct("(".to_string()));
+        tokens.push(Token::List);
+        tokens.push(Token::Punct(")".to_string()));
+    }
+}
+
+fn is_operator(symbol: &str) -> bool {
+    matches!(
+        symbol,
+        "+" | "-" | "*" | "/" | "<" | ">" | "=" | "~" | "!" | "@" | "#" | "%" | "^" | "&" | "|"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::DatabaseType;
+
+    #[test]
+    fn normalizes_queries() {
+        let mariadb = |sql| normalize(DatabaseType::MariaDB, sql);
+        assert_eq!(
+            mariadb(
+                "SELECT  name, `Total`  FROM users /* admin */ WHERE id IN (1, 2,3)\n\
+                 AND email = 'a@b.c' AND note = \"it\\\"s\" AND score > -1.5e3 # trailing\n;"
+            ),
+            "select name, `Total` from users where id in (...) and email = ? and note = ? and score > ?"
+        );
+        assert_eq!(
+            mariadb("select name,`Total` from USERS where id in (?) and email=_utf8mb4'x' and note=? and score>?"),
+            mariadb("SELECT name, `Total` FROM users WHERE id IN (1, 2) AND email = 'a' AND note = 'b' AND score > 2")
+        );
+        assert_eq!(
+            mariadb("SELECT a-1, @@session.x"),
+            "select a - ?, @@session.x"
+        );
+
+        let postgres = |sql| normalize(DatabaseType::PostgresSQL, sql);
+        assert_eq!(
+            postgres(
+                "select \"Id\", payload->>'k' from t where x = ANY($1::int[]) and s = E'it\\'s' \
+                 and body = $fn$ it's $$ $fn$ /* outer /* inner */ */ and f(x) not in ($2, $3)"
+            ),
+            "select \"Id\", payload ->> ? from t where x = any (?::int []) and s = ? and body = ? and f (x) not in (...)"
+        );
+        assert_eq!(
+            postgres("SELECT x FROM t WHERE y=-1"),
+            "select x from t where y = ?"
+        );
+        assert_eq!(postgres("SELECT $$a$$;;"), "select ?");
+    }
+
+    #[test]
+    fn digests_shapes() {
+        let simple = Fingerprint::new(
+            DatabaseType::PostgresSQL,
+            "SELECT * FROM orders WHERE id = 42",
+        );
+        let prepared = Fingerprint::new(
+            DatabaseType::PostgresSQL,
+            "select *\n  from orders\n where id = $1",
+        );
+        assert_eq!(simple, prepared);
+        assert_eq!(simple.normalized, "select * from orders where id = ?");
+        let other = Fingerprint::new(DatabaseType::PostgresSQL, "SELECT * FROM orders");
+        assert_ne!(simple.digest, other.digest);
+        // FNV-1a test vectors
+        assert_eq!(digest(""), 0xcbf29ce484222325);
+        assert_eq!(digest("a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(
+            format!("{}", Fingerprint::new(DatabaseType::MariaDB, "")),
+            "cbf29ce484222325"
+        );
+    }
+}
@@ -3,10 +3,18 @@
 #[macro_use]
 extern crate log;
 
+pub mod context;
+pub mod fingerprint;
+pub mod mariadb;
 pub mod packet;
 pub mod packet_handler;
 pub mod pipe;
+pub mod policy;
+pub mod postgres;
 pub mod server;
+pub mod session;
+pub mod sql;
+pub mod transaction;
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,1239 @@
+//! Replication: the binlog events sent by the server after COM_BINLOG_DUMP or
+//! COM_BINLOG_DUMP_GTID, and the row changes of row-based replication
+//! https://mariadb.com/kb/en/replication-protocol/
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use flate2::Crc;
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{Error, ErrorKind},
+};
+
+use super::{to_string, value::*, Reader};
+use crate::{
+    packet::{Packet, PacketType},
+    postgres::value::civil_from_days,
+};
+
+// Event types, see Log_event_type in log_event.h
+pub const QUERY_EVENT: u8 = 2;
+pub const ROTATE_EVENT: u8 = 4;
+pub const FORMAT_DESCRIPTION_EVENT: u8 = 15;
+pub const XID_EVENT: u8 = 16;
+pub const TABLE_MAP_EVENT: u8 = 19;
+pub const WRITE_ROWS_EVENT_V1: u8 = 23;
+pub const UPDATE_ROWS_EVENT_V1: u8 = 24;
+pub const DELETE_ROWS_EVENT_V1: u8 = 25;
+pub const HEARTBEAT_LOG_EVENT: u8 = 27;
+pub const WRITE_ROWS_EVENT: u8 = 30;
+pub const UPDATE_ROWS_EVENT: u8 = 31;
+pub const DELETE_ROWS_EVENT: u8 = 32;
+pub const ANNOTATE_ROWS_EVENT: u8 = 160;
+pub const BINLOG_CHECKPOINT_EVENT: u8 = 161;
+pub const GTID_EVENT: u8 = 162;
+pub const GTID_LIST_EVENT: u8 = 163;
+
+/// Common header of every event
+pub const EVENT_HEADER_LEN: usize = 19;
+
+/// CRC32 appended to events when binlog_checksum is CRC32
+pub const CHECKSUM_LEN: usize = 4;
+
+/// COM_BINLOG_DUMP flag: send EOF at the end of the last binlog instead of waiting
+pub const BINLOG_DUMP_NON_BLOCK: u16 = 1;
+
+/// COM_BINLOG_DUMP_GTID flag: the GTID set is sent
+const BINLOG_THROUGH_GTID: u16 = 4;
+
+/// Rows event flag: last rows event of a statement
+pub const STMT_END_F: u16 = 1;
+
+const BINLOG_CHECKSUM_ALG_CRC32: u8 = 1;
+
+// Optional metadata of table map events, with binlog_row_metadata
+const SIGNEDNESS: u8 = 1;
+const COLUMN_NAME: u8 = 4;
+
+/// COM_BINLOG_DUMP or COM_BINLOG_DUMP_GTID, sent by a replica to start the binlog stream
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BinlogDump {
+    pub position: u64,
+    pub flags: u16,
+    /// Server id of the replica
+    pub server_id: u32,
+    /// Binlog file to start from, empty for the first one
+    pub filename: String,
+    /// Encoded GTID set of COM_BINLOG_DUMP_GTID. MariaDB replicas send COM_BINLOG_DUMP
+    /// instead, after setting @slave_connect_state.
+    pub gtid_set: Vec<u8>,
+}
+
+impl BinlogDump {
+    pub fn parse(packet: &Packet) -> Result<BinlogDump, Error> {
+        let mut r = Reader::new(packet.get_payload()?);
+        match r.read_u8()? {
+            0x12 => {
+                let position = r.read_u32()? as u64;
+                let flags = r.read_u16()?;
+                let server_id = r.read_u32()?;
+                Ok(BinlogDump {
+                    position,
+                    flags,
+                    server_id,
+                    filename: to_string(r.read_rest())?,
+                    gtid_set: Vec::new(),
+                })
+            }
+            0x1e => {
+                let flags = r.read_u16()?;
+                let server_id = r.read_u32()?;
+                let len = r.read_u32()? as usize;
+                let filename = to_string(r.read_bytes(len)?)?;
+                let position = r.read_u64()?;
+                let gtid_set = if flags & BINLOG_THROUGH_GTID != 0 {
+                    let len = r.read_u32()? as usize;
+                    r.read_bytes(len)?.to_vec()
+                } else {
+                    Vec::new()
+                };
+                Ok(BinlogDump {
+                    position,
+                    flags,
+                    server_id,
+                    filename,
+                    gtid_set,
+                })
+            }
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Packet is not a COM_BINLOG_DUMP",
+            )),
+        }
+    }
+
+    /// The server sends EOF at the end of the last binlog instead of waiting for more events
+    pub fn is_non_blocking(&self) -> bool {
+        self.flags & BINLOG_DUMP_NON_BLOCK != 0
+    }
+}
+
+/// A MariaDB global transaction id, written like `0-1-100`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Gtid {
+    pub domain_id: u32,
+    pub server_id: u32,
+    pub sequence: u64,
+}
+
+impl fmt::Display for Gtid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}-{}", self.domain_id, self.server_id, self.sequence)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EventHeader {
+    /// Seconds since the Unix epoch
+    pub timestamp: u32,
+    pub event_type: u8,
+    /// Server id of the server that wrote the event
+    pub server_id: u32,
+    pub event_size: u32,
+    /// Position of the next event in the binlog file, 0 for events that are not in a file
+    pub log_pos: u32,
+    pub flags: u16,
+}
+
+/// Sent first in each binlog file, it describes the events that follow
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FormatDescription {
+    pub binlog_version: u16,
+    pub server_version: String,
+    pub create_timestamp: u32,
+    pub header_length: u8,
+    /// Post-header length of each event type, starting from type 1
+    pub post_header_lengths: Vec<u8>,
+    /// Events end with a CRC32
+    pub checksum: bool,
+}
+
+/// Column types of a table, sent before the rows events changing it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TableMap {
+    pub table_id: u64,
+    pub schema: String,
+    pub table: String,
+    pub column_types: Vec<u8>,
+    /// Type parameters, like the maximum length of VARCHAR or the precision of DECIMAL
+    pub column_metadata: Vec<u16>,
+    pub nullable: Vec<bool>,
+    /// Numeric columns declared UNSIGNED, only known with binlog_row_metadata
+    pub unsigned: Vec<bool>,
+    /// Column names, only sent with binlog_row_metadata=FULL
+    pub column_names: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChangeKind {
+    Insert,
+    Update,
+    Delete,
+}
+
+/// Rows event, version 1 or 2. Rows are decoded with the table map of the table.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RowsEvent {
+    pub kind: ChangeKind,
+    pub table_id: u64,
+    pub flags: u16,
+    pub column_count: usize,
+    /// Columns sent in the rows, all of them unless binlog_row_image is not FULL
+    pub present: Vec<bool>,
+    /// Columns sent in the after image of updated rows
+    pub present_after: Vec<bool>,
+    /// Encoded rows, an update sends the row before and after it
+    pub rows: Vec<u8>,
+}
+
+/// A row image, with None for columns not sent
+pub type Row = Vec<Option<Value>>;
+
+/// A row changed by a rows event
+#[derive(Clone, Debug, PartialEq)]
+pub struct RowChange {
+    /// Deleted row, or updated row before the update
+    pub before: Option<Row>,
+    /// Inserted row, or updated row after the update
+    pub after: Option<Row>,
+}
+
+impl RowsEvent {
+    /// The rows of the event, decoded with the table map of its table
+    pub fn decode_rows(&self, table: &TableMap) -> Result<Vec<RowChange>, Error> {
+        if table.column_types.len() != self.column_count {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Rows event has {} columns, but table {}.{} has {}",
+                    self.column_count,
+                    table.schema,
+                    table.table,
+                    table.column_types.len()
+                ),
+            ));
+        }
+        let mut r = Reader::new(&self.rows);
+        let mut rows = Vec::new();
+        while !r.is_empty() {
+            let row = match self.kind {
+                ChangeKind::Insert => RowChange {
+                    before: None,
+                    after: Some(read_row(&mut r, table, &self.present)?),
+                },
+                ChangeKind::Delete => RowChange {
+                    before: Some(read_row(&mut r, table, &self.present)?),
+                    after: None,
+                },
+                ChangeKind::Update => RowChange {
+                    before: Some(read_row(&mut r, table, &self.present)?),
+                    after: Some(read_row(&mut r, table, &self.present_after)?),
+                },
+            };
+            rows.push(row);
+        }
+        Ok(rows)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum EventData {
+    FormatDescription(FormatDescription),
+    /// The binlog continues in another file. A fake rotate, with a log_pos of 0, starts the
+    /// stream.
+    Rotate {
+        position: u64,
+        filename: String,
+    },
+    /// Starts a transaction, or a standalone statement
+    Gtid {
+        gtid: Gtid,
+        flags: u8,
+        /// Transactions with the same commit id were group committed
+        commit_id: Option<u64>,
+    },
+    /// Last GTID of each replication domain, at the start of each binlog file
+    GtidList(Vec<Gtid>),
+    Query {
+        thread_id: u32,
+        exec_time: u32,
+        error_code: u16,
+        /// Default database of the statement
+        schema: String,
+        query: String,
+    },
+    /// Commits a transaction
+    Xid(u64),
+    /// The statement that changed the rows of the following rows events, with
+    /// binlog_annotate_row_events
+    AnnotateRows(String),
+    TableMap(TableMap),
+    Rows(RowsEvent),
+    /// Any other event, with its body
+    Other(Vec<u8>),
+}
+
+/// A binlog event, as sent by the server after an OK byte
+#[derive(Clone, Debug, PartialEq)]
+pub struct BinlogEvent {
+    pub header: EventHeader,
+    pub data: EventData,
+}
+
+impl BinlogEvent {
+    /// Parse an event. `checksum` tells whether events end with a CRC32, which the format
+    /// description event says.
+    pub fn parse(packet: &Packet, checksum: bool) -> Result<BinlogEvent, Error> {
+        let payload = packet.get_payload()?;
+        if payload.first() != Some(&0x00) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Packet is not a binlog event",
+            ));
+        }
+        let event = &payload[1..];
+        let mut r = Reader::new(event);
+        let header = EventHeader {
+            timestamp: r.read_u32()?,
+            event_type: r.read_u8()?,
+            server_id: r.read_u32()?,
+            event_size: r.read_u32()?,
+            log_pos: r.read_u32()?,
+            flags: r.read_u16()?,
+        };
+        // The format description always ends with the checksum algorithm and a checksum,
+        // which is only meaningful when the algorithm is CRC32
+        let (checksum, trailer) = if header.event_type == FORMAT_DESCRIPTION_EVENT {
+            let alg = event.len().checked_sub(CHECKSUM_LEN + 1).map(|i| event[i]);
+            (alg == Some(BINLOG_CHECKSUM_ALG_CRC32), CHECKSUM_LEN + 1)
+        } else if checksum {
+            (true, CHECKSUM_LEN)
+        } else {
+            (false, 0)
+        };
+        if event.len() < EVENT_HEADER_LEN + trailer {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Packet too short reading binlog event",
+            ));
+        }
+        if checksum && !has_valid_checksum(event) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Binlog event checksum mismatch",
+            ));
+        }
+        let body = &event[EVENT_HEADER_LEN..event.len() - trailer];
+        let data = parse_event_data(&header, body, &event[event.len() - trailer..])?;
+        Ok(BinlogEvent { header, data })
+    }
+}
+
+/// Whether an event ends with the CRC32 of the rest of it
+fn has_valid_checksum(event: &[u8]) -> bool {
+    if event.len() < EVENT_HEADER_LEN + CHECKSUM_LEN {
+        return false;
+    }
+    let (data, checksum) = event.split_at(event.len() - CHECKSUM_LEN);
+    let mut crc = Crc::new();
+    crc.update(data);
+    crc.sum() == LittleEndian::read_u32(checksum)
+}
+
+fn parse_event_data(header: &EventHeader, body: &[u8], trailer: &[u8]) -> Result<EventData, Error> {
+    let mut r = Reader::new(body);
+    let data = match header.event_type {
+        FORMAT_DESCRIPTION_EVENT => {
+            let binlog_version = r.read_u16()?;
+            let server_version = r.read_bytes(50)?;
+            let end = server_version.iter().position(|b| *b == 0).unwrap_or(50);
+            EventData::FormatDescription(FormatDescription {
+                binlog_version,
+                server_version: to_string(&server_version[..end])?,
+                create_timestamp: r.read_u32()?,
+                header_length: r.read_u8()?,
+                post_header_lengths: r.read_rest().to_vec(),
+                checksum: trailer.first() == Some(&BINLOG_CHECKSUM_ALG_CRC32),
+            })
+        }
+        ROTATE_EVENT => EventData::Rotate {
+            position: r.read_u64()?,
+            filename: to_string(r.read_rest())?,
+        },
+        GTID_EVENT => {
+            let sequence = r.read_u64()?;
+            let domain_id = r.read_u32()?;
+            let flags = r.read_u8()?;
+            // FL_GROUP_COMMIT_ID
+            let commit_id = if flags & 2 != 0 {
+                Some(r.read_u64()?)
+            } else {
+                None
+            };
+            EventData::Gtid {
+                gtid: Gtid {
+                    domain_id,
+                    server_id: header.server_id,
+                    sequence,
+                },
+                flags,
+                commit_id,
+            }
+        }
+        GTID_LIST_EVENT => {
+            let count = r.read_u32()? & 0x0fff_ffff;
+            let mut gtids = Vec::new();
+            for _ in 0..count {
+                gtids.push(Gtid {
+                    domain_id: r.read_u32()?,
+                    server_id: r.read_u32()?,
+                    sequence: r.read_u64()?,
+                });
+            }
+            EventData::GtidList(gtids)
+        }
+        QUERY_EVENT => {
+            let thread_id = r.read_u32()?;
+            let exec_time = r.read_u32()?;
+            let schema_len = r.read_u8()? as usize;
+            let error_code = r.read_u16()?;
+            let status_vars_len = r.read_u16()? as usize;
+            r.read_bytes(status_vars_len)?;
+            let schema = to_string(r.read_bytes(schema_len)?)?;
+            r.read_u8()?;
+            EventData::Query {
+                thread_id,
+                exec_time,
+                error_code,
+                schema,
+                query: String::from_utf8_lossy(r.read_rest()).into_owned(),
+            }
+        }
+        XID_EVENT => EventData::Xid(r.read_u64()?),
+        ANNOTATE_ROWS_EVENT => {
+            EventData::AnnotateRows(String::from_utf8_lossy(r.read_rest()).into_owned())
+        }
+        TABLE_MAP_EVENT => EventData::TableMap(parse_table_map(&mut r)?),
+        WRITE_ROWS_EVENT_V1 | UPDATE_ROWS_EVENT_V1 | DELETE_ROWS_EVENT_V1 | WRITE_ROWS_EVENT
+        | UPDATE_ROWS_EVENT | DELETE_ROWS_EVENT => {
+            EventData::Rows(parse_rows(header.event_type, &mut r)?)
+        }
+        _ => EventData::Other(body.to_vec()),
+    };
+    Ok(data)
+}
+
+fn read_table_id(r: &mut Reader) -> Result<u64, Error> {
+    Ok(LittleEndian::read_u48(r.read_bytes(6)?))
+}
+
+/// A bitmap of `count` bits, least significant bit first
+fn read_bitmap(r: &mut Reader, count: usize) -> Result<Vec<bool>, Error> {
+    let bytes = r.read_bytes(count.div_ceil(8))?;
+    Ok((0..count)
+        .map(|i| bytes[i / 8] & (1 << (i % 8)) != 0)
+        .collect())
+}
+
+fn parse_table_map(r: &mut Reader) -> Result<TableMap, Error> {
+    let table_id = read_table_id(r)?;
+    r.read_u16()?;
+    let len = r.read_u8()? as usize;
+    let schema = to_string(r.read_bytes(len)?)?;
+    r.read_u8()?;
+    let len = r.read_u8()? as usize;
+    let table = to_string(r.read_bytes(len)?)?;
+    r.read_u8()?;
+    let count = r.read_lenenc_int()?.unwrap_or(0) as usize;
+    let column_types = r.read_bytes(count)?.to_vec();
+    let mut metadata = Reader::new(r.read_lenenc_bytes()?.unwrap_or(&[]));
+    let mut column_metadata = Vec::with_capacity(count);
+    for column_type in &column_types {
+        let meta = match *column_type {
+            MYSQL_TYPE_FLOAT
+            | MYSQL_TYPE_DOUBLE
+            | MYSQL_TYPE_BLOB
+            | MYSQL_TYPE_GEOMETRY
+            | MYSQL_TYPE_JSON
+            | MYSQL_TYPE_TIMESTAMP2
+            | MYSQL_TYPE_DATETIME2
+            | MYSQL_TYPE_TIME2 => metadata.read_u8()? as u16,
+            MYSQL_TYPE_VARCHAR | MYSQL_TYPE_VAR_STRING | MYSQL_TYPE_BIT => metadata.read_u16()?,
+            MYSQL_TYPE_NEWDECIMAL | MYSQL_TYPE_STRING | MYSQL_TYPE_ENUM | MYSQL_TYPE_SET => {
+                BigEndian::read_u16(metadata.read_bytes(2)?)
+            }
+            _ => 0,
+        };
+        column_metadata.push(meta);
+    }
+    let nullable = read_bitmap(r, count)?;
+
+    let mut unsigned = vec![false; count];
+    let mut column_names = Vec::new();
+    while !r.is_empty() {
+        let field_type = r.read_u8()?;
+        let value = r.read_lenenc_bytes()?.unwrap_or(&[]);
+        match field_type {
+            // One bit per numeric column, most significant bit first
+            SIGNEDNESS => {
+                let numeric = column_types
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, t)| is_numeric(**t));
+                for (bit, (i, _)) in numeric.enumerate() {
+                    unsigned[i] = value
+                        .get(bit / 8)
+                        .is_some_and(|b| b & (0x80 >> (bit % 8)) != 0);
+                }
+            }
+            COLUMN_NAME => {
+                let mut names = Reader::new(value);
+                while !names.is_empty() {
+                    column_names.push(to_string(names.read_lenenc_bytes()?.unwrap_or(&[]))?);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(TableMap {
+        table_id,
+        schema,
+        table,
+        column_types,
+        column_metadata,
+        nullable,
+        unsigned,
+        column_names,
+    })
+}
+
+fn is_numeric(column_type: u8) -> bool {
+    matches!(
+        column_type,
+        MYSQL_TYPE_TINY
+            | MYSQL_TYPE_SHORT
+            | MYSQL_TYPE_INT24
+            | MYSQL_TYPE_LONG
+            | MYSQL_TYPE_LONGLONG
+            | MYSQL_TYPE_FLOAT
+            | MYSQL_TYPE_DOUBLE
+            | MYSQL_TYPE_NEWDECIMAL
+    )
+}
+
+fn parse_rows(event_type: u8, r: &mut Reader) -> Result<RowsEvent, Error> {
+    let kind = match event_type {
+        WRITE_ROWS_EVENT_V1 | WRITE_ROWS_EVENT => ChangeKind::Insert,
+        UPDATE_ROWS_EVENT_V1 | UPDATE_ROWS_EVENT => ChangeKind::Update,
+        _ => ChangeKind::Delete,
+    };
+    let table_id = read_table_id(r)?;
+    let flags = r.read_u16()?;
+    if event_type >= WRITE_ROWS_EVENT {
+        // The length of the extra data includes itself
+        let len = r.read_u16()? as usize;
+        r.read_bytes(len.saturating_sub(2))?;
+    }
+    let column_count = r.read_lenenc_int()?.unwrap_or(0) as usize;
+    let present = read_bitmap(r, column_count)?;
+    let present_after = if kind == ChangeKind::Update {
+        read_bitmap(r, column_count)?
+    } else {
+        present.clone()
+    };
+    Ok(RowsEvent {
+        kind,
+        table_id,
+        flags,
+        column_count,
+        present,
+        present_after,
+        rows: r.read_rest().to_vec(),
+    })
+}
+
+fn read_row(r: &mut Reader, table: &TableMap, present: &[bool]) -> Result<Row, Error> {
+    let sent = present.iter().filter(|p| **p).count();
+    let nulls = read_bitmap(r, sent)?;
+    let mut row = Vec::with_capacity(present.len());
+    let mut n = 0;
+    for (i, present) in present.iter().enumerate() {
+        if !present {
+            row.push(None);
+            continue;
+        }
+        let value = if nulls[n] {
+            Value::Null
+        } else {
+            read_value(
+                r,
+                table.column_types[i],
+                table.column_metadata[i],
+                table.unsigned[i],
+            )?
+        };
+        row.push(Some(value));
+        n += 1;
+    }
+    Ok(row)
+}
+
+/// Read a column value in the binlog row format, which differs from the binary protocol
+fn read_value(r: &mut Reader, column_type: u8, meta: u16, unsigned: bool) -> Result<Value, Error> {
+    let int = |n: u64, bits: u32| {
+        if unsigned {
+            Value::UInt(n)
+        } else {
+            let shift = 64 - bits;
+            Value::Int(((n << shift) as i64) >> shift)
+        }
+    };
+    let value = match column_type {
+        MYSQL_TYPE_TINY => int(r.read_u8()? as u64, 8),
+        MYSQL_TYPE_SHORT => int(r.read_u16()? as u64, 16),
+        MYSQL_TYPE_INT24 => int(r.read_u24()? as u64, 24),
+        MYSQL_TYPE_LONG => int(r.read_u32()? as u64, 32),
+        MYSQL_TYPE_LONGLONG => int(r.read_u64()?, 64),
+        MYSQL_TYPE_FLOAT => Value::Float(f32::from_bits(r.read_u32()?)),
+        MYSQL_TYPE_DOUBLE => Value::Double(f64::from_bits(r.read_u64()?)),
+        MYSQL_TYPE_YEAR => match r.read_u8()? {
+            0 => Value::UInt(0),
+            n => Value::UInt(1900 + n as u64),
+        },
+        MYSQL_TYPE_NULL => Value::Null,
+        MYSQL_TYPE_NEWDECIMAL => Value::Decimal(read_decimal(r, (meta >> 8) as u8, meta as u8)?),
+        MYSQL_TYPE_DATE | MYSQL_TYPE_NEWDATE => {
+            let n = r.read_u24()?;
+            Value::Date {
+                year: (n >> 9) as u16,
+                month: ((n >> 5) & 0x0f) as u8,
+                day: (n & 0x1f) as u8,
+            }
+        }
+        MYSQL_TYPE_TIMESTAMP => timestamp(r.read_u32()?, 0),
+        MYSQL_TYPE_TIMESTAMP2 => {
+            let seconds = BigEndian::read_u32(r.read_bytes(4)?);
+            timestamp(seconds, read_fraction(r, meta)?)
+        }
+        MYSQL_TYPE_DATETIME => {
+            let n = r.read_u64()?;
+            let (date, time) = (n / 1_000_000, n % 1_000_000);
+            Value::DateTime {
+                year: (date / 10_000) as u16,
+                month: (date / 100 % 100) as u8,
+                day: (date % 100) as u8,
+                hour: (time / 10_000) as u8,
+                minute: (time / 100 % 100) as u8,
+                second: (time % 100) as u8,
+                microsecond: 0,
+            }
+        }
+        MYSQL_TYPE_DATETIME2 => {
+            let packed = BigEndian::read_uint(r.read_bytes(5)?, 5)
+                .checked_sub(0x80_0000_0000)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Negative DATETIME2 value"))?;
+            let (ymd, hms) = (packed >> 17, packed & 0x1_ffff);
+            let ym = ymd >> 5;
+            Value::DateTime {
+                year: (ym / 13) as u16,
+                month: (ym % 13) as u8,
+                day: (ymd & 0x1f) as u8,
+                hour: (hms >> 12) as u8,
+                minute: ((hms >> 6) & 0x3f) as u8,
+                second: (hms & 0x3f) as u8,
+                microsecond: read_fraction(r, meta)?,
+            }
+        }
+        MYSQL_TYPE_TIME => {
+            let n = r.read_u24()? as i64;
+            let n = (n << 40) >> 40;
+            let abs = n.unsigned_abs();
+            time(n < 0, abs / 10_000, abs / 100 % 100, abs % 100, 0)
+        }
+        MYSQL_TYPE_TIME2 => read_time2(r, meta)?,
+        MYSQL_TYPE_VARCHAR | MYSQL_TYPE_VAR_STRING => {
+            let len = if meta < 256 {
+                r.read_u8()? as usize
+            } else {
+                r.read_u16()? as usize
+            };
+            text_value(r.read_bytes(len)?)
+        }
+        MYSQL_TYPE_STRING | MYSQL_TYPE_ENUM | MYSQL_TYPE_SET => {
+            // The real type is in the first byte, with the high bits of the length
+            let (mut real_type, mut len) = ((meta >> 8) as u8, meta & 0xff);
+            if real_type & 0x30 != 0x30 {
+                len |= ((real_type as u16 & 0x30) ^ 0x30) << 4;
+                real_type |= 0x30;
+            }
+            match real_type {
+                MYSQL_TYPE_ENUM | MYSQL_TYPE_SET => Value::UInt(LittleEndian::read_uint(
+                    r.read_bytes(len as usize)?,
+                    len as usize,
+                )),
+                _ => {
+                    let len = if len < 256 {
+                        r.read_u8()? as usize
+                    } else {
+                        r.read_u16()? as usize
+                    };
+                    text_value(r.read_bytes(len)?)
+                }
+            }
+        }
+        MYSQL_TYPE_BIT => {
+            let len = (meta >> 8) as usize + ((meta & 0xff) as usize).div_ceil(8);
+            Value::Bytes(r.read_bytes(len)?.to_vec())
+        }
+        MYSQL_TYPE_TINY_BLOB
+        | MYSQL_TYPE_MEDIUM_BLOB
+        | MYSQL_TYPE_LONG_BLOB
+        | MYSQL_TYPE_BLOB
+        | MYSQL_TYPE_GEOMETRY
+        | MYSQL_TYPE_JSON => {
+            let len_bytes = (meta as usize).clamp(1, 4);
+            let len = LittleEndian::read_uint(r.read_bytes(len_bytes)?, len_bytes) as usize;
+            Value::Bytes(r.read_bytes(len)?.to_vec())
+        }
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unsupported binlog column type {}", column_type),
+            ))
+        }
+    };
+    Ok(value)
+}
+
+/// Strings are sent without their character set, they are text when valid UTF-8
+fn text_value(bytes: &[u8]) -> Value {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => Value::Text(text.to_string()),
+        Err(_) => Value::Bytes(bytes.to_vec()),
+    }
+}
+
+/// Fractional seconds of TIMESTAMP2 and DATETIME2, in microseconds
+fn read_fraction(r: &mut Reader, fsp: u16) -> Result<u32, Error> {
+    let fraction = match fsp {
+        1 | 2 => r.read_u8()? as u32 * 10_000,
+        3 | 4 => BigEndian::read_u16(r.read_bytes(2)?) as u32 * 100,
+        5 | 6 => BigEndian::read_u24(r.read_bytes(3)?),
+        _ => 0,
+    };
+    Ok(fraction)
+}
+
+/// A TIMESTAMP, in UTC
+fn timestamp(seconds: u32, microsecond: u32) -> Value {
+    let (year, month, day) = civil_from_days(seconds as i64 / 86_400);
+    let time = seconds % 86_400;
+    Value::DateTime {
+        year: year as u16,
+        month: month as u8,
+        day: day as u8,
+        hour: (time / 3600) as u8,
+        minute: (time / 60 % 60) as u8,
+        second: (time % 60) as u8,
+        microsecond,
+    }
+}
+
+fn time(negative: bool, hours: u64, minutes: u64, seconds: u64, microseconds: u32) -> Value {
+    Value::Time {
+        negative,
+        days: (hours / 24) as u32,
+        hours: (hours % 24) as u8,
+        minutes: minutes as u8,
+        seconds: seconds as u8,
+        microseconds,
+    }
+}
+
+/// TIME2: a 3-byte packed time and its fractional seconds, big endian and offset so they
+/// sort as unsigned numbers. See my_time_packed_from_binary in MariaDB.
+fn read_time2(r: &mut Reader, fsp: u16) -> Result<Value, Error> {
+    const INT_OFFSET: i64 = 0x80_0000;
+    let packed = match fsp {
+        1..=4 => {
+            let mut int = BigEndian::read_u24(r.read_bytes(3)?) as i64 - INT_OFFSET;
+            let (mut fraction, scale, wrap) = if fsp <= 2 {
+                (r.read_u8()? as i64, 10_000, 0x100)
+            } else {
+                (BigEndian::read_u16(r.read_bytes(2)?) as i64, 100, 0x1_0000)
+            };
+            if int < 0 && fraction != 0 {
+                int += 1;
+                fraction -= wrap;
+            }
+            (int << 24) + fraction * scale
+        }
+        5 | 6 => BigEndian::read_u48(r.read_bytes(6)?) as i64 - (INT_OFFSET << 24),
+        _ => (BigEndian::read_u24(r.read_bytes(3)?) as i64 - INT_OFFSET) << 24,
+    };
+    let abs = packed.unsigned_abs();
+    let hms = abs >> 24;
+    Ok(time(
+        packed < 0,
+        (hms >> 12) & 0x3ff,
+        (hms >> 6) & 0x3f,
+        hms & 0x3f,
+        (abs & 0xff_ffff) as u32,
+    ))
+}
+
+/// DECIMAL: groups of 9 digits in 4 bytes, with shorter groups for the leftover digits on
+/// each side of the point. See bin2decimal in MariaDB.
+fn read_decimal(r: &mut Reader, precision: u8, scale: u8) -> Result<String, Error> {
+    const DIGITS_BYTES: [usize; 10] = [0, 1, 1, 2, 2, 3, 3, 4, 4, 4];
+    let (integral, scale) = (precision.saturating_sub(scale) as usize, scale as usize);
+    let groups = [
+        (integral % 9, DIGITS_BYTES[integral % 9]),
+        (9, 4 * (integral / 9)),
+        (9, 4 * (scale / 9)),
+        (scale % 9, DIGITS_BYTES[scale % 9]),
+    ];
+    let size: usize = groups.iter().map(|(_, bytes)| bytes).sum();
+    let mut bytes = r.read_bytes(size)?.to_vec();
+    if bytes.is_empty() {
+        return Ok("0".to_string());
+    }
+    // The sign bit is flipped, and negative numbers have every bit flipped
+    let negative = bytes[0] & 0x80 == 0;
+    bytes[0] ^= 0x80;
+    if negative {
+        bytes.iter_mut().for_each(|b| *b = !*b);
+    }
+
+    let mut digits = String::new();
+    let mut pos = 0;
+    for (i, (width, len)) in groups.iter().enumerate() {
+        let step = if *width == 9 { 4 } else { *len };
+        let mut remaining = *len;
+        while remaining > 0 {
+            let n = BigEndian::read_uint(&bytes[pos..pos + step], step);
+            digits.push_str(&format!("{:0width$}", n, width = width));
+            pos += step;
+            remaining -= step;
+        }
+        if i == 1 {
+            digits.push('.');
+        }
+    }
+    let (int_part, fraction) = digits.split_at(digits.find('.').unwrap_or(digits.len()));
+    let int_part = int_part.trim_start_matches('0');
+    let mut decimal = String::new();
+    if negative {
+        decimal.push('-');
+    }
+    decimal.push_str(if int_part.is_empty() { "0" } else { int_part });
+    if scale > 0 {
+        decimal.push_str(fraction);
+    }
+    Ok(decimal)
+}
+
+/// A column of a changed row
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChangeColumn {
+    /// Position of the column in the table
+    pub index: usize,
+    /// Only known with binlog_row_metadata=FULL
+    pub name: Option<String>,
+    pub column_type: u8,
+    pub value: Value,
+}
+
+/// A row inserted, updated or deleted, with the table it belongs to
+#[derive(Clone, Debug, PartialEq)]
+pub struct Change {
+    pub kind: ChangeKind,
+    pub schema: String,
+    pub table: String,
+    /// Transaction of the change, from the GTID event before it
+    pub gtid: Option<Gtid>,
+    /// Binlog file and position of the event following the rows event
+    pub log_file: String,
+    pub log_pos: u32,
+    /// Deleted row, or updated row before the update. Only the columns sent are included,
+    /// all of them unless binlog_row_image is not FULL.
+    pub before: Option<Vec<ChangeColumn>>,
+    /// Inserted row, or updated row after the update
+    pub after: Option<Vec<ChangeColumn>>,
+}
+
+/// State of a binlog stream, started by COM_BINLOG_DUMP or COM_BINLOG_DUMP_GTID
+#[derive(Clone, Debug)]
+pub struct BinlogStream {
+    dump: BinlogDump,
+    format: Option<FormatDescription>,
+    /// Tables mapped by the server, by table id
+    tables: HashMap<u64, TableMap>,
+    log_file: String,
+    log_pos: u64,
+    /// GTID of the last transaction started
+    gtid: Option<Gtid>,
+}
+
+impl BinlogStream {
+    pub fn new(dump: BinlogDump) -> BinlogStream {
+        BinlogStream {
+            log_file: dump.filename.clone(),
+            log_pos: dump.position,
+            dump,
+            format: None,
+            tables: HashMap::new(),
+            gtid: None,
+        }
+    }
+
+    /// The command that started the stream
+    pub fn get_dump(&self) -> &BinlogDump {
+        &self.dump
+    }
+
+    /// The format description of the current binlog file, once sent
+    pub fn get_format_description(&self) -> Option<&FormatDescription> {
+        self.format.as_ref()
+    }
+
+    pub fn get_table(&self, table_id: u64) -> Option<&TableMap> {
+        self.tables.get(&table_id)
+    }
+
+    /// Binlog file being streamed
+    pub fn get_log_file(&self) -> &str {
+        &self.log_file
+    }
+
+    /// Position of the next event in the binlog file
+    pub fn get_log_pos(&self) -> u64 {
+        self.log_pos
+    }
+
+    /// GTID of the last transaction started
+    pub fn get_gtid(&self) -> Option<Gtid> {
+        self.gtid
+    }
+
+    /// Update the stream from an event sent by the server
+    pub fn update(&mut self, packet: &Packet) -> Result<(), Error> {
+        let event = self.parse_event(packet)?;
+        match event.data {
+            EventData::FormatDescription(format) => self.format = Some(format),
+            EventData::Rotate { position, filename } => {
+                self.log_file = filename;
+                self.log_pos = position;
+                return Ok(());
+            }
+            EventData::Gtid { gtid, .. } => self.gtid = Some(gtid),
+            EventData::TableMap(table) => {
+                self.tables.insert(table.table_id, table);
+            }
+            _ => {}
+        }
+        if event.header.log_pos != 0 {
+            self.log_pos = event.header.log_pos as u64;
+        }
+        Ok(())
+    }
+
+    /// Parse an event sent by the server. Before the format description, a checksum is
+    /// assumed when the event ends with a valid one.
+    pub fn decode_event(&self, packet: &Packet) -> Result<BinlogEvent, Error> {
+        if packet.get_packet_type()? != PacketType::BinlogEvent {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Packet is not a binlog event",
+            ));
+        }
+        self.parse_event(packet)
+    }
+
+    fn parse_event(&self, packet: &Packet) -> Result<BinlogEvent, Error> {
+        let checksum = match &self.format {
+            Some(format) => format.checksum,
+            None => packet
+                .get_payload()?
+                .get(1..)
+                .is_some_and(has_valid_checksum),
+        };
+        BinlogEvent::parse(packet, checksum)
+    }
+
+    /// The rows changed by a rows event, named after the table map sent before it. Empty
+    /// for other events.
+    pub fn decode_changes(&self, packet: &Packet) -> Result<Vec<Change>, Error> {
+        let event = self.decode_event(packet)?;
+        let log_pos = event.header.log_pos;
+        let rows = match event.data {
+            EventData::Rows(rows) => rows,
+            _ => return Ok(Vec::new()),
+        };
+        let table = self.tables.get(&rows.table_id).ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "Rows event for table {} sent before its table map",
+                    rows.table_id
+                ),
+            )
+        })?;
+        let columns = |row: Option<Row>| {
+            row.map(|row| {
+                row.into_iter()
+                    .enumerate()
+                    .filter_map(|(index, value)| {
+                        Some(ChangeColumn {
+                            index,
+                            name: table.column_names.get(index).cloned(),
+                            column_type: table.column_types[index],
+                            value: value?,
+                        })
+                    })
+                    .collect()
+            })
+        };
+        Ok(rows
+            .decode_rows(table)?
+            .into_iter()
+            .map(|row| Change {
+                kind: rows.kind,
+                schema: table.schema.clone(),
+                table: table.table.clone(),
+                gtid: self.gtid,
+                log_file: self.log_file.clone(),
+                log_pos,
+                before: columns(row.before),
+                after: columns(row.after),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mariadb::{write_lenenc_bytes, write_lenenc_int};
+    use byteorder::WriteBytesExt;
+
+    /// A binlog event packet, with a CRC32 when `checksum` is set
+    fn event(event_type: u8, log_pos: u32, body: &[u8], checksum: bool) -> Packet {
+        let mut event = Vec::new();
+        event.write_u32::<LittleEndian>(1_600_000_000).unwrap();
+        event.push(event_type);
+        event.write_u32::<LittleEndian>(1).unwrap();
+        let size = EVENT_HEADER_LEN + body.len() + if checksum { CHECKSUM_LEN } else { 0 };
+        event.write_u32::<LittleEndian>(size as u32).unwrap();
+        event.write_u32::<LittleEndian>(log_pos).unwrap();
+        event.write_u16::<LittleEndian>(0).unwrap();
+        event.extend_from_slice(body);
+        if checksum {
+            let mut crc = Crc::new();
+            crc.update(&event);
+            event.write_u32::<LittleEndian>(crc.sum()).unwrap();
+        }
+        let mut payload = vec![0x00];
+        payload.extend_from_slice(&event);
+        let mut packet = Packet::new_mariadb(1, &payload);
+        packet.set_packet_type(PacketType::BinlogEvent);
+        packet
+    }
+
+    fn format_description() -> Vec<u8> {
+        let mut body = Vec::new();
+        body.write_u16::<LittleEndian>(4).unwrap();
+        let mut version = b"10.5.8-MariaDB-log".to_vec();
+        version.resize(50, 0);
+        body.extend_from_slice(&version);
+        body.write_u32::<LittleEndian>(0).unwrap();
+        body.push(EVENT_HEADER_LEN as u8);
+        body.extend_from_slice(&[0; 164]);
+        // The algorithm, before the checksum added by event()
+        body.push(BINLOG_CHECKSUM_ALG_CRC32);
+        body
+    }
+
+    fn table_map() -> Vec<u8> {
+        let mut body = vec![7, 0, 0, 0, 0, 0, 1, 0];
+        body.extend_from_slice(b"\x03app\x00\x05users\x00");
+        let types = [
+            MYSQL_TYPE_LONG,
+            MYSQL_TYPE_VARCHAR,
+            MYSQL_TYPE_NEWDECIMAL,
+            MYSQL_TYPE_DATETIME2,
+            MYSQL_TYPE_TIME2,
+        ];
+        write_lenenc_int(&mut body, types.len() as u64);
+        body.extend_from_slice(&types);
+        write_lenenc_bytes(&mut body, &[0xff, 0x00, 10, 2, 0, 0]);
+        body.push(0b11110);
+        body.push(SIGNEDNESS);
+        write_lenenc_bytes(&mut body, &[0x80]);
+        let mut names = Vec::new();
+        for name in &["id", "name", "balance", "created", "elapsed"] {
+            write_lenenc_bytes(&mut names, name.as_bytes());
+        }
+        body.push(COLUMN_NAME);
+        write_lenenc_bytes(&mut body, &names);
+        body
+    }
+
+    #[test]
+    fn decodes_row_changes() {
+        let dump = BinlogDump::parse(&Packet::new_mariadb(
+            0,
+            b"\x12\x04\x00\x00\x00\x00\x00\x02\x00\x00\x00",
+        ))
+        .unwrap();
+        assert_eq!(dump.position, 4);
+        assert_eq!(dump.server_id, 2);
+        assert!(!dump.is_non_blocking());
+        let mut stream = BinlogStream::new(dump);
+
+        let mut rotate = 4u64.to_le_bytes().to_vec();
+        rotate.extend_from_slice(b"mariadb-bin.000001");
+        let rotate = event(ROTATE_EVENT, 0, &rotate, true);
+        stream.update(&rotate).unwrap();
+        assert_eq!(stream.get_log_file(), "mariadb-bin.000001");
+        stream
+            .update(&event(
+                FORMAT_DESCRIPTION_EVENT,
+                256,
+                &format_description(),
+                true,
+            ))
+            .unwrap();
+        assert!(stream.get_format_description().unwrap().checksum);
+
+        let mut gtid = 100u64.to_le_bytes().to_vec();
+        gtid.extend_from_slice(&[0, 0, 0, 0, 0]);
+        stream.update(&event(GTID_EVENT, 300, &gtid, true)).unwrap();
+        assert_eq!(stream.get_gtid().unwrap().to_string(), "0-1-100");
+
+        let mut query = vec![9, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0];
+        query.extend_from_slice(b"app\x00BEGIN");
+        let query = event(QUERY_EVENT, 350, &query, true);
+        match stream.decode_event(&query).unwrap().data {
+            EventData::Query { schema, query, .. } => {
+                assert_eq!(schema, "app");
+                assert_eq!(query, "BEGIN");
+            }
+            data => panic!("Unexpected event {:?}", data),
+        }
+        assert!(stream.decode_changes(&query).unwrap().is_empty());
+        stream
+            .update(&event(TABLE_MAP_EVENT, 400, &table_map(), true))
+            .unwrap();
+        assert_eq!(stream.get_table(7).unwrap().table, "users");
+
+        // id 1, name 'ann', balance -12.34, created 2020-05-17 10:20:30, elapsed NULL
+        let row: &[u8] = &[
+            0b10000, 1, 0, 0, 0, 3, b'a', b'n', b'n', 0x7f, 0xff, 0xff, 0xf3, 0xdd, 0x99, 0xa6,
+            0x62, 0xa5, 0x1e,
+        ];
+        let mut write = vec![7, 0, 0, 0, 0, 0, 1, 0, 2, 0, 5, 0x1f];
+        write.extend_from_slice(row);
+        let write = event(WRITE_ROWS_EVENT, 500, &write, true);
+        stream.update(&write).unwrap();
+        let changes = stream.decode_changes(&write).unwrap();
+        assert_eq!(changes.len(), 1);
+        let change = &changes[0];
+        assert_eq!(change.kind, ChangeKind::Insert);
+        assert_eq!(
+            (change.schema.as_str(), change.table.as_str()),
+            ("app", "users")
+        );
+        assert_eq!(change.gtid.unwrap().sequence, 100);
+        assert_eq!(change.log_pos, 500);
+        assert!(change.before.is_none());
+        let values: Vec<(Option<&str>, &Value)> = change
+            .after
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(|c| (c.name.as_deref(), &c.value))
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                (Some("id"), &Value::UInt(1)),
+                (Some("name"), &Value::Text("ann".to_string())),
+                (Some("balance"), &Value::Decimal("-12.34".to_string())),
+                (
+                    Some("created"),
+                    &Value::DateTime {
+                        year: 2020,
+                        month: 5,
+                        day: 17,
+                        hour: 10,
+                        minute: 20,
+                        second: 30,
+                        microsecond: 0
+                    }
+                ),
+                (Some("elapsed"), &Value::Null),
+            ]
+        );
+
+        // A minimal row image: the key before, the changed column after
+        let mut update = vec![7, 0, 0, 0, 0, 0, 1, 0, 5, 0x01, 0x02];
+        update.extend_from_slice(&[0, 1, 0, 0, 0, 0, 3, b'b', b'o', b'b']);
+        let update = event(UPDATE_ROWS_EVENT_V1, 600, &update, true);
+        let change = stream.decode_changes(&update).unwrap().remove(0);
+        assert_eq!(change.kind, ChangeKind::Update);
+        let before = change.before.unwrap();
+        assert_eq!((before.len(), before[0].index), (1, 0));
+        let after = change.after.unwrap();
+        assert_eq!(after[0].name.as_deref(), Some("name"));
+        assert_eq!(after[0].value, Value::Text("bob".to_string()));
+
+        let mut corrupt = event(XID_EVENT, 700, &5u64.to_le_bytes(), true)
+            .bytes
+            .to_vec();
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 0xff;
+        let mut corrupt = Packet::new(crate::packet::DatabaseType::MariaDB, corrupt);
+        corrupt.set_packet_type(PacketType::BinlogEvent);
+        assert!(stream.decode_event(&corrupt).is_err());
+    }
+
+    #[test]
+    fn reads_time_values() {
+        let time2 = |bytes: &[u8], fsp| read_time2(&mut Reader::new(bytes), fsp).unwrap();
+        assert_eq!(time2(&[0x80, 0x10, 0x8f], 0), time(false, 1, 2, 15, 0));
+        assert_eq!(
+            time2(&[0x7f, 0xef, 0x70, 0xff], 2),
+            time(true, 1, 2, 15, 10_000)
+        );
+        assert_eq!(
+            time2(&[0x81, 0x90, 0x00, 0x00, 0x00, 0x01], 6),
+            time(false, 25, 0, 0, 1)
+        );
+        let decimal = |bytes: &[u8], precision, scale| {
+            read_decimal(&mut Reader::new(bytes), precision, scale).unwrap()
+        };
+        assert_eq!(
+            decimal(&[0x81, 0x0d, 0xfb, 0x38, 0xd2, 0x04, 0xd2], 14, 4),
+            "1234567890.1234"
+        );
+        assert_eq!(decimal(&[0x80, 0x00], 4, 0), "0");
+        assert!(read_value(
+            &mut Reader::new(&[0x7f, 0xff, 0xff, 0xff, 0xff]),
+            MYSQL_TYPE_DATETIME2,
+            0,
+            false
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,147 @@
+//! Character sets, so SQL text can be decoded in the one chosen by the client, either in its
+//! handshake response or later with SET NAMES
+//! https://mariadb.com/kb/en/supported-character-sets-and-collations/
+
+use encoding_rs::{
+    Encoding, BIG5, EUC_JP, EUC_KR, GB18030, GBK, IBM866, ISO_8859_13, ISO_8859_2, ISO_8859_7,
+    ISO_8859_8, KOI8_R, KOI8_U, MACINTOSH, SHIFT_JIS, UTF_16BE, UTF_16LE, UTF_8, WINDOWS_1250,
+    WINDOWS_1251, WINDOWS_1252, WINDOWS_1254, WINDOWS_1256, WINDOWS_1257, WINDOWS_874,
+};
+
+/// Name of the character set of a collation id, as sent in the handshake
+pub fn charset_name(collation_id: u8) -> Option<&'static str> {
+    let name = match collation_id {
+        1 | 84 => "big5",
+        2 | 9 | 21 | 27 | 77 => "latin2",
+        5 | 8 | 15 | 31 | 47 | 48 | 49 | 94 => "latin1",
+        7 | 74 => "koi8r",
+        11 | 65 => "ascii",
+        12 | 91 => "ujis",
+        13 | 88 => "sjis",
+        14 | 23 | 50 | 51 | 52 => "cp1251",
+        16 | 71 => "hebrew",
+        18 | 89 => "tis620",
+        19 | 85 => "euckr",
+        20 | 41 | 42 | 79 => "latin7",
+        22 | 75 => "koi8u",
+        24 | 86 => "gb2312",
+        25 | 70 => "greek",
+        26 | 34 | 44 | 66 | 99 => "cp1250",
+        28 | 87 => "gbk",
+        29 | 58 | 59 => "cp1257",
+        30 | 78 => "latin5",
+        33 | 83 | 192..=215 => "utf8mb3",
+        35 | 90 | 128..=151 => "ucs2",
+        36 | 68 => "cp866",
+        39 | 53 => "macroman",
+        45 | 46 | 224..=247 | 255 => "utf8mb4",
+        54 | 55 | 101..=124 => "utf16",
+        56 | 62 => "utf16le",
+        57 | 67 => "cp1256",
+        63 => "binary",
+        95 | 96 => "cp932",
+        97 | 98 => "eucjpms",
+        248..=250 => "gb18030",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// Encoding of a character set name. `binary` has no encoding and is read as UTF-8.
+pub fn encoding_for_name(name: &str) -> Option<&'static Encoding> {
+    let encoding = match name.to_ascii_lowercase().as_str() {
+        "utf8" | "utf8mb3" | "utf8mb4" | "binary" => UTF_8,
+        // MariaDB's latin1 is cp1252, and ascii is a subset of it
+        "latin1" | "ascii" => WINDOWS_1252,
+        "latin2" => ISO_8859_2,
+        "latin5" => WINDOWS_1254,
+        "latin7" => ISO_8859_13,
+        "cp1250" => WINDOWS_1250,
+        "cp1251" => WINDOWS_1251,
+        "cp1256" => WINDOWS_1256,
+        "cp1257" => WINDOWS_1257,
+        "cp866" => IBM866,
+        "koi8r" => KOI8_R,
+        "koi8u" => KOI8_U,
+        "greek" => ISO_8859_7,
+        "hebrew" => ISO_8859_8,
+        "tis620" => WINDOWS_874,
+        "macroman" => MACINTOSH,
+        "big5" => BIG5,
+        "gb2312" | "gbk" => GBK,
+        "gb18030" => GB18030,
+        "sjis" | "cp932" => SHIFT_JIS,
+        "ujis" | "eucjpms" => EUC_JP,
+        "euckr" => EUC_KR,
+        "ucs2" | "utf16" => UTF_16BE,
+        "utf16le" => UTF_16LE,
+        _ => return None,
+    };
+    Some(encoding)
+}
+
+/// Encoding of a collation id, as sent in the handshake
+pub fn encoding_for_collation(collation_id: u8) -> Option<&'static Encoding> {
+    charset_name(collation_id).and_then(encoding_for_name)
+}
+
+/// Character set chosen by a query changing the client's character set: `SET NAMES`,
+/// `SET CHARACTER SET` or `SET character_set_client`. `DEFAULT` is not resolved, and returns
+/// None like any other query.
+pub fn parse_set_charset(sql: &[u8]) -> Option<String> {
+    let sql = String::from_utf8_lossy(sql).to_ascii_lowercase();
+    let words: Vec<&str> = sql
+        .split(|c: char| c.is_whitespace() || c == '=' || c == ';')
+        .filter(|w| !w.is_empty())
+        .collect();
+    let name = match words.as_slice() {
+        ["set", "names", name, ..]
+        | ["set", "character", "set", name, ..]
+        | ["set", "charset", name, ..] => name,
+        ["set", "session" | "local", variable, name, ..] | ["set", variable, name, ..]
+            if variable
+                .trim_start_matches("@@session.")
+                .trim_start_matches("@@")
+                == "character_set_client" =>
+        {
+            name
+        }
+        _ => return None,
+    };
+    let name = name.trim_matches(|c| c == '\'' || c == '"' || c == '`');
+    if name == "default" {
+        return None;
+    }
+    Some(name.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_client_charset() {
+        assert_eq!(encoding_for_collation(8), Some(WINDOWS_1252));
+        assert_eq!(encoding_for_collation(224), Some(UTF_8));
+        assert_eq!(encoding_for_collation(0), None);
+
+        assert_eq!(
+            parse_set_charset(b"SET NAMES 'latin1' COLLATE 'latin1_swedish_ci'").as_deref(),
+            Some("latin1")
+        );
+        assert_eq!(
+            parse_set_charset(b"set character set gbk").as_deref(),
+            Some("gbk")
+        );
+        assert_eq!(
+            parse_set_charset(b"SET @@session.character_set_client=sjis").as_deref(),
+            Some("sjis")
+        );
+        assert_eq!(
+            parse_set_charset(b"SET SESSION character_set_client = utf8mb4;").as_deref(),
+            Some("utf8mb4")
+        );
+        assert_eq!(parse_set_charset(b"SET NAMES DEFAULT"), None);
+        assert_eq!(parse_set_charset(b"SET autocommit = 0"), None);
+    }
+}
@@ -0,0 +1,304 @@
+//! Compressed protocol, used after authentication once CLIENT_COMPRESS is negotiated. Each
+//! compressed packet carries one or more regular packets, usually zlib compressed.
+//! https://mariadb.com/kb/en/0-packet/#compressed-packet
+
+use byteorder::{ByteOrder, LittleEndian};
+use bytes::{buf::BufMutExt, Buf, BytesMut};
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression as Level};
+use std::io::{self, Error, ErrorKind, Read, Write};
+
+use super::handshake::{CapabilityFlags, HandshakeResponse41, HandshakeV10};
+use crate::{
+    packet::{Packet, MARIADB_MAX_PAYLOAD},
+    packet_handler::Direction,
+};
+
+/// Compressed packet header: compressed length, compressed sequence id, uncompressed length
+pub const COMPRESSED_HEADER_LEN: usize = 7;
+
+/// Payloads shorter than this are not worth compressing, and are sent as is
+pub const MIN_COMPRESS_LENGTH: usize = 50;
+
+/// Which sides of the proxy use the compressed protocol
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum CompressionMode {
+    /// Both sides are compressed when the client and server negotiate it
+    #[default]
+    Passthrough,
+    /// Compression is terminated at the proxy: the client side is compressed when the client
+    /// asks for it, the server side never is
+    Client,
+    /// The server side is compressed when the server supports it, the client side never is
+    Server,
+}
+
+/// One of the two connections of the proxy
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+impl Side {
+    /// The side a pipe reads from
+    pub fn source(direction: &Direction) -> Side {
+        match direction {
+            Direction::Forward => Side::Client,
+            Direction::Backward => Side::Server,
+        }
+    }
+
+    /// The side a pipe writes to
+    pub fn sink(direction: &Direction) -> Side {
+        match direction {
+            Direction::Forward => Side::Server,
+            Direction::Backward => Side::Client,
+        }
+    }
+}
+
+/// Compression state of a single connection
+#[derive(Clone, Debug, Default)]
+pub struct Compression {
+    mode: CompressionMode,
+    /// The handshake sent to the client advertised CLIENT_COMPRESS
+    client_advertised: bool,
+    /// The client asked for CLIENT_COMPRESS in its handshake response
+    client_requested: bool,
+    /// The server advertised CLIENT_COMPRESS
+    server_supported: bool,
+    /// The handshake response sent to the server asked for CLIENT_COMPRESS
+    server_requested: bool,
+    /// Authentication succeeded, compression starts once the OK is sent to the client
+    starting: bool,
+    client: bool,
+    server: bool,
+    /// Next compressed sequence id on each side
+    client_sequence_id: u8,
+    server_sequence_id: u8,
+}
+
+impl Compression {
+    pub fn new(mode: CompressionMode) -> Compression {
+        Compression {
+            mode,
+            ..Compression::default()
+        }
+    }
+
+    pub fn get_mode(&self) -> CompressionMode {
+        self.mode
+    }
+
+    /// Whether a side currently uses the compressed protocol
+    pub fn is_active(&self, side: Side) -> bool {
+        match side {
+            Side::Client => self.client,
+            Side::Server => self.server,
+        }
+    }
+
+    /// Adjust the server's handshake before it is sent to the client, so the client only
+    /// asks for compression when the client side is to be compressed
+    pub fn rewrite_handshake(
+        &mut self,
+        server_capabilities: CapabilityFlags,
+        packet: &Packet,
+    ) -> Option<Packet> {
+        self.server_supported = server_capabilities.contains(CapabilityFlags::CLIENT_COMPRESS);
+        let mut handshake = HandshakeV10::parse(packet).ok()?;
+        let advertised = handshake
+            .capabilities
+            .contains(CapabilityFlags::CLIENT_COMPRESS);
+        self.client_advertised = match self.mode {
+            CompressionMode::Passthrough => advertised,
+            CompressionMode::Client => true,
+            CompressionMode::Server => false,
+        };
+        if self.client_advertised == advertised {
+            return None;
+        }
+        handshake
+            .capabilities
+            .set(CapabilityFlags::CLIENT_COMPRESS, self.client_advertised);
+        Some(handshake.to_packet())
+    }
+
+    /// Adjust the client's handshake response before it is sent to the server, so the server
+    /// only compresses when the server side is to be compressed
+    pub fn rewrite_handshake_response(&mut self, packet: &Packet) -> Option<Packet> {
+        let mut response = HandshakeResponse41::parse(packet).ok()?;
+        self.client_requested = response
+            .capabilities
+            .contains(CapabilityFlags::CLIENT_COMPRESS);
+        self.server_requested = match self.mode {
+            CompressionMode::Passthrough => self.client_requested,
+            CompressionMode::Client => false,
+            CompressionMode::Server => self.server_supported,
+        };
+        if self.server_requested == self.client_requested {
+            return None;
+        }
+        response
+            .capabilities
+            .set(CapabilityFlags::CLIENT_COMPRESS, self.server_requested);
+        Some(response.to_packet(packet.get_sequence_id().ok()?))
+    }
+
+    /// The server accepted the authentication
+    pub fn authenticated(&mut self) {
+        self.starting = true;
+    }
+
+    /// Switch to the compressed protocol if the OK ending authentication was just sent to the
+    /// client. Packets up to and including that OK are not compressed.
+    pub fn start(&mut self) {
+        if !self.starting {
+            return;
+        }
+        self.starting = false;
+        self.client = self.client_advertised && self.client_requested;
+        self.server = self.server_supported && self.server_requested;
+        if self.client || self.server {
+            debug!(
+                "Compression started, client side: {}, server side: {}",
+                self.client, self.server
+            );
+        }
+    }
+
+    /// The client started a new command, which resets the compressed sequence ids
+    pub fn next_command(&mut self) {
+        self.server_sequence_id = 0;
+    }
+
+    /// Move the content of complete compressed packets from `compressed_buf` to
+    /// `packet_buf`, leaving incomplete ones in `compressed_buf`
+    pub fn read(
+        &mut self,
+        side: Side,
+        compressed_buf: &mut BytesMut,
+        packet_buf: &mut BytesMut,
+    ) -> Result<(), Error> {
+        while compressed_buf.len() >= COMPRESSED_HEADER_LEN {
+            let length = LittleEndian::read_u24(&compressed_buf[0..3]) as usize;
+            let end = COMPRESSED_HEADER_LEN + length;
+            if compressed_buf.len() < end {
+                break;
+            }
+            let mut frame = compressed_buf.split_to(end);
+            let sequence_id = frame[3];
+            let uncompressed_length = LittleEndian::read_u24(&frame[4..7]) as usize;
+            frame.advance(COMPRESSED_HEADER_LEN);
+            if uncompressed_length == 0 {
+                packet_buf.unsplit(frame);
+            } else {
+                packet_buf.reserve(uncompressed_length);
+                let inflated = io::copy(
+                    &mut ZlibDecoder::new(&frame[..]).take(uncompressed_length as u64 + 1),
+                    &mut (&mut *packet_buf).writer(),
+                )? as usize;
+                if inflated != uncompressed_length {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "Compressed packet inflated to {} bytes, expected {}",
+                            inflated, uncompressed_length
+                        ),
+                    ));
+                }
+            }
+            *self.sequence_id_mut(side) = sequence_id.wrapping_add(1);
+        }
+        Ok(())
+    }
+
+    /// Append `bytes` to `buf` as compressed packets
+    pub fn write(&mut self, side: Side, bytes: &[u8], buf: &mut Vec<u8>) {
+        for chunk in bytes.chunks(MARIADB_MAX_PAYLOAD) {
+            let sequence_id = self.sequence_id_mut(side);
+            let header_start = buf.len();
+            buf.extend_from_slice(&[0; COMPRESSED_HEADER_LEN]);
+            buf[header_start + 3] = *sequence_id;
+            *sequence_id = sequence_id.wrapping_add(1);
+
+            let mut uncompressed_length = 0;
+            if chunk.len() >= MIN_COMPRESS_LENGTH {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Level::default());
+                encoder.write_all(chunk).unwrap();
+                let compressed = encoder.finish().unwrap();
+                // Incompressible data is sent as is
+                if compressed.len() < chunk.len() {
+                    buf.extend_from_slice(&compressed);
+                    uncompressed_length = chunk.len();
+                }
+            }
+            if uncompressed_length == 0 {
+                buf.extend_from_slice(chunk);
+            }
+            let length = buf.len() - header_start - COMPRESSED_HEADER_LEN;
+            LittleEndian::write_u24(&mut buf[header_start..header_start + 3], length as u32);
+            LittleEndian::write_u24(
+                &mut buf[header_start + 4..header_start + 7],
+                uncompressed_length as u32,
+            );
+        }
+    }
+
+    fn sequence_id_mut(&mut self, side: Side) -> &mut u8 {
+        match side {
+            Side::Client => &mut self.client_sequence_id,
+            Side::Server => &mut self.server_sequence_id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BufMut;
+
+    #[test]
+    fn compressed_packets_round_trip() {
+        let mut compression = Compression::new(CompressionMode::Passthrough);
+        let ping = Packet::new_mariadb(0, &[0x0e]);
+        let mut query = vec![0x03];
+        query.extend_from_slice("SELECT 1 UNION ALL ".repeat(20).as_bytes());
+        let query = Packet::new_mariadb(0, &query);
+
+        let mut wire = Vec::new();
+        compression.write(Side::Server, &ping.bytes, &mut wire);
+        // Short payloads are not compressed
+        assert_eq!(wire[..COMPRESSED_HEADER_LEN], [5, 0, 0, 0, 0, 0, 0]);
+        compression.write(Side::Server, &query.bytes, &mut wire);
+        let compressed = &wire[COMPRESSED_HEADER_LEN + 5..];
+        assert_eq!(compressed[3], 1);
+        assert_eq!(
+            LittleEndian::read_u24(&compressed[4..7]) as usize,
+            query.bytes.len()
+        );
+        assert!(compressed.len() < query.bytes.len());
+
+        // Incomplete compressed packets are kept for later
+        let mut packet_buf = BytesMut::new();
+        let mut compressed_buf = BytesMut::from(&wire[..wire.len() - 1]);
+        compression
+            .read(Side::Client, &mut compressed_buf, &mut packet_buf)
+            .unwrap();
+        assert_eq!(packet_buf, ping.bytes);
+        compressed_buf.put_u8(wire[wire.len() - 1]);
+        compression
+            .read(Side::Client, &mut compressed_buf, &mut packet_buf)
+            .unwrap();
+        assert!(compressed_buf.is_empty());
+        assert_eq!(packet_buf[5..], query.bytes[..]);
+        assert_eq!(compression.client_sequence_id, 2);
+
+        let mut corrupt = BytesMut::from(&wire[..]);
+        let last = corrupt.len() - 2;
+        corrupt[last] ^= 0xff;
+        assert!(compression
+            .read(Side::Client, &mut corrupt, &mut BytesMut::new())
+            .is_err());
+    }
+}
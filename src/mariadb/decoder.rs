@@ -0,0 +1,770 @@
+//! Connection phase tracking, so packets can be labeled by what they mean rather than by
+//! their first byte. A result set row and a command look the same on the wire.
+//! https://mariadb.com/kb/en/clientserver-protocol/
+
+use encoding_rs::{Encoding, UTF_8};
+use std::{
+    collections::VecDeque,
+    io::{Error, ErrorKind},
+};
+
+use super::{
+    binlog::{BinlogDump, BinlogStream},
+    charset::{encoding_for_collation, encoding_for_name, parse_set_charset},
+    handshake::{CapabilityFlags, HandshakeResponse41, HandshakeV10, StatusFlags},
+    response::{is_eof_payload, EofPacket, OkPacket},
+    resultset::{decode_row, ColumnDefinition},
+    statement::{Statements, StmtExecute},
+    value::Value,
+    Reader,
+};
+use crate::packet::{Packet, PacketType};
+
+/// Where a connection is in the protocol, from the point of view of the next server packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Waiting for the server's initial handshake
+    Handshake,
+    /// Waiting for the client's handshake response
+    HandshakeResponse,
+    /// Authentication exchange, after the handshake response or COM_CHANGE_USER
+    AuthSwitch,
+    /// No response outstanding, waiting for the client's next command
+    Command,
+    /// Waiting for OK, ERR, a LOCAL INFILE request, or the column count of a result set
+    Result { binary: bool },
+    /// Column definitions, followed by `then`
+    ColumnDefinitions { remaining: u64, then: Then },
+    /// EOF after column definitions (not sent with CLIENT_DEPRECATE_EOF), followed by `then`
+    ColumnDefinitionsEof { then: Then },
+    /// Result set rows, until EOF/OK or ERR
+    Rows { binary: bool },
+    /// The client is sending the file requested by a LOCAL INFILE request
+    LocalInfile,
+    /// Waiting for COM_STMT_PREPARE_OK or ERR
+    StmtPrepare,
+    /// Waiting for a single OK, ERR or EOF packet
+    Status,
+    /// Waiting for the COM_STATISTICS string
+    Statistics,
+    /// Column definitions answering COM_FIELD_LIST, until EOF
+    FieldList,
+    /// Binlog events following COM_BINLOG_DUMP, until EOF or ERR
+    BinlogStream,
+}
+
+/// What follows a block of column definitions
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Then {
+    Rows {
+        binary: bool,
+    },
+    /// Column definitions of a prepared statement, after its parameter definitions
+    StmtColumns(u16),
+    Done,
+}
+
+/// Tracks the phase of a single MariaDB connection from both directions and labels packets
+#[derive(Clone, Debug)]
+pub struct Decoder {
+    phase: Phase,
+    /// Commands sent by the client whose response has not started yet
+    pending: VecDeque<PacketType>,
+    handshake: Option<HandshakeV10>,
+    handshake_response: Option<HandshakeResponse41>,
+    capabilities: CapabilityFlags,
+    /// Character set of the SQL text sent by the client
+    charset: &'static Encoding,
+    /// Column definitions of the result set being sent
+    columns: Vec<ColumnDefinition>,
+    statements: Statements,
+    /// The last COM_STMT_EXECUTE, decoded
+    execute: Option<StmtExecute>,
+    /// Binlog stream started by COM_BINLOG_DUMP or COM_BINLOG_DUMP_GTID
+    binlog: Option<BinlogStream>,
+}
+
+impl Default for Decoder {
+    fn default() -> Decoder {
+        Decoder::new()
+    }
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder {
+            phase: Phase::Handshake,
+            pending: VecDeque::new(),
+            handshake: None,
+            handshake_response: None,
+            capabilities: CapabilityFlags::empty(),
+            charset: UTF_8,
+            columns: Vec::new(),
+            statements: Statements::new(),
+            execute: None,
+            binlog: None,
+        }
+    }
+
+    pub fn get_phase(&self) -> Phase {
+        self.phase
+    }
+
+    /// The server's initial handshake, once seen
+    pub fn get_handshake(&self) -> Option<&HandshakeV10> {
+        self.handshake.as_ref()
+    }
+
+    /// The client's handshake response, once seen
+    pub fn get_handshake_response(&self) -> Option<&HandshakeResponse41> {
+        self.handshake_response.as_ref()
+    }
+
+    /// Capabilities supported by both the server and the client
+    pub fn get_capabilities(&self) -> CapabilityFlags {
+        self.capabilities
+    }
+
+    /// Character set of the SQL text sent by the client, from its handshake response or the
+    /// last SET NAMES. Unknown character sets are read as UTF-8.
+    pub fn get_client_encoding(&self) -> &'static Encoding {
+        self.charset
+    }
+
+    /// Column definitions of the current (or last) result set
+    pub fn get_columns(&self) -> &[ColumnDefinition] {
+        &self.columns
+    }
+
+    /// Decode a packet labeled TextRow or BinaryRow using the current column definitions
+    pub fn decode_row(&self, packet: &Packet) -> Result<Vec<Value>, Error> {
+        let binary = match packet.get_packet_type()? {
+            PacketType::TextRow => false,
+            PacketType::BinaryRow => true,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "Packet is not a result set row",
+                ))
+            }
+        };
+        decode_row(&self.columns, binary, packet)
+    }
+
+    /// Statements prepared with COM_STMT_PREPARE
+    pub fn get_statements(&self) -> &Statements {
+        &self.statements
+    }
+
+    /// The statement run by a COM_STMT_EXECUTE, with its SQL text and decoded parameters
+    pub fn get_execute(&self, packet: &Packet) -> Result<&StmtExecute, Error> {
+        let payload = packet.get_payload()?;
+        let statement_id = match (packet.get_packet_type()?, payload.get(1..5)) {
+            (PacketType::ComStmtExecute, Some(id)) => Reader::new(id).read_u32()?,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "Packet is not a COM_STMT_EXECUTE",
+                ))
+            }
+        };
+        match &self.execute {
+            Some(execute) if execute.statement_id == statement_id => Ok(execute),
+            _ => Err(Error::new(
+                ErrorKind::NotFound,
+                format!("Unknown prepared statement {}", statement_id),
+            )),
+        }
+    }
+
+    /// The binlog stream, once the client sent COM_BINLOG_DUMP or COM_BINLOG_DUMP_GTID. The
+    /// packets labeled BinlogEvent carry its events.
+    pub fn get_binlog(&self) -> Option<&BinlogStream> {
+        self.binlog.as_ref()
+    }
+
+    /// Label a packet sent by the client
+    pub fn decode_request(&mut self, packet: &Packet) -> PacketType {
+        let payload = match packet.get_payload() {
+            Ok(payload) => payload,
+            Err(_) => return PacketType::ComUnknown,
+        };
+        match self.phase {
+            Phase::Handshake | Phase::HandshakeResponse => {
+                if HandshakeResponse41::is_ssl_request(packet) {
+                    return PacketType::SSLRequest;
+                }
+                match HandshakeResponse41::parse(packet) {
+                    Ok(response) => {
+                        self.capabilities = response.capabilities
+                            & self
+                                .handshake
+                                .as_ref()
+                                .map(|h| h.capabilities)
+                                .unwrap_or_else(CapabilityFlags::all);
+                        self.charset = encoding_for_collation(response.charset).unwrap_or(UTF_8);
+                        self.handshake_response = Some(response);
+                    }
+                    Err(e) => warn!("Unable to parse handshake response: {}", e),
+                }
+                self.phase = Phase::AuthSwitch;
+                PacketType::HandshakeResponse
+            }
+            Phase::AuthSwitch => PacketType::AuthSwitchResponse,
+            Phase::LocalInfile => {
+                // An empty packet ends the file
+                if payload.is_empty() {
+                    self.phase = Phase::Status;
+                }
+                PacketType::LocalInfileData
+            }
+            _ => {
+                let command = packet.get_packet_type().unwrap_or(PacketType::ComUnknown);
+                self.update_statements(command, packet);
+                match command {
+                    PacketType::ComQuery => self.update_charset(packet),
+                    PacketType::ComBinlogDump | PacketType::ComBinlogDumpGtid => {
+                        match BinlogDump::parse(packet) {
+                            Ok(dump) => self.binlog = Some(BinlogStream::new(dump)),
+                            Err(e) => warn!("Unable to parse binlog dump command: {}", e),
+                        }
+                    }
+                    _ => {}
+                }
+                match command {
+                    // No response is sent for these
+                    PacketType::ComQuit
+                    | PacketType::ComStmtClose
+                    | PacketType::ComStmtSendLongData => {}
+                    _ => {
+                        self.pending.push_back(command);
+                        if self.phase == Phase::Command {
+                            self.next_command();
+                        }
+                    }
+                }
+                command
+            }
+        }
+    }
+
+    /// Label a packet sent by the server
+    pub fn decode_response(&mut self, packet: &Packet) -> PacketType {
+        let payload = match packet.get_payload() {
+            Ok(payload) => payload,
+            Err(_) => return PacketType::ComUnknown,
+        };
+        let header = match payload.first() {
+            Some(header) => *header,
+            None => return PacketType::ComUnknown,
+        };
+        if header == 0xff {
+            // ERR ends whatever was in progress
+            match self.phase {
+                Phase::StmtPrepare => {
+                    self.statements.prepare_failed();
+                    self.finish();
+                }
+                Phase::Handshake | Phase::HandshakeResponse | Phase::AuthSwitch => {}
+                _ => self.finish(),
+            }
+            return PacketType::ComErr;
+        }
+
+        match self.phase {
+            Phase::Handshake => {
+                match HandshakeV10::parse(packet) {
+                    Ok(handshake) => {
+                        self.charset = encoding_for_collation(handshake.charset).unwrap_or(UTF_8);
+                        self.handshake = Some(handshake);
+                    }
+                    Err(e) => warn!("Unable to parse handshake: {}", e),
+                }
+                self.phase = Phase::HandshakeResponse;
+                PacketType::Handshake
+            }
+            Phase::HandshakeResponse | Phase::AuthSwitch => match header {
+                0x00 => {
+                    self.phase = Phase::Command;
+                    self.next_command();
+                    PacketType::ComOk
+                }
+                0xfe => PacketType::AuthSwitchRequest,
+                0x01 => PacketType::AuthMoreData,
+                _ => PacketType::ComUnknown,
+            },
+            Phase::Command => {
+                debug!("Unexpected MariaDB server packet while no command is in progress");
+                PacketType::ComUnknown
+            }
+            Phase::Result { binary } => match header {
+                0x00 => {
+                    let more = self
+                        .ok_status(packet)
+                        .contains(StatusFlags::SERVER_MORE_RESULTS_EXISTS);
+                    self.end_result(more);
+                    PacketType::ComOk
+                }
+                0xfb => {
+                    self.phase = Phase::LocalInfile;
+                    PacketType::LocalInfileRequest
+                }
+                _ => {
+                    let count = Reader::new(payload)
+                        .read_lenenc_int()
+                        .ok()
+                        .flatten()
+                        .unwrap_or(0);
+                    self.columns.clear();
+                    self.phase = Phase::ColumnDefinitions {
+                        remaining: count,
+                        then: Then::Rows { binary },
+                    };
+                    PacketType::ColumnCount
+                }
+            },
+            Phase::ColumnDefinitions { remaining, then } => {
+                if let Then::Rows { .. } = then {
+                    match ColumnDefinition::parse(packet, self.capabilities) {
+                        Ok(column) => self.columns.push(column),
+                        Err(e) => warn!("Unable to parse column definition: {}", e),
+                    }
+                }
+                if remaining > 1 {
+                    self.phase = Phase::ColumnDefinitions {
+                        remaining: remaining - 1,
+                        then,
+                    };
+                } else if self.deprecate_eof() {
+                    self.after_definitions(then);
+                } else {
+                    self.phase = Phase::ColumnDefinitionsEof { then };
+                }
+                PacketType::ColumnDefinition
+            }
+            Phase::ColumnDefinitionsEof { then } => {
+                let cursor = EofPacket::parse(packet)
+                    .map(|eof| {
+                        eof.status
+                            .contains(StatusFlags::SERVER_STATUS_CURSOR_EXISTS)
+                    })
+                    .unwrap_or(false);
+                if cursor {
+                    // Rows will be fetched with COM_STMT_FETCH
+                    self.finish();
+                } else {
+                    self.after_definitions(then);
+                }
+                PacketType::ComEof
+            }
+            Phase::Rows { binary } => {
+                if is_eof_payload(payload) {
+                    let more = if self.deprecate_eof() {
+                        self.ok_status(packet)
+                    } else {
+                        EofPacket::parse(packet)
+                            .map(|eof| eof.status)
+                            .unwrap_or_else(|_| StatusFlags::empty())
+                    }
+                    .contains(StatusFlags::SERVER_MORE_RESULTS_EXISTS);
+                    self.end_result(more);
+                    if self.deprecate_eof() {
+                        PacketType::ComOk
+                    } else {
+                        PacketType::ComEof
+                    }
+                } else if binary {
+                    PacketType::BinaryRow
+                } else {
+                    PacketType::TextRow
+                }
+            }
+            Phase::LocalInfile => PacketType::ComUnknown,
+            Phase::StmtPrepare => {
+                let counts = self
+                    .statements
+                    .prepared(packet)
+                    .map(|statement| (statement.num_columns, statement.num_params));
+                match counts {
+                    Ok((columns, params)) if params > 0 => {
+                        self.phase = Phase::ColumnDefinitions {
+                            remaining: params as u64,
+                            then: Then::StmtColumns(columns),
+                        };
+                    }
+                    Ok((columns, _)) => self.after_definitions(Then::StmtColumns(columns)),
+                    Err(e) => {
+                        warn!("Unable to parse COM_STMT_PREPARE_OK: {}", e);
+                        self.finish();
+                    }
+                }
+                PacketType::StmtPrepareOk
+            }
+            Phase::Status => {
+                self.finish();
+                match header {
+                    0x00 => PacketType::ComOk,
+                    0xfe => PacketType::ComEof,
+                    _ => PacketType::ComUnknown,
+                }
+            }
+            Phase::Statistics => {
+                self.finish();
+                PacketType::StatisticsResponse
+            }
+            Phase::FieldList => {
+                if is_eof_payload(payload) {
+                    self.finish();
+                    PacketType::ComEof
+                } else {
+                    PacketType::ColumnDefinition
+                }
+            }
+            Phase::BinlogStream => {
+                if is_eof_payload(payload) {
+                    self.finish();
+                    PacketType::ComEof
+                } else {
+                    self.update_binlog(packet);
+                    PacketType::BinlogEvent
+                }
+            }
+        }
+    }
+
+    fn update_statements(&mut self, command: PacketType, packet: &Packet) {
+        let result = match command {
+            PacketType::ComStmtPrepare => self.statements.prepare(packet, self.charset),
+            PacketType::ComStmtExecute => match self.statements.execute(packet) {
+                Ok(execute) => {
+                    self.execute = Some(execute);
+                    Ok(())
+                }
+                Err(e) => {
+                    self.execute = None;
+                    Err(e)
+                }
+            },
+            PacketType::ComStmtSendLongData => self.statements.send_long_data(packet),
+            PacketType::ComStmtReset => self.statements.reset(packet),
+            PacketType::ComStmtClose => self.statements.close(packet),
+            _ => Ok(()),
+        };
+        if let Err(e) = result {
+            debug!("Unable to track prepared statements: {}", e);
+        }
+    }
+
+    /// Follow the binlog stream through the events sent by the server
+    fn update_binlog(&mut self, packet: &Packet) {
+        if let Some(binlog) = self.binlog.as_mut() {
+            if let Err(e) = binlog.update(packet) {
+                debug!("Unable to follow binlog stream: {}", e);
+            }
+        }
+    }
+
+    /// Follow SET NAMES and its variants. The new character set applies from the next query.
+    fn update_charset(&mut self, packet: &Packet) {
+        let name = match packet.get_query_bytes().ok().and_then(parse_set_charset) {
+            Some(name) => name,
+            None => return,
+        };
+        match encoding_for_name(&name) {
+            Some(encoding) => self.charset = encoding,
+            None => {
+                debug!("Unknown character set {}, reading queries as UTF-8", name);
+                self.charset = UTF_8;
+            }
+        }
+    }
+
+    fn deprecate_eof(&self) -> bool {
+        self.capabilities
+            .contains(CapabilityFlags::CLIENT_DEPRECATE_EOF)
+    }
+
+    fn ok_status(&self, packet: &Packet) -> StatusFlags {
+        OkPacket::parse(packet, self.capabilities)
+            .map(|ok| ok.status)
+            .unwrap_or_else(|_| StatusFlags::empty())
+    }
+
+    fn after_definitions(&mut self, then: Then) {
+        match then {
+            Then::Rows { binary } => self.phase = Phase::Rows { binary },
+            Then::StmtColumns(0) | Then::Done => self.finish(),
+            Then::StmtColumns(columns) => {
+                self.phase = Phase::ColumnDefinitions {
+                    remaining: columns as u64,
+                    then: Then::Done,
+                };
+            }
+        }
+    }
+
+    /// A result ended. More may follow for multi-statements and stored procedures.
+    fn end_result(&mut self, more_results: bool) {
+        if more_results {
+            if let Phase::Rows { binary } | Phase::Result { binary } = self.phase {
+                self.phase = Phase::Result { binary };
+                return;
+            }
+        }
+        self.finish();
+    }
+
+    /// The response to the current command is complete
+    fn finish(&mut self) {
+        self.phase = Phase::Command;
+        self.next_command();
+    }
+
+    /// Start expecting the response to the oldest pending command
+    fn next_command(&mut self) {
+        if self.phase != Phase::Command {
+            return;
+        }
+        if let Some(command) = self.pending.pop_front() {
+            self.phase = match command {
+                PacketType::ComQuery | PacketType::ComProcessInfo => {
+                    Phase::Result { binary: false }
+                }
+                PacketType::ComStmtExecute => Phase::Result { binary: true },
+                PacketType::ComStmtFetch => Phase::Rows { binary: true },
+                PacketType::ComStmtPrepare => Phase::StmtPrepare,
+                PacketType::ComFieldList => Phase::FieldList,
+                PacketType::ComStatistics => Phase::Statistics,
+                PacketType::ComChangeUser => Phase::AuthSwitch,
+                PacketType::ComBinlogDump | PacketType::ComBinlogDumpGtid => Phase::BinlogStream,
+                _ => Phase::Status,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::DatabaseType;
+
+    fn packet(sequence_id: u8, payload: &[u8]) -> Packet {
+        Packet::new_mariadb(sequence_id, payload)
+    }
+
+    fn authenticated(capabilities: CapabilityFlags) -> Decoder {
+        let mut decoder = Decoder::new();
+        decoder.phase = Phase::Command;
+        decoder.capabilities = capabilities;
+        decoder
+    }
+
+    #[test]
+    fn labels_text_result_set() {
+        let mut decoder = authenticated(CapabilityFlags::CLIENT_PROTOCOL_41);
+        let query = packet(0, b"\x03SELECT a, b, c FROM t");
+        assert_eq!(decoder.decode_request(&query), PacketType::ComQuery);
+
+        let labels: Vec<PacketType> = [
+            packet(1, &[0x03]),
+            packet(2, b"\x03def\x00\x01t\x01t\x01a\x01a\x0c\x3f\x00\x0b\x00\x00\x00\x03\x00\x00\x00\x00\x00"),
+            packet(3, b"\x03def\x00\x01t\x01t\x01b\x01b\x0c\x3f\x00\x0b\x00\x00\x00\x03\x00\x00\x00\x00\x00"),
+            packet(4, b"\x03def\x00\x01t\x01t\x01c\x01c\x0c\x3f\x00\x0b\x00\x00\x00\x03\x00\x00\x00\x00\x00"),
+            packet(5, &[0xfe, 0x00, 0x00, 0x22, 0x00]),
+            packet(6, b"\x011\x012\x013"),
+            packet(7, &[0x00, 0x01, 0x02]),
+            packet(8, &[0xfe, 0x00, 0x00, 0x22, 0x00]),
+        ]
+        .iter()
+        .map(|p| decoder.decode_response(p))
+        .collect();
+        assert_eq!(
+            labels,
+            vec![
+                PacketType::ColumnCount,
+                PacketType::ColumnDefinition,
+                PacketType::ColumnDefinition,
+                PacketType::ColumnDefinition,
+                PacketType::ComEof,
+                PacketType::TextRow,
+                PacketType::TextRow,
+                PacketType::ComEof,
+            ]
+        );
+        assert_eq!(decoder.get_phase(), Phase::Command);
+        assert_eq!(decoder.get_columns().len(), 3);
+        let mut row = packet(7, &[0x00, 0x01, 0x02]);
+        row.set_packet_type(PacketType::TextRow);
+        assert!(decoder.decode_row(&row).is_err());
+    }
+
+    #[test]
+    fn labels_multi_result_sets_with_deprecate_eof() {
+        let mut decoder = authenticated(
+            CapabilityFlags::CLIENT_PROTOCOL_41 | CapabilityFlags::CLIENT_DEPRECATE_EOF,
+        );
+        decoder.decode_request(&packet(0, b"\x03SELECT 1; SELECT 2"));
+        let more_results = [0xfe, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00];
+        let last = [0xfe, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00];
+        let labels: Vec<PacketType> = [
+            packet(1, &[0x01]),
+            packet(
+                2,
+                b"\x03def\x00\x00\x00\x011\x00\x0c\x3f\x00\x01\x00\x00\x00\x08\x81\x00\x00\x00\x00",
+            ),
+            packet(3, b"\x011"),
+            packet(4, &more_results),
+            packet(5, &[0x01]),
+            packet(
+                6,
+                b"\x03def\x00\x00\x00\x012\x00\x0c\x3f\x00\x01\x00\x00\x00\x08\x81\x00\x00\x00\x00",
+            ),
+            packet(7, b"\x012"),
+            packet(8, &last),
+        ]
+        .iter()
+        .map(|p| decoder.decode_response(p))
+        .collect();
+        assert_eq!(
+            labels,
+            vec![
+                PacketType::ColumnCount,
+                PacketType::ColumnDefinition,
+                PacketType::TextRow,
+                PacketType::ComOk,
+                PacketType::ColumnCount,
+                PacketType::ColumnDefinition,
+                PacketType::TextRow,
+                PacketType::ComOk,
+            ]
+        );
+        assert_eq!(decoder.get_phase(), Phase::Command);
+    }
+
+    #[test]
+    fn tracks_prepared_statement_execution() {
+        let mut decoder = authenticated(
+            CapabilityFlags::CLIENT_PROTOCOL_41 | CapabilityFlags::CLIENT_DEPRECATE_EOF,
+        );
+        decoder.decode_request(&packet(0, b"\x16SELECT 1"));
+        let ok = packet(1, &[0x00, 3, 0, 0, 0, 1, 0, 0, 0, 0x00, 0, 0]);
+        assert_eq!(decoder.decode_response(&ok), PacketType::StmtPrepareOk);
+        let column = packet(
+            2,
+            b"\x03def\x00\x00\x00\x011\x00\x0c\x3f\x00\x01\x00\x00\x00\x08\x81\x00\x00\x00\x00",
+        );
+        assert_eq!(
+            decoder.decode_response(&column),
+            PacketType::ColumnDefinition
+        );
+        assert_eq!(decoder.get_phase(), Phase::Command);
+
+        let execute = packet(0, &[0x17, 3, 0, 0, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(decoder.decode_request(&execute), PacketType::ComStmtExecute);
+        assert_eq!(decoder.get_execute(&execute).unwrap().query, "SELECT 1");
+        assert_eq!(decoder.get_phase(), Phase::Result { binary: true });
+    }
+
+    #[test]
+    fn labels_local_infile_exchange() {
+        let mut decoder = authenticated(CapabilityFlags::CLIENT_PROTOCOL_41);
+        decoder.decode_request(&packet(
+            0,
+            b"\x03LOAD DATA LOCAL INFILE '/tmp/a' INTO TABLE t",
+        ));
+        let request = packet(1, b"\xfb/tmp/a");
+        assert_eq!(
+            decoder.decode_response(&request),
+            PacketType::LocalInfileRequest
+        );
+        assert_eq!(
+            decoder.decode_request(&packet(2, b"1,2\n")),
+            PacketType::LocalInfileData
+        );
+        assert_eq!(
+            decoder.decode_request(&packet(3, b"")),
+            PacketType::LocalInfileData
+        );
+        let ok = packet(4, &[0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00]);
+        assert_eq!(decoder.decode_response(&ok), PacketType::ComOk);
+        assert_eq!(decoder.get_phase(), Phase::Command);
+    }
+
+    #[test]
+    fn labels_connection_phase() {
+        let mut decoder = Decoder::new();
+        let handshake = HandshakeV10 {
+            protocol_version: 10,
+            server_version: "10.4.12-MariaDB".to_string(),
+            connection_id: 7,
+            auth_plugin_data: vec![1; 20],
+            capabilities: CapabilityFlags::CLIENT_PROTOCOL_41
+                | CapabilityFlags::CLIENT_SECURE_CONNECTION
+                | CapabilityFlags::CLIENT_PLUGIN_AUTH
+                | CapabilityFlags::CLIENT_DEPRECATE_EOF,
+            charset: 8,
+            status: StatusFlags::SERVER_STATUS_AUTOCOMMIT,
+            auth_plugin_name: Some("mysql_native_password".to_string()),
+        };
+        let response = HandshakeResponse41 {
+            capabilities: CapabilityFlags::CLIENT_PROTOCOL_41
+                | CapabilityFlags::CLIENT_SECURE_CONNECTION
+                | CapabilityFlags::CLIENT_PLUGIN_AUTH,
+            max_packet_size: 0,
+            charset: 8,
+            username: "root".to_string(),
+            auth_response: vec![2; 20],
+            database: None,
+            auth_plugin_name: Some("mysql_native_password".to_string()),
+            connect_attrs: Vec::new(),
+        };
+        assert_eq!(
+            decoder.decode_response(&handshake.to_packet()),
+            PacketType::Handshake
+        );
+        assert_eq!(
+            decoder.decode_request(&response.to_packet(1)),
+            PacketType::HandshakeResponse
+        );
+        assert!(!decoder
+            .get_capabilities()
+            .contains(CapabilityFlags::CLIENT_DEPRECATE_EOF));
+        let switch = Packet::new(DatabaseType::MariaDB, b"\x02\x00\x00\x02\xfe\x00".to_vec());
+        assert_eq!(
+            decoder.decode_response(&switch),
+            PacketType::AuthSwitchRequest
+        );
+        assert_eq!(
+            decoder.decode_request(&packet(3, &[3; 20])),
+            PacketType::AuthSwitchResponse
+        );
+        let ok = packet(4, &[0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00]);
+        assert_eq!(decoder.decode_response(&ok), PacketType::ComOk);
+        assert_eq!(decoder.get_phase(), Phase::Command);
+    }
+
+    #[test]
+    fn follows_binlog_stream() {
+        let mut decoder = authenticated(CapabilityFlags::CLIENT_PROTOCOL_41);
+        let dump = packet(0, b"\x12\x04\x00\x00\x00\x01\x00\x02\x00\x00\x00");
+        assert_eq!(decoder.decode_request(&dump), PacketType::ComBinlogDump);
+        assert!(decoder.get_binlog().unwrap().get_dump().is_non_blocking());
+
+        // A fake rotate without checksum, then EOF at the end of the last binlog
+        let mut rotate = vec![
+            0x00, 0, 0, 0, 0, 4, 1, 0, 0, 0, 44, 0, 0, 0, 0, 0, 0, 0, 0x20, 0,
+        ];
+        rotate.extend_from_slice(&4u64.to_le_bytes());
+        rotate.extend_from_slice(b"mariadb-bin.000002");
+        assert_eq!(
+            decoder.decode_response(&packet(1, &rotate)),
+            PacketType::BinlogEvent
+        );
+        let binlog = decoder.get_binlog().unwrap();
+        assert_eq!(binlog.get_log_file(), "mariadb-bin.000002");
+        assert_eq!(binlog.get_log_pos(), 4);
+        let eof = packet(2, &[0xfe, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(decoder.decode_response(&eof), PacketType::ComEof);
+        assert_eq!(decoder.get_phase(), Phase::Command);
+    }
+}
@@ -0,0 +1,518 @@
+//! Connection phase packets
+//! https://mariadb.com/kb/en/connection/
+
+use bitflags::bitflags;
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::io::{Error, ErrorKind};
+
+use super::{to_string, write_lenenc_bytes, write_lenenc_int, write_null_terminated, Reader};
+use crate::packet::{DatabaseType, Packet};
+
+bitflags! {
+    /// Capabilities advertised by the server in HandshakeV10 and requested by the client
+    /// in HandshakeResponse41. Bits 32-63 are MariaDB extended capabilities, which are only
+    /// sent when CLIENT_MYSQL is unset.
+    /// https://mariadb.com/kb/en/connection/#capabilities
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    pub struct CapabilityFlags: u64 {
+        const CLIENT_MYSQL = 1;
+        const CLIENT_FOUND_ROWS = 1 << 1;
+        const CLIENT_LONG_FLAG = 1 << 2;
+        const CLIENT_CONNECT_WITH_DB = 1 << 3;
+        const CLIENT_NO_SCHEMA = 1 << 4;
+        const CLIENT_COMPRESS = 1 << 5;
+        const CLIENT_ODBC = 1 << 6;
+        const CLIENT_LOCAL_FILES = 1 << 7;
+        const CLIENT_IGNORE_SPACE = 1 << 8;
+        const CLIENT_PROTOCOL_41 = 1 << 9;
+        const CLIENT_INTERACTIVE = 1 << 10;
+        const CLIENT_SSL = 1 << 11;
+        const CLIENT_IGNORE_SIGPIPE = 1 << 12;
+        const CLIENT_TRANSACTIONS = 1 << 13;
+        const CLIENT_RESERVED = 1 << 14;
+        const CLIENT_SECURE_CONNECTION = 1 << 15;
+        const CLIENT_MULTI_STATEMENTS = 1 << 16;
+        const CLIENT_MULTI_RESULTS = 1 << 17;
+        const CLIENT_PS_MULTI_RESULTS = 1 << 18;
+        const CLIENT_PLUGIN_AUTH = 1 << 19;
+        const CLIENT_CONNECT_ATTRS = 1 << 20;
+        const CLIENT_PLUGIN_AUTH_LENENC_CLIENT_DATA = 1 << 21;
+        const CLIENT_CAN_HANDLE_EXPIRED_PASSWORDS = 1 << 22;
+        const CLIENT_SESSION_TRACK = 1 << 23;
+        const CLIENT_DEPRECATE_EOF = 1 << 24;
+        const CLIENT_ZSTD_COMPRESSION_ALGORITHM = 1 << 26;
+        const CLIENT_SSL_VERIFY_SERVER_CERT = 1 << 30;
+        const CLIENT_REMEMBER_OPTIONS = 1 << 31;
+        const MARIADB_CLIENT_PROGRESS = 1 << 32;
+        const MARIADB_CLIENT_COM_MULTI = 1 << 33;
+        const MARIADB_CLIENT_STMT_BULK_OPERATIONS = 1 << 34;
+        const MARIADB_CLIENT_EXTENDED_TYPE_INFO = 1 << 35;
+        const MARIADB_CLIENT_CACHE_METADATA = 1 << 36;
+    }
+}
+
+bitflags! {
+    /// Server status flags, sent in HandshakeV10 and in OK/EOF packets
+    /// https://mariadb.com/kb/en/ok_packet/#server-status-flag
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    pub struct StatusFlags: u16 {
+        const SERVER_STATUS_IN_TRANS = 1;
+        const SERVER_STATUS_AUTOCOMMIT = 1 << 1;
+        const SERVER_MORE_RESULTS_EXISTS = 1 << 3;
+        const SERVER_STATUS_NO_GOOD_INDEX_USED = 1 << 4;
+        const SERVER_STATUS_NO_INDEX_USED = 1 << 5;
+        const SERVER_STATUS_CURSOR_EXISTS = 1 << 6;
+        const SERVER_STATUS_LAST_ROW_SENT = 1 << 7;
+        const SERVER_STATUS_DB_DROPPED = 1 << 8;
+        const SERVER_STATUS_NO_BACKSLASH_ESCAPES = 1 << 9;
+        const SERVER_STATUS_METADATA_CHANGED = 1 << 10;
+        const SERVER_QUERY_WAS_SLOW = 1 << 11;
+        const SERVER_PS_OUT_PARAMS = 1 << 12;
+        const SERVER_STATUS_IN_TRANS_READONLY = 1 << 13;
+        const SERVER_SESSION_STATE_CHANGED = 1 << 14;
+    }
+}
+
+/// Initial handshake packet sent by the server
+/// https://mariadb.com/kb/en/connection/#initial-handshake-packet
+#[derive(Clone, Debug, PartialEq)]
+pub struct HandshakeV10 {
+    pub protocol_version: u8,
+    pub server_version: String,
+    pub connection_id: u32,
+    /// Scramble (both parts concatenated, without the trailing NUL)
+    pub auth_plugin_data: Vec<u8>,
+    pub capabilities: CapabilityFlags,
+    pub charset: u8,
+    pub status: StatusFlags,
+    pub auth_plugin_name: Option<String>,
+}
+
+impl HandshakeV10 {
+    pub fn parse(packet: &Packet) -> Result<HandshakeV10, Error> {
+        let mut r = Reader::new(mariadb_payload(packet)?);
+        let protocol_version = r.read_u8()?;
+        if protocol_version != 10 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Unsupported handshake protocol version {}",
+                    protocol_version
+                ),
+            ));
+        }
+        let server_version = to_string(r.read_null_terminated()?)?;
+        let connection_id = r.read_u32()?;
+        let mut auth_plugin_data = r.read_bytes(8)?.to_vec();
+        r.read_u8()?; // reserved
+        let mut caps = r.read_u16()? as u64;
+        let charset = r.read_u8()?;
+        let status = StatusFlags::from_bits_retain(r.read_u16()?);
+        caps |= (r.read_u16()? as u64) << 16;
+        let auth_plugin_data_len = r.read_u8()? as usize;
+        r.read_bytes(6)?; // filler
+        let extended_caps = r.read_u32()? as u64;
+        if caps & CapabilityFlags::CLIENT_MYSQL.bits() == 0 {
+            caps |= extended_caps << 32;
+        }
+        let capabilities = CapabilityFlags::from_bits_retain(caps);
+
+        if capabilities.contains(CapabilityFlags::CLIENT_SECURE_CONNECTION) {
+            // 2nd part of the scramble, followed by a NUL
+            let n = std::cmp::max(13, auth_plugin_data_len.saturating_sub(8));
+            let part2 = r.read_bytes(std::cmp::min(n, r.remaining()))?;
+            auth_plugin_data.extend_from_slice(part2.strip_suffix(&[0]).unwrap_or(part2));
+        }
+
+        let mut auth_plugin_name = None;
+        if capabilities.contains(CapabilityFlags::CLIENT_PLUGIN_AUTH) {
+            // Some servers omit the trailing NUL of the plugin name
+            let name = match r.read_null_terminated() {
+                Ok(name) => name,
+                Err(_) => r.read_rest(),
+            };
+            auth_plugin_name = Some(to_string(name)?);
+        }
+
+        Ok(HandshakeV10 {
+            protocol_version,
+            server_version,
+            connection_id,
+            auth_plugin_data,
+            capabilities,
+            charset,
+            status,
+            auth_plugin_name,
+        })
+    }
+
+    pub fn to_packet(&self) -> Packet {
+        let caps = self.capabilities.bits();
+        let mut payload: Vec<u8> = Vec::with_capacity(64);
+        payload.push(self.protocol_version);
+        write_null_terminated(&mut payload, self.server_version.as_bytes());
+        payload
+            .write_u32::<LittleEndian>(self.connection_id)
+            .unwrap();
+        let split = std::cmp::min(8, self.auth_plugin_data.len());
+        let (part1, part2) = self.auth_plugin_data.split_at(split);
+        payload.extend_from_slice(part1);
+        payload.resize(payload.len() + 8 - part1.len(), 0);
+        payload.push(0); // reserved
+        payload.write_u16::<LittleEndian>(caps as u16).unwrap();
+        payload.push(self.charset);
+        payload
+            .write_u16::<LittleEndian>(self.status.bits())
+            .unwrap();
+        payload
+            .write_u16::<LittleEndian>((caps >> 16) as u16)
+            .unwrap();
+        if self
+            .capabilities
+            .contains(CapabilityFlags::CLIENT_PLUGIN_AUTH)
+        {
+            payload.push((self.auth_plugin_data.len() + 1) as u8);
+        } else {
+            payload.push(0);
+        }
+        payload.extend_from_slice(&[0; 6]); // filler
+        if self.capabilities.contains(CapabilityFlags::CLIENT_MYSQL) {
+            payload.extend_from_slice(&[0; 4]);
+        } else {
+            payload
+                .write_u32::<LittleEndian>((caps >> 32) as u32)
+                .unwrap();
+        }
+        if self
+            .capabilities
+            .contains(CapabilityFlags::CLIENT_SECURE_CONNECTION)
+        {
+            payload.extend_from_slice(part2);
+            // 2nd part is at least 12 bytes, plus the NUL
+            payload.resize(payload.len() + 12_usize.saturating_sub(part2.len()), 0);
+            payload.push(0);
+        }
+        if let Some(name) = &self.auth_plugin_name {
+            if self
+                .capabilities
+                .contains(CapabilityFlags::CLIENT_PLUGIN_AUTH)
+            {
+                write_null_terminated(&mut payload, name.as_bytes());
+            }
+        }
+        Packet::new_mariadb(0, &payload)
+    }
+}
+
+/// Handshake response sent by the client
+/// https://mariadb.com/kb/en/connection/#handshake-response-packet
+#[derive(Clone, Debug, PartialEq)]
+pub struct HandshakeResponse41 {
+    pub capabilities: CapabilityFlags,
+    pub max_packet_size: u32,
+    pub charset: u8,
+    pub username: String,
+    pub auth_response: Vec<u8>,
+    pub database: Option<String>,
+    pub auth_plugin_name: Option<String>,
+    pub connect_attrs: Vec<(String, String)>,
+}
+
+impl HandshakeResponse41 {
+    /// Length of the truncated response a client sends before switching to TLS
+    pub const SSL_REQUEST_LEN: usize = 32;
+
+    /// Whether this packet is the SSLRequest that precedes the TLS handshake, rather than a
+    /// full handshake response
+    pub fn is_ssl_request(packet: &Packet) -> bool {
+        match mariadb_payload(packet) {
+            Ok(payload) => {
+                payload.len() == HandshakeResponse41::SSL_REQUEST_LEN
+                    && payload[1] & (CapabilityFlags::CLIENT_SSL.bits() >> 8) as u8 != 0
+            }
+            Err(_) => false,
+        }
+    }
+
+    pub fn parse(packet: &Packet) -> Result<HandshakeResponse41, Error> {
+        let mut r = Reader::new(mariadb_payload(packet)?);
+        let mut caps = r.read_u32()? as u64;
+        let max_packet_size = r.read_u32()?;
+        let charset = r.read_u8()?;
+        r.read_bytes(19)?; // reserved
+        let extended_caps = r.read_u32()? as u64;
+        if caps & CapabilityFlags::CLIENT_MYSQL.bits() == 0 {
+            caps |= extended_caps << 32;
+        }
+        let capabilities = CapabilityFlags::from_bits_retain(caps);
+        let username = to_string(r.read_null_terminated()?)?;
+
+        let auth_response =
+            if capabilities.contains(CapabilityFlags::CLIENT_PLUGIN_AUTH_LENENC_CLIENT_DATA) {
+                r.read_lenenc_bytes()?.unwrap_or_default().to_vec()
+            } else if capabilities.contains(CapabilityFlags::CLIENT_SECURE_CONNECTION) {
+                let n = r.read_u8()? as usize;
+                r.read_bytes(n)?.to_vec()
+            } else {
+                r.read_null_terminated()?.to_vec()
+            };
+
+        let mut database = None;
+        if capabilities.contains(CapabilityFlags::CLIENT_CONNECT_WITH_DB) && !r.is_empty() {
+            database = Some(to_string(r.read_null_terminated()?)?);
+        }
+        let mut auth_plugin_name = None;
+        if capabilities.contains(CapabilityFlags::CLIENT_PLUGIN_AUTH) && !r.is_empty() {
+            auth_plugin_name = Some(to_string(r.read_null_terminated()?)?);
+        }
+        let mut connect_attrs = Vec::new();
+        if capabilities.contains(CapabilityFlags::CLIENT_CONNECT_ATTRS) && !r.is_empty() {
+            let n = r.read_lenenc_int()?.unwrap_or(0) as usize;
+            let mut attrs = Reader::new(r.read_bytes(n)?);
+            while !attrs.is_empty() {
+                let key = to_string(attrs.read_lenenc_bytes()?.unwrap_or_default())?;
+                let value = to_string(attrs.read_lenenc_bytes()?.unwrap_or_default())?;
+                connect_attrs.push((key, value));
+            }
+        }
+
+        Ok(HandshakeResponse41 {
+            capabilities,
+            max_packet_size,
+            charset,
+            username,
+            auth_response,
+            database,
+            auth_plugin_name,
+            connect_attrs,
+        })
+    }
+
+    pub fn to_packet(&self, sequence_id: u8) -> Packet {
+        let caps = self.capabilities.bits();
+        let mut payload: Vec<u8> = Vec::with_capacity(128);
+        payload.write_u32::<LittleEndian>(caps as u32).unwrap();
+        payload
+            .write_u32::<LittleEndian>(self.max_packet_size)
+            .unwrap();
+        payload.push(self.charset);
+        payload.extend_from_slice(&[0; 19]); // reserved
+        if self.capabilities.contains(CapabilityFlags::CLIENT_MYSQL) {
+            payload.extend_from_slice(&[0; 4]);
+        } else {
+            payload
+                .write_u32::<LittleEndian>((caps >> 32) as u32)
+                .unwrap();
+        }
+        write_null_terminated(&mut payload, self.username.as_bytes());
+
+        if self
+            .capabilities
+            .contains(CapabilityFlags::CLIENT_PLUGIN_AUTH_LENENC_CLIENT_DATA)
+        {
+            write_lenenc_bytes(&mut payload, &self.auth_response);
+        } else if self
+            .capabilities
+            .contains(CapabilityFlags::CLIENT_SECURE_CONNECTION)
+        {
+            payload.push(self.auth_response.len() as u8);
+            payload.extend_from_slice(&self.auth_response);
+        } else {
+            write_null_terminated(&mut payload, &self.auth_response);
+        }
+
+        if self
+            .capabilities
+            .contains(CapabilityFlags::CLIENT_CONNECT_WITH_DB)
+        {
+            let database = self.database.as_deref().unwrap_or("");
+            write_null_terminated(&mut payload, database.as_bytes());
+        }
+        if self
+            .capabilities
+            .contains(CapabilityFlags::CLIENT_PLUGIN_AUTH)
+        {
+            let name = self.auth_plugin_name.as_deref().unwrap_or("");
+            write_null_terminated(&mut payload, name.as_bytes());
+        }
+        if self
+            .capabilities
+            .contains(CapabilityFlags::CLIENT_CONNECT_ATTRS)
+        {
+            let mut attrs: Vec<u8> = Vec::new();
+            for (key, value) in &self.connect_attrs {
+                write_lenenc_bytes(&mut attrs, key.as_bytes());
+                write_lenenc_bytes(&mut attrs, value.as_bytes());
+            }
+            write_lenenc_int(&mut payload, attrs.len() as u64);
+            payload.extend_from_slice(&attrs);
+        }
+        Packet::new_mariadb(sequence_id, &payload)
+    }
+}
+
+/// COM_CHANGE_USER, which authenticates as another user and resets the session
+/// https://mariadb.com/kb/en/com_change_user/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChangeUser {
+    pub username: String,
+    pub auth_response: Vec<u8>,
+    pub database: String,
+    pub charset: Option<u16>,
+    pub auth_plugin_name: Option<String>,
+}
+
+impl ChangeUser {
+    /// Parse a COM_CHANGE_USER with the capabilities negotiated during the handshake
+    pub fn parse(packet: &Packet, capabilities: CapabilityFlags) -> Result<ChangeUser, Error> {
+        let mut r = Reader::new(mariadb_payload(packet)?);
+        if r.read_u8()? != 0x11 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Packet is not a COM_CHANGE_USER",
+            ));
+        }
+        let username = to_string(r.read_null_terminated()?)?;
+        let auth_response = if capabilities.contains(CapabilityFlags::CLIENT_SECURE_CONNECTION) {
+            let n = r.read_u8()? as usize;
+            r.read_bytes(n)?.to_vec()
+        } else {
+            r.read_null_terminated()?.to_vec()
+        };
+        let database = to_string(r.read_null_terminated()?)?;
+        let mut charset = None;
+        if r.remaining() >= 2 {
+            charset = Some(r.read_u16()?);
+        }
+        let mut auth_plugin_name = None;
+        if capabilities.contains(CapabilityFlags::CLIENT_PLUGIN_AUTH) && !r.is_empty() {
+            auth_plugin_name = Some(to_string(r.read_null_terminated()?)?);
+        }
+        Ok(ChangeUser {
+            username,
+            auth_response,
+            database,
+            charset,
+            auth_plugin_name,
+        })
+    }
+}
+
+fn mariadb_payload(packet: &Packet) -> Result<&[u8], Error> {
+    if packet.get_db_type() != DatabaseType::MariaDB {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Expected a MariaDB packet",
+        ));
+    }
+    packet.get_payload()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::{collection::vec, option, prelude::*};
+
+    // https://dev.mysql.com/doc/internals/en/connection-phase-packets.html
+    const HANDSHAKE_V10: [u8; 58] = [
+        0x36, 0x00, 0x00, 0x00, 0x0a, 0x35, 0x2e, 0x35, 0x2e, 0x32, 0x2d, 0x6d, 0x32, 0x00, 0x0b,
+        0x00, 0x00, 0x00, 0x64, 0x76, 0x48, 0x40, 0x49, 0x2d, 0x43, 0x4a, 0x00, 0xff, 0xf7, 0x08,
+        0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x2a, 0x34, 0x64, 0x7c, 0x63, 0x5a, 0x77, 0x6b, 0x34, 0x5e, 0x5d, 0x3a, 0x00,
+    ];
+
+    const HANDSHAKE_RESPONSE_41: [u8; 88] = [
+        0x54, 0x00, 0x00, 0x01, 0x8d, 0xa6, 0x0f, 0x00, 0x00, 0x00, 0x00, 0x01, 0x08, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x70, 0x61, 0x6d, 0x00, 0x14, 0xab, 0x09, 0xee, 0xf6,
+        0xbc, 0xb1, 0x32, 0x3e, 0x61, 0x14, 0x38, 0x65, 0xc0, 0x99, 0x1d, 0x95, 0x7d, 0x75, 0xd4,
+        0x47, 0x74, 0x65, 0x73, 0x74, 0x00, 0x6d, 0x79, 0x73, 0x71, 0x6c, 0x5f, 0x6e, 0x61, 0x74,
+        0x69, 0x76, 0x65, 0x5f, 0x70, 0x61, 0x73, 0x73, 0x77, 0x6f, 0x72, 0x64, 0x00,
+    ];
+
+    #[test]
+    fn handshake_v10_round_trip() {
+        let packet = Packet::new(DatabaseType::MariaDB, HANDSHAKE_V10.to_vec());
+        let handshake = HandshakeV10::parse(&packet).unwrap();
+        assert_eq!(handshake.server_version, "5.5.2-m2");
+        assert_eq!(handshake.connection_id, 11);
+        assert_eq!(handshake.auth_plugin_data.len(), 20);
+        assert_eq!(handshake.charset, 8);
+        assert!(handshake
+            .status
+            .contains(StatusFlags::SERVER_STATUS_AUTOCOMMIT));
+        assert!(handshake
+            .capabilities
+            .contains(CapabilityFlags::CLIENT_PROTOCOL_41));
+        assert_eq!(handshake.auth_plugin_name, None);
+        assert_eq!(handshake.to_packet(), packet);
+    }
+
+    #[test]
+    fn handshake_response_41_round_trip() {
+        let packet = Packet::new(DatabaseType::MariaDB, HANDSHAKE_RESPONSE_41.to_vec());
+        assert!(!HandshakeResponse41::is_ssl_request(&packet));
+        let response = HandshakeResponse41::parse(&packet).unwrap();
+        assert_eq!(response.username, "pam");
+        assert_eq!(response.auth_response.len(), 20);
+        assert_eq!(response.database.as_deref(), Some("test"));
+        assert_eq!(
+            response.auth_plugin_name.as_deref(),
+            Some("mysql_native_password")
+        );
+        assert_eq!(response.to_packet(1), packet);
+    }
+
+    #[test]
+    fn handshake_response_41_connect_attrs() {
+        let response = HandshakeResponse41 {
+            capabilities: CapabilityFlags::CLIENT_PROTOCOL_41
+                | CapabilityFlags::CLIENT_SECURE_CONNECTION
+                | CapabilityFlags::CLIENT_PLUGIN_AUTH
+                | CapabilityFlags::CLIENT_CONNECT_ATTRS
+                | CapabilityFlags::CLIENT_DEPRECATE_EOF
+                | CapabilityFlags::MARIADB_CLIENT_EXTENDED_TYPE_INFO,
+            max_packet_size: 16_777_216,
+            charset: 45,
+            username: "root".to_string(),
+            auth_response: vec![1; 20],
+            database: None,
+            auth_plugin_name: Some("mysql_native_password".to_string()),
+            connect_attrs: vec![("_client_name".to_string(), "sql-proxy".to_string())],
+        };
+        let parsed = HandshakeResponse41::parse(&response.to_packet(1)).unwrap();
+        assert_eq!(parsed, response);
+    }
+
+    proptest! {
+        #[test]
+        fn handshake_response_41_round_trips(
+            extra_bits in any::<u64>(),
+            username in "[^\u{0}]{0,16}",
+            auth_response in vec(any::<u8>(), 0..64),
+            database in option::of("[^\u{0}]{0,16}"),
+            auth_plugin_name in option::of("[^\u{0}]{0,16}"),
+            connect_attrs in vec((".{0,8}", ".{0,8}"), 0..4),
+        ) {
+            // Fields are only present with their capability, which depends on the other fields
+            let mut capabilities = CapabilityFlags::from_bits_truncate(extra_bits)
+                | CapabilityFlags::CLIENT_PROTOCOL_41
+                | CapabilityFlags::CLIENT_PLUGIN_AUTH_LENENC_CLIENT_DATA;
+            capabilities.remove(CapabilityFlags::CLIENT_MYSQL);
+            capabilities.set(CapabilityFlags::CLIENT_CONNECT_WITH_DB, database.is_some());
+            capabilities.set(CapabilityFlags::CLIENT_PLUGIN_AUTH, auth_plugin_name.is_some());
+            capabilities.set(CapabilityFlags::CLIENT_CONNECT_ATTRS, !connect_attrs.is_empty());
+            let response = HandshakeResponse41 {
+                capabilities,
+                max_packet_size: 16_777_216,
+                charset: 45,
+                username,
+                auth_response,
+                database,
+                auth_plugin_name,
+                connect_attrs,
+            };
+            prop_assert_eq!(HandshakeResponse41::parse(&response.to_packet(1)).unwrap(), response);
+        }
+    }
+}
@@ -0,0 +1,292 @@
+//! LOAD DATA LOCAL INFILE: the server answers the query with a request for a file of the
+//! client, which the client sends as data packets ending with an empty one. Any server can
+//! ask for any file, so the proxy applies a policy to the requests.
+//! https://mariadb.com/kb/en/local_infile-packet/
+
+use std::{
+    io::{Error, ErrorKind},
+    path::{Component, Path, PathBuf},
+};
+
+use tokio::io::AsyncReadExt;
+
+use super::response::ErrPacket;
+use crate::packet::{Packet, PacketType};
+
+/// Size of the data packets of a file sent by the proxy
+pub const LOCAL_INFILE_CHUNK: usize = 0x10000;
+
+/// ER_NOT_ALLOWED_COMMAND, the error clients report when they refuse to send a file
+const NOT_ALLOWED_COMMAND: u16 = 1148;
+
+/// The request for a file sent by the server, with the 0xfb header
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LocalInfileRequest {
+    pub filename: String,
+}
+
+impl LocalInfileRequest {
+    pub fn parse(packet: &Packet) -> Result<LocalInfileRequest, Error> {
+        match packet.get_payload()?.split_first() {
+            Some((0xfb, filename)) => Ok(LocalInfileRequest {
+                filename: String::from_utf8_lossy(filename).into_owned(),
+            }),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                "Packet is not a LOCAL INFILE request",
+            )),
+        }
+    }
+
+    pub fn to_packet(&self, sequence_id: u8) -> Packet {
+        let mut payload = vec![0xfb];
+        payload.extend_from_slice(self.filename.as_bytes());
+        let mut packet = Packet::new_mariadb(sequence_id, &payload);
+        packet.set_packet_type(PacketType::LocalInfileRequest);
+        packet
+    }
+}
+
+/// What the proxy does with the LOCAL INFILE requests of the server
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub enum LocalInfilePolicy {
+    /// Forward requests to the client, which decides whether to send the file. A malicious
+    /// or compromised server can then read any file the client agrees to send, so only use
+    /// it with clients that check the requests themselves.
+    Allow,
+    /// Refuse every request: the client gets an error, and the server an empty file
+    #[default]
+    Deny,
+    /// Forward requests for files under one of these absolute paths, and refuse the others.
+    /// The files are on the client, so requested paths are compared as written, after
+    /// removing `.` components: relative paths and paths with `..` are refused.
+    Allowlist(Vec<PathBuf>),
+    /// Send files from this directory of the proxy, without asking the client. Requested
+    /// names are relative paths inside the directory, and symbolic links leading out of it
+    /// are refused, as well as files larger than `max_size` bytes.
+    Serve { directory: PathBuf, max_size: u64 },
+}
+
+/// The outcome of the policy for a request
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LocalInfileDecision {
+    Forward,
+    /// Refused, with the error message sent to the client
+    Deny(String),
+    /// The proxy sends this file, unless it is larger than `max_size` bytes
+    Serve {
+        path: PathBuf,
+        max_size: u64,
+    },
+}
+
+impl LocalInfilePolicy {
+    pub fn decide(&self, filename: &str) -> LocalInfileDecision {
+        let path = Path::new(filename);
+        let escapes = path.components().any(|c| c == Component::ParentDir);
+        match self {
+            LocalInfilePolicy::Allow => LocalInfileDecision::Forward,
+            LocalInfilePolicy::Deny => deny(filename),
+            LocalInfilePolicy::Allowlist(paths) => match normalize(path) {
+                Some(path)
+                    if paths
+                        .iter()
+                        .filter_map(|allowed| normalize(allowed))
+                        .any(|allowed| path.starts_with(allowed)) =>
+                {
+                    LocalInfileDecision::Forward
+                }
+                _ => deny(filename),
+            },
+            LocalInfilePolicy::Serve {
+                directory,
+                max_size,
+            } => {
+                if escapes || !path.is_relative() || filename.is_empty() {
+                    return deny(filename);
+                }
+                match (
+                    directory.canonicalize(),
+                    directory.join(path).canonicalize(),
+                ) {
+                    (Ok(directory), Ok(path)) if path.starts_with(&directory) => {
+                        LocalInfileDecision::Serve {
+                            path,
+                            max_size: *max_size,
+                        }
+                    }
+                    _ => deny(filename),
+                }
+            }
+        }
+    }
+}
+
+/// An absolute path without `.` components, or None for relative paths and paths with `..`
+fn normalize(path: &Path) -> Option<PathBuf> {
+    if !path.is_absolute() {
+        return None;
+    }
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::ParentDir => return None,
+            Component::CurDir => {}
+            _ => normalized.push(component),
+        }
+    }
+    Some(normalized)
+}
+
+fn deny(filename: &str) -> LocalInfileDecision {
+    LocalInfileDecision::Deny(format!(
+        "LOAD DATA LOCAL INFILE of '{}' is not allowed by the proxy",
+        filename
+    ))
+}
+
+/// A LOCAL INFILE exchange, from the request to the server's answer
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LocalInfile {
+    pub filename: String,
+    pub decision: LocalInfileDecision,
+    /// File bytes sent to the server so far
+    pub bytes: u64,
+}
+
+/// Read a file served by the proxy, in blocks of the size of its data packets. Files
+/// larger than `max_size` bytes are refused.
+pub async fn read_file(path: &Path, max_size: u64) -> Result<Vec<u8>, Error> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut contents = Vec::new();
+    let mut block = vec![0; LOCAL_INFILE_CHUNK];
+    loop {
+        let n = file.read(&mut block).await?;
+        if n == 0 {
+            return Ok(contents);
+        }
+        if (contents.len() + n) as u64 > max_size {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("File is larger than {} bytes", max_size),
+            ));
+        }
+        contents.extend_from_slice(&block[..n]);
+    }
+}
+
+/// The error sent to the client for a refused request
+pub fn deny_packet(sequence_id: u8, message: &str) -> Packet {
+    ErrPacket {
+        code: NOT_ALLOWED_COMMAND,
+        sql_state: Some("42000".to_string()),
+        message: message.to_string(),
+    }
+    .to_packet(sequence_id)
+}
+
+/// Data packets of a file starting at `sequence_id`, with the empty packet ending it
+pub fn data_packets(sequence_id: u8, contents: &[u8]) -> Vec<Packet> {
+    let mut sequence_id = sequence_id;
+    let mut packets = Vec::new();
+    for chunk in contents.chunks(LOCAL_INFILE_CHUNK).chain(Some(&[][..])) {
+        packets.push(Packet::new_mariadb(sequence_id, chunk));
+        sequence_id = sequence_id.wrapping_add(1);
+    }
+    packets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decides_requests() {
+        let directory = std::env::temp_dir().join(format!("infile-policy-{}", std::process::id()));
+        let import = directory.join("import");
+        std::fs::create_dir_all(import.join("daily")).unwrap();
+        std::fs::write(import.join("daily/a.csv"), b"1,2\n").unwrap();
+        std::fs::write(directory.join("secret"), b"password").unwrap();
+        std::os::unix::fs::symlink(directory.join("secret"), import.join("link")).unwrap();
+        let path = |name: &str| directory.join(name).to_string_lossy().into_owned();
+
+        let request = LocalInfileRequest {
+            filename: path("import/daily/a.csv"),
+        };
+        assert_eq!(
+            LocalInfileRequest::parse(&request.to_packet(1)).unwrap(),
+            request
+        );
+
+        let allowlist = LocalInfilePolicy::Allowlist(vec![PathBuf::from("/data/import/")]);
+        let decisions: Vec<LocalInfileDecision> = [
+            "/data/import/daily/a.csv",
+            "/data/./import//daily/b.csv",
+            "/data/import/../secret",
+            "/data/importer/b.csv",
+            "data/import/a.csv",
+            "",
+        ]
+        .iter()
+        .map(|filename| allowlist.decide(filename))
+        .collect();
+        assert_eq!(
+            decisions[..2],
+            [LocalInfileDecision::Forward, LocalInfileDecision::Forward]
+        );
+        for decision in &decisions[2..] {
+            assert!(matches!(decision, LocalInfileDecision::Deny(_)));
+        }
+
+        let serve = LocalInfilePolicy::Serve {
+            directory: import.clone(),
+            max_size: 4,
+        };
+        let decisions: Vec<LocalInfileDecision> = [
+            "daily/a.csv".to_string(),
+            path("secret"),
+            "../secret".to_string(),
+            "link".to_string(),
+            "missing.csv".to_string(),
+        ]
+        .iter()
+        .map(|filename| serve.decide(filename))
+        .collect();
+        let served = import.canonicalize().unwrap().join("daily/a.csv");
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(
+            decisions[0],
+            LocalInfileDecision::Serve {
+                path: served,
+                max_size: 4
+            }
+        );
+        for decision in &decisions[1..] {
+            assert!(matches!(decision, LocalInfileDecision::Deny(_)));
+        }
+        assert!(matches!(
+            LocalInfilePolicy::default().decide("a.csv"),
+            LocalInfileDecision::Deny(_)
+        ));
+
+        let packets = data_packets(2, &vec![b'x'; LOCAL_INFILE_CHUNK + 1]);
+        let sizes: Vec<(u8, usize)> = packets
+            .iter()
+            .map(|p| (p.get_sequence_id().unwrap(), p.get_payload().unwrap().len()))
+            .collect();
+        assert_eq!(sizes, vec![(2, LOCAL_INFILE_CHUNK), (3, 1), (4, 0)]);
+    }
+
+    #[tokio::test]
+    async fn reads_served_files_up_to_the_limit() {
+        let path = std::env::temp_dir().join(format!("infile-read-{}.csv", std::process::id()));
+        let contents = vec![b'x'; LOCAL_INFILE_CHUNK + 1];
+        std::fs::write(&path, &contents).unwrap();
+        let read = read_file(&path, contents.len() as u64).await;
+        let too_large = read_file(&path, LOCAL_INFILE_CHUNK as u64).await;
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read.unwrap(), contents);
+        assert_eq!(too_large.unwrap_err().kind(), ErrorKind::InvalidData);
+        assert!(read_file(&path, 1).await.is_err());
+    }
+}
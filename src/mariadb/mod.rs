@@ -0,0 +1,155 @@
+//! MariaDB client/server protocol
+//! For reference, see https://mariadb.com/kb/en/clientserver-protocol/
+
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use std::io::{Error, ErrorKind};
+
+pub mod binlog;
+pub mod charset;
+pub mod compress;
+pub mod decoder;
+pub mod handshake;
+pub mod infile;
+pub mod response;
+pub mod resultset;
+pub mod statement;
+pub mod value;
+
+/// Cursor over a MariaDB payload that reads the protocol's primitive data types
+/// https://mariadb.com/kb/en/protocol-data-types/
+#[derive(Clone, Debug)]
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader { buf, pos: 0 }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    pub fn peek_u8(&self) -> Result<u8, Error> {
+        self.buf
+            .get(self.pos)
+            .copied()
+            .ok_or_else(|| too_short("int<1>"))
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, Error> {
+        let b = self.peek_u8()?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, Error> {
+        Ok(LittleEndian::read_u16(self.read_bytes(2)?))
+    }
+
+    pub fn read_u24(&mut self) -> Result<u32, Error> {
+        Ok(LittleEndian::read_u24(self.read_bytes(3)?))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, Error> {
+        Ok(LittleEndian::read_u32(self.read_bytes(4)?))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, Error> {
+        Ok(LittleEndian::read_u64(self.read_bytes(8)?))
+    }
+
+    /// string<fix>
+    pub fn read_bytes(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.remaining() < n {
+            return Err(too_short(&format!("string<{}>", n)));
+        }
+        let bytes = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    /// string<EOF>
+    pub fn read_rest(&mut self) -> &'a [u8] {
+        let bytes = &self.buf[self.pos..];
+        self.pos = self.buf.len();
+        bytes
+    }
+
+    /// string<NUL>, the terminating NUL is consumed but not returned
+    pub fn read_null_terminated(&mut self) -> Result<&'a [u8], Error> {
+        match self.buf[self.pos..].iter().position(|b| *b == 0) {
+            Some(n) => {
+                let bytes = &self.buf[self.pos..self.pos + n];
+                self.pos += n + 1;
+                Ok(bytes)
+            }
+            None => Err(too_short("string<NUL>")),
+        }
+    }
+
+    /// int<lenenc>, returns None for the NULL marker (0xfb)
+    pub fn read_lenenc_int(&mut self) -> Result<Option<u64>, Error> {
+        match self.read_u8()? {
+            0xfb => Ok(None),
+            0xfc => Ok(Some(self.read_u16()? as u64)),
+            0xfd => Ok(Some(self.read_u24()? as u64)),
+            0xfe => Ok(Some(self.read_u64()?)),
+            0xff => Err(Error::new(
+                ErrorKind::InvalidData,
+                "Invalid length-encoded integer prefix 0xff",
+            )),
+            b => Ok(Some(b as u64)),
+        }
+    }
+
+    /// string<lenenc>, returns None for the NULL marker (0xfb)
+    pub fn read_lenenc_bytes(&mut self) -> Result<Option<&'a [u8]>, Error> {
+        match self.read_lenenc_int()? {
+            Some(n) => Ok(Some(self.read_bytes(n as usize)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+pub fn write_lenenc_int(buf: &mut Vec<u8>, n: u64) {
+    if n < 0xfb {
+        buf.push(n as u8);
+    } else if n <= 0xffff {
+        buf.push(0xfc);
+        buf.write_u16::<LittleEndian>(n as u16).unwrap();
+    } else if n <= 0xff_ffff {
+        buf.push(0xfd);
+        buf.write_u24::<LittleEndian>(n as u32).unwrap();
+    } else {
+        buf.push(0xfe);
+        buf.write_u64::<LittleEndian>(n).unwrap();
+    }
+}
+
+pub fn write_lenenc_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    write_lenenc_int(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+pub fn write_null_terminated(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(bytes);
+    buf.push(0);
+}
+
+fn too_short(field: &str) -> Error {
+    Error::new(
+        ErrorKind::UnexpectedEof,
+        format!("Packet too short reading {}", field),
+    )
+}
+
+fn to_string(bytes: &[u8]) -> Result<String, Error> {
+    String::from_utf8(bytes.to_vec()).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
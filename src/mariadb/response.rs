@@ -0,0 +1,222 @@
+//! Generic response packets
+//! https://mariadb.com/kb/en/ok_packet/
+//! https://mariadb.com/kb/en/err_packet/
+//! https://mariadb.com/kb/en/eof_packet/
+
+use std::io::{Error, ErrorKind};
+
+use super::{
+    handshake::{CapabilityFlags, StatusFlags},
+    to_string, write_lenenc_bytes, write_lenenc_int, Reader,
+};
+use crate::packet::{Packet, PacketType};
+
+/// OK packet, sent on success. With CLIENT_DEPRECATE_EOF it also ends a result set, using a
+/// 0xfe header instead of 0x00.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OkPacket {
+    pub header: u8,
+    pub affected_rows: u64,
+    pub last_insert_id: u64,
+    pub status: StatusFlags,
+    pub warnings: u16,
+    pub info: Vec<u8>,
+    /// Raw session state changes, present with CLIENT_SESSION_TRACK and
+    /// SERVER_SESSION_STATE_CHANGED
+    pub session_state_changes: Vec<u8>,
+}
+
+impl OkPacket {
+    pub fn parse(packet: &Packet, capabilities: CapabilityFlags) -> Result<OkPacket, Error> {
+        let mut r = Reader::new(packet.get_payload()?);
+        let header = r.read_u8()?;
+        if header != 0x00 && header != 0xfe {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid OK packet header {:#04x}", header),
+            ));
+        }
+        let affected_rows = r.read_lenenc_int()?.unwrap_or(0);
+        let last_insert_id = r.read_lenenc_int()?.unwrap_or(0);
+        let status = StatusFlags::from_bits_retain(r.read_u16()?);
+        let warnings = r.read_u16()?;
+        let mut info = Vec::new();
+        let mut session_state_changes = Vec::new();
+        if capabilities.contains(CapabilityFlags::CLIENT_SESSION_TRACK) {
+            if !r.is_empty() {
+                info = r.read_lenenc_bytes()?.unwrap_or_default().to_vec();
+            }
+            if status.contains(StatusFlags::SERVER_SESSION_STATE_CHANGED) && !r.is_empty() {
+                session_state_changes = r.read_lenenc_bytes()?.unwrap_or_default().to_vec();
+            }
+        } else {
+            info = r.read_rest().to_vec();
+        }
+        Ok(OkPacket {
+            header,
+            affected_rows,
+            last_insert_id,
+            status,
+            warnings,
+            info,
+            session_state_changes,
+        })
+    }
+
+    pub fn to_packet(&self, sequence_id: u8, capabilities: CapabilityFlags) -> Packet {
+        let mut payload = vec![self.header];
+        write_lenenc_int(&mut payload, self.affected_rows);
+        write_lenenc_int(&mut payload, self.last_insert_id);
+        payload.extend_from_slice(&self.status.bits().to_le_bytes());
+        payload.extend_from_slice(&self.warnings.to_le_bytes());
+        if capabilities.contains(CapabilityFlags::CLIENT_SESSION_TRACK) {
+            let state_changed = self
+                .status
+                .contains(StatusFlags::SERVER_SESSION_STATE_CHANGED);
+            if !self.info.is_empty() || state_changed {
+                write_lenenc_bytes(&mut payload, &self.info);
+            }
+            if state_changed {
+                write_lenenc_bytes(&mut payload, &self.session_state_changes);
+            }
+        } else {
+            payload.extend_from_slice(&self.info);
+        }
+        let mut packet = Packet::new_mariadb(sequence_id, &payload);
+        packet.set_packet_type(PacketType::ComOk);
+        packet
+    }
+
+    /// Decode the session state changes
+    pub fn get_session_tracks(&self) -> Result<Vec<SessionTrack>, Error> {
+        let mut tracks = Vec::new();
+        let mut r = Reader::new(&self.session_state_changes);
+        while !r.is_empty() {
+            let track_type = r.read_u8()?;
+            let data = r.read_lenenc_bytes()?.unwrap_or_default();
+            let mut d = Reader::new(data);
+            let mut string = || {
+                d.read_lenenc_bytes()
+                    .map(|s| String::from_utf8_lossy(s.unwrap_or_default()).into_owned())
+            };
+            let track = match track_type {
+                0 => SessionTrack::SystemVariable {
+                    name: string()?,
+                    value: string()?,
+                },
+                1 => SessionTrack::Schema(string()?),
+                2 => SessionTrack::StateChange(string()? == "1"),
+                3 => SessionTrack::Gtids(data.to_vec()),
+                4 => SessionTrack::TransactionCharacteristics(string()?),
+                5 => SessionTrack::TransactionState(string()?),
+                _ => SessionTrack::Unknown(track_type, data.to_vec()),
+            };
+            tracks.push(track);
+        }
+        Ok(tracks)
+    }
+}
+
+/// A session state change in an OK packet, with CLIENT_SESSION_TRACK
+/// https://mariadb.com/kb/en/ok_packet/#session-change-type
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SessionTrack {
+    /// A tracked system variable changed, see session_track_system_variables
+    SystemVariable {
+        name: String,
+        value: String,
+    },
+    /// The current database changed
+    Schema(String),
+    /// Some session state changed, with session_track_state_change
+    StateChange(bool),
+    Gtids(Vec<u8>),
+    /// Statement restoring the characteristics of the transaction in progress
+    TransactionCharacteristics(String),
+    /// Eight characters describing the transaction in progress, with
+    /// session_track_transaction_info
+    TransactionState(String),
+    Unknown(u8, Vec<u8>),
+}
+
+/// ERR packet
+#[derive(Clone, Debug, PartialEq)]
+pub struct ErrPacket {
+    pub code: u16,
+    pub sql_state: Option<String>,
+    pub message: String,
+}
+
+impl ErrPacket {
+    pub fn parse(packet: &Packet) -> Result<ErrPacket, Error> {
+        let mut r = Reader::new(packet.get_payload()?);
+        if r.read_u8()? != 0xff {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Invalid ERR packet header",
+            ));
+        }
+        let code = r.read_u16()?;
+        let mut sql_state = None;
+        if r.peek_u8().ok() == Some(b'#') {
+            r.read_u8()?;
+            sql_state = Some(to_string(r.read_bytes(5)?)?);
+        }
+        let message = String::from_utf8_lossy(r.read_rest()).into_owned();
+        Ok(ErrPacket {
+            code,
+            sql_state,
+            message,
+        })
+    }
+
+    pub fn to_packet(&self, sequence_id: u8) -> Packet {
+        let mut payload = vec![0xff];
+        payload.extend_from_slice(&self.code.to_le_bytes());
+        if let Some(sql_state) = &self.sql_state {
+            payload.push(b'#');
+            payload.extend_from_slice(sql_state.as_bytes());
+        }
+        payload.extend_from_slice(self.message.as_bytes());
+        let mut packet = Packet::new_mariadb(sequence_id, &payload);
+        packet.set_packet_type(PacketType::ComErr);
+        packet
+    }
+}
+
+/// EOF packet, ends column definitions and rows unless CLIENT_DEPRECATE_EOF was negotiated
+#[derive(Clone, Debug, PartialEq)]
+pub struct EofPacket {
+    pub warnings: u16,
+    pub status: StatusFlags,
+}
+
+impl EofPacket {
+    pub fn parse(packet: &Packet) -> Result<EofPacket, Error> {
+        let mut r = Reader::new(packet.get_payload()?);
+        if r.read_u8()? != 0xfe {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Invalid EOF packet header",
+            ));
+        }
+        let warnings = r.read_u16()?;
+        let status = StatusFlags::from_bits_retain(r.read_u16()?);
+        Ok(EofPacket { warnings, status })
+    }
+
+    pub fn to_packet(&self, sequence_id: u8) -> Packet {
+        let mut payload = vec![0xfe];
+        payload.extend_from_slice(&self.warnings.to_le_bytes());
+        payload.extend_from_slice(&self.status.bits().to_le_bytes());
+        let mut packet = Packet::new_mariadb(sequence_id, &payload);
+        packet.set_packet_type(PacketType::ComEof);
+        packet
+    }
+}
+
+/// Whether a payload starting with 0xfe is an EOF/OK terminator rather than a row whose first
+/// column is longer than 16MB
+pub fn is_eof_payload(payload: &[u8]) -> bool {
+    payload.first() == Some(&0xfe) && payload.len() < 0xff_ffff
+}
@@ -0,0 +1,512 @@
+//! Result sets returned by COM_QUERY (text protocol) and COM_STMT_EXECUTE (binary protocol)
+//! https://mariadb.com/kb/en/result-set-packets/
+
+use bitflags::bitflags;
+use std::io::{Error, ErrorKind};
+
+use super::{
+    handshake::{CapabilityFlags, StatusFlags},
+    response::{is_eof_payload, EofPacket, OkPacket},
+    to_string,
+    value::*,
+    write_lenenc_bytes, write_lenenc_int, Reader,
+};
+use crate::packet::{Packet, PacketType};
+
+bitflags! {
+    /// Column definition flags
+    /// https://mariadb.com/kb/en/result-set-packets/#field-details-flag
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    pub struct ColumnFlags: u16 {
+        const NOT_NULL = 1;
+        const PRIMARY_KEY = 1 << 1;
+        const UNIQUE_KEY = 1 << 2;
+        const MULTIPLE_KEY = 1 << 3;
+        const BLOB = 1 << 4;
+        const UNSIGNED = 1 << 5;
+        const ZEROFILL = 1 << 6;
+        const BINARY_COLLATION = 1 << 7;
+        const ENUM = 1 << 8;
+        const AUTO_INCREMENT = 1 << 9;
+        const TIMESTAMP = 1 << 10;
+        const SET = 1 << 11;
+        const NO_DEFAULT_VALUE = 1 << 12;
+        const ON_UPDATE_NOW = 1 << 13;
+        const NUM = 1 << 15;
+    }
+}
+
+/// ColumnDefinition41 packet, describing one column of a result set
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColumnDefinition {
+    pub catalog: String,
+    pub schema: String,
+    /// Table alias
+    pub table: String,
+    pub org_table: String,
+    /// Column alias
+    pub name: String,
+    pub org_name: String,
+    /// MariaDB extended type info (MARIADB_CLIENT_EXTENDED_TYPE_INFO)
+    pub extended_type_info: Vec<u8>,
+    pub charset: u16,
+    pub column_length: u32,
+    pub column_type: u8,
+    pub flags: ColumnFlags,
+    pub decimals: u8,
+}
+
+/// Collation id of utf8mb4_general_ci
+const UTF8MB4_CHARSET: u16 = 45;
+
+impl ColumnDefinition {
+    /// A column that is not read from a table, like a computed value
+    pub fn new(name: &str, column_type: u8) -> ColumnDefinition {
+        let (charset, column_length, flags) = match column_type {
+            MYSQL_TYPE_TINY => (BINARY_CHARSET, 4, ColumnFlags::NUM),
+            MYSQL_TYPE_SHORT | MYSQL_TYPE_YEAR => (BINARY_CHARSET, 6, ColumnFlags::NUM),
+            MYSQL_TYPE_INT24 => (BINARY_CHARSET, 9, ColumnFlags::NUM),
+            MYSQL_TYPE_LONG => (BINARY_CHARSET, 11, ColumnFlags::NUM),
+            MYSQL_TYPE_LONGLONG => (BINARY_CHARSET, 20, ColumnFlags::NUM),
+            MYSQL_TYPE_FLOAT => (BINARY_CHARSET, 12, ColumnFlags::NUM),
+            MYSQL_TYPE_DOUBLE => (BINARY_CHARSET, 22, ColumnFlags::NUM),
+            MYSQL_TYPE_DECIMAL | MYSQL_TYPE_NEWDECIMAL => (BINARY_CHARSET, 67, ColumnFlags::NUM),
+            MYSQL_TYPE_DATE | MYSQL_TYPE_NEWDATE => {
+                (BINARY_CHARSET, 10, ColumnFlags::BINARY_COLLATION)
+            }
+            MYSQL_TYPE_TIME | MYSQL_TYPE_TIME2 => {
+                (BINARY_CHARSET, 17, ColumnFlags::BINARY_COLLATION)
+            }
+            MYSQL_TYPE_DATETIME
+            | MYSQL_TYPE_DATETIME2
+            | MYSQL_TYPE_TIMESTAMP
+            | MYSQL_TYPE_TIMESTAMP2 => (BINARY_CHARSET, 26, ColumnFlags::BINARY_COLLATION),
+            MYSQL_TYPE_TINY_BLOB
+            | MYSQL_TYPE_MEDIUM_BLOB
+            | MYSQL_TYPE_LONG_BLOB
+            | MYSQL_TYPE_BLOB
+            | MYSQL_TYPE_GEOMETRY => (
+                BINARY_CHARSET,
+                u32::MAX,
+                ColumnFlags::BLOB | ColumnFlags::BINARY_COLLATION,
+            ),
+            MYSQL_TYPE_BIT => (BINARY_CHARSET, 64, ColumnFlags::UNSIGNED),
+            _ => (UTF8MB4_CHARSET, 1024, ColumnFlags::empty()),
+        };
+        ColumnDefinition {
+            catalog: "def".to_string(),
+            schema: String::new(),
+            table: String::new(),
+            org_table: String::new(),
+            name: name.to_string(),
+            org_name: String::new(),
+            extended_type_info: Vec::new(),
+            charset,
+            column_length,
+            column_type,
+            flags,
+            decimals: 0,
+        }
+    }
+
+    pub fn parse(
+        packet: &Packet,
+        capabilities: CapabilityFlags,
+    ) -> Result<ColumnDefinition, Error> {
+        let mut r = Reader::new(packet.get_payload()?);
+        let mut lenenc_string =
+            || -> Result<String, Error> { to_string(r.read_lenenc_bytes()?.unwrap_or_default()) };
+        let catalog = lenenc_string()?;
+        let schema = lenenc_string()?;
+        let table = lenenc_string()?;
+        let org_table = lenenc_string()?;
+        let name = lenenc_string()?;
+        let org_name = lenenc_string()?;
+        let extended_type_info =
+            if capabilities.contains(CapabilityFlags::MARIADB_CLIENT_EXTENDED_TYPE_INFO) {
+                r.read_lenenc_bytes()?.unwrap_or_default().to_vec()
+            } else {
+                Vec::new()
+            };
+        // Length of the fixed fields, always 0x0c
+        r.read_lenenc_int()?;
+        let charset = r.read_u16()?;
+        let column_length = r.read_u32()?;
+        let column_type = r.read_u8()?;
+        let flags = ColumnFlags::from_bits_retain(r.read_u16()?);
+        let decimals = r.read_u8()?;
+        Ok(ColumnDefinition {
+            catalog,
+            schema,
+            table,
+            org_table,
+            name,
+            org_name,
+            extended_type_info,
+            charset,
+            column_length,
+            column_type,
+            flags,
+            decimals,
+        })
+    }
+
+    pub fn to_packet(&self, sequence_id: u8, capabilities: CapabilityFlags) -> Packet {
+        let mut payload = Vec::new();
+        write_lenenc_bytes(&mut payload, self.catalog.as_bytes());
+        write_lenenc_bytes(&mut payload, self.schema.as_bytes());
+        write_lenenc_bytes(&mut payload, self.table.as_bytes());
+        write_lenenc_bytes(&mut payload, self.org_table.as_bytes());
+        write_lenenc_bytes(&mut payload, self.name.as_bytes());
+        write_lenenc_bytes(&mut payload, self.org_name.as_bytes());
+        if capabilities.contains(CapabilityFlags::MARIADB_CLIENT_EXTENDED_TYPE_INFO) {
+            write_lenenc_bytes(&mut payload, &self.extended_type_info);
+        }
+        write_lenenc_int(&mut payload, 0x0c);
+        payload.extend_from_slice(&self.charset.to_le_bytes());
+        payload.extend_from_slice(&self.column_length.to_le_bytes());
+        payload.push(self.column_type);
+        payload.extend_from_slice(&self.flags.bits().to_le_bytes());
+        payload.push(self.decimals);
+        // Unused
+        payload.extend_from_slice(&[0, 0]);
+        let mut packet = Packet::new_mariadb(sequence_id, &payload);
+        packet.set_packet_type(PacketType::ColumnDefinition);
+        packet
+    }
+}
+
+/// A complete result set: column metadata and rows of typed values
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ResultSet {
+    pub columns: Vec<ColumnDefinition>,
+    pub rows: Vec<Vec<Value>>,
+}
+
+impl ResultSet {
+    /// Parse the packets of a result set response, from the column count up to and including
+    /// the terminating EOF or OK. `binary` is set for responses to COM_STMT_EXECUTE.
+    pub fn parse(
+        packets: &[Packet],
+        binary: bool,
+        capabilities: CapabilityFlags,
+    ) -> Result<ResultSet, Error> {
+        let mut packets = packets.iter();
+        let mut next = || {
+            packets.next().ok_or_else(|| {
+                Error::new(ErrorKind::UnexpectedEof, "Result set is missing packets")
+            })
+        };
+        let count = Reader::new(next()?.get_payload()?)
+            .read_lenenc_int()?
+            .unwrap_or(0);
+        let mut columns = Vec::new();
+        for _ in 0..count {
+            columns.push(ColumnDefinition::parse(next()?, capabilities)?);
+        }
+        if !capabilities.contains(CapabilityFlags::CLIENT_DEPRECATE_EOF) {
+            next()?;
+        }
+        let mut rows = Vec::new();
+        loop {
+            let packet = next()?;
+            let payload = packet.get_payload()?;
+            if is_eof_payload(payload) {
+                break;
+            }
+            if payload.first() == Some(&0xff) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Result set ended with an ERR packet",
+                ));
+            }
+            rows.push(decode_row(&columns, binary, packet)?);
+        }
+        Ok(ResultSet { columns, rows })
+    }
+
+    /// Build the packets of a result set response, for the proxy to answer a query itself.
+    /// `sequence_id` is the id of the first packet, the command's sequence id plus one.
+    /// `status` is sent in the terminating EOF or OK packet, which should reflect the
+    /// connection's transaction state.
+    pub fn to_packets(
+        &self,
+        sequence_id: u8,
+        binary: bool,
+        capabilities: CapabilityFlags,
+        status: StatusFlags,
+    ) -> Result<Vec<Packet>, Error> {
+        let mut packets = Vec::with_capacity(self.columns.len() + self.rows.len() + 3);
+        let mut sequence_id = sequence_id;
+        let mut next_id = || {
+            let id = sequence_id;
+            sequence_id = sequence_id.wrapping_add(1);
+            id
+        };
+
+        let mut count = Vec::new();
+        write_lenenc_int(&mut count, self.columns.len() as u64);
+        let mut packet = Packet::new_mariadb(next_id(), &count);
+        packet.set_packet_type(PacketType::ColumnCount);
+        packets.push(packet);
+        for column in &self.columns {
+            packets.push(column.to_packet(next_id(), capabilities));
+        }
+        let deprecate_eof = capabilities.contains(CapabilityFlags::CLIENT_DEPRECATE_EOF);
+        if !deprecate_eof {
+            packets.push(
+                EofPacket {
+                    warnings: 0,
+                    status,
+                }
+                .to_packet(next_id()),
+            );
+        }
+
+        for row in &self.rows {
+            if row.len() != self.columns.len() {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Row has {} values, but there are {} columns",
+                        row.len(),
+                        self.columns.len()
+                    ),
+                ));
+            }
+            let mut payload = Vec::new();
+            if binary {
+                payload.push(0x00);
+                let bitmap_start = payload.len();
+                payload.resize(bitmap_start + (self.columns.len() + 7 + 2) / 8, 0);
+                for (i, (column, value)) in self.columns.iter().zip(row.iter()).enumerate() {
+                    if *value == Value::Null {
+                        let bit = i + 2;
+                        payload[bitmap_start + bit / 8] |= 1 << (bit % 8);
+                    } else {
+                        value.write_binary(column, &mut payload)?;
+                    }
+                }
+            } else {
+                for value in row {
+                    value.write_text(&mut payload);
+                }
+            }
+            let mut packet = Packet::new_mariadb(next_id(), &payload);
+            packet.set_packet_type(if binary {
+                PacketType::BinaryRow
+            } else {
+                PacketType::TextRow
+            });
+            packets.push(packet);
+        }
+
+        if deprecate_eof {
+            let ok = OkPacket {
+                header: 0xfe,
+                affected_rows: 0,
+                last_insert_id: 0,
+                status,
+                warnings: 0,
+                info: Vec::new(),
+                session_state_changes: Vec::new(),
+            };
+            packets.push(ok.to_packet(next_id(), capabilities));
+        } else {
+            packets.push(
+                EofPacket {
+                    warnings: 0,
+                    status,
+                }
+                .to_packet(next_id()),
+            );
+        }
+        Ok(packets)
+    }
+}
+
+/// Decode a text or binary protocol row
+pub fn decode_row(
+    columns: &[ColumnDefinition],
+    binary: bool,
+    packet: &Packet,
+) -> Result<Vec<Value>, Error> {
+    if binary {
+        decode_binary_row(columns, packet)
+    } else {
+        decode_text_row(columns, packet)
+    }
+}
+
+/// Text protocol row: one string<lenenc> per column, 0xfb for NULL
+pub fn decode_text_row(columns: &[ColumnDefinition], packet: &Packet) -> Result<Vec<Value>, Error> {
+    let mut r = Reader::new(packet.get_payload()?);
+    let row = columns
+        .iter()
+        .map(|column| Value::decode_text(column, r.read_lenenc_bytes()?))
+        .collect::<Result<Vec<Value>, Error>>()?;
+    if !r.is_empty() {
+        return Err(column_count_mismatch());
+    }
+    Ok(row)
+}
+
+/// Binary protocol row: a 0x00 header, a NULL bitmap with an offset of 2 bits, then the
+/// values of the non-NULL columns
+pub fn decode_binary_row(
+    columns: &[ColumnDefinition],
+    packet: &Packet,
+) -> Result<Vec<Value>, Error> {
+    let mut r = Reader::new(packet.get_payload()?);
+    if r.read_u8()? != 0x00 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Invalid binary row header",
+        ));
+    }
+    let null_bitmap = r.read_bytes((columns.len() + 7 + 2) / 8)?;
+    let row = columns
+        .iter()
+        .enumerate()
+        .map(|(i, column)| {
+            let bit = i + 2;
+            if null_bitmap[bit / 8] & (1 << (bit % 8)) != 0 {
+                Ok(Value::Null)
+            } else {
+                Value::read_binary(column, &mut r)
+            }
+        })
+        .collect::<Result<Vec<Value>, Error>>()?;
+    if !r.is_empty() {
+        return Err(column_count_mismatch());
+    }
+    Ok(row)
+}
+
+fn column_count_mismatch() -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        "Row has more values than column definitions",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn column(name: &str, column_type: u8, flags: ColumnFlags) -> ColumnDefinition {
+        ColumnDefinition {
+            catalog: "def".to_string(),
+            schema: "test".to_string(),
+            table: "t".to_string(),
+            org_table: "t".to_string(),
+            name: name.to_string(),
+            org_name: name.to_string(),
+            extended_type_info: Vec::new(),
+            charset: 33,
+            column_length: 11,
+            column_type,
+            flags,
+            decimals: 0,
+        }
+    }
+
+    #[test]
+    fn parses_text_result_set() {
+        let capabilities = CapabilityFlags::CLIENT_PROTOCOL_41;
+        let id = column("id", MYSQL_TYPE_LONG, ColumnFlags::UNSIGNED);
+        let name = column("name", MYSQL_TYPE_VAR_STRING, ColumnFlags::empty());
+        let eof = Packet::new_mariadb(3, &[0xfe, 0x00, 0x00, 0x02, 0x00]);
+        let packets = [
+            Packet::new_mariadb(0, &[0x02]),
+            id.to_packet(1, capabilities),
+            name.to_packet(2, capabilities),
+            eof.clone(),
+            Packet::new_mariadb(4, b"\x017\x03bob"),
+            Packet::new_mariadb(5, b"\x018\xfb"),
+            eof,
+        ];
+        let result_set = ResultSet::parse(&packets, false, capabilities).unwrap();
+        assert_eq!(result_set.columns, vec![id, name]);
+        assert_eq!(
+            result_set.rows,
+            vec![
+                vec![Value::UInt(7), Value::Text("bob".to_string())],
+                vec![Value::UInt(8), Value::Null],
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_binary_row_with_nulls() {
+        let columns = [
+            column("id", MYSQL_TYPE_LONG, ColumnFlags::empty()),
+            column("name", MYSQL_TYPE_VAR_STRING, ColumnFlags::empty()),
+            column("created", MYSQL_TYPE_DATETIME, ColumnFlags::empty()),
+        ];
+        // The second column is NULL: bit 1 + 2
+        let row = Packet::new_mariadb(
+            4,
+            b"\x00\x08\xf9\xff\xff\xff\x07\xe4\x07\x02\x1d\x0c\x22\x38",
+        );
+        assert_eq!(
+            decode_binary_row(&columns, &row).unwrap(),
+            vec![
+                Value::Int(-7),
+                Value::Null,
+                Value::DateTime {
+                    year: 2020,
+                    month: 2,
+                    day: 29,
+                    hour: 12,
+                    minute: 34,
+                    second: 56,
+                    microsecond: 0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn builds_result_sets() {
+        let result_set = ResultSet {
+            columns: vec![
+                ColumnDefinition::new("id", MYSQL_TYPE_LONGLONG),
+                ColumnDefinition::new("name", MYSQL_TYPE_VAR_STRING),
+                ColumnDefinition::new("born", MYSQL_TYPE_DATE),
+            ],
+            rows: vec![
+                vec![
+                    Value::Int(1),
+                    Value::Text("alice".to_string()),
+                    Value::Date {
+                        year: 1990,
+                        month: 1,
+                        day: 2,
+                    },
+                ],
+                vec![Value::Int(-2), Value::Null, Value::Null],
+            ],
+        };
+        let status = StatusFlags::SERVER_STATUS_AUTOCOMMIT;
+        for capabilities in [
+            CapabilityFlags::CLIENT_PROTOCOL_41,
+            CapabilityFlags::CLIENT_PROTOCOL_41 | CapabilityFlags::CLIENT_DEPRECATE_EOF,
+        ]
+        .iter()
+        {
+            for binary in [false, true].iter() {
+                let packets = result_set
+                    .to_packets(1, *binary, *capabilities, status)
+                    .unwrap();
+                let sequence_ids: Vec<u8> = packets.iter().map(|p| p.bytes[3]).collect();
+                let expected: Vec<u8> = (1..=packets.len() as u8).collect();
+                assert_eq!(sequence_ids, expected);
+                assert_eq!(
+                    ResultSet::parse(&packets, *binary, *capabilities).unwrap(),
+                    result_set
+                );
+            }
+        }
+    }
+}
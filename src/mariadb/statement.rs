@@ -0,0 +1,331 @@
+//! Prepared statements of the binary protocol, so the SQL text and bound values are known
+//! when a statement is executed
+//! https://mariadb.com/kb/en/com_stmt_prepare/
+//! https://mariadb.com/kb/en/com_stmt_execute/
+
+use encoding_rs::Encoding;
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{Error, ErrorKind},
+};
+
+use super::{
+    resultset::{ColumnDefinition, ColumnFlags},
+    to_string,
+    value::{Value, MYSQL_TYPE_NULL},
+    Reader,
+};
+use crate::packet::Packet;
+
+/// COM_STMT_PREPARE_OK, the server's response to a successful COM_STMT_PREPARE. Parameter
+/// and column definitions follow it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StmtPrepareOk {
+    pub statement_id: u32,
+    pub num_columns: u16,
+    pub num_params: u16,
+    pub warnings: u16,
+}
+
+impl StmtPrepareOk {
+    pub fn parse(packet: &Packet) -> Result<StmtPrepareOk, Error> {
+        let mut r = Reader::new(packet.get_payload()?);
+        if r.read_u8()? != 0x00 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Invalid COM_STMT_PREPARE_OK header",
+            ));
+        }
+        let statement_id = r.read_u32()?;
+        let num_columns = r.read_u16()?;
+        let num_params = r.read_u16()?;
+        let mut warnings = 0;
+        if !r.is_empty() {
+            // Reserved
+            r.read_u8()?;
+            warnings = r.read_u16()?;
+        }
+        Ok(StmtPrepareOk {
+            statement_id,
+            num_columns,
+            num_params,
+            warnings,
+        })
+    }
+}
+
+/// Type of a bound parameter, as sent in COM_STMT_EXECUTE
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ParamType {
+    pub column_type: u8,
+    pub unsigned: bool,
+}
+
+/// A statement prepared by the server
+#[derive(Clone, Debug, PartialEq)]
+pub struct PreparedStatement {
+    pub statement_id: u32,
+    pub query: String,
+    pub num_columns: u16,
+    pub num_params: u16,
+    /// Parameter types sent by the client. They are only sent when they change, so they are
+    /// remembered between executions.
+    pub param_types: Vec<ParamType>,
+    /// Parameter values sent with COM_STMT_SEND_LONG_DATA for the next execution
+    long_data: HashMap<u16, Vec<u8>>,
+}
+
+/// A COM_STMT_EXECUTE with the statement's SQL text and the decoded parameter values
+#[derive(Clone, Debug, PartialEq)]
+pub struct StmtExecute {
+    pub statement_id: u32,
+    pub query: String,
+    /// Cursor type flags
+    pub flags: u8,
+    pub params: Vec<Value>,
+}
+
+/// Prepared statements of a connection
+#[derive(Clone, Debug, Default)]
+pub struct Statements {
+    statements: HashMap<u32, PreparedStatement>,
+    /// SQL text of COM_STMT_PREPARE commands waiting for a response
+    pending: VecDeque<String>,
+}
+
+impl Statements {
+    pub fn new() -> Statements {
+        Statements::default()
+    }
+
+    pub fn get_statement(&self, statement_id: u32) -> Option<&PreparedStatement> {
+        self.statements.get(&statement_id)
+    }
+
+    /// The client sent COM_STMT_PREPARE, with SQL text in the client's character set
+    pub fn prepare(&mut self, packet: &Packet, encoding: &'static Encoding) -> Result<(), Error> {
+        let sql = command_body(packet)?.read_rest();
+        let (sql, _) = encoding.decode_without_bom_handling(sql);
+        self.pending.push_back(sql.into_owned());
+        Ok(())
+    }
+
+    /// The server answered the oldest COM_STMT_PREPARE with COM_STMT_PREPARE_OK
+    pub fn prepared(&mut self, packet: &Packet) -> Result<&PreparedStatement, Error> {
+        let query = self.pending.pop_front().unwrap_or_default();
+        let ok = StmtPrepareOk::parse(packet)?;
+        let statement = PreparedStatement {
+            statement_id: ok.statement_id,
+            query,
+            num_columns: ok.num_columns,
+            num_params: ok.num_params,
+            param_types: Vec::new(),
+            long_data: HashMap::new(),
+        };
+        self.statements.insert(ok.statement_id, statement);
+        Ok(&self.statements[&ok.statement_id])
+    }
+
+    /// The server answered the oldest COM_STMT_PREPARE with an error
+    pub fn prepare_failed(&mut self) {
+        self.pending.pop_front();
+    }
+
+    /// The client sent COM_STMT_SEND_LONG_DATA
+    pub fn send_long_data(&mut self, packet: &Packet) -> Result<(), Error> {
+        let mut r = command_body(packet)?;
+        let statement = self.statement_mut(r.read_u32()?)?;
+        let param_id = r.read_u16()?;
+        statement
+            .long_data
+            .entry(param_id)
+            .or_default()
+            .extend_from_slice(r.read_rest());
+        Ok(())
+    }
+
+    /// The client sent COM_STMT_EXECUTE. Decodes the parameters, remembering their types
+    /// for the next execution.
+    pub fn execute(&mut self, packet: &Packet) -> Result<StmtExecute, Error> {
+        let mut r = command_body(packet)?;
+        let statement_id = r.read_u32()?;
+        let flags = r.read_u8()?;
+        // Iteration count, always 1
+        r.read_u32()?;
+        let statement = self.statement_mut(statement_id)?;
+        let num_params = statement.num_params as usize;
+        let mut params = Vec::with_capacity(num_params);
+        if num_params > 0 {
+            let null_bitmap = r.read_bytes(num_params.div_ceil(8))?;
+            if r.read_u8()? == 1 {
+                statement.param_types = (0..num_params)
+                    .map(|_| {
+                        Ok(ParamType {
+                            column_type: r.read_u8()?,
+                            unsigned: r.read_u8()? & 0x80 != 0,
+                        })
+                    })
+                    .collect::<Result<Vec<ParamType>, Error>>()?;
+            }
+            if statement.param_types.len() != num_params {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "COM_STMT_EXECUTE without parameter types",
+                ));
+            }
+            for (i, param_type) in statement.param_types.iter().enumerate() {
+                let mut column = ColumnDefinition::new("?", param_type.column_type);
+                column.flags.set(ColumnFlags::UNSIGNED, param_type.unsigned);
+                let value = if null_bitmap[i / 8] & (1 << (i % 8)) != 0
+                    || param_type.column_type == MYSQL_TYPE_NULL
+                {
+                    Value::Null
+                } else if let Some(data) = statement.long_data.remove(&(i as u16)) {
+                    // Sent with COM_STMT_SEND_LONG_DATA, so not part of this packet
+                    match to_string(&data) {
+                        Ok(text) => Value::Text(text),
+                        Err(_) => Value::Bytes(data),
+                    }
+                } else {
+                    Value::read_binary(&column, &mut r)?
+                };
+                params.push(value);
+            }
+        }
+        statement.long_data.clear();
+        Ok(StmtExecute {
+            statement_id,
+            query: statement.query.clone(),
+            flags,
+            params,
+        })
+    }
+
+    /// The client sent COM_STMT_RESET, which discards long data but keeps the statement
+    pub fn reset(&mut self, packet: &Packet) -> Result<(), Error> {
+        let statement_id = command_body(packet)?.read_u32()?;
+        self.statement_mut(statement_id)?.long_data.clear();
+        Ok(())
+    }
+
+    /// The client sent COM_STMT_CLOSE
+    pub fn close(&mut self, packet: &Packet) -> Result<(), Error> {
+        let statement_id = command_body(packet)?.read_u32()?;
+        self.statements.remove(&statement_id);
+        Ok(())
+    }
+
+    fn statement_mut(&mut self, statement_id: u32) -> Result<&mut PreparedStatement, Error> {
+        self.statements.get_mut(&statement_id).ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("Unknown prepared statement {}", statement_id),
+            )
+        })
+    }
+}
+
+/// Reader positioned after the command byte
+fn command_body(packet: &Packet) -> Result<Reader<'_>, Error> {
+    let mut r = Reader::new(packet.get_payload()?);
+    r.read_u8()?;
+    Ok(r)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mariadb::value::{MYSQL_TYPE_LONGLONG, MYSQL_TYPE_VAR_STRING};
+    use encoding_rs::{SHIFT_JIS, UTF_8, WINDOWS_1252};
+
+    #[test]
+    fn tracks_prepared_statements() {
+        let mut statements = Statements::new();
+        statements
+            .prepare(
+                &Packet::new_mariadb(
+                    0,
+                    b"\x16SELECT * FROM t WHERE id = ? AND name = ? AND note = ?",
+                ),
+                UTF_8,
+            )
+            .unwrap();
+        let ok = Packet::new_mariadb(1, &[0x00, 7, 0, 0, 0, 2, 0, 3, 0, 0x00, 0, 0]);
+        assert_eq!(statements.prepared(&ok).unwrap().num_params, 3);
+
+        statements
+            .send_long_data(&Packet::new_mariadb(0, b"\x18\x07\x00\x00\x00\x02\x00long"))
+            .unwrap();
+        let mut execute = vec![0x17, 7, 0, 0, 0, 0x00, 1, 0, 0, 0];
+        // NULL bitmap: the second parameter is NULL, then new parameter types
+        execute.extend_from_slice(&[0b010, 1]);
+        execute.extend_from_slice(&[MYSQL_TYPE_LONGLONG, 0x80]);
+        execute.extend_from_slice(&[MYSQL_TYPE_VAR_STRING, 0]);
+        execute.extend_from_slice(&[MYSQL_TYPE_VAR_STRING, 0]);
+        execute.extend_from_slice(&42u64.to_le_bytes());
+        let executed = statements
+            .execute(&Packet::new_mariadb(0, &execute))
+            .unwrap();
+        assert_eq!(
+            executed.query,
+            "SELECT * FROM t WHERE id = ? AND name = ? AND note = ?"
+        );
+        assert_eq!(
+            executed.params,
+            vec![
+                Value::UInt(42),
+                Value::Null,
+                Value::Text("long".to_string())
+            ]
+        );
+
+        // Types are not sent again, and long data only applies to one execution
+        let mut execute = vec![0x17, 7, 0, 0, 0, 0x00, 1, 0, 0, 0, 0b000, 0];
+        execute.extend_from_slice(&43u64.to_le_bytes());
+        execute.extend_from_slice(b"\x03bob\x00");
+        let executed = statements
+            .execute(&Packet::new_mariadb(0, &execute))
+            .unwrap();
+        assert_eq!(
+            executed.params,
+            vec![
+                Value::UInt(43),
+                Value::Text("bob".to_string()),
+                Value::Text(String::new())
+            ]
+        );
+
+        statements
+            .close(&Packet::new_mariadb(0, &[0x19, 7, 0, 0, 0]))
+            .unwrap();
+        assert!(statements.get_statement(7).is_none());
+    }
+
+    #[test]
+    fn decodes_prepared_sql_in_client_charset() {
+        let mut statements = Statements::new();
+        let ok = |statement_id: u8| {
+            Packet::new_mariadb(1, &[0x00, statement_id, 0, 0, 0, 0, 0, 0, 0, 0x00, 0, 0])
+        };
+        statements
+            .prepare(
+                &Packet::new_mariadb(0, b"\x16SELECT 'caf\xe9'"),
+                WINDOWS_1252,
+            )
+            .unwrap();
+        assert_eq!(
+            statements.prepared(&ok(1)).unwrap().query,
+            "SELECT 'caf\u{e9}'"
+        );
+        statements
+            .prepare(
+                &Packet::new_mariadb(0, b"\x16SELECT '\x93\xfa\x96\x7b'"),
+                SHIFT_JIS,
+            )
+            .unwrap();
+        assert_eq!(
+            statements.prepared(&ok(2)).unwrap().query,
+            "SELECT '\u{65e5}\u{672c}'"
+        );
+    }
+}
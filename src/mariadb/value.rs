@@ -0,0 +1,504 @@
+//! Decoding column values of text and binary protocol rows
+//! https://mariadb.com/kb/en/resultset-row/
+
+use std::{
+    convert::TryFrom,
+    io::{Error, ErrorKind},
+};
+
+use super::{
+    resultset::{ColumnDefinition, ColumnFlags},
+    write_lenenc_bytes, Reader,
+};
+
+// Field types, see enum_field_types in mysql_com.h
+pub const MYSQL_TYPE_DECIMAL: u8 = 0;
+pub const MYSQL_TYPE_TINY: u8 = 1;
+pub const MYSQL_TYPE_SHORT: u8 = 2;
+pub const MYSQL_TYPE_LONG: u8 = 3;
+pub const MYSQL_TYPE_FLOAT: u8 = 4;
+pub const MYSQL_TYPE_DOUBLE: u8 = 5;
+pub const MYSQL_TYPE_NULL: u8 = 6;
+pub const MYSQL_TYPE_TIMESTAMP: u8 = 7;
+pub const MYSQL_TYPE_LONGLONG: u8 = 8;
+pub const MYSQL_TYPE_INT24: u8 = 9;
+pub const MYSQL_TYPE_DATE: u8 = 10;
+pub const MYSQL_TYPE_TIME: u8 = 11;
+pub const MYSQL_TYPE_DATETIME: u8 = 12;
+pub const MYSQL_TYPE_YEAR: u8 = 13;
+pub const MYSQL_TYPE_NEWDATE: u8 = 14;
+pub const MYSQL_TYPE_VARCHAR: u8 = 15;
+pub const MYSQL_TYPE_BIT: u8 = 16;
+pub const MYSQL_TYPE_TIMESTAMP2: u8 = 17;
+pub const MYSQL_TYPE_DATETIME2: u8 = 18;
+pub const MYSQL_TYPE_TIME2: u8 = 19;
+pub const MYSQL_TYPE_JSON: u8 = 245;
+pub const MYSQL_TYPE_NEWDECIMAL: u8 = 246;
+pub const MYSQL_TYPE_ENUM: u8 = 247;
+pub const MYSQL_TYPE_SET: u8 = 248;
+pub const MYSQL_TYPE_TINY_BLOB: u8 = 249;
+pub const MYSQL_TYPE_MEDIUM_BLOB: u8 = 250;
+pub const MYSQL_TYPE_LONG_BLOB: u8 = 251;
+pub const MYSQL_TYPE_BLOB: u8 = 252;
+pub const MYSQL_TYPE_VAR_STRING: u8 = 253;
+pub const MYSQL_TYPE_STRING: u8 = 254;
+pub const MYSQL_TYPE_GEOMETRY: u8 = 255;
+
+/// Collation id of binary strings, which are returned as bytes rather than text
+pub const BINARY_CHARSET: u16 = 63;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Int(i64),
+    /// Integer column with the UNSIGNED flag
+    UInt(u64),
+    Float(f32),
+    Double(f64),
+    /// Exact decimal representation
+    Decimal(String),
+    Text(String),
+    /// Binary strings, blobs, bits and geometry
+    Bytes(Vec<u8>),
+    Date {
+        year: u16,
+        month: u8,
+        day: u8,
+    },
+    DateTime {
+        year: u16,
+        month: u8,
+        day: u8,
+        hour: u8,
+        minute: u8,
+        second: u8,
+        microsecond: u32,
+    },
+    Time {
+        negative: bool,
+        days: u32,
+        hours: u8,
+        minutes: u8,
+        seconds: u8,
+        microseconds: u32,
+    },
+}
+
+impl Value {
+    /// Decode a text protocol column value, where every value is sent as a string
+    pub fn decode_text(column: &ColumnDefinition, bytes: Option<&[u8]>) -> Result<Value, Error> {
+        let bytes = match bytes {
+            Some(bytes) => bytes,
+            None => return Ok(Value::Null),
+        };
+        let text = || std::str::from_utf8(bytes).map_err(|_| invalid("text value"));
+        let unsigned = column.flags.contains(ColumnFlags::UNSIGNED);
+        let value = match column.column_type {
+            MYSQL_TYPE_TINY | MYSQL_TYPE_SHORT | MYSQL_TYPE_LONG | MYSQL_TYPE_INT24
+            | MYSQL_TYPE_LONGLONG | MYSQL_TYPE_YEAR => {
+                if unsigned {
+                    Value::UInt(text()?.parse().map_err(|_| invalid("integer"))?)
+                } else {
+                    Value::Int(text()?.parse().map_err(|_| invalid("integer"))?)
+                }
+            }
+            MYSQL_TYPE_FLOAT => Value::Float(text()?.parse().map_err(|_| invalid("float"))?),
+            MYSQL_TYPE_DOUBLE => Value::Double(text()?.parse().map_err(|_| invalid("double"))?),
+            MYSQL_TYPE_DECIMAL | MYSQL_TYPE_NEWDECIMAL => Value::Decimal(text()?.to_string()),
+            MYSQL_TYPE_DATE | MYSQL_TYPE_NEWDATE => parse_date(text()?)?,
+            MYSQL_TYPE_DATETIME
+            | MYSQL_TYPE_DATETIME2
+            | MYSQL_TYPE_TIMESTAMP
+            | MYSQL_TYPE_TIMESTAMP2 => parse_datetime(text()?)?,
+            MYSQL_TYPE_TIME | MYSQL_TYPE_TIME2 => parse_time(text()?)?,
+            MYSQL_TYPE_NULL => Value::Null,
+            _ => string_value(column, bytes),
+        };
+        Ok(value)
+    }
+
+    /// Read a binary protocol column value. NULL values are not sent, the row's NULL bitmap
+    /// marks them instead.
+    pub fn read_binary(column: &ColumnDefinition, r: &mut Reader) -> Result<Value, Error> {
+        let unsigned = column.flags.contains(ColumnFlags::UNSIGNED);
+        let value = match column.column_type {
+            MYSQL_TYPE_TINY => {
+                let n = r.read_u8()?;
+                if unsigned {
+                    Value::UInt(n as u64)
+                } else {
+                    Value::Int(n as i8 as i64)
+                }
+            }
+            MYSQL_TYPE_SHORT | MYSQL_TYPE_YEAR => {
+                let n = r.read_u16()?;
+                if unsigned {
+                    Value::UInt(n as u64)
+                } else {
+                    Value::Int(n as i16 as i64)
+                }
+            }
+            MYSQL_TYPE_LONG | MYSQL_TYPE_INT24 => {
+                let n = r.read_u32()?;
+                if unsigned {
+                    Value::UInt(n as u64)
+                } else {
+                    Value::Int(n as i32 as i64)
+                }
+            }
+            MYSQL_TYPE_LONGLONG => {
+                let n = r.read_u64()?;
+                if unsigned {
+                    Value::UInt(n)
+                } else {
+                    Value::Int(n as i64)
+                }
+            }
+            MYSQL_TYPE_FLOAT => Value::Float(f32::from_bits(r.read_u32()?)),
+            MYSQL_TYPE_DOUBLE => Value::Double(f64::from_bits(r.read_u64()?)),
+            MYSQL_TYPE_DATE | MYSQL_TYPE_NEWDATE => match read_datetime(r)? {
+                Value::DateTime {
+                    year, month, day, ..
+                } => Value::Date { year, month, day },
+                value => value,
+            },
+            MYSQL_TYPE_DATETIME
+            | MYSQL_TYPE_DATETIME2
+            | MYSQL_TYPE_TIMESTAMP
+            | MYSQL_TYPE_TIMESTAMP2 => read_datetime(r)?,
+            MYSQL_TYPE_TIME | MYSQL_TYPE_TIME2 => read_time(r)?,
+            MYSQL_TYPE_NULL => Value::Null,
+            MYSQL_TYPE_DECIMAL | MYSQL_TYPE_NEWDECIMAL => {
+                let bytes = r.read_lenenc_bytes()?.unwrap_or_default();
+                Value::Decimal(
+                    std::str::from_utf8(bytes)
+                        .map_err(|_| invalid("decimal"))?
+                        .to_string(),
+                )
+            }
+            _ => string_value(column, r.read_lenenc_bytes()?.unwrap_or_default()),
+        };
+        Ok(value)
+    }
+
+    /// Write a text protocol column value: a string<lenenc>, or 0xfb for NULL
+    pub fn write_text(&self, buf: &mut Vec<u8>) {
+        let text = match self {
+            Value::Null => {
+                buf.push(0xfb);
+                return;
+            }
+            Value::Int(n) => n.to_string(),
+            Value::UInt(n) => n.to_string(),
+            Value::Float(n) => n.to_string(),
+            Value::Double(n) => n.to_string(),
+            Value::Decimal(s) | Value::Text(s) => s.clone(),
+            Value::Bytes(bytes) => return write_lenenc_bytes(buf, bytes),
+            Value::Date { year, month, day } => format!("{:04}-{:02}-{:02}", year, month, day),
+            Value::DateTime {
+                year,
+                month,
+                day,
+                hour,
+                minute,
+                second,
+                microsecond,
+            } => {
+                let mut s = format!(
+                    "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+                    year, month, day, hour, minute, second
+                );
+                if *microsecond > 0 {
+                    s.push_str(&format!(".{:06}", microsecond));
+                }
+                s
+            }
+            Value::Time {
+                negative,
+                days,
+                hours,
+                minutes,
+                seconds,
+                microseconds,
+            } => {
+                let mut s = format!(
+                    "{}{:02}:{:02}:{:02}",
+                    if *negative { "-" } else { "" },
+                    *days * 24 + *hours as u32,
+                    minutes,
+                    seconds
+                );
+                if *microseconds > 0 {
+                    s.push_str(&format!(".{:06}", microseconds));
+                }
+                s
+            }
+        };
+        write_lenenc_bytes(buf, text.as_bytes());
+    }
+
+    /// Write a non-NULL binary protocol column value in the representation of the column's
+    /// type
+    pub fn write_binary(&self, column: &ColumnDefinition, buf: &mut Vec<u8>) -> Result<(), Error> {
+        let integer = || match self {
+            Value::Int(n) => Ok(*n as u64),
+            Value::UInt(n) => Ok(*n),
+            _ => Err(mismatch(column)),
+        };
+        match column.column_type {
+            MYSQL_TYPE_TINY => buf.push(integer()? as u8),
+            MYSQL_TYPE_SHORT | MYSQL_TYPE_YEAR => {
+                buf.extend_from_slice(&(integer()? as u16).to_le_bytes())
+            }
+            MYSQL_TYPE_LONG | MYSQL_TYPE_INT24 => {
+                buf.extend_from_slice(&(integer()? as u32).to_le_bytes())
+            }
+            MYSQL_TYPE_LONGLONG => buf.extend_from_slice(&integer()?.to_le_bytes()),
+            MYSQL_TYPE_FLOAT => match self {
+                Value::Float(n) => buf.extend_from_slice(&n.to_le_bytes()),
+                _ => return Err(mismatch(column)),
+            },
+            MYSQL_TYPE_DOUBLE => match self {
+                Value::Double(n) => buf.extend_from_slice(&n.to_le_bytes()),
+                _ => return Err(mismatch(column)),
+            },
+            MYSQL_TYPE_DATE
+            | MYSQL_TYPE_NEWDATE
+            | MYSQL_TYPE_DATETIME
+            | MYSQL_TYPE_DATETIME2
+            | MYSQL_TYPE_TIMESTAMP
+            | MYSQL_TYPE_TIMESTAMP2 => {
+                let (year, month, day, hour, minute, second, microsecond) = match *self {
+                    Value::Date { year, month, day } => (year, month, day, 0, 0, 0, 0),
+                    Value::DateTime {
+                        year,
+                        month,
+                        day,
+                        hour,
+                        minute,
+                        second,
+                        microsecond,
+                    } => (year, month, day, hour, minute, second, microsecond),
+                    _ => return Err(mismatch(column)),
+                };
+                let len = if microsecond > 0 {
+                    11
+                } else if hour > 0 || minute > 0 || second > 0 {
+                    7
+                } else if year > 0 || month > 0 || day > 0 {
+                    4
+                } else {
+                    0
+                };
+                buf.push(len);
+                if len >= 4 {
+                    buf.extend_from_slice(&year.to_le_bytes());
+                    buf.extend_from_slice(&[month, day]);
+                }
+                if len >= 7 {
+                    buf.extend_from_slice(&[hour, minute, second]);
+                }
+                if len >= 11 {
+                    buf.extend_from_slice(&microsecond.to_le_bytes());
+                }
+            }
+            MYSQL_TYPE_TIME | MYSQL_TYPE_TIME2 => {
+                let (negative, days, hours, minutes, seconds, microseconds) = match *self {
+                    Value::Time {
+                        negative,
+                        days,
+                        hours,
+                        minutes,
+                        seconds,
+                        microseconds,
+                    } => (negative, days, hours, minutes, seconds, microseconds),
+                    _ => return Err(mismatch(column)),
+                };
+                let len = if microseconds > 0 {
+                    12
+                } else if days > 0 || hours > 0 || minutes > 0 || seconds > 0 {
+                    8
+                } else {
+                    0
+                };
+                buf.push(len);
+                if len >= 8 {
+                    buf.push(negative as u8);
+                    buf.extend_from_slice(&days.to_le_bytes());
+                    buf.extend_from_slice(&[hours, minutes, seconds]);
+                }
+                if len >= 12 {
+                    buf.extend_from_slice(&microseconds.to_le_bytes());
+                }
+            }
+            MYSQL_TYPE_NULL => {}
+            // Everything else is sent as a string<lenenc>, like in the text protocol
+            _ => self.write_text(buf),
+        }
+        Ok(())
+    }
+}
+
+/// Strings are text unless they use the binary collation or are not valid UTF-8
+fn string_value(column: &ColumnDefinition, bytes: &[u8]) -> Value {
+    match column.column_type {
+        MYSQL_TYPE_BIT | MYSQL_TYPE_GEOMETRY => return Value::Bytes(bytes.to_vec()),
+        _ => {}
+    }
+    if column.charset == BINARY_CHARSET {
+        return Value::Bytes(bytes.to_vec());
+    }
+    match std::str::from_utf8(bytes) {
+        Ok(text) => Value::Text(text.to_string()),
+        Err(_) => Value::Bytes(bytes.to_vec()),
+    }
+}
+
+/// Binary DATETIME: a length of 0, 4, 7 or 11 bytes, trailing zero parts are omitted
+fn read_datetime(r: &mut Reader) -> Result<Value, Error> {
+    let len = r.read_u8()?;
+    let mut value = (0, 0, 0, 0, 0, 0, 0);
+    if len >= 4 {
+        value.0 = r.read_u16()?;
+        value.1 = r.read_u8()?;
+        value.2 = r.read_u8()?;
+    }
+    if len >= 7 {
+        value.3 = r.read_u8()?;
+        value.4 = r.read_u8()?;
+        value.5 = r.read_u8()?;
+    }
+    if len >= 11 {
+        value.6 = r.read_u32()?;
+    }
+    if !matches!(len, 0 | 4 | 7 | 11) {
+        return Err(invalid("datetime length"));
+    }
+    Ok(Value::DateTime {
+        year: value.0,
+        month: value.1,
+        day: value.2,
+        hour: value.3,
+        minute: value.4,
+        second: value.5,
+        microsecond: value.6,
+    })
+}
+
+/// Binary TIME: a length of 0, 8 or 12 bytes
+fn read_time(r: &mut Reader) -> Result<Value, Error> {
+    let len = r.read_u8()?;
+    let mut value = (false, 0, 0, 0, 0, 0);
+    if len >= 8 {
+        value.0 = r.read_u8()? == 1;
+        value.1 = r.read_u32()?;
+        value.2 = r.read_u8()?;
+        value.3 = r.read_u8()?;
+        value.4 = r.read_u8()?;
+    }
+    if len >= 12 {
+        value.5 = r.read_u32()?;
+    }
+    if !matches!(len, 0 | 8 | 12) {
+        return Err(invalid("time length"));
+    }
+    Ok(Value::Time {
+        negative: value.0,
+        days: value.1,
+        hours: value.2,
+        minutes: value.3,
+        seconds: value.4,
+        microseconds: value.5,
+    })
+}
+
+/// YYYY-MM-DD
+fn parse_date(text: &str) -> Result<Value, Error> {
+    let (year, month, day) = parse_ymd(text)?;
+    Ok(Value::Date { year, month, day })
+}
+
+/// YYYY-MM-DD HH:MM:SS[.ffffff]
+fn parse_datetime(text: &str) -> Result<Value, Error> {
+    let (date, time) = match text.find(' ') {
+        Some(i) => (&text[..i], &text[i + 1..]),
+        None => (text, "00:00:00"),
+    };
+    let (year, month, day) = parse_ymd(date)?;
+    let (hour, minute, second, microsecond) = parse_clock(time, "datetime")?;
+    Ok(Value::DateTime {
+        year,
+        month,
+        day,
+        hour: u8::try_from(hour).map_err(|_| invalid("datetime"))?,
+        minute,
+        second,
+        microsecond,
+    })
+}
+
+fn parse_ymd(text: &str) -> Result<(u16, u8, u8), Error> {
+    let mut parts = text.splitn(3, '-');
+    let mut next = || -> Result<&str, Error> { parts.next().ok_or_else(|| invalid("date")) };
+    Ok((
+        number(next()?, "date")?,
+        number(next()?, "date")?,
+        number(next()?, "date")?,
+    ))
+}
+
+/// [-]HHH:MM:SS[.ffffff], where hours may exceed 24
+fn parse_time(text: &str) -> Result<Value, Error> {
+    let (negative, text) = match text.strip_prefix('-') {
+        Some(text) => (true, text),
+        None => (false, text),
+    };
+    let (hours, minutes, seconds, microseconds) = parse_clock(text, "time")?;
+    Ok(Value::Time {
+        negative,
+        days: hours / 24,
+        hours: (hours % 24) as u8,
+        minutes,
+        seconds,
+        microseconds,
+    })
+}
+
+/// HH:MM:SS[.ffffff] into hours, minutes, seconds and microseconds
+fn parse_clock(text: &str, what: &str) -> Result<(u32, u8, u8, u32), Error> {
+    let (clock, fraction) = match text.find('.') {
+        Some(i) => (&text[..i], &text[i + 1..]),
+        None => (text, ""),
+    };
+    let mut parts = clock.splitn(3, ':');
+    let mut next = || -> Result<&str, Error> { parts.next().ok_or_else(|| invalid(what)) };
+    let hours = number(next()?, what)?;
+    let minutes = number(next()?, what)?;
+    let seconds = number(next()?, what)?;
+    let microseconds = if fraction.is_empty() {
+        0
+    } else if fraction.len() <= 6 {
+        number::<u32>(fraction, what)? * 10u32.pow(6 - fraction.len() as u32)
+    } else {
+        return Err(invalid(what));
+    };
+    Ok((hours, minutes, seconds, microseconds))
+}
+
+fn number<T: std::str::FromStr>(text: &str, what: &str) -> Result<T, Error> {
+    text.parse().map_err(|_| invalid(what))
+}
+
+fn mismatch(column: &ColumnDefinition) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!(
+            "Value does not match the type {} of column {}",
+            column.column_type, column.name
+        ),
+    )
+}
+
+fn invalid(what: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Invalid {}", what))
+}
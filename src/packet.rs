@@ -1,82 +1,243 @@
-use std::io::{Error, ErrorKind};
+use std::{
+    collections::VecDeque,
+    io::{Error, ErrorKind, IoSlice},
+};
 
 use byteorder::{BigEndian, ByteOrder, LittleEndian, WriteBytesExt};
+use bytes::{Buf, Bytes};
+use encoding_rs::Encoding;
 
-/// A packet is just a wrapper for a Vec<u8>
+use crate::{mariadb::response::ErrPacket, postgres};
+
+/// SQL text decoded in the client's character set
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueryText {
+    pub text: String,
+    /// Some bytes were invalid in the character set, and were replaced with U+FFFD
+    pub lossy: bool,
+}
+
+/// A packet is just a wrapper for its bytes, header included. The bytes are reference
+/// counted, so cloning a packet or forwarding it unchanged does not copy them.
 /// For reference, see https://dev.mysql.com/doc/internals/en/mysql-packet.html
 #[derive(Clone, Debug, PartialEq)]
 pub struct Packet {
     db_type: DatabaseType,
-    pub bytes: Vec<u8>,
+    packet_type: Option<PacketType>,
+    pub bytes: Bytes,
 }
 
 impl Packet {
-    pub fn new(db_type: DatabaseType, bytes: Vec<u8>) -> Packet {
-        Packet { db_type, bytes }
+    pub fn new(db_type: DatabaseType, bytes: impl Into<Bytes>) -> Packet {
+        Packet {
+            db_type,
+            packet_type: None,
+            bytes: bytes.into(),
+        }
+    }
+
+    /// Wrap a MariaDB payload with its 4-byte header (3-byte length, 1-byte sequence id).
+    /// Payloads of MARIADB_MAX_PAYLOAD bytes or more are kept whole, with the length capped,
+    /// and are only split when written with `write_mariadb_fragments`.
+    pub fn new_mariadb(sequence_id: u8, payload: &[u8]) -> Packet {
+        let mut bytes: Vec<u8> = Vec::with_capacity(4 + payload.len());
+        bytes
+            .write_u24::<LittleEndian>(payload.len().min(MARIADB_MAX_PAYLOAD) as u32)
+            .unwrap();
+        bytes.push(sequence_id);
+        bytes.extend_from_slice(payload);
+        Packet::new(DatabaseType::MariaDB, bytes)
+    }
+
+    /// Wrap a PostgresSQL message body with its type byte and 4-byte length
+    pub fn new_postgres(id: u8, body: &[u8]) -> Packet {
+        let mut bytes: Vec<u8> = Vec::with_capacity(5 + body.len());
+        bytes.push(id);
+        bytes.write_u32::<BigEndian>(4 + body.len() as u32).unwrap();
+        bytes.extend_from_slice(body);
+        Packet::new(DatabaseType::PostgresSQL, bytes)
     }
 
     /**
-     * Create an error packet for MariaDB
+     * Create an error packet for MariaDB, answering a command with sequence id 0
      **/
     pub fn error_packet_mariadb(code: u16, state: [u8; 5], msg: String) -> Self {
-        // start building payload
-        let mut payload: Vec<u8> = Vec::with_capacity(9 + msg.len());
-        payload.push(0xff); // packet type
-        payload.write_u16::<LittleEndian>(code).unwrap(); // error code
-        payload.extend_from_slice(b"#"); // sql_state_marker
-        payload.extend_from_slice(&state); // SQL STATE
-        payload.extend_from_slice(msg.as_bytes());
-
-        // create header with length and sequence id
-        let mut header: Vec<u8> = Vec::with_capacity(4 + 9 + msg.len());
-        header
-            .write_u32::<LittleEndian>(payload.len() as u32)
-            .unwrap();
-        header.pop(); // we need 3 byte length, so discard last byte
-        header.push(1); // sequence_id
-
-        // combine the vectors
-        header.extend_from_slice(&payload);
+        Packet::error_packet_mariadb_with_sequence_id(1, code, state, msg)
+    }
 
-        // now move the vector into the packet
-        Packet {
-            db_type: DatabaseType::MariaDB,
-            bytes: header,
+    /**
+     * Create an error packet for MariaDB. In response to a command, `sequence_id` is the
+     * command's sequence id plus one.
+     **/
+    pub fn error_packet_mariadb_with_sequence_id(
+        sequence_id: u8,
+        code: u16,
+        state: [u8; 5],
+        msg: String,
+    ) -> Self {
+        ErrPacket {
+            code,
+            sql_state: Some(String::from_utf8_lossy(&state).into_owned()),
+            message: msg,
         }
+        .to_packet(sequence_id)
     }
 
     pub fn get_size(&self) -> usize {
         self.bytes.len()
     }
 
-    pub fn get_query(&self) -> Result<String, Error> {
+    pub fn get_db_type(&self) -> DatabaseType {
+        self.db_type
+    }
+
+    /// Returns the bytes following the packet header
+    /// - MariaDB: everything after the 3-byte length and sequence id
+    /// - PostgresSQL: everything after the (optional) type byte and 4-byte length
+    pub fn get_payload(&self) -> Result<&[u8], Error> {
+        let header = match self.db_type {
+            DatabaseType::MariaDB => 4,
+            DatabaseType::PostgresSQL => match self.bytes.first() {
+                Some(b) if POSTGRES_IDS.contains(&(*b as char)) => 5,
+                _ => 4,
+            },
+        };
+        if self.bytes.len() < header {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Packet is shorter than its header",
+            ));
+        }
+        Ok(&self.bytes[header..])
+    }
+
+    /// SQL text of a MariaDB COM_QUERY or COM_STMT_PREPARE, or of a PostgresSQL Query or
+    /// Parse message, exactly as sent. The SQL run by a MariaDB COM_STMT_EXECUTE or a
+    /// PostgresSQL Execute is tracked per connection, see the `get_execute` methods of the
+    /// decoders.
+    pub fn get_query_bytes(&self) -> Result<&[u8], Error> {
         match (self.db_type, self.get_packet_type()) {
-            (DatabaseType::MariaDB, Ok(PacketType::ComQuery)) => {
-                Ok(String::from_utf8(self.bytes[5..].to_vec()).expect("Invalid UTF-8"))
+            (DatabaseType::MariaDB, Ok(PacketType::ComQuery))
+            | (DatabaseType::MariaDB, Ok(PacketType::ComStmtPrepare)) => {
+                // Skip the command byte
+                match self.get_payload()?.get(1..) {
+                    Some(sql) => Ok(sql),
+                    None => Err(Error::new(ErrorKind::UnexpectedEof, "Empty command")),
+                }
             }
             (DatabaseType::PostgresSQL, Ok(PacketType::Query)) => {
-                Ok(String::from_utf8(self.bytes[5..].to_vec()).expect("Invalid UTF-8"))
+                postgres::Reader::new(self.get_payload()?).read_cstr_bytes()
             }
-            _ => Err(Error::new(ErrorKind::Other, "Packet is not a query")),
+            (DatabaseType::PostgresSQL, Ok(PacketType::Parse)) => {
+                let mut r = postgres::Reader::new(self.get_payload()?);
+                // Skip the statement name
+                r.read_cstr_bytes()?;
+                r.read_cstr_bytes()
+            }
+            _ => Err(Error::other("Packet is not a query")),
         }
     }
 
+    /// SQL text of the packet (see `get_query_bytes`) read as UTF-8. Text in another
+    /// character set is an InvalidData error, `Context::get_query` decodes it in the client's
+    /// character set instead.
+    pub fn get_query(&self) -> Result<String, Error> {
+        String::from_utf8(self.get_query_bytes()?.to_vec())
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+
+    /// SQL text of the packet (see `get_query_bytes`) decoded with `encoding`. Invalid
+    /// sequences are replaced with U+FFFD, and reported with `lossy`.
+    pub fn decode_query(&self, encoding: &'static Encoding) -> Result<QueryText, Error> {
+        let (text, lossy) = encoding.decode_without_bom_handling(self.get_query_bytes()?);
+        Ok(QueryText {
+            text: text.into_owned(),
+            lossy,
+        })
+    }
+
     pub fn get_sequence_id(&self) -> Result<u8, Error> {
         match self.db_type {
-            DatabaseType::MariaDB => Ok(self.bytes[3]),
-            DatabaseType::PostgresSQL => Err(Error::new(
-                ErrorKind::Other,
-                "PostgresSQL does not use sequence IDs",
-            )),
+            DatabaseType::MariaDB => self.bytes.get(3).copied().ok_or_else(|| {
+                Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "Packet is shorter than its header",
+                )
+            }),
+            DatabaseType::PostgresSQL => Err(Error::other("PostgresSQL does not use sequence IDs")),
         }
     }
 
+    /// Number of physical MariaDB packets needed to send the payload
+    pub fn get_fragment_count(&self) -> usize {
+        match self.get_payload() {
+            Ok(payload) => payload.len() / MARIADB_MAX_PAYLOAD + 1,
+            Err(_) => 1,
+        }
+    }
+
+    /// Queue the packet as sent on the wire. MariaDB payloads of MARIADB_MAX_PAYLOAD bytes or
+    /// more are split into several packets with consecutive sequence ids starting at
+    /// `sequence_id`, ending with a shorter (possibly empty) one. The payload is not copied,
+    /// only headers that changed are written again. Returns the number of packets written.
+    pub fn write_mariadb_fragments(&self, sequence_id: u8, out: &mut WriteQueue) -> usize {
+        if self.bytes.len() < 4 {
+            out.push(self.bytes.clone());
+            return 1;
+        }
+        if self.bytes.len() - 4 < MARIADB_MAX_PAYLOAD && self.bytes[3] == sequence_id {
+            out.push(self.bytes.clone());
+            return 1;
+        }
+        let mut sequence_id = sequence_id;
+        let mut count = 0;
+        let mut start = 4;
+        loop {
+            let end = self.bytes.len().min(start + MARIADB_MAX_PAYLOAD);
+            let mut header = Vec::with_capacity(4);
+            header
+                .write_u24::<LittleEndian>((end - start) as u32)
+                .unwrap();
+            header.push(sequence_id);
+            out.push(Bytes::from(header));
+            out.push(self.bytes.slice(start..end));
+            sequence_id = sequence_id.wrapping_add(1);
+            count += 1;
+            if end - start < MARIADB_MAX_PAYLOAD {
+                return count;
+            }
+            start = end;
+        }
+    }
+
+    /// Label the packet with a type determined from the connection state.
+    /// The label takes precedence over what can be guessed from the bytes alone.
+    pub fn set_packet_type(&mut self, packet_type: PacketType) {
+        self.packet_type = Some(packet_type);
+    }
+
     /// Determine the type of packet
+    /// Packets read by a `Pipe` are labeled from the connection state. Otherwise, the type is
+    /// guessed from the bytes alone, treating MariaDB packets as commands.
     pub fn get_packet_type(&self) -> Result<PacketType, Error> {
+        if let Some(packet_type) = self.packet_type {
+            return Ok(packet_type);
+        }
+        // Command byte for MariaDB, message type for PostgresSQL
+        let first = match self.db_type {
+            DatabaseType::MariaDB => self.get_payload()?.first(),
+            DatabaseType::PostgresSQL => self.bytes.first(),
+        };
+        let first = *first.ok_or_else(|| {
+            Error::new(
+                ErrorKind::UnexpectedEof,
+                "Invalid packet type: Empty packet",
+            )
+        })?;
         match self.db_type {
             // https://dev.mysql.com/doc/internals/en/mysql-packet.html
             // https://dev.mysql.com/doc/internals/en/text-protocol.html
-            DatabaseType::MariaDB => match self.bytes[4] {
+            DatabaseType::MariaDB => match first {
                 0x00 => Ok(PacketType::ComSleep),
                 0x01 => Ok(PacketType::ComQuit),
                 0x02 => Ok(PacketType::ComInitDb),
@@ -109,27 +270,17 @@ impl Packet {
                 0x1d => Ok(PacketType::ComDaemon),
                 0x1e => Ok(PacketType::ComBinlogDumpGtid),
                 0x1f => Ok(PacketType::ComResetConnection),
-
-                0x8f => Ok(PacketType::ComUnknown), // TODO: fix
-                0x8c => Ok(PacketType::ComUnknown), // TODO: fix
-                0xfc => Ok(PacketType::ComUnknown), // TODO: fix
-                0xfd => Ok(PacketType::ComUnknown), // TODO: fix
-
                 0xfe => Ok(PacketType::ComEof),
                 0xff => Ok(PacketType::ComErr),
-                _ => Err(Error::new(
-                    ErrorKind::Other,
-                    format!("Invalid packet type {:#04x}", self.bytes[4]),
-                )),
+                _ => Err(Error::other(format!("Invalid packet type {:#04x}", first))),
             },
 
             // https://www.postgresql.org/docs/12/protocol-message-types.html
             // https://www.postgresql.org/docs/12/protocol-message-formats.html
-            DatabaseType::PostgresSQL => match self.bytes[0] as char {
+            DatabaseType::PostgresSQL => match first as char {
                 'R' => {
                     if self.bytes.len() < 9 {
-                        return Err(Error::new(
-                            ErrorKind::Other,
+                        return Err(Error::other(
                             "Invalid packet type: Authentication Packet too short",
                         ));
                     }
@@ -147,8 +298,7 @@ impl Packet {
                         (_, 10) => Ok(PacketType::AuthenticationSASL),
                         (_, 11) => Ok(PacketType::AuthenticationSASLContinue),
                         (_, 12) => Ok(PacketType::AuthenticationSASLFinal),
-                        _ => Err(Error::new(
-                            ErrorKind::Other,
+                        _ => Err(Error::other(
                             "Invalid packet type: Authentication Packet unrecognized",
                         )),
                     }
@@ -159,8 +309,7 @@ impl Packet {
                 '3' => Ok(PacketType::CloseComplete),
                 'C' => {
                     if self.bytes.len() < 6 {
-                        Err(Error::new(
-                            ErrorKind::Other,
+                        Err(Error::other(
                             "Invalid packet type: Close/CommandComplete packet too short",
                         ))
                     } else if self.bytes[5] as char == 'S' || self.bytes[5] as char == 'P' {
@@ -175,9 +324,8 @@ impl Packet {
                 'G' => Ok(PacketType::CopyInResponse),
                 'H' => {
                     if self.bytes.len() < 5 {
-                        return Err(Error::new(
-                            ErrorKind::Other,
-                            "Invalid packet type: Authentication Packet too short",
+                        return Err(Error::other(
+                            "Invalid packet type: Flush/CopyOutResponse Packet too short",
                         ));
                     }
                     let length = BigEndian::read_u32(&self.bytes[1..5]);
@@ -190,8 +338,7 @@ impl Packet {
                 'W' => Ok(PacketType::CopyBothResponse),
                 'D' => {
                     if self.bytes.len() < 6 {
-                        Err(Error::new(
-                            ErrorKind::Other,
+                        Err(Error::other(
                             "Invalid packet type: DataRow/Describe packet too short",
                         ))
                     } else if self.bytes[5] as char == 'S' || self.bytes[5] as char == 'P' {
@@ -203,32 +350,11 @@ impl Packet {
                 'I' => Ok(PacketType::EmptyQueryResponse),
                 'E' => {
                     if self.bytes.len() < 6 {
-                        Err(Error::new(
-                            ErrorKind::Other,
+                        Err(Error::other(
                             "Invalid packet type: Execute/ErrorResponse packet too short",
                         ))
                     // https://www.postgresql.org/docs/12/protocol-error-fields.html
-                    } else if self.bytes[5] as char == 'S'
-                        || self.bytes[5] as char == 'V'
-                        || self.bytes[5] as char == 'V'
-                        || self.bytes[5] as char == 'C'
-                        || self.bytes[5] as char == 'M'
-                        || self.bytes[5] as char == 'D'
-                        || self.bytes[5] as char == 'H'
-                        || self.bytes[5] as char == 'P'
-                        || self.bytes[5] as char == 'p'
-                        || self.bytes[5] as char == 'q'
-                        || self.bytes[5] as char == 'W'
-                        || self.bytes[5] as char == 's'
-                        || self.bytes[5] as char == 't'
-                        || self.bytes[5] as char == 'c'
-                        || self.bytes[5] as char == 'd'
-                        || self.bytes[5] as char == 'n'
-                        || self.bytes[5] as char == 'F'
-                        || self.bytes[5] as char == 'L'
-                        || self.bytes[5] as char == 'R'
-                        || self.bytes[5] as char == 'P'
-                    {
+                    } else if "SVCMDHPpqWstcdnFLR".contains(self.bytes[5] as char) {
                         Ok(PacketType::ErrorResponse)
                     } else {
                         Ok(PacketType::Execute)
@@ -244,8 +370,7 @@ impl Packet {
                 't' => Ok(PacketType::ParameterDescription),
                 'S' => {
                     if self.bytes.len() < 5 {
-                        return Err(Error::new(
-                            ErrorKind::Other,
+                        return Err(Error::other(
                             "Invalid packet type: Sync/ParameterStatus Packet too short",
                         ));
                     }
@@ -265,8 +390,7 @@ impl Packet {
                 'X' => Ok(PacketType::Terminate),
                 _ => {
                     if self.bytes.len() < 8 {
-                        return Err(Error::new(
-                            ErrorKind::Other,
+                        return Err(Error::other(
                             "Invalid packet type: Default packet too short",
                         ));
                     }
@@ -277,7 +401,7 @@ impl Packet {
                         (8, 80_877_103) => Ok(PacketType::SSLRequest),
                         (8, 80_877_104) => Ok(PacketType::GSSENCRequest),
                         (_, 196_608) => Ok(PacketType::StartupMessage),
-                        _ => Err(Error::new(ErrorKind::Other, "Invalid packet type")),
+                        _ => Err(Error::other("Invalid packet type")),
                     }
                 }
             }, // end match packet_type
@@ -285,17 +409,81 @@ impl Packet {
     } // end fn
 }
 
+/// Bytes waiting to be written to a socket, kept as the buffers they were read or built in.
+/// Sockets supporting vectored writes send several of them with a single system call.
+#[derive(Clone, Debug, Default)]
+pub struct WriteQueue {
+    chunks: VecDeque<Bytes>,
+    len: usize,
+}
+
+impl WriteQueue {
+    pub fn new() -> WriteQueue {
+        WriteQueue::default()
+    }
+
+    pub fn push(&mut self, bytes: Bytes) {
+        if !bytes.is_empty() {
+            self.len += bytes.len();
+            self.chunks.push_back(bytes);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Buf for WriteQueue {
+    fn remaining(&self) -> usize {
+        self.len
+    }
+
+    fn bytes(&self) -> &[u8] {
+        self.chunks.front().map(|chunk| &chunk[..]).unwrap_or(&[])
+    }
+
+    fn bytes_vectored<'a>(&'a self, dst: &mut [IoSlice<'a>]) -> usize {
+        let mut n = 0;
+        for (slice, chunk) in dst.iter_mut().zip(self.chunks.iter()) {
+            *slice = IoSlice::new(chunk);
+            n += 1;
+        }
+        n
+    }
+
+    fn advance(&mut self, mut cnt: usize) {
+        self.len -= cnt;
+        while let Some(front) = self.chunks.front_mut() {
+            if cnt < front.len() {
+                front.advance(cnt);
+                return;
+            }
+            cnt -= front.len();
+            self.chunks.pop_front();
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum DatabaseType {
     MariaDB,
     PostgresSQL,
 }
 
+/// Largest payload of a single MariaDB packet. Longer payloads are split across several.
+/// https://mariadb.com/kb/en/0-packet/#packet-splitting
+pub const MARIADB_MAX_PAYLOAD: usize = 0xff_ffff;
+
 pub const POSTGRES_IDS: [char; 31] = [
     'R', 'K', 'B', '2', '3', 'C', 'd', 'c', 'f', 'G', 'H', 'W', 'D', 'I', 'E', 'F', 'V', 'p', 'v',
     'n', 'N', 'A', 't', 'S', 'P', '1', 's', 'Q', 'Z', 'T', 'X',
 ];
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum PacketType {
     // MariaDB
     ComSleep = 0x00,
@@ -334,6 +522,23 @@ pub enum PacketType {
     ComErr = 0xff,
     ComUnknown,
 
+    // MariaDB, only known from the connection phase
+    Handshake,
+    HandshakeResponse,
+    AuthSwitchRequest,
+    AuthMoreData,
+    AuthSwitchResponse,
+    ComOk,
+    ColumnCount,
+    ColumnDefinition,
+    TextRow,
+    BinaryRow,
+    LocalInfileRequest,
+    LocalInfileData,
+    StmtPrepareOk,
+    StatisticsResponse,
+    BinlogEvent,
+
     //PostgresSQL
     AuthenticationOk,
     AuthenticationKerberosV5,
@@ -386,7 +591,72 @@ pub enum PacketType {
     SASLResponse,
     SSLRequest,
     GSSENCRequest,
+    EncryptionResponse, // the single byte S or N answering SSLRequest and GSSENCRequest
     StartupMessage,
     Sync,
     Terminate,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::{collection::vec, prelude::*};
+
+    proptest! {
+        #[test]
+        fn accessors_never_panic(bytes in vec(any::<u8>(), 0..32)) {
+            for db_type in [DatabaseType::MariaDB, DatabaseType::PostgresSQL] {
+                let packet = Packet::new(db_type, bytes.clone());
+                let _ = packet.get_payload();
+                let _ = packet.get_packet_type();
+                let _ = packet.get_sequence_id();
+                let _ = packet.get_query_bytes();
+                let _ = packet.get_fragment_count();
+                packet.write_mariadb_fragments(0, &mut WriteQueue::new());
+            }
+        }
+    }
+
+    #[test]
+    fn queues_unchanged_packets_without_copying() {
+        let packet = Packet::new_mariadb(1, b"\x03SELECT 1");
+        let mut queue = WriteQueue::new();
+        assert_eq!(packet.write_mariadb_fragments(1, &mut queue), 1);
+        assert_eq!(queue.bytes().as_ptr(), packet.bytes.as_ptr());
+
+        // Only the header is written again when the sequence id changes
+        let mut queue = WriteQueue::new();
+        packet.write_mariadb_fragments(2, &mut queue);
+        assert_eq!(queue.bytes(), [9, 0, 0, 2]);
+        queue.advance(4);
+        assert_eq!(queue.bytes().as_ptr(), packet.bytes[4..].as_ptr());
+        assert_eq!(queue.remaining(), 9);
+    }
+
+    #[test]
+    fn short_packets_are_errors() {
+        let empty = Packet::new(DatabaseType::MariaDB, Vec::new());
+        assert!(empty.get_sequence_id().is_err());
+        assert!(empty.get_packet_type().is_err());
+        assert!(Packet::new_mariadb(0, &[]).get_packet_type().is_err());
+        assert!(Packet::new(DatabaseType::PostgresSQL, Vec::new())
+            .get_packet_type()
+            .is_err());
+    }
+
+    #[test]
+    fn builds_mariadb_error_packets() {
+        let packet = Packet::error_packet_mariadb(1045, *b"28000", "Access denied".to_string());
+        let mut expected = vec![0x16, 0, 0, 1, 0xff, 0x15, 0x04, b'#'];
+        expected.extend_from_slice(b"28000Access denied");
+        assert_eq!(packet.bytes, expected);
+        let packet = Packet::error_packet_mariadb_with_sequence_id(
+            3,
+            1045,
+            *b"28000",
+            "Access denied".to_string(),
+        );
+        assert_eq!(packet.get_sequence_id().unwrap(), 3);
+        assert_eq!(packet.get_payload().unwrap(), &expected[4..]);
+    }
+}
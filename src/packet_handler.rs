@@ -1,14 +1,74 @@
-use crate::packet::Packet;
+use crate::{context::Context, packet::Packet};
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Direction {
     Forward,  // corresponds to handle_request
     Backward, // corresponds to handle_response
 }
 
+impl Direction {
+    /// The direction packets sent back to the source of a pipe travel in
+    pub fn reverse(&self) -> Direction {
+        match self {
+            Direction::Forward => Direction::Backward,
+            Direction::Backward => Direction::Forward,
+        }
+    }
+}
+
+/// What a pipe does with a packet once a handler saw it. Packets sent on go to the sink of
+/// the pipe: the server for requests, the client for responses. Replies go back to the
+/// source: the client for requests, the server for responses.
+///
+/// MariaDB sequence ids are renumbered by the proxy, so both peers keep seeing consecutive
+/// ids whatever the number of packets sent on or replied.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    /// Send this packet on, usually the one received
+    Forward(Packet),
+    /// Send nothing on
+    Drop,
+    /// Send these packets on in place of the received one
+    Replace(Vec<Packet>),
+    /// Send nothing on, and answer the source with these packets
+    Reply(Vec<Packet>),
+    /// Answer the source with the packets, and send the packet on
+    ReplyAndForward(Vec<Packet>, Packet),
+}
+
+impl Action {
+    /// The packets sent on, and the ones sent back to the source
+    pub fn split(self) -> (Vec<Packet>, Vec<Packet>) {
+        match self {
+            Action::Forward(packet) => (vec![packet], Vec::new()),
+            Action::Drop => (Vec::new(), Vec::new()),
+            Action::Replace(packets) => (packets, Vec::new()),
+            Action::Reply(replies) => (Vec::new(), replies),
+            Action::ReplyAndForward(replies, packet) => (vec![packet], replies),
+        }
+    }
+}
+
+impl From<Packet> for Action {
+    fn from(packet: Packet) -> Action {
+        Action::Forward(packet)
+    }
+}
+
 /// Packet handlers need to implement this trait
+/// The context holds the state of the connection the packet was read from
+///
+/// Packets replied by a handler are shown to it in turn, as requests when they go to the
+/// server and as responses when they go to the client. Only the packets sent on by the
+/// actions returned for them are used.
+///
+/// The connection's context stays locked while the handler runs, and both directions of the
+/// connection wait for it, so a slow handler delays the client and the server alike.
+/// Handlers must not lock a context themselves, nor call `server::Connections`, which locks
+/// every context: on the connection being handled this never returns. Logic that needs the
+/// other connections runs next to the proxy instead.
 #[async_trait::async_trait]
 pub trait PacketHandler {
-    async fn handle_request(&mut self, p: &Packet) -> Packet;
-    async fn handle_response(&mut self, p: &Packet) -> Packet;
+    async fn handle_request(&mut self, context: &Context, p: &Packet) -> Action;
+    async fn handle_response(&mut self, context: &Context, p: &Packet) -> Action;
 }
@@ -1,4 +1,4 @@
-use byteorder::{BigEndian, ByteOrder};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
 use futures::{
     channel::mpsc::{Receiver, Sender},
     lock::Mutex,
@@ -10,6 +10,7 @@ use futures::{
 //    future::FutureExt,
 //    stream::StreamExt,
 //};
+use bytes::{Buf, BytesMut};
 use std::{
     io::{Error, ErrorKind},
     sync::Arc,
@@ -17,14 +18,20 @@ use std::{
 use tokio::io::{AsyncReadExt, AsyncWriteExt, Result};
 
 use crate::{
-    packet::{DatabaseType, Packet, PacketType, POSTGRES_IDS},
-    packet_handler::{Direction, PacketHandler},
+    context::Context,
+    mariadb::infile,
+    packet::{DatabaseType, Packet, PacketType, WriteQueue, MARIADB_MAX_PAYLOAD, POSTGRES_IDS},
+    packet_handler::{Action, Direction, PacketHandler},
 };
 
+/// Bytes read from a socket at once, at least
+const READ_BUF_SIZE: usize = 4096;
+
 pub struct Pipe<T: AsyncReadExt, U: AsyncWriteExt> {
     name: String,
     db_type: DatabaseType,
     packet_handler: Arc<Mutex<dyn PacketHandler + Send>>,
+    context: Arc<Mutex<Context>>,
     direction: Direction,
     source: T,
     sink: U,
@@ -35,6 +42,7 @@ impl<T: AsyncReadExt + Unpin, U: AsyncWriteExt + Unpin> Pipe<T, U> {
         name: String,
         db_type: DatabaseType,
         packet_handler: Arc<Mutex<dyn PacketHandler + Send>>,
+        context: Arc<Mutex<Context>>,
         direction: Direction,
         reader: T,
         writer: U,
@@ -43,6 +51,7 @@ impl<T: AsyncReadExt + Unpin, U: AsyncWriteExt + Unpin> Pipe<T, U> {
             name,
             db_type,
             packet_handler,
+            context,
             direction,
             source: reader,
             sink: writer,
@@ -58,28 +67,32 @@ impl<T: AsyncReadExt + Unpin, U: AsyncWriteExt + Unpin> Pipe<T, U> {
         //let source = Arc::get_mut(&mut self.source).unwrap();
         //let sink = Arc::get_mut(&mut self.sink).unwrap();
         let mut other_pipe_receiver = other_pipe_receiver.into_future().fuse();
-        let mut read_buf: Vec<u8> = vec![0_u8; 4096];
-        let mut packet_buf: Vec<u8> = Vec::with_capacity(4096);
-        let mut write_buf: Vec<u8> = Vec::with_capacity(4096);
+        // Packets are split off the buffers they were read into, so they share their memory
+        let mut read_buf = BytesMut::with_capacity(READ_BUF_SIZE);
+        let mut packet_buf = BytesMut::new();
+        let mut compressed_buf = BytesMut::new();
+        let mut write_buf = WriteQueue::new();
 
         loop {
+            read_buf.reserve(READ_BUF_SIZE);
             select! {
                 // Read from the source to read_buf, append to packet_buf
-                read_result = self.source.read(&mut read_buf[..]).fuse() => {
-                    //let n = self.source.read(&mut read_buf[..]).await?;
-                    self.process_read_buf(read_result, &read_buf, &mut packet_buf, &mut write_buf, &mut other_pipe_sender).await?;
+                read_result = self.source.read_buf(&mut read_buf).fuse() => {
+                    self.process_read_buf(read_result, &mut read_buf, &mut compressed_buf, &mut packet_buf, &mut write_buf, &mut other_pipe_sender).await?;
                 },
-                // Support short-circuit
+                // Write the replies of the other pipe, whose source is our sink
                 (packet, recv) = other_pipe_receiver => {
-                    self.process_short_circuit(packet, &mut write_buf)?;
+                    self.process_reply(packet, &mut write_buf)?;
                     other_pipe_receiver = recv.into_future().fuse();
                 },
             } // end select!
 
             // Write all to sink
             while !write_buf.is_empty() {
-                let n = self.sink.write(&write_buf[..]).await?;
-                let _: Vec<u8> = write_buf.drain(0..n).collect();
+                let n = self.sink.write_buf(&mut write_buf).await?;
+                if n == 0 {
+                    return Err(self.create_error("Sink closed".to_string()));
+                }
                 self.trace(format!("{} bytes written to sink", n));
             }
         } // end loop
@@ -88,18 +101,30 @@ impl<T: AsyncReadExt + Unpin, U: AsyncWriteExt + Unpin> Pipe<T, U> {
     async fn process_read_buf(
         &self,
         read_result: Result<usize>,
-        read_buf: &[u8],
-        mut packet_buf: &mut Vec<u8>,
-        write_buf: &mut Vec<u8>,
+        read_buf: &mut BytesMut,
+        compressed_buf: &mut BytesMut,
+        packet_buf: &mut BytesMut,
+        write_buf: &mut WriteQueue,
         other_pipe_sender: &mut Sender<Packet>,
     ) -> Result<()> {
         if let Ok(n) = read_result {
             if n == 0 {
                 let e = self.create_error(format!("Read {} bytes, closing pipe.", n));
-                warn!("{}", e.to_string());
+                warn!("{}", e);
+                return Err(e);
+            }
+            if let Err(e) = self.context.lock().await.read(
+                &self.direction,
+                read_buf.split(),
+                compressed_buf,
+                packet_buf,
+            ) {
+                warn!(
+                    "[{}:{:?}]: Invalid compressed packet: {}",
+                    self.name, self.direction, e
+                );
                 return Err(e);
             }
-            packet_buf.extend_from_slice(&read_buf[0..n]);
             self.trace(format!(
                 "{} bytes read from source, {} bytes in packet_buf",
                 n,
@@ -107,31 +132,54 @@ impl<T: AsyncReadExt + Unpin, U: AsyncWriteExt + Unpin> Pipe<T, U> {
             ));
 
             // Process all packets in packet_buf, put into write_buf
-            while let Some(packet) = get_packet(self.db_type, &mut packet_buf) {
+            while let Some(mut packet) = self.next_packet(packet_buf)? {
                 self.trace("Processing packet".to_string());
+                let mut context = self.context.lock().await;
+                context.decode(&self.direction, &mut packet);
+                // Files served to LOCAL INFILE requests are read without holding the context
+                let contents = match context.get_served_local_infile(&packet) {
+                    Some((path, max_size)) => {
+                        drop(context);
+                        let contents = infile::read_file(&path, max_size).await;
+                        context = self.context.lock().await;
+                        Some(contents)
+                    }
+                    None => None,
+                };
                 // TODO: support SSL. For now, respond that we don't support SSL
                 // https://www.postgresql.org/docs/12/protocol-flow.html#id-1.10.5.7.11
-                if let Ok(PacketType::SSLRequest) = packet.get_packet_type() {
+                let action = if self.db_type == DatabaseType::PostgresSQL
+                    && packet.get_packet_type().ok() == Some(PacketType::SSLRequest)
+                {
                     self.debug("Got SSLRequest, responding no thanks".to_string());
-                    if let Err(_e) = other_pipe_sender
-                        .send(Packet::new(self.db_type, String::from("N").into_bytes()))
-                        .await
-                    {
+                    Action::Reply(vec![Packet::new(
+                        self.db_type,
+                        String::from("N").into_bytes(),
+                    )])
+                } else if let Some(action) = context.reply_local_infile(&packet, contents) {
+                    self.debug(format!(
+                        "Answering LOCAL INFILE request at the proxy: {:?}",
+                        context.get_local_infile()
+                    ));
+                    action
+                } else {
+                    self.handle(&self.direction, &context, &packet).await
+                };
+                let (packets, replies) = action.split();
+                context.encode_all(&self.direction, &packet, &packets, write_buf);
+                let reply = if replies.is_empty() {
+                    None
+                } else {
+                    self.reply(&mut context, &packet, replies).await
+                };
+                // The other pipe may need the context before it makes room for the reply
+                drop(context);
+                if let Some(reply) = reply {
+                    if let Err(_e) = other_pipe_sender.send(reply).await {
                         return Err(
-                            self.create_error("Error sending SSL response of no".to_string())
+                            self.create_error("Error sending reply to the other pipe".to_string())
                         );
                     }
-                } else {
-                    let transformed_packet: Packet;
-                    {
-                        // Scope for self.packet_handler Mutex
-                        let mut h = self.packet_handler.lock().await;
-                        transformed_packet = match self.direction {
-                            Direction::Forward => h.handle_request(&packet).await,
-                            Direction::Backward => h.handle_response(&packet).await,
-                        };
-                    }
-                    write_buf.extend_from_slice(&transformed_packet.bytes);
                 }
             } // end while
             Ok(())
@@ -142,21 +190,63 @@ impl<T: AsyncReadExt + Unpin, U: AsyncWriteExt + Unpin> Pipe<T, U> {
             );
             Err(e)
         } else {
-            Err(Error::new(ErrorKind::Other, "This should never happen"))
+            Err(Error::other("This should never happen"))
         }
     }
 
-    fn process_short_circuit(&self, packet: Option<Packet>, write_buf: &mut Vec<u8>) -> Result<()> {
+    async fn handle(&self, direction: &Direction, context: &Context, packet: &Packet) -> Action {
+        let mut h = self.packet_handler.lock().await;
+        match direction {
+            Direction::Forward => h.handle_request(context, packet).await,
+            Direction::Backward => h.handle_response(context, packet).await,
+        }
+    }
+
+    /// Encode packets sent back to the source, for the other pipe which writes to it. They
+    /// are labeled and handled as packets of the other direction.
+    async fn reply(
+        &self,
+        context: &mut Context,
+        received: &Packet,
+        replies: Vec<Packet>,
+    ) -> Option<Packet> {
+        let reverse = self.direction.reverse();
+        let mut handled = Vec::new();
+        for mut reply in context.number_replies(&self.direction, received, replies) {
+            context.decode(&reverse, &mut reply);
+            let (packets, ignored) = self.handle(&reverse, context, &reply).await.split();
+            if !ignored.is_empty() {
+                self.debug(format!(
+                    "Ignoring {} packets replied to a reply",
+                    ignored.len()
+                ));
+            }
+            handled.push((reply, packets));
+        }
+        let mut reply_buf = WriteQueue::new();
+        context.encode_replies(&self.direction, &handled, &mut reply_buf);
+        if reply_buf.is_empty() {
+            return None;
+        }
+        Some(Packet::new(self.db_type, reply_buf.to_bytes()))
+    }
+
+    fn next_packet(&self, packet_buf: &mut BytesMut) -> Result<Option<Packet>> {
+        get_packet(self.db_type, packet_buf).map_err(|e| {
+            let e = self.create_error(format!("Invalid packet framing: {}", e));
+            warn!("{}", e);
+            e
+        })
+    }
+
+    fn process_reply(&self, packet: Option<Packet>, write_buf: &mut WriteQueue) -> Result<()> {
         if let Some(p) = packet {
-            self.trace(format!(
-                "Got short circuit packet of {} bytes",
-                p.get_size()
-            ));
-            write_buf.extend_from_slice(&p.bytes);
+            self.trace(format!("Got reply of {} bytes", p.get_size()));
+            write_buf.push(p.bytes);
             Ok(())
         } else {
             let e = self.create_error("other_pipe_receiver prematurely closed".to_string());
-            warn!("{}", e.to_string());
+            warn!("{}", e);
             Err(e)
         }
     }
@@ -170,33 +260,25 @@ impl<T: AsyncReadExt + Unpin, U: AsyncWriteExt + Unpin> Pipe<T, U> {
     }
 
     fn create_error(&self, string: String) -> Error {
-        Error::new(
-            ErrorKind::Other,
-            format!("[{}:{:?}]: {}", self.name, self.direction, string),
-        )
+        Error::other(format!("[{}:{:?}]: {}", self.name, self.direction, string))
     }
 } // end impl
 
-fn get_packet(db_type: DatabaseType, packet_buf: &mut Vec<u8>) -> Option<Packet> {
+/// Largest PostgresSQL message accepted, the same limit as the server's
+/// https://www.postgresql.org/docs/12/protocol-overview.html#PROTOCOL-MESSAGE-CONCEPTS
+pub const POSTGRES_MAX_MESSAGE: usize = 0x3fff_ffff;
+
+/// Largest MariaDB payload accepted once reassembled, the largest max_allowed_packet of the
+/// server
+/// https://mariadb.com/kb/en/server-system-variables/#max_allowed_packet
+pub const MARIADB_MAX_PACKET: usize = 0x4000_0000;
+
+/// Take the next complete packet from the start of `packet_buf`. Returns None until enough
+/// bytes were read, and an error when the bytes cannot be a packet, after which the stream
+/// cannot be resynchronized.
+pub fn get_packet(db_type: DatabaseType, packet_buf: &mut BytesMut) -> Result<Option<Packet>> {
     match db_type {
-        DatabaseType::MariaDB => {
-            // Check for header
-            if packet_buf.len() < 4 {
-                return None;
-            }
-            let l: usize = (((packet_buf[2] as u32) << 16)
-                | ((packet_buf[1] as u32) << 8)
-                | packet_buf[0] as u32) as usize;
-            let s = 4 + l;
-            // Check for entire packet size
-            if packet_buf.len() < s {
-                return None;
-            }
-            Some(Packet::new(
-                DatabaseType::MariaDB,
-                packet_buf.drain(0..s).collect(),
-            ))
-        } // end MariaDB
+        DatabaseType::MariaDB => get_mariadb_packet(packet_buf, MARIADB_MAX_PACKET),
         DatabaseType::PostgresSQL => {
             // Nothing in packet_buf
             if packet_buf.is_empty() {
@@ -204,7 +286,7 @@ fn get_packet(db_type: DatabaseType, packet_buf: &mut Vec<u8>) -> Option<Packet>
                     "get_packet(PostgresSQL): FAIL packet_buf(size={}) trying to read first byte",
                     packet_buf.len()
                 );
-                return None;
+                return Ok(None);
             }
             let id = packet_buf[0] as char;
             let mut size = 0;
@@ -218,9 +300,16 @@ fn get_packet(db_type: DatabaseType, packet_buf: &mut Vec<u8>) -> Option<Packet>
                     "get_packet(PostgresSQL): FAIL packet_buf(size={}) trying to read length, firstbyte={:#04x}={}, size={}",
                     packet_buf.len(), packet_buf[0], id, size+4
                 );
-                return None;
+                return Ok(None);
             }
             let length = BigEndian::read_u32(&packet_buf[size..(size + 4)]) as usize; // read length
+                                                                                      // The length includes itself
+            if !(4..=POSTGRES_MAX_MESSAGE).contains(&length) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Invalid PostgresSQL message length {}", length),
+                ));
+            }
             size += length;
 
             // Check if don't have entire packet
@@ -229,7 +318,7 @@ fn get_packet(db_type: DatabaseType, packet_buf: &mut Vec<u8>) -> Option<Packet>
                     "get_packet(PostgresSQL): FAIL packet_buf(size={}) too small, firstbyte={:#04x}={}, size={}, length={}",
                     packet_buf.len(), packet_buf[0], id, size, length
                 );
-                return None;
+                return Ok(None);
             }
             trace!(
                 "get_packet(PostgresSQL): SUCCESS firstbyte={:#04x}={}, size={}, length={}",
@@ -239,10 +328,346 @@ fn get_packet(db_type: DatabaseType, packet_buf: &mut Vec<u8>) -> Option<Packet>
                 length
             );
 
-            Some(Packet::new(
+            Ok(Some(Packet::new(
                 DatabaseType::PostgresSQL,
-                packet_buf.drain(0..size).collect(),
-            ))
+                packet_buf.split_to(size).freeze(),
+            )))
         } // end PostgresSQL
     } // end match
 } // end get_packet
+
+/// Take the next MariaDB packet, reassembled when its payload of `max_payload` bytes at most
+/// is split across several packets
+fn get_mariadb_packet(packet_buf: &mut BytesMut, max_payload: usize) -> Result<Option<Packet>> {
+    // Payloads of MARIADB_MAX_PAYLOAD bytes or more are split across several packets,
+    // the last one being shorter. Wait for all of them.
+    let mut s = 0;
+    let mut fragments = 0;
+    loop {
+        // Check for header
+        if packet_buf.len() < s + 4 {
+            return Ok(None);
+        }
+        let l: usize = (((packet_buf[s + 2] as u32) << 16)
+            | ((packet_buf[s + 1] as u32) << 8)
+            | packet_buf[s] as u32) as usize;
+        s += 4 + l;
+        fragments += 1;
+        if s - 4 * fragments > max_payload {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("MariaDB payload of more than {} bytes", max_payload),
+            ));
+        }
+        // Check for entire packet size
+        if packet_buf.len() < s {
+            return Ok(None);
+        }
+        if l < MARIADB_MAX_PAYLOAD {
+            break;
+        }
+    }
+    if fragments == 1 {
+        return Ok(Some(Packet::new(
+            DatabaseType::MariaDB,
+            packet_buf.split_to(s).freeze(),
+        )));
+    }
+
+    // Reassemble into one packet, keeping the first header
+    trace!(
+        "get_packet(MariaDB): reassembling {} packets of {} bytes",
+        fragments,
+        s
+    );
+    let mut bytes = Vec::with_capacity(s - 4 * (fragments - 1));
+    bytes.extend_from_slice(&packet_buf[0..4]);
+    let mut pos = 0;
+    while pos < s {
+        let l = LittleEndian::read_u24(&packet_buf[pos..pos + 3]) as usize;
+        bytes.extend_from_slice(&packet_buf[pos + 4..pos + 4 + l]);
+        pos += 4 + l;
+    }
+    packet_buf.advance(s);
+    Ok(Some(Packet::new(DatabaseType::MariaDB, bytes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::{collection::vec, prelude::*};
+
+    /// Feed `wire` to get_packet `chunk` bytes at a time, as if read from a socket
+    fn read_in_chunks(db_type: DatabaseType, wire: &[u8], chunk: usize) -> Vec<Packet> {
+        let mut packet_buf = BytesMut::new();
+        let mut packets = Vec::new();
+        for bytes in wire.chunks(chunk) {
+            packet_buf.extend_from_slice(bytes);
+            while let Some(packet) = get_packet(db_type, &mut packet_buf).unwrap() {
+                packets.push(packet);
+            }
+        }
+        assert!(packet_buf.is_empty());
+        packets
+    }
+
+    proptest! {
+        #[test]
+        fn mariadb_framing_round_trips(
+            payloads in vec(vec(any::<u8>(), 0..64), 0..8),
+            chunk in 1_usize..32,
+        ) {
+            let packets: Vec<Packet> = payloads
+                .iter()
+                .enumerate()
+                .map(|(i, payload)| Packet::new_mariadb(i as u8, payload))
+                .collect();
+            let mut wire = WriteQueue::new();
+            for packet in &packets {
+                packet.write_mariadb_fragments(packet.get_sequence_id().unwrap(), &mut wire);
+            }
+            let wire = wire.to_bytes();
+            prop_assert_eq!(read_in_chunks(DatabaseType::MariaDB, &wire, chunk), packets);
+        }
+
+        #[test]
+        fn postgres_framing_round_trips(
+            messages in vec((0..POSTGRES_IDS.len(), vec(any::<u8>(), 0..64)), 0..8),
+            chunk in 1_usize..32,
+        ) {
+            let packets: Vec<Packet> = messages
+                .iter()
+                .map(|(id, body)| Packet::new_postgres(POSTGRES_IDS[*id] as u8, body))
+                .collect();
+            let wire: Vec<u8> = packets.iter().flat_map(|p| p.bytes.clone()).collect();
+            prop_assert_eq!(read_in_chunks(DatabaseType::PostgresSQL, &wire, chunk), packets);
+        }
+
+        #[test]
+        fn framing_never_panics(bytes in vec(any::<u8>(), 0..256)) {
+            for db_type in [DatabaseType::MariaDB, DatabaseType::PostgresSQL] {
+                let mut packet_buf = BytesMut::from(&bytes[..]);
+                while let Ok(Some(packet)) = get_packet(db_type, &mut packet_buf) {
+                    prop_assert!(!packet.bytes.is_empty());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_postgres_lengths() {
+        let mut packet_buf = BytesMut::from(&[b'Q', 0, 0, 0, 3, 0][..]);
+        assert!(get_packet(DatabaseType::PostgresSQL, &mut packet_buf).is_err());
+        let mut packet_buf = BytesMut::from(&[0; 8][..]);
+        assert!(get_packet(DatabaseType::PostgresSQL, &mut packet_buf).is_err());
+    }
+
+    #[test]
+    fn rejects_mariadb_payloads_over_the_limit() {
+        let mut payload = vec![0x03];
+        payload.resize(MARIADB_MAX_PAYLOAD + 20, b'x');
+        let mut wire = WriteQueue::new();
+        Packet::new_mariadb(0, &payload).write_mariadb_fragments(0, &mut wire);
+        let wire = wire.to_bytes();
+
+        let mut packet_buf = BytesMut::from(&wire[..]);
+        assert!(
+            get_mariadb_packet(&mut packet_buf, MARIADB_MAX_PAYLOAD + 20)
+                .unwrap()
+                .is_some()
+        );
+        // The limit applies as soon as the header of the fragment going over it is read
+        let mut packet_buf = BytesMut::from(&wire[..MARIADB_MAX_PAYLOAD + 8]);
+        let e = get_mariadb_packet(&mut packet_buf, MARIADB_MAX_PAYLOAD + 19).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reassembles_and_splits_large_mariadb_packets() {
+        let mut payload = vec![0x03];
+        payload.resize(MARIADB_MAX_PAYLOAD + 10, b'x');
+        let mut wire = WriteQueue::new();
+        let large = Packet::new_mariadb(0, &payload);
+        assert_eq!(large.write_mariadb_fragments(0, &mut wire), 2);
+        Packet::new_mariadb(2, b"\x01").write_mariadb_fragments(2, &mut wire);
+        let wire = wire.to_bytes();
+
+        let mut packet_buf = BytesMut::from(&wire[..wire.len() - 6]);
+        assert_eq!(
+            get_packet(DatabaseType::MariaDB, &mut packet_buf).unwrap(),
+            None
+        );
+        let mut packet_buf = BytesMut::from(&wire[..]);
+        let packet = get_packet(DatabaseType::MariaDB, &mut packet_buf)
+            .unwrap()
+            .unwrap();
+        assert_eq!(packet, large);
+        assert_eq!(packet.get_fragment_count(), 2);
+        assert_eq!(packet.get_query().unwrap().len(), MARIADB_MAX_PAYLOAD + 9);
+        let quit = get_packet(DatabaseType::MariaDB, &mut packet_buf)
+            .unwrap()
+            .unwrap();
+        assert_eq!(quit.bytes, vec![1, 0, 0, 2, 1]);
+        assert!(packet_buf.is_empty());
+
+        // A payload of exactly the maximum size ends with an empty packet
+        let mut wire = WriteQueue::new();
+        let exact = Packet::new_mariadb(3, &payload[..MARIADB_MAX_PAYLOAD]);
+        assert_eq!(exact.write_mariadb_fragments(3, &mut wire), 2);
+        let mut wire = BytesMut::from(&wire.to_bytes()[..]);
+        assert_eq!(&wire[wire.len() - 4..], &[0, 0, 0, 4]);
+        assert_eq!(
+            get_packet(DatabaseType::MariaDB, &mut wire)
+                .unwrap()
+                .unwrap(),
+            exact
+        );
+    }
+
+    /// Replies every request to the client, and forwards the responses of the server
+    struct Echo;
+
+    #[async_trait::async_trait]
+    impl PacketHandler for Echo {
+        async fn handle_request(&mut self, _: &Context, p: &Packet) -> Action {
+            Action::Reply(vec![p.clone()])
+        }
+        async fn handle_response(&mut self, _: &Context, p: &Packet) -> Action {
+            Action::Forward(p.clone())
+        }
+    }
+
+    /// Forwards packets, and checks that the context it shares is locked meanwhile
+    struct LockCheck {
+        context: Arc<Mutex<Context>>,
+        handled: usize,
+    }
+
+    #[async_trait::async_trait]
+    impl PacketHandler for LockCheck {
+        async fn handle_request(&mut self, _: &Context, p: &Packet) -> Action {
+            assert!(self.context.try_lock().is_none());
+            self.handled += 1;
+            Action::Forward(p.clone())
+        }
+        async fn handle_response(&mut self, _: &Context, p: &Packet) -> Action {
+            assert!(self.context.try_lock().is_none());
+            self.handled += 1;
+            Action::Forward(p.clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn handlers_run_with_the_context_locked() {
+        use tokio::net::UnixStream;
+
+        let (client, proxy_client) = UnixStream::pair().unwrap();
+        let (mut server, proxy_server) = UnixStream::pair().unwrap();
+        let context = Arc::new(Mutex::new(Context::new(
+            "127.0.0.1:1234".to_string(),
+            DatabaseType::MariaDB,
+        )));
+        let handler = Arc::new(Mutex::new(LockCheck {
+            context: context.clone(),
+            handled: 0,
+        }));
+        let (client_reader, _) = tokio::io::split(proxy_client);
+        let (_, server_writer) = tokio::io::split(proxy_server);
+        let mut forward = Pipe::new(
+            "test".to_string(),
+            DatabaseType::MariaDB,
+            handler.clone(),
+            context.clone(),
+            Direction::Forward,
+            client_reader,
+            server_writer,
+        );
+        let (fb_tx, _fb_rx) = futures::channel::mpsc::channel(128);
+        let (_bf_tx, bf_rx) = futures::channel::mpsc::channel(128);
+        tokio::spawn(async move { forward.run(fb_tx, bf_rx).await });
+
+        let ping = Packet::new_mariadb(0, &[0x0e]);
+        let (_, mut client_writer) = tokio::io::split(client);
+        client_writer.write_all(&ping.bytes).await.unwrap();
+        let mut received = vec![0; ping.get_size()];
+        // A failed check stops the pipe, and nothing reaches the server
+        tokio::time::timeout(
+            std::time::Duration::from_secs(10),
+            server.read_exact(&mut received),
+        )
+        .await
+        .expect("The handler found the context unlocked")
+        .unwrap();
+        assert_eq!(received, ping.bytes);
+        assert_eq!(handler.lock().await.handled, 1);
+        // The context is free again between packets
+        assert!(context.try_lock().is_some());
+    }
+
+    #[tokio::test]
+    async fn replies_while_the_other_pipe_has_traffic() {
+        use futures::channel::mpsc;
+        use tokio::net::UnixStream;
+
+        const COUNT: usize = 5000;
+        let (client, proxy_client) = UnixStream::pair().unwrap();
+        let (server, proxy_server) = UnixStream::pair().unwrap();
+        let (client_reader, client_writer) = tokio::io::split(proxy_client);
+        let (server_reader, server_writer) = tokio::io::split(proxy_server);
+        let context = Arc::new(Mutex::new(Context::new(
+            "127.0.0.1:1234".to_string(),
+            DatabaseType::MariaDB,
+        )));
+        let handler: Arc<Mutex<dyn PacketHandler + Send>> = Arc::new(Mutex::new(Echo));
+        let mut forward = Pipe::new(
+            "test".to_string(),
+            DatabaseType::MariaDB,
+            handler.clone(),
+            context.clone(),
+            Direction::Forward,
+            client_reader,
+            server_writer,
+        );
+        let mut backward = Pipe::new(
+            "test".to_string(),
+            DatabaseType::MariaDB,
+            handler,
+            context,
+            Direction::Backward,
+            server_reader,
+            client_writer,
+        );
+        let (fb_tx, fb_rx) = mpsc::channel(128);
+        let (bf_tx, bf_rx) = mpsc::channel(128);
+        tokio::spawn(async move {
+            select! {
+                _ = forward.run(fb_tx, bf_rx).fuse() => {},
+                _ = backward.run(bf_tx, fb_rx).fuse() => {},
+            }
+        });
+
+        let ping = Packet::new_mariadb(0, &[0x0e]);
+        let ok = Packet::new_mariadb(1, &[0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00]);
+        let expected = COUNT * (ping.get_size() + ok.get_size());
+        let (mut client_reader, mut client_writer) = tokio::io::split(client);
+        let (_server_reader, mut server_writer) = tokio::io::split(server);
+        tokio::spawn(async move {
+            for _ in 0..COUNT {
+                client_writer.write_all(&ping.bytes).await.unwrap();
+            }
+        });
+        tokio::spawn(async move {
+            for _ in 0..COUNT {
+                server_writer.write_all(&ok.bytes).await.unwrap();
+            }
+        });
+        let mut received = vec![0; expected];
+        tokio::time::timeout(
+            std::time::Duration::from_secs(10),
+            client_reader.read_exact(&mut received),
+        )
+        .await
+        .expect("Pipes are stuck")
+        .unwrap();
+    }
+}
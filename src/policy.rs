@@ -0,0 +1,155 @@
+//! Policies on query batches. A simple Query or a COM_QUERY can carry several statements, so
+//! each one is checked, and a single refused statement rejects the whole batch.
+
+use crate::{
+    context::Context,
+    packet::{DatabaseType, Packet},
+    packet_handler::Action,
+    postgres::error::{ErrorResponse, Severity},
+    sql::{self, Syntax},
+    transaction::TransactionStatus,
+};
+
+/// Error code of a MariaDB SIGNAL, which clients already handle as a refusal raised by SQL
+const MARIADB_SIGNAL_ERROR: u16 = 1644;
+
+/// A batch refused because of one of its statements
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rejection {
+    /// Position of the refused statement in the batch
+    pub index: usize,
+    pub statement: String,
+    pub reason: String,
+}
+
+impl Rejection {
+    /// The proxy's answer to the refused batch: an error with the reason sent back to the
+    /// client, while the server never sees the query.
+    ///
+    /// MariaDB gets an ERR packet following the query's sequence id, and PostgresSQL an
+    /// ErrorResponse and a ReadyForQuery keeping the transaction status of the connection.
+    pub fn to_reply(&self, context: &Context, query: &Packet) -> Action {
+        match context.get_db_type() {
+            DatabaseType::MariaDB => {
+                let sequence_id = query.get_sequence_id().unwrap_or(0).wrapping_add(1);
+                Action::Reply(vec![Packet::error_packet_mariadb_with_sequence_id(
+                    sequence_id,
+                    MARIADB_SIGNAL_ERROR,
+                    *b"42000",
+                    self.reason.clone(),
+                )])
+            }
+            DatabaseType::PostgresSQL => {
+                let transaction_status = match context.get_transaction().get_status() {
+                    TransactionStatus::Idle => b'I',
+                    TransactionStatus::InTransaction => b'T',
+                    TransactionStatus::Failed => b'E',
+                };
+                Action::Reply(
+                    ErrorResponse::new(Severity::Error, "42501", &self.reason)
+                        .reject_simple_query(transaction_status),
+                )
+            }
+        }
+    }
+}
+
+/// Check every statement of a batch with `allow`, which returns why it refuses one. Checking
+/// stops at the first refused statement. Pass the connection's `Context::get_syntax` so the
+/// batch is split as the server will.
+pub fn check_batch<S, F>(syntax: S, sql: &str, mut allow: F) -> Result<(), Rejection>
+where
+    S: Into<Syntax>,
+    F: FnMut(&str) -> Result<(), String>,
+{
+    for (index, statement) in sql::split(syntax, sql).into_iter().enumerate() {
+        if let Err(reason) = allow(statement) {
+            return Err(Rejection {
+                index,
+                statement: statement.to_string(),
+                reason,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mariadb::response::ErrPacket, postgres::message::FrontendMessage, sql::StatementKind,
+    };
+
+    fn deny_drop(db_type: DatabaseType) -> impl FnMut(&str) -> Result<(), String> {
+        move |statement| match sql::parse(db_type, statement) {
+            Ok(parsed)
+                if parsed
+                    .iter()
+                    .any(|s| StatementKind::of(s) == StatementKind::Drop) =>
+            {
+                Err("DROP is not allowed".to_string())
+            }
+            Ok(_) => Ok(()),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    #[test]
+    fn rejects_batches() {
+        let db_type = DatabaseType::MariaDB;
+        assert!(check_batch(
+            db_type,
+            "SELECT 1; SELECT ';DROP TABLE x'",
+            deny_drop(db_type)
+        )
+        .is_ok());
+        let rejection = check_batch(
+            db_type,
+            "SELECT 1; /* cleanup */ drop table users; SELECT 2",
+            deny_drop(db_type),
+        )
+        .unwrap_err();
+        assert_eq!(rejection.index, 1);
+        assert_eq!(rejection.statement, "/* cleanup */ drop table users");
+
+        let context = Context::new("127.0.0.1:1234".to_string(), db_type);
+        let query = Packet::new_mariadb(0, b"\x03SELECT 1; drop table users");
+        let rejection = check_batch(
+            context.get_syntax(),
+            &context.get_query(&query).unwrap().text,
+            deny_drop(db_type),
+        )
+        .unwrap_err();
+        let expected = ErrPacket {
+            code: MARIADB_SIGNAL_ERROR,
+            sql_state: Some("42000".to_string()),
+            message: "DROP is not allowed".to_string(),
+        };
+        assert_eq!(
+            rejection.to_reply(&context, &query),
+            Action::Reply(vec![expected.to_packet(1)])
+        );
+    }
+
+    #[test]
+    fn rejects_postgres_batches_with_an_error_reply() {
+        let db_type = DatabaseType::PostgresSQL;
+        let rejection = check_batch(
+            db_type,
+            "SELECT $tag$a;b$tag$; DROP SCHEMA app CASCADE",
+            deny_drop(db_type),
+        )
+        .unwrap_err();
+        assert_eq!(rejection.index, 1);
+        let context = Context::new("127.0.0.1:1234".to_string(), db_type);
+        let query = FrontendMessage::Query(rejection.statement.clone()).to_packet();
+        assert_eq!(
+            rejection.to_reply(&context, &query),
+            Action::Reply(
+                ErrorResponse::new(Severity::Error, "42501", "DROP is not allowed")
+                    .reject_simple_query(b'I')
+            )
+        );
+    }
+}
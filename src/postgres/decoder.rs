@@ -0,0 +1,729 @@
+//! Labels PostgresSQL messages using the direction they travel in, instead of guessing from
+//! their contents
+
+use byteorder::{BigEndian, ByteOrder};
+use encoding_rs::{Encoding, UTF_8};
+use std::io::{Error, ErrorKind};
+
+use super::{
+    charset::encoding_for_name,
+    copy::{CopyDirection, CopyState, CopyStatement},
+    message::{
+        Authentication, BackendMessage, FrontendMessage, CANCEL_REQUEST_CODE, GSSENC_REQUEST_CODE,
+        SSL_REQUEST_CODE,
+    },
+    replication::{ReplicationStream, StartReplication},
+    resultset::{Column, ResultSet},
+    startup::StartupMessage,
+    statement::{Portal, Statements},
+};
+use crate::{
+    packet::{Packet, PacketType, POSTGRES_IDS},
+    packet_handler::Direction,
+};
+
+/// Tracks the state of a single PostgresSQL connection needed to label and decode its
+/// messages
+#[derive(Clone, Debug, Default)]
+pub struct Decoder {
+    /// StartupMessage sent by the client
+    startup: Option<StartupMessage>,
+    /// Last authentication request, which decides how a 'p' message is interpreted
+    authentication: Option<Authentication>,
+    result_set: ResultSet,
+    statements: Statements,
+    /// Last client_encoding reported by the server
+    client_encoding: Option<&'static Encoding>,
+    /// COPY statement of the last Query or Execute, until the server starts the COPY
+    copy_statement: Option<CopyStatement>,
+    copy: Option<CopyState>,
+    /// Replication stream started by START_REPLICATION on a replication connection
+    replication: Option<ReplicationStream>,
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder::default()
+    }
+
+    /// The StartupMessage sent by the client, once seen. Handlers may have changed the one
+    /// sent to the server.
+    pub fn get_startup(&self) -> Option<&StartupMessage> {
+        self.startup.as_ref()
+    }
+
+    /// Whether the client asked for a replication connection in its StartupMessage
+    pub fn is_replication(&self) -> bool {
+        self.startup
+            .as_ref()
+            .and_then(|startup| startup.get_replication())
+            .is_some()
+    }
+
+    /// The replication stream, once the client sent START_REPLICATION on a replication
+    /// connection. The CopyData of its CopyBoth mode carry replication messages.
+    pub fn get_replication(&self) -> Option<&ReplicationStream> {
+        self.replication.as_ref()
+    }
+
+    /// The last authentication request sent by the server
+    pub fn get_authentication(&self) -> Option<&Authentication> {
+        self.authentication.as_ref()
+    }
+
+    pub fn get_result_set(&self) -> &ResultSet {
+        &self.result_set
+    }
+
+    /// Decode a DataRow using the last RowDescription
+    pub fn decode_row(&self, packet: &Packet) -> Result<Vec<Column>, Error> {
+        self.result_set.decode_row(packet)
+    }
+
+    /// Character set of the SQL text sent by the client, from the client_encoding reported
+    /// by the server. UTF-8 until reported, and for unknown encodings.
+    pub fn get_client_encoding(&self) -> &'static Encoding {
+        self.client_encoding.unwrap_or(UTF_8)
+    }
+
+    /// Prepared statements and portals of the extended query protocol
+    pub fn get_statements(&self) -> &Statements {
+        &self.statements
+    }
+
+    /// The COPY statement sent by the client in the last Query, or run by the last Execute,
+    /// until the server starts the COPY. A handler can check it before the statement
+    /// reaches the server, for instance to refuse `COPY ... TO PROGRAM`.
+    pub fn get_copy_statement(&self) -> Option<&CopyStatement> {
+        self.copy_statement.as_ref()
+    }
+
+    /// The COPY in progress, from the response starting it to the CommandComplete or
+    /// ErrorResponse ending it
+    pub fn get_copy(&self) -> Option<&CopyState> {
+        self.copy.as_ref().filter(|copy| !copy.is_done())
+    }
+
+    /// The portal run by an Execute message, with its SQL text and bound parameters
+    pub fn get_execute(&self, packet: &Packet) -> Result<&Portal, Error> {
+        match FrontendMessage::parse_with_encoding(packet, self.get_client_encoding())? {
+            FrontendMessage::Execute { portal, .. } => {
+                self.statements.get_portal(&portal).ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::NotFound,
+                        format!("Execute of unknown portal {:?}", portal),
+                    )
+                })
+            }
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Packet is not an Execute message",
+            )),
+        }
+    }
+
+    /// Label a message sent by the client
+    pub fn decode_request(&mut self, packet: &Packet) -> Result<PacketType, Error> {
+        let id = match packet.bytes.first() {
+            Some(id) => *id as char,
+            None => return Err(Error::new(ErrorKind::UnexpectedEof, "Empty message")),
+        };
+        if !POSTGRES_IDS.contains(&id) {
+            if packet.bytes.len() < 8 {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "Startup packet too short",
+                ));
+            }
+            return Ok(match BigEndian::read_i32(&packet.bytes[4..8]) {
+                CANCEL_REQUEST_CODE => PacketType::CancelRequest,
+                SSL_REQUEST_CODE => PacketType::SSLRequest,
+                GSSENC_REQUEST_CODE => PacketType::GSSENCRequest,
+                _ => {
+                    match StartupMessage::parse(packet) {
+                        Ok(startup) => self.startup = Some(startup),
+                        Err(e) => debug!("Unable to parse StartupMessage: {}", e),
+                    }
+                    PacketType::StartupMessage
+                }
+            });
+        }
+        let packet_type = match id {
+            'B' => {
+                self.update_statements(packet);
+                PacketType::Bind
+            }
+            'C' => {
+                self.update_statements(packet);
+                PacketType::Close
+            }
+            'd' => {
+                self.read_copy_data(CopyDirection::In, packet);
+                PacketType::CopyData
+            }
+            'c' => {
+                self.finish_copy(CopyDirection::In);
+                PacketType::CopyDone
+            }
+            'f' => PacketType::CopyFail,
+            'D' => {
+                self.update_statements(packet);
+                PacketType::Describe
+            }
+            'E' => {
+                // Rows are sent in the result formats of the portal being executed
+                if let FrontendMessage::Execute { portal, .. } =
+                    FrontendMessage::parse_with_encoding(packet, self.get_client_encoding())?
+                {
+                    let portal = self.statements.get_portal(&portal);
+                    self.result_set
+                        .set_result_formats(portal.map(|portal| portal.result_formats.clone()));
+                    self.copy_statement =
+                        portal.and_then(|portal| CopyStatement::parse(&portal.query));
+                }
+                PacketType::Execute
+            }
+            'H' => PacketType::Flush,
+            'F' => PacketType::FunctionCall,
+            'p' => match self.authentication {
+                Some(Authentication::GSS)
+                | Some(Authentication::SSPI)
+                | Some(Authentication::GSSContinue(_)) => PacketType::GSSResponse,
+                Some(Authentication::CleartextPassword)
+                | Some(Authentication::MD5Password { .. }) => PacketType::PasswordMessage,
+                Some(Authentication::SASL { .. }) => PacketType::SASLInitialResponse,
+                Some(Authentication::SASLContinue(_)) => PacketType::SASLResponse,
+                _ => PacketType::AuthenticationResponse,
+            },
+            'P' => {
+                self.update_statements(packet);
+                PacketType::Parse
+            }
+            'Q' => {
+                self.update_statements(packet);
+                self.result_set.set_result_formats(None);
+                let query = packet.decode_query(self.get_client_encoding()).ok();
+                self.copy_statement = query
+                    .as_ref()
+                    .and_then(|query| CopyStatement::parse(&query.text));
+                if self.is_replication() {
+                    if let Some(start) = query.and_then(|q| StartReplication::parse(&q.text)) {
+                        self.replication = Some(ReplicationStream::new(start));
+                    }
+                }
+                PacketType::Query
+            }
+            'S' => PacketType::Sync,
+            'X' => PacketType::Terminate,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Invalid frontend message type {:?}", id),
+                ))
+            }
+        };
+        Ok(packet_type)
+    }
+
+    fn update_statements(&mut self, packet: &Packet) {
+        let result = FrontendMessage::parse_with_encoding(packet, self.get_client_encoding())
+            .and_then(|m| self.statements.update(&m));
+        if let Err(e) = result {
+            debug!("Unable to track prepared statements: {}", e);
+        }
+    }
+
+    fn start_copy(&mut self, direction: CopyDirection, packet: &Packet) -> Result<(), Error> {
+        let response = match BackendMessage::parse(packet)? {
+            BackendMessage::CopyInResponse(response)
+            | BackendMessage::CopyOutResponse(response)
+            | BackendMessage::CopyBothResponse(response) => response,
+            message => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Expected a copy response, got {:?}", message),
+                ))
+            }
+        };
+        self.copy = Some(CopyState::new(
+            self.copy_statement.take(),
+            direction,
+            response,
+        ));
+        Ok(())
+    }
+
+    /// Split the rows of CopyData flowing in the direction of the COPY
+    fn read_copy_data(&mut self, direction: CopyDirection, packet: &Packet) {
+        let copy = match self.copy.as_mut() {
+            Some(copy) if copy.get_direction() == direction && !copy.is_done() => copy,
+            _ => return,
+        };
+        if let Err(e) = packet.get_payload().and_then(|data| copy.read(data)) {
+            debug!("Unable to read COPY rows: {}", e);
+        }
+    }
+
+    /// Follow the replication stream through the CopyData sent by the server in CopyBoth mode
+    fn update_replication(&mut self, packet: &Packet) {
+        let in_copy_both = self
+            .get_copy()
+            .is_some_and(|copy| copy.get_direction() == CopyDirection::Both);
+        if let (true, Some(replication)) = (in_copy_both, self.replication.as_mut()) {
+            if let Err(e) = replication.update(packet) {
+                debug!("Unable to follow replication stream: {}", e);
+            }
+        }
+    }
+
+    fn finish_copy(&mut self, direction: CopyDirection) {
+        if let Some(copy) = self.copy.as_mut() {
+            if copy.get_direction() == direction {
+                copy.finish();
+            }
+        }
+    }
+
+    fn end_copy(&mut self) {
+        if let Some(copy) = self.copy.as_mut() {
+            copy.end();
+        }
+    }
+
+    /// Whether a packet returned by a handler for `received` is sent. Once a handler
+    /// replaced one of the messages of a COPY with the one built by `CopyState::abort`, the rest
+    /// of the COPY is dropped: the client's data after a CopyFail, or the server's data and
+    /// CommandComplete after an ErrorResponse.
+    pub fn should_send(
+        &mut self,
+        direction: &Direction,
+        received: &Packet,
+        packet: &Packet,
+    ) -> bool {
+        let (copy_direction, abort) = match direction {
+            Direction::Forward => (CopyDirection::In, PacketType::CopyFail),
+            Direction::Backward => (CopyDirection::Out, PacketType::ErrorResponse),
+        };
+        let copy = match self.copy.as_mut() {
+            Some(copy) if copy.get_direction() == copy_direction => copy,
+            _ => return true,
+        };
+        let received = received.get_packet_type().ok();
+        let part_of_copy = matches!(
+            received,
+            Some(PacketType::CopyData)
+                | Some(PacketType::CopyDone)
+                | Some(PacketType::CopyFail)
+                | Some(PacketType::CopyOutResponse)
+                | Some(PacketType::CommandComplete)
+        );
+        if copy.is_aborted() {
+            return !part_of_copy;
+        }
+        if part_of_copy && received != Some(abort) && packet.get_packet_type().ok() == Some(abort) {
+            debug!("COPY aborted by handler");
+            copy.set_aborted();
+        }
+        true
+    }
+
+    /// Label a message sent by the server
+    pub fn decode_response(&mut self, packet: &Packet) -> Result<PacketType, Error> {
+        let id = match packet.bytes.first() {
+            Some(id) => *id as char,
+            None => return Err(Error::new(ErrorKind::UnexpectedEof, "Empty message")),
+        };
+        if packet.bytes.len() == 1 {
+            return Ok(PacketType::EncryptionResponse);
+        }
+        let packet_type = match id {
+            'R' => match BackendMessage::parse(packet)? {
+                BackendMessage::Authentication(auth) => {
+                    let packet_type = auth.get_packet_type();
+                    self.authentication = Some(auth);
+                    packet_type
+                }
+                message => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Expected an authentication message, got {:?}", message),
+                    ))
+                }
+            },
+            'K' => PacketType::BackendKeyData,
+            '2' => PacketType::BindComplete,
+            '3' => PacketType::CloseComplete,
+            'C' => {
+                self.end_copy();
+                PacketType::CommandComplete
+            }
+            'd' => {
+                self.read_copy_data(CopyDirection::Out, packet);
+                self.update_replication(packet);
+                PacketType::CopyData
+            }
+            'c' => {
+                self.finish_copy(CopyDirection::Out);
+                PacketType::CopyDone
+            }
+            'G' => {
+                self.start_copy(CopyDirection::In, packet)?;
+                PacketType::CopyInResponse
+            }
+            'H' => {
+                self.start_copy(CopyDirection::Out, packet)?;
+                PacketType::CopyOutResponse
+            }
+            'W' => {
+                self.start_copy(CopyDirection::Both, packet)?;
+                PacketType::CopyBothResponse
+            }
+            'D' => PacketType::DataRow,
+            'I' => PacketType::EmptyQueryResponse,
+            'E' => {
+                self.end_copy();
+                self.statements.forget_descriptions();
+                PacketType::ErrorResponse
+            }
+            'V' => PacketType::FunctionCallResponse,
+            'v' => PacketType::NegotiateProtocolVersion,
+            'n' => PacketType::NoData,
+            'N' => PacketType::NoticeResponse,
+            'A' => PacketType::NotificationResponse,
+            't' => {
+                if let BackendMessage::ParameterDescription(types) = BackendMessage::parse(packet)?
+                {
+                    self.statements.describe_parameters(&types);
+                }
+                PacketType::ParameterDescription
+            }
+            'S' => {
+                if let BackendMessage::ParameterStatus { name, value } =
+                    BackendMessage::parse(packet)?
+                {
+                    if name == "client_encoding" {
+                        self.client_encoding = encoding_for_name(&value);
+                        if self.client_encoding.is_none() {
+                            debug!(
+                                "Unknown client_encoding {}, reading queries as UTF-8",
+                                value
+                            );
+                        }
+                    }
+                }
+                PacketType::ParameterStatus
+            }
+            '1' => PacketType::ParseComplete,
+            's' => PacketType::PortalSuspended,
+            'Z' => {
+                self.copy = None;
+                self.statements.forget_descriptions();
+                PacketType::ReadyForQuery
+            }
+            'T' => {
+                if let BackendMessage::RowDescription(fields) = BackendMessage::parse(packet)? {
+                    self.result_set.set_fields(fields);
+                }
+                PacketType::RowDescription
+            }
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Invalid backend message type {:?}", id),
+                ))
+            }
+        };
+        Ok(packet_type)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::DatabaseType;
+    use crate::postgres::{
+        error::{ErrorResponse, Severity},
+        message::{CopyResponse, FrontendMessage, Target},
+        replication::{ChangeKind, ReplicationMessage},
+        value::{Value, BINARY_FORMAT, INT8_OID, TEXT_FORMAT},
+    };
+
+    #[test]
+    fn labels_by_direction() {
+        let mut decoder = Decoder::new();
+        let execute = FrontendMessage::Execute {
+            portal: "S_1".to_string(),
+            max_rows: 0,
+        }
+        .to_packet();
+        assert_eq!(
+            decoder.decode_request(&execute).unwrap(),
+            PacketType::Execute
+        );
+        assert_eq!(
+            decoder.decode_response(&execute).unwrap(),
+            PacketType::ErrorResponse
+        );
+        let sync = FrontendMessage::Sync.to_packet();
+        assert_eq!(decoder.decode_request(&sync).unwrap(), PacketType::Sync);
+        let no = Packet::new(DatabaseType::PostgresSQL, vec![b'N']);
+        assert_eq!(
+            decoder.decode_response(&no).unwrap(),
+            PacketType::EncryptionResponse
+        );
+    }
+
+    #[test]
+    fn tracks_extended_query() {
+        let mut decoder = Decoder::new();
+        let messages = [
+            FrontendMessage::Parse {
+                name: String::new(),
+                query: "SELECT name FROM users WHERE id = $1".to_string(),
+                param_types: vec![INT8_OID],
+            },
+            FrontendMessage::Bind {
+                portal: String::new(),
+                statement: String::new(),
+                param_formats: vec![TEXT_FORMAT],
+                params: vec![Some(b"7".to_vec())],
+                result_formats: vec![BINARY_FORMAT],
+            },
+        ];
+        for message in messages.iter() {
+            decoder.decode_request(&message.to_packet()).unwrap();
+        }
+        let execute = FrontendMessage::Execute {
+            portal: String::new(),
+            max_rows: 0,
+        }
+        .to_packet();
+        decoder.decode_request(&execute).unwrap();
+
+        let portal = decoder.get_execute(&execute).unwrap();
+        assert_eq!(portal.query, "SELECT name FROM users WHERE id = $1");
+        assert_eq!(portal.params[0].decode().unwrap(), Value::Int8(7));
+        assert_eq!(decoder.get_result_set().get_format(0), BINARY_FORMAT);
+        assert_eq!(
+            messages[0].to_packet().get_query().unwrap(),
+            "SELECT name FROM users WHERE id = $1"
+        );
+    }
+
+    #[test]
+    fn infers_parameter_types_of_described_statements() {
+        // tokio-postgres leaves parameter types to the server, and binds binary values
+        let mut decoder = Decoder::new();
+        let prepare = [
+            FrontendMessage::Parse {
+                name: "s0".to_string(),
+                query: "SELECT name FROM users WHERE id = $1".to_string(),
+                param_types: vec![0],
+            },
+            FrontendMessage::Describe {
+                target: Target::Statement,
+                name: "s0".to_string(),
+            },
+            FrontendMessage::Sync,
+        ];
+        for message in prepare.iter() {
+            decoder.decode_request(&message.to_packet()).unwrap();
+        }
+        let description = BackendMessage::ParameterDescription(vec![INT8_OID]).to_packet();
+        assert_eq!(
+            decoder.decode_response(&description).unwrap(),
+            PacketType::ParameterDescription
+        );
+        let bind = FrontendMessage::Bind {
+            portal: String::new(),
+            statement: "s0".to_string(),
+            param_formats: vec![BINARY_FORMAT],
+            params: vec![Some(7_i64.to_be_bytes().to_vec())],
+            result_formats: Vec::new(),
+        };
+        decoder.decode_request(&bind.to_packet()).unwrap();
+        let execute = FrontendMessage::Execute {
+            portal: String::new(),
+            max_rows: 0,
+        }
+        .to_packet();
+        decoder.decode_request(&execute).unwrap();
+        let portal = decoder.get_execute(&execute).unwrap();
+        assert_eq!(portal.params[0].decode().unwrap(), Value::Int8(7));
+    }
+
+    #[test]
+    fn labels_authentication_response() {
+        let mut decoder = Decoder::new();
+        let request = BackendMessage::Authentication(Authentication::SASL {
+            mechanisms: vec!["SCRAM-SHA-256".to_string()],
+        })
+        .to_packet();
+        assert_eq!(
+            decoder.decode_response(&request).unwrap(),
+            PacketType::AuthenticationSASL
+        );
+        let response =
+            FrontendMessage::AuthenticationResponse(b"SCRAM-SHA-256\x00".to_vec()).to_packet();
+        assert_eq!(
+            decoder.decode_request(&response).unwrap(),
+            PacketType::SASLInitialResponse
+        );
+    }
+
+    #[test]
+    fn keeps_startup_message() {
+        let mut decoder = Decoder::new();
+        let mut startup = StartupMessage::new("alice");
+        startup.set("database", "app");
+        assert_eq!(
+            decoder.decode_request(&startup.to_packet()).unwrap(),
+            PacketType::StartupMessage
+        );
+        assert_eq!(decoder.get_startup(), Some(&startup));
+    }
+
+    #[test]
+    fn follows_logical_replication() {
+        let mut decoder = Decoder::new();
+        let mut startup = StartupMessage::new("replicator");
+        startup.set("replication", "database");
+        decoder.decode_request(&startup.to_packet()).unwrap();
+        assert!(decoder.is_replication());
+        let start = FrontendMessage::Query(
+            "START_REPLICATION SLOT s LOGICAL 0/0 (proto_version '1', publication_names 'p')"
+                .to_string(),
+        )
+        .to_packet();
+        decoder.decode_request(&start).unwrap();
+        let copy_both = BackendMessage::CopyBothResponse(CopyResponse {
+            format: 0,
+            column_formats: vec![],
+        })
+        .to_packet();
+        decoder.decode_response(&copy_both).unwrap();
+
+        let mut relation = b"R\x00\x00\x40\x00public\x00t\x00d\x00\x01".to_vec();
+        relation.extend_from_slice(b"\x01id\x00\x00\x00\x00\x17\xff\xff\xff\xff");
+        let insert = b"I\x00\x00\x40\x00N\x00\x01t\x00\x00\x00\x011";
+        let mut change = None;
+        for (lsn, data) in [(0x10, &relation[..]), (0x20, &insert[..])] {
+            let xlog_data = ReplicationMessage::XLogData {
+                start: lsn,
+                end: lsn,
+                time: 0,
+                data: data.to_vec(),
+            }
+            .to_packet();
+            decoder.decode_response(&xlog_data).unwrap();
+            change = decoder
+                .get_replication()
+                .unwrap()
+                .decode_change(&xlog_data)
+                .unwrap();
+        }
+        let change = change.unwrap();
+        assert_eq!(change.kind, ChangeKind::Insert);
+        assert_eq!(change.table, "t");
+        assert_eq!(decoder.get_replication().unwrap().get_wal_end(), 0x20);
+    }
+
+    #[test]
+    fn tracks_copy() {
+        let mut decoder = Decoder::new();
+        let mut query =
+            FrontendMessage::Query("COPY users (id, name) FROM STDIN".to_string()).to_packet();
+        query.set_packet_type(decoder.decode_request(&query).unwrap());
+        assert_eq!(
+            decoder.get_copy_statement().unwrap().table.as_deref(),
+            Some("users")
+        );
+        let response = BackendMessage::CopyInResponse(CopyResponse {
+            format: 0,
+            column_formats: vec![0, 0],
+        })
+        .to_packet();
+        decoder.decode_response(&response).unwrap();
+        assert!(decoder.get_copy_statement().is_none());
+        let copy = decoder.get_copy().unwrap();
+        assert_eq!(copy.get_direction(), CopyDirection::In);
+        assert_eq!(copy.get_statement().unwrap().columns, vec!["id", "name"]);
+
+        let mut data = FrontendMessage::CopyData(b"1\talice\n2\tb".to_vec()).to_packet();
+        data.set_packet_type(decoder.decode_request(&data).unwrap());
+        assert_eq!(decoder.get_copy().unwrap().get_rows().len(), 1);
+
+        // A handler aborts the COPY, the rest of the client's data is dropped
+        let abort = decoder.get_copy().unwrap().abort("blocked by proxy");
+        assert!(decoder.should_send(&Direction::Forward, &data, &abort));
+        assert!(decoder.get_copy().unwrap().is_aborted());
+        let mut done = FrontendMessage::CopyDone.to_packet();
+        done.set_packet_type(decoder.decode_request(&done).unwrap());
+        assert!(!decoder.should_send(&Direction::Forward, &done, &done));
+
+        let error = ErrorResponse::new(Severity::Error, "57014", "COPY from stdin failed")
+            .to_error_packet();
+        decoder.decode_response(&error).unwrap();
+        assert!(decoder.get_copy().is_none());
+        decoder
+            .decode_response(&BackendMessage::ReadyForQuery(b'I').to_packet())
+            .unwrap();
+        let sync = FrontendMessage::Sync.to_packet();
+        assert!(decoder.should_send(&Direction::Forward, &sync, &sync));
+    }
+
+    #[test]
+    fn decodes_queries_in_client_encoding() {
+        let mut decoder = Decoder::new();
+        let status = BackendMessage::ParameterStatus {
+            name: "client_encoding".to_string(),
+            value: "LATIN1".to_string(),
+        }
+        .to_packet();
+        decoder.decode_response(&status).unwrap();
+
+        let mut query = Packet::new_postgres(b'Q', b"SELECT 'caf\xe9'\x00");
+        query.set_packet_type(decoder.decode_request(&query).unwrap());
+        assert_eq!(query.get_query_bytes().unwrap(), b"SELECT 'caf\xe9'");
+        assert!(query.get_query().is_err());
+        let text = query.decode_query(decoder.get_client_encoding()).unwrap();
+        assert_eq!(text.text, "SELECT 'caf\u{e9}'");
+        assert!(!text.lossy);
+
+        let text = query.decode_query(UTF_8).unwrap();
+        assert_eq!(text.text, "SELECT 'caf\u{fffd}'");
+        assert!(text.lossy);
+    }
+
+    #[test]
+    fn tracks_extended_query_in_client_encoding() {
+        let mut decoder = Decoder::new();
+        let status = BackendMessage::ParameterStatus {
+            name: "client_encoding".to_string(),
+            value: "SJIS".to_string(),
+        }
+        .to_packet();
+        decoder.decode_response(&status).unwrap();
+
+        // Statement "表" running SELECT '日本' in Shift_JIS
+        let mut parse = b"\x95\x5c\x00SELECT '\x93\xfa\x96\x7b'\x00".to_vec();
+        parse.extend_from_slice(&[0, 0]);
+        let mut bind = b"\x00\x95\x5c\x00".to_vec();
+        bind.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        let messages = [
+            Packet::new_postgres(b'P', &parse),
+            Packet::new_postgres(b'B', &bind),
+        ];
+        for message in messages.iter() {
+            decoder.decode_request(message).unwrap();
+        }
+        assert!(decoder.get_statements().get_statement("\u{8868}").is_some());
+        let execute = Packet::new_postgres(b'E', &[0, 0, 0, 0, 0]);
+        assert_eq!(
+            decoder.decode_request(&execute).unwrap(),
+            PacketType::Execute
+        );
+        let portal = decoder.get_execute(&execute).unwrap();
+        assert_eq!(portal.query, "SELECT '\u{65e5}\u{672c}'");
+    }
+}
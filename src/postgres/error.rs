@@ -0,0 +1,303 @@
+//! Building ErrorResponse and NoticeResponse messages
+//! https://www.postgresql.org/docs/12/protocol-error-fields.html
+
+use std::io::{Error, ErrorKind};
+
+use super::message::BackendMessage;
+use crate::packet::Packet;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    // ErrorResponse
+    Error,
+    Fatal,
+    Panic,
+    // NoticeResponse
+    Warning,
+    Notice,
+    Debug,
+    Info,
+    Log,
+}
+
+impl Severity {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Severity::Error => "ERROR",
+            Severity::Fatal => "FATAL",
+            Severity::Panic => "PANIC",
+            Severity::Warning => "WARNING",
+            Severity::Notice => "NOTICE",
+            Severity::Debug => "DEBUG",
+            Severity::Info => "INFO",
+            Severity::Log => "LOG",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Severity> {
+        match s {
+            "ERROR" => Some(Severity::Error),
+            "FATAL" => Some(Severity::Fatal),
+            "PANIC" => Some(Severity::Panic),
+            "WARNING" => Some(Severity::Warning),
+            "NOTICE" => Some(Severity::Notice),
+            "DEBUG" => Some(Severity::Debug),
+            "INFO" => Some(Severity::Info),
+            "LOG" => Some(Severity::Log),
+            _ => None,
+        }
+    }
+}
+
+/// Fields of an ErrorResponse or NoticeResponse, built up from the required severity, SQLSTATE
+/// and message
+#[derive(Clone, Debug, PartialEq)]
+pub struct ErrorResponse {
+    pub severity: Severity,
+    /// SQLSTATE code, see https://www.postgresql.org/docs/12/errcodes-appendix.html
+    pub code: String,
+    pub message: String,
+    pub detail: Option<String>,
+    pub hint: Option<String>,
+    /// 1-based character index into the query string
+    pub position: Option<u32>,
+    pub internal_position: Option<u32>,
+    pub internal_query: Option<String>,
+    pub where_: Option<String>,
+    pub schema: Option<String>,
+    pub table: Option<String>,
+    pub column: Option<String>,
+    pub data_type: Option<String>,
+    pub constraint: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    pub routine: Option<String>,
+}
+
+impl ErrorResponse {
+    pub fn new(severity: Severity, code: &str, message: &str) -> ErrorResponse {
+        ErrorResponse {
+            severity,
+            code: code.to_string(),
+            message: message.to_string(),
+            detail: None,
+            hint: None,
+            position: None,
+            internal_position: None,
+            internal_query: None,
+            where_: None,
+            schema: None,
+            table: None,
+            column: None,
+            data_type: None,
+            constraint: None,
+            file: None,
+            line: None,
+            routine: None,
+        }
+    }
+
+    pub fn detail(mut self, detail: &str) -> ErrorResponse {
+        self.detail = Some(detail.to_string());
+        self
+    }
+
+    pub fn hint(mut self, hint: &str) -> ErrorResponse {
+        self.hint = Some(hint.to_string());
+        self
+    }
+
+    pub fn position(mut self, position: u32) -> ErrorResponse {
+        self.position = Some(position);
+        self
+    }
+
+    pub fn internal_query(mut self, query: &str, position: u32) -> ErrorResponse {
+        self.internal_query = Some(query.to_string());
+        self.internal_position = Some(position);
+        self
+    }
+
+    pub fn where_(mut self, context: &str) -> ErrorResponse {
+        self.where_ = Some(context.to_string());
+        self
+    }
+
+    pub fn schema(mut self, schema: &str) -> ErrorResponse {
+        self.schema = Some(schema.to_string());
+        self
+    }
+
+    pub fn table(mut self, table: &str) -> ErrorResponse {
+        self.table = Some(table.to_string());
+        self
+    }
+
+    pub fn column(mut self, column: &str) -> ErrorResponse {
+        self.column = Some(column.to_string());
+        self
+    }
+
+    pub fn data_type(mut self, data_type: &str) -> ErrorResponse {
+        self.data_type = Some(data_type.to_string());
+        self
+    }
+
+    pub fn constraint(mut self, constraint: &str) -> ErrorResponse {
+        self.constraint = Some(constraint.to_string());
+        self
+    }
+
+    pub fn source(mut self, file: &str, line: u32, routine: &str) -> ErrorResponse {
+        self.file = Some(file.to_string());
+        self.line = Some(line);
+        self.routine = Some(routine.to_string());
+        self
+    }
+
+    /// Read the fields of a parsed ErrorResponse or NoticeResponse
+    pub fn from_fields(fields: &[(u8, String)]) -> Result<ErrorResponse, Error> {
+        let mut response = ErrorResponse::new(Severity::Error, "", "");
+        let mut severity = None;
+        for (code, value) in fields {
+            let value = value.clone();
+            match code {
+                // 'V' is never localized, so prefer it over 'S'
+                b'V' => severity = Severity::parse(&value),
+                b'S' if severity.is_none() => severity = Severity::parse(&value),
+                b'C' => response.code = value,
+                b'M' => response.message = value,
+                b'D' => response.detail = Some(value),
+                b'H' => response.hint = Some(value),
+                b'P' => response.position = value.parse().ok(),
+                b'p' => response.internal_position = value.parse().ok(),
+                b'q' => response.internal_query = Some(value),
+                b'W' => response.where_ = Some(value),
+                b's' => response.schema = Some(value),
+                b't' => response.table = Some(value),
+                b'c' => response.column = Some(value),
+                b'd' => response.data_type = Some(value),
+                b'n' => response.constraint = Some(value),
+                b'F' => response.file = Some(value),
+                b'L' => response.line = value.parse().ok(),
+                b'R' => response.routine = Some(value),
+                // Unrecognized fields must be ignored
+                _ => {}
+            }
+        }
+        match severity {
+            Some(severity) => response.severity = severity,
+            None => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "ErrorResponse is missing its severity",
+                ))
+            }
+        }
+        Ok(response)
+    }
+
+    pub fn parse(packet: &Packet) -> Result<ErrorResponse, Error> {
+        match BackendMessage::parse(packet)? {
+            BackendMessage::ErrorResponse(fields) | BackendMessage::NoticeResponse(fields) => {
+                ErrorResponse::from_fields(&fields)
+            }
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Packet is not an ErrorResponse or NoticeResponse",
+            )),
+        }
+    }
+
+    pub fn to_fields(&self) -> Vec<(u8, String)> {
+        let severity = self.severity.as_str().to_string();
+        let mut fields = vec![
+            (b'S', severity.clone()),
+            (b'V', severity),
+            (b'C', self.code.clone()),
+            (b'M', self.message.clone()),
+        ];
+        let optional = [
+            (b'D', self.detail.clone()),
+            (b'H', self.hint.clone()),
+            (b'P', self.position.map(|p| p.to_string())),
+            (b'p', self.internal_position.map(|p| p.to_string())),
+            (b'q', self.internal_query.clone()),
+            (b'W', self.where_.clone()),
+            (b's', self.schema.clone()),
+            (b't', self.table.clone()),
+            (b'c', self.column.clone()),
+            (b'd', self.data_type.clone()),
+            (b'n', self.constraint.clone()),
+            (b'F', self.file.clone()),
+            (b'L', self.line.map(|l| l.to_string())),
+            (b'R', self.routine.clone()),
+        ];
+        for (code, value) in optional.iter() {
+            if let Some(value) = value {
+                fields.push((*code, value.clone()));
+            }
+        }
+        fields
+    }
+
+    pub fn to_error_packet(&self) -> Packet {
+        BackendMessage::ErrorResponse(self.to_fields()).to_packet()
+    }
+
+    pub fn to_notice_packet(&self) -> Packet {
+        BackendMessage::NoticeResponse(self.to_fields()).to_packet()
+    }
+
+    /// The complete reply to a simple Query that the proxy refuses to forward: the
+    /// ErrorResponse followed by ReadyForQuery. Clients wait for ReadyForQuery before sending
+    /// anything else.
+    ///
+    /// `transaction_status` is the status from the last ReadyForQuery sent by the server
+    /// ('I', 'T' or 'E'). The server never sees the rejected query, so its transaction is
+    /// unchanged.
+    pub fn reject_simple_query(&self, transaction_status: u8) -> Vec<Packet> {
+        vec![
+            self.to_error_packet(),
+            BackendMessage::ReadyForQuery(transaction_status).to_packet(),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::PacketType;
+
+    #[test]
+    fn error_response_round_trip() {
+        let error = ErrorResponse::new(
+            Severity::Error,
+            "42P01",
+            "relation \"users\" does not exist",
+        )
+        .position(15)
+        .hint("Check the search_path")
+        .table("users");
+        let packet = error.to_error_packet();
+        assert_eq!(ErrorResponse::parse(&packet).unwrap(), error);
+
+        let notice = ErrorResponse::new(Severity::Warning, "01000", "query was rewritten");
+        assert_eq!(
+            ErrorResponse::parse(&notice.to_notice_packet()).unwrap(),
+            notice
+        );
+    }
+
+    #[test]
+    fn rejecting_a_query_ends_with_ready_for_query() {
+        let packets = ErrorResponse::new(Severity::Error, "42501", "blocked by proxy")
+            .reject_simple_query(b'T');
+        let messages: Vec<BackendMessage> = packets
+            .iter()
+            .map(|p| BackendMessage::parse(p).unwrap())
+            .collect();
+        assert_eq!(messages[0].get_packet_type(), PacketType::ErrorResponse);
+        assert_eq!(messages[1], BackendMessage::ReadyForQuery(b'T'));
+    }
+}
@@ -0,0 +1,1156 @@
+//! Typed PostgresSQL messages. Several message types share a type byte between the frontend
+//! and the backend ('C', 'D', 'E', 'H', 'S', ...), so the direction decides how to decode.
+//! https://www.postgresql.org/docs/12/protocol-message-formats.html
+
+use byteorder::{BigEndian, WriteBytesExt};
+use encoding_rs::Encoding;
+use std::io::{Error, ErrorKind};
+
+use super::{
+    value::{type_size, TEXT_FORMAT},
+    write_cstr, Reader,
+};
+use crate::packet::{DatabaseType, Packet, PacketType, POSTGRES_IDS};
+
+pub const PROTOCOL_VERSION_3: i32 = 196_608;
+pub const CANCEL_REQUEST_CODE: i32 = 80_877_102;
+pub const SSL_REQUEST_CODE: i32 = 80_877_103;
+pub const GSSENC_REQUEST_CODE: i32 = 80_877_104;
+
+/// Whether a Close or Describe message refers to a prepared statement or a portal
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Target {
+    Statement,
+    Portal,
+}
+
+impl Target {
+    fn parse(b: u8) -> Result<Target, Error> {
+        match b {
+            b'S' => Ok(Target::Statement),
+            b'P' => Ok(Target::Portal),
+            _ => Err(invalid(format!("Invalid Close/Describe target {:#04x}", b))),
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            Target::Statement => b'S',
+            Target::Portal => b'P',
+        }
+    }
+}
+
+/// Messages sent by the client
+#[derive(Clone, Debug, PartialEq)]
+pub enum FrontendMessage {
+    Bind {
+        portal: String,
+        statement: String,
+        param_formats: Vec<i16>,
+        params: Vec<Option<Vec<u8>>>,
+        result_formats: Vec<i16>,
+    },
+    CancelRequest {
+        process_id: i32,
+        secret_key: i32,
+    },
+    Close {
+        target: Target,
+        name: String,
+    },
+    CopyData(Vec<u8>),
+    CopyDone,
+    CopyFail(String),
+    Describe {
+        target: Target,
+        name: String,
+    },
+    Execute {
+        portal: String,
+        max_rows: i32,
+    },
+    Flush,
+    FunctionCall {
+        function_oid: u32,
+        arg_formats: Vec<i16>,
+        args: Vec<Option<Vec<u8>>>,
+        result_format: i16,
+    },
+    GSSENCRequest,
+    /// GSSResponse, PasswordMessage, SASLInitialResponse or SASLResponse. They share a type
+    /// byte, so which one depends on the authentication request being answered. See
+    /// `AuthenticationResponse::parse`.
+    AuthenticationResponse(Vec<u8>),
+    Parse {
+        name: String,
+        query: String,
+        param_types: Vec<u32>,
+    },
+    Query(String),
+    SSLRequest,
+    StartupMessage {
+        protocol_version: i32,
+        parameters: Vec<(String, String)>,
+    },
+    Sync,
+    Terminate,
+}
+
+impl FrontendMessage {
+    /// Parse a message whose SQL text and names are UTF-8
+    pub fn parse(packet: &Packet) -> Result<FrontendMessage, Error> {
+        FrontendMessage::parse_with(packet, |bytes| {
+            String::from_utf8(bytes.to_vec()).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+        })
+    }
+
+    /// Parse a message whose SQL text and names are in `encoding`, the client_encoding of
+    /// the connection. Invalid sequences are replaced with U+FFFD.
+    pub fn parse_with_encoding(
+        packet: &Packet,
+        encoding: &'static Encoding,
+    ) -> Result<FrontendMessage, Error> {
+        FrontendMessage::parse_with(packet, |bytes| {
+            Ok(encoding.decode_without_bom_handling(bytes).0.into_owned())
+        })
+    }
+
+    fn parse_with<F>(packet: &Packet, text: F) -> Result<FrontendMessage, Error>
+    where
+        F: Fn(&[u8]) -> Result<String, Error>,
+    {
+        check_db_type(packet)?;
+        let bytes = &packet.bytes;
+        if bytes.is_empty() {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Empty message"));
+        }
+        if !POSTGRES_IDS.contains(&(bytes[0] as char)) {
+            return FrontendMessage::parse_untagged(bytes);
+        }
+        let mut r = Reader::new(body(packet)?);
+        let message = match bytes[0] {
+            b'B' => {
+                let portal = text(r.read_cstr_bytes()?)?;
+                let statement = text(r.read_cstr_bytes()?)?;
+                let param_formats = read_i16_array(&mut r)?;
+                let n = r.read_i16()?;
+                let mut params = Vec::with_capacity(n.max(0) as usize);
+                for _ in 0..n {
+                    params.push(r.read_nullable_bytes()?.map(|v| v.to_vec()));
+                }
+                let result_formats = read_i16_array(&mut r)?;
+                FrontendMessage::Bind {
+                    portal,
+                    statement,
+                    param_formats,
+                    params,
+                    result_formats,
+                }
+            }
+            b'C' => FrontendMessage::Close {
+                target: Target::parse(r.read_u8()?)?,
+                name: text(r.read_cstr_bytes()?)?,
+            },
+            b'd' => FrontendMessage::CopyData(r.read_rest().to_vec()),
+            b'c' => FrontendMessage::CopyDone,
+            b'f' => FrontendMessage::CopyFail(text(r.read_cstr_bytes()?)?),
+            b'D' => FrontendMessage::Describe {
+                target: Target::parse(r.read_u8()?)?,
+                name: text(r.read_cstr_bytes()?)?,
+            },
+            b'E' => FrontendMessage::Execute {
+                portal: text(r.read_cstr_bytes()?)?,
+                max_rows: r.read_i32()?,
+            },
+            b'H' => FrontendMessage::Flush,
+            b'F' => {
+                let function_oid = r.read_u32()?;
+                let arg_formats = read_i16_array(&mut r)?;
+                let n = r.read_i16()?;
+                let mut args = Vec::with_capacity(n.max(0) as usize);
+                for _ in 0..n {
+                    args.push(r.read_nullable_bytes()?.map(|v| v.to_vec()));
+                }
+                FrontendMessage::FunctionCall {
+                    function_oid,
+                    arg_formats,
+                    args,
+                    result_format: r.read_i16()?,
+                }
+            }
+            b'p' => FrontendMessage::AuthenticationResponse(r.read_rest().to_vec()),
+            b'P' => {
+                let name = text(r.read_cstr_bytes()?)?;
+                let query = text(r.read_cstr_bytes()?)?;
+                let n = r.read_i16()?;
+                let mut param_types = Vec::with_capacity(n.max(0) as usize);
+                for _ in 0..n {
+                    param_types.push(r.read_u32()?);
+                }
+                FrontendMessage::Parse {
+                    name,
+                    query,
+                    param_types,
+                }
+            }
+            b'Q' => FrontendMessage::Query(text(r.read_cstr_bytes()?)?),
+            b'S' => FrontendMessage::Sync,
+            b'X' => FrontendMessage::Terminate,
+            b => return Err(invalid(format!("Invalid frontend message type {:#04x}", b))),
+        };
+        Ok(message)
+    }
+
+    /// Messages sent before the startup completes have no type byte
+    fn parse_untagged(bytes: &[u8]) -> Result<FrontendMessage, Error> {
+        let mut r = Reader::new(bytes);
+        let length = r.read_i32()?;
+        if length as usize != bytes.len() {
+            return Err(invalid(format!(
+                "Message length {} does not match {} bytes read",
+                length,
+                bytes.len()
+            )));
+        }
+        let message = match r.read_i32()? {
+            CANCEL_REQUEST_CODE => FrontendMessage::CancelRequest {
+                process_id: r.read_i32()?,
+                secret_key: r.read_i32()?,
+            },
+            SSL_REQUEST_CODE => FrontendMessage::SSLRequest,
+            GSSENC_REQUEST_CODE => FrontendMessage::GSSENCRequest,
+            protocol_version => {
+                let mut parameters = Vec::new();
+                loop {
+                    let name = r.read_cstr()?;
+                    if name.is_empty() {
+                        break;
+                    }
+                    parameters.push((name, r.read_cstr()?));
+                }
+                FrontendMessage::StartupMessage {
+                    protocol_version,
+                    parameters,
+                }
+            }
+        };
+        Ok(message)
+    }
+
+    pub fn to_packet(&self) -> Packet {
+        let mut packet = self.encode();
+        packet.set_packet_type(self.get_packet_type());
+        packet
+    }
+
+    fn encode(&self) -> Packet {
+        let mut body: Vec<u8> = Vec::new();
+        let id = match self {
+            FrontendMessage::Bind {
+                portal,
+                statement,
+                param_formats,
+                params,
+                result_formats,
+            } => {
+                write_cstr(&mut body, portal);
+                write_cstr(&mut body, statement);
+                write_i16_array(&mut body, param_formats);
+                body.write_i16::<BigEndian>(params.len() as i16).unwrap();
+                for param in params {
+                    write_nullable_bytes(&mut body, param.as_deref());
+                }
+                write_i16_array(&mut body, result_formats);
+                b'B'
+            }
+            FrontendMessage::CancelRequest {
+                process_id,
+                secret_key,
+            } => {
+                body.write_i32::<BigEndian>(CANCEL_REQUEST_CODE).unwrap();
+                body.write_i32::<BigEndian>(*process_id).unwrap();
+                body.write_i32::<BigEndian>(*secret_key).unwrap();
+                return untagged(&body);
+            }
+            FrontendMessage::Close { target, name } => {
+                body.push(target.to_byte());
+                write_cstr(&mut body, name);
+                b'C'
+            }
+            FrontendMessage::CopyData(data) => {
+                body.extend_from_slice(data);
+                b'd'
+            }
+            FrontendMessage::CopyDone => b'c',
+            FrontendMessage::CopyFail(message) => {
+                write_cstr(&mut body, message);
+                b'f'
+            }
+            FrontendMessage::Describe { target, name } => {
+                body.push(target.to_byte());
+                write_cstr(&mut body, name);
+                b'D'
+            }
+            FrontendMessage::Execute { portal, max_rows } => {
+                write_cstr(&mut body, portal);
+                body.write_i32::<BigEndian>(*max_rows).unwrap();
+                b'E'
+            }
+            FrontendMessage::Flush => b'H',
+            FrontendMessage::FunctionCall {
+                function_oid,
+                arg_formats,
+                args,
+                result_format,
+            } => {
+                body.write_u32::<BigEndian>(*function_oid).unwrap();
+                write_i16_array(&mut body, arg_formats);
+                body.write_i16::<BigEndian>(args.len() as i16).unwrap();
+                for arg in args {
+                    write_nullable_bytes(&mut body, arg.as_deref());
+                }
+                body.write_i16::<BigEndian>(*result_format).unwrap();
+                b'F'
+            }
+            FrontendMessage::GSSENCRequest => {
+                body.write_i32::<BigEndian>(GSSENC_REQUEST_CODE).unwrap();
+                return untagged(&body);
+            }
+            FrontendMessage::AuthenticationResponse(data) => {
+                body.extend_from_slice(data);
+                b'p'
+            }
+            FrontendMessage::Parse {
+                name,
+                query,
+                param_types,
+            } => {
+                write_cstr(&mut body, name);
+                write_cstr(&mut body, query);
+                body.write_i16::<BigEndian>(param_types.len() as i16)
+                    .unwrap();
+                for oid in param_types {
+                    body.write_u32::<BigEndian>(*oid).unwrap();
+                }
+                b'P'
+            }
+            FrontendMessage::Query(query) => {
+                write_cstr(&mut body, query);
+                b'Q'
+            }
+            FrontendMessage::SSLRequest => {
+                body.write_i32::<BigEndian>(SSL_REQUEST_CODE).unwrap();
+                return untagged(&body);
+            }
+            FrontendMessage::StartupMessage {
+                protocol_version,
+                parameters,
+            } => {
+                body.write_i32::<BigEndian>(*protocol_version).unwrap();
+                for (name, value) in parameters {
+                    write_cstr(&mut body, name);
+                    write_cstr(&mut body, value);
+                }
+                body.push(0);
+                return untagged(&body);
+            }
+            FrontendMessage::Sync => b'S',
+            FrontendMessage::Terminate => b'X',
+        };
+        Packet::new_postgres(id, &body)
+    }
+
+    pub fn get_packet_type(&self) -> PacketType {
+        match self {
+            FrontendMessage::Bind { .. } => PacketType::Bind,
+            FrontendMessage::CancelRequest { .. } => PacketType::CancelRequest,
+            FrontendMessage::Close { .. } => PacketType::Close,
+            FrontendMessage::CopyData(_) => PacketType::CopyData,
+            FrontendMessage::CopyDone => PacketType::CopyDone,
+            FrontendMessage::CopyFail(_) => PacketType::CopyFail,
+            FrontendMessage::Describe { .. } => PacketType::Describe,
+            FrontendMessage::Execute { .. } => PacketType::Execute,
+            FrontendMessage::Flush => PacketType::Flush,
+            FrontendMessage::FunctionCall { .. } => PacketType::FunctionCall,
+            FrontendMessage::GSSENCRequest => PacketType::GSSENCRequest,
+            FrontendMessage::AuthenticationResponse(_) => PacketType::AuthenticationResponse,
+            FrontendMessage::Parse { .. } => PacketType::Parse,
+            FrontendMessage::Query(_) => PacketType::Query,
+            FrontendMessage::SSLRequest => PacketType::SSLRequest,
+            FrontendMessage::StartupMessage { .. } => PacketType::StartupMessage,
+            FrontendMessage::Sync => PacketType::Sync,
+            FrontendMessage::Terminate => PacketType::Terminate,
+        }
+    }
+}
+
+/// Authentication request sent by the backend ('R')
+#[derive(Clone, Debug, PartialEq)]
+pub enum Authentication {
+    Ok,
+    KerberosV5,
+    CleartextPassword,
+    MD5Password { salt: [u8; 4] },
+    SCMCredential,
+    GSS,
+    GSSContinue(Vec<u8>),
+    SSPI,
+    SASL { mechanisms: Vec<String> },
+    SASLContinue(Vec<u8>),
+    SASLFinal(Vec<u8>),
+}
+
+impl Authentication {
+    fn parse(r: &mut Reader) -> Result<Authentication, Error> {
+        let auth = match r.read_i32()? {
+            0 => Authentication::Ok,
+            2 => Authentication::KerberosV5,
+            3 => Authentication::CleartextPassword,
+            5 => {
+                let mut salt = [0; 4];
+                salt.copy_from_slice(r.read_bytes(4)?);
+                Authentication::MD5Password { salt }
+            }
+            6 => Authentication::SCMCredential,
+            7 => Authentication::GSS,
+            8 => Authentication::GSSContinue(r.read_rest().to_vec()),
+            9 => Authentication::SSPI,
+            10 => {
+                let mut mechanisms = Vec::new();
+                loop {
+                    let mechanism = r.read_cstr()?;
+                    if mechanism.is_empty() {
+                        break;
+                    }
+                    mechanisms.push(mechanism);
+                }
+                Authentication::SASL { mechanisms }
+            }
+            11 => Authentication::SASLContinue(r.read_rest().to_vec()),
+            12 => Authentication::SASLFinal(r.read_rest().to_vec()),
+            code => return Err(invalid(format!("Invalid authentication request {}", code))),
+        };
+        Ok(auth)
+    }
+
+    fn write(&self, body: &mut Vec<u8>) {
+        let (code, data): (i32, &[u8]) = match self {
+            Authentication::Ok => (0, &[]),
+            Authentication::KerberosV5 => (2, &[]),
+            Authentication::CleartextPassword => (3, &[]),
+            Authentication::MD5Password { salt } => (5, salt),
+            Authentication::SCMCredential => (6, &[]),
+            Authentication::GSS => (7, &[]),
+            Authentication::GSSContinue(data) => (8, data),
+            Authentication::SSPI => (9, &[]),
+            Authentication::SASL { mechanisms } => {
+                body.write_i32::<BigEndian>(10).unwrap();
+                for mechanism in mechanisms {
+                    write_cstr(body, mechanism);
+                }
+                body.push(0);
+                return;
+            }
+            Authentication::SASLContinue(data) => (11, data),
+            Authentication::SASLFinal(data) => (12, data),
+        };
+        body.write_i32::<BigEndian>(code).unwrap();
+        body.extend_from_slice(data);
+    }
+
+    pub fn get_packet_type(&self) -> PacketType {
+        match self {
+            Authentication::Ok => PacketType::AuthenticationOk,
+            Authentication::KerberosV5 => PacketType::AuthenticationKerberosV5,
+            Authentication::CleartextPassword => PacketType::AuthenticationCleartextPassword,
+            Authentication::MD5Password { .. } => PacketType::AuthenticationMD5Password,
+            Authentication::SCMCredential => PacketType::AuthenticationSCMCredential,
+            Authentication::GSS => PacketType::AuthenticationGSS,
+            Authentication::GSSContinue(_) => PacketType::AuthenticationGSSContinue,
+            Authentication::SSPI => PacketType::AuthenticationSSPI,
+            Authentication::SASL { .. } => PacketType::AuthenticationSASL,
+            Authentication::SASLContinue(_) => PacketType::AuthenticationSASLContinue,
+            Authentication::SASLFinal(_) => PacketType::AuthenticationSASLFinal,
+        }
+    }
+}
+
+/// The body of a frontend 'p' message, decoded using the authentication request it answers
+#[derive(Clone, Debug, PartialEq)]
+pub enum AuthenticationResponse {
+    GSSResponse(Vec<u8>),
+    PasswordMessage(Vec<u8>),
+    SASLInitialResponse {
+        mechanism: String,
+        data: Option<Vec<u8>>,
+    },
+    SASLResponse(Vec<u8>),
+}
+
+impl AuthenticationResponse {
+    pub fn parse(data: &[u8], request: &Authentication) -> Result<AuthenticationResponse, Error> {
+        let mut r = Reader::new(data);
+        let response = match request {
+            Authentication::GSS | Authentication::SSPI | Authentication::GSSContinue(_) => {
+                AuthenticationResponse::GSSResponse(data.to_vec())
+            }
+            Authentication::CleartextPassword | Authentication::MD5Password { .. } => {
+                AuthenticationResponse::PasswordMessage(r.read_cstr_bytes()?.to_vec())
+            }
+            Authentication::SASL { .. } => AuthenticationResponse::SASLInitialResponse {
+                mechanism: r.read_cstr()?,
+                data: r.read_nullable_bytes()?.map(|v| v.to_vec()),
+            },
+            Authentication::SASLContinue(_) => AuthenticationResponse::SASLResponse(data.to_vec()),
+            _ => {
+                return Err(invalid(format!(
+                    "No authentication response expected for {:?}",
+                    request
+                )))
+            }
+        };
+        Ok(response)
+    }
+
+    pub fn to_message(&self) -> FrontendMessage {
+        let mut body: Vec<u8> = Vec::new();
+        match self {
+            AuthenticationResponse::GSSResponse(data)
+            | AuthenticationResponse::SASLResponse(data) => body.extend_from_slice(data),
+            AuthenticationResponse::PasswordMessage(password) => {
+                body.extend_from_slice(password);
+                body.push(0);
+            }
+            AuthenticationResponse::SASLInitialResponse { mechanism, data } => {
+                write_cstr(&mut body, mechanism);
+                write_nullable_bytes(&mut body, data.as_deref());
+            }
+        }
+        FrontendMessage::AuthenticationResponse(body)
+    }
+
+    pub fn get_packet_type(&self) -> PacketType {
+        match self {
+            AuthenticationResponse::GSSResponse(_) => PacketType::GSSResponse,
+            AuthenticationResponse::PasswordMessage(_) => PacketType::PasswordMessage,
+            AuthenticationResponse::SASLInitialResponse { .. } => PacketType::SASLInitialResponse,
+            AuthenticationResponse::SASLResponse(_) => PacketType::SASLResponse,
+        }
+    }
+}
+
+/// Overall and per-column format of a COPY, sent in CopyIn/CopyOut/CopyBothResponse
+#[derive(Clone, Debug, PartialEq)]
+pub struct CopyResponse {
+    /// 0 for text, 1 for binary
+    pub format: u8,
+    pub column_formats: Vec<i16>,
+}
+
+impl CopyResponse {
+    fn parse(r: &mut Reader) -> Result<CopyResponse, Error> {
+        Ok(CopyResponse {
+            format: r.read_u8()?,
+            column_formats: read_i16_array(r)?,
+        })
+    }
+
+    fn write(&self, body: &mut Vec<u8>) {
+        body.push(self.format);
+        write_i16_array(body, &self.column_formats);
+    }
+}
+
+/// A single field of a RowDescription
+#[derive(Clone, Debug, PartialEq)]
+pub struct FieldDescription {
+    pub name: String,
+    /// OID of the source table, or 0
+    pub table_oid: u32,
+    /// Attribute number of the source column, or 0
+    pub column_id: i16,
+    pub type_oid: u32,
+    pub type_size: i16,
+    pub type_modifier: i32,
+    /// 0 for text, 1 for binary
+    pub format: i16,
+}
+
+impl FieldDescription {
+    /// A text format field that is not a table column, like a computed value
+    pub fn new(name: &str, type_oid: u32) -> FieldDescription {
+        FieldDescription {
+            name: name.to_string(),
+            table_oid: 0,
+            column_id: 0,
+            type_oid,
+            type_size: type_size(type_oid),
+            type_modifier: -1,
+            format: TEXT_FORMAT,
+        }
+    }
+}
+
+/// Messages sent by the server
+#[derive(Clone, Debug, PartialEq)]
+pub enum BackendMessage {
+    Authentication(Authentication),
+    BackendKeyData {
+        process_id: i32,
+        secret_key: i32,
+    },
+    BindComplete,
+    CloseComplete,
+    CommandComplete(String),
+    CopyData(Vec<u8>),
+    CopyDone,
+    CopyInResponse(CopyResponse),
+    CopyOutResponse(CopyResponse),
+    CopyBothResponse(CopyResponse),
+    DataRow(Vec<Option<Vec<u8>>>),
+    EmptyQueryResponse,
+    /// Fields identified by their type byte
+    /// https://www.postgresql.org/docs/12/protocol-error-fields.html
+    ErrorResponse(Vec<(u8, String)>),
+    FunctionCallResponse(Option<Vec<u8>>),
+    NegotiateProtocolVersion {
+        minor_version: i32,
+        options: Vec<String>,
+    },
+    NoData,
+    NoticeResponse(Vec<(u8, String)>),
+    NotificationResponse {
+        process_id: i32,
+        channel: String,
+        payload: String,
+    },
+    ParameterDescription(Vec<u32>),
+    ParameterStatus {
+        name: String,
+        value: String,
+    },
+    ParseComplete,
+    PortalSuspended,
+    /// Transaction status: 'I' idle, 'T' in a transaction block, 'E' in a failed transaction
+    ReadyForQuery(u8),
+    RowDescription(Vec<FieldDescription>),
+}
+
+impl BackendMessage {
+    pub fn parse(packet: &Packet) -> Result<BackendMessage, Error> {
+        check_db_type(packet)?;
+        let id = match packet.bytes.first() {
+            Some(id) => *id,
+            None => return Err(Error::new(ErrorKind::UnexpectedEof, "Empty message")),
+        };
+        let mut r = Reader::new(body(packet)?);
+        let message = match id {
+            b'R' => BackendMessage::Authentication(Authentication::parse(&mut r)?),
+            b'K' => BackendMessage::BackendKeyData {
+                process_id: r.read_i32()?,
+                secret_key: r.read_i32()?,
+            },
+            b'2' => BackendMessage::BindComplete,
+            b'3' => BackendMessage::CloseComplete,
+            b'C' => BackendMessage::CommandComplete(r.read_cstr()?),
+            b'd' => BackendMessage::CopyData(r.read_rest().to_vec()),
+            b'c' => BackendMessage::CopyDone,
+            b'G' => BackendMessage::CopyInResponse(CopyResponse::parse(&mut r)?),
+            b'H' => BackendMessage::CopyOutResponse(CopyResponse::parse(&mut r)?),
+            b'W' => BackendMessage::CopyBothResponse(CopyResponse::parse(&mut r)?),
+            b'D' => {
+                let n = r.read_i16()?;
+                let mut values = Vec::with_capacity(n.max(0) as usize);
+                for _ in 0..n {
+                    values.push(r.read_nullable_bytes()?.map(|v| v.to_vec()));
+                }
+                BackendMessage::DataRow(values)
+            }
+            b'I' => BackendMessage::EmptyQueryResponse,
+            b'E' => BackendMessage::ErrorResponse(read_fields(&mut r)?),
+            b'V' => {
+                BackendMessage::FunctionCallResponse(r.read_nullable_bytes()?.map(|v| v.to_vec()))
+            }
+            b'v' => {
+                let minor_version = r.read_i32()?;
+                let n = r.read_i32()?;
+                let mut options = Vec::with_capacity(n.max(0) as usize);
+                for _ in 0..n {
+                    options.push(r.read_cstr()?);
+                }
+                BackendMessage::NegotiateProtocolVersion {
+                    minor_version,
+                    options,
+                }
+            }
+            b'n' => BackendMessage::NoData,
+            b'N' => BackendMessage::NoticeResponse(read_fields(&mut r)?),
+            b'A' => BackendMessage::NotificationResponse {
+                process_id: r.read_i32()?,
+                channel: r.read_cstr()?,
+                payload: r.read_cstr()?,
+            },
+            b't' => {
+                let n = r.read_i16()?;
+                let mut types = Vec::with_capacity(n.max(0) as usize);
+                for _ in 0..n {
+                    types.push(r.read_u32()?);
+                }
+                BackendMessage::ParameterDescription(types)
+            }
+            b'S' => BackendMessage::ParameterStatus {
+                name: r.read_cstr()?,
+                value: r.read_cstr()?,
+            },
+            b'1' => BackendMessage::ParseComplete,
+            b's' => BackendMessage::PortalSuspended,
+            b'Z' => BackendMessage::ReadyForQuery(r.read_u8()?),
+            b'T' => {
+                let n = r.read_i16()?;
+                let mut fields = Vec::with_capacity(n.max(0) as usize);
+                for _ in 0..n {
+                    fields.push(FieldDescription {
+                        name: r.read_cstr()?,
+                        table_oid: r.read_u32()?,
+                        column_id: r.read_i16()?,
+                        type_oid: r.read_u32()?,
+                        type_size: r.read_i16()?,
+                        type_modifier: r.read_i32()?,
+                        format: r.read_i16()?,
+                    });
+                }
+                BackendMessage::RowDescription(fields)
+            }
+            b => return Err(invalid(format!("Invalid backend message type {:#04x}", b))),
+        };
+        Ok(message)
+    }
+
+    pub fn to_packet(&self) -> Packet {
+        let mut packet = self.encode();
+        packet.set_packet_type(self.get_packet_type());
+        packet
+    }
+
+    fn encode(&self) -> Packet {
+        let mut body: Vec<u8> = Vec::new();
+        let id = match self {
+            BackendMessage::Authentication(auth) => {
+                auth.write(&mut body);
+                b'R'
+            }
+            BackendMessage::BackendKeyData {
+                process_id,
+                secret_key,
+            } => {
+                body.write_i32::<BigEndian>(*process_id).unwrap();
+                body.write_i32::<BigEndian>(*secret_key).unwrap();
+                b'K'
+            }
+            BackendMessage::BindComplete => b'2',
+            BackendMessage::CloseComplete => b'3',
+            BackendMessage::CommandComplete(tag) => {
+                write_cstr(&mut body, tag);
+                b'C'
+            }
+            BackendMessage::CopyData(data) => {
+                body.extend_from_slice(data);
+                b'd'
+            }
+            BackendMessage::CopyDone => b'c',
+            BackendMessage::CopyInResponse(response) => {
+                response.write(&mut body);
+                b'G'
+            }
+            BackendMessage::CopyOutResponse(response) => {
+                response.write(&mut body);
+                b'H'
+            }
+            BackendMessage::CopyBothResponse(response) => {
+                response.write(&mut body);
+                b'W'
+            }
+            BackendMessage::DataRow(values) => {
+                body.write_i16::<BigEndian>(values.len() as i16).unwrap();
+                for value in values {
+                    write_nullable_bytes(&mut body, value.as_deref());
+                }
+                b'D'
+            }
+            BackendMessage::EmptyQueryResponse => b'I',
+            BackendMessage::ErrorResponse(fields) => {
+                write_fields(&mut body, fields);
+                b'E'
+            }
+            BackendMessage::FunctionCallResponse(value) => {
+                write_nullable_bytes(&mut body, value.as_deref());
+                b'V'
+            }
+            BackendMessage::NegotiateProtocolVersion {
+                minor_version,
+                options,
+            } => {
+                body.write_i32::<BigEndian>(*minor_version).unwrap();
+                body.write_i32::<BigEndian>(options.len() as i32).unwrap();
+                for option in options {
+                    write_cstr(&mut body, option);
+                }
+                b'v'
+            }
+            BackendMessage::NoData => b'n',
+            BackendMessage::NoticeResponse(fields) => {
+                write_fields(&mut body, fields);
+                b'N'
+            }
+            BackendMessage::NotificationResponse {
+                process_id,
+                channel,
+                payload,
+            } => {
+                body.write_i32::<BigEndian>(*process_id).unwrap();
+                write_cstr(&mut body, channel);
+                write_cstr(&mut body, payload);
+                b'A'
+            }
+            BackendMessage::ParameterDescription(types) => {
+                body.write_i16::<BigEndian>(types.len() as i16).unwrap();
+                for oid in types {
+                    body.write_u32::<BigEndian>(*oid).unwrap();
+                }
+                b't'
+            }
+            BackendMessage::ParameterStatus { name, value } => {
+                write_cstr(&mut body, name);
+                write_cstr(&mut body, value);
+                b'S'
+            }
+            BackendMessage::ParseComplete => b'1',
+            BackendMessage::PortalSuspended => b's',
+            BackendMessage::ReadyForQuery(status) => {
+                body.push(*status);
+                b'Z'
+            }
+            BackendMessage::RowDescription(fields) => {
+                body.write_i16::<BigEndian>(fields.len() as i16).unwrap();
+                for field in fields {
+                    write_cstr(&mut body, &field.name);
+                    body.write_u32::<BigEndian>(field.table_oid).unwrap();
+                    body.write_i16::<BigEndian>(field.column_id).unwrap();
+                    body.write_u32::<BigEndian>(field.type_oid).unwrap();
+                    body.write_i16::<BigEndian>(field.type_size).unwrap();
+                    body.write_i32::<BigEndian>(field.type_modifier).unwrap();
+                    body.write_i16::<BigEndian>(field.format).unwrap();
+                }
+                b'T'
+            }
+        };
+        Packet::new_postgres(id, &body)
+    }
+
+    pub fn get_packet_type(&self) -> PacketType {
+        match self {
+            BackendMessage::Authentication(auth) => auth.get_packet_type(),
+            BackendMessage::BackendKeyData { .. } => PacketType::BackendKeyData,
+            BackendMessage::BindComplete => PacketType::BindComplete,
+            BackendMessage::CloseComplete => PacketType::CloseComplete,
+            BackendMessage::CommandComplete(_) => PacketType::CommandComplete,
+            BackendMessage::CopyData(_) => PacketType::CopyData,
+            BackendMessage::CopyDone => PacketType::CopyDone,
+            BackendMessage::CopyInResponse(_) => PacketType::CopyInResponse,
+            BackendMessage::CopyOutResponse(_) => PacketType::CopyOutResponse,
+            BackendMessage::CopyBothResponse(_) => PacketType::CopyBothResponse,
+            BackendMessage::DataRow(_) => PacketType::DataRow,
+            BackendMessage::EmptyQueryResponse => PacketType::EmptyQueryResponse,
+            BackendMessage::ErrorResponse(_) => PacketType::ErrorResponse,
+            BackendMessage::FunctionCallResponse(_) => PacketType::FunctionCallResponse,
+            BackendMessage::NegotiateProtocolVersion { .. } => PacketType::NegotiateProtocolVersion,
+            BackendMessage::NoData => PacketType::NoData,
+            BackendMessage::NoticeResponse(_) => PacketType::NoticeResponse,
+            BackendMessage::NotificationResponse { .. } => PacketType::NotificationResponse,
+            BackendMessage::ParameterDescription(_) => PacketType::ParameterDescription,
+            BackendMessage::ParameterStatus { .. } => PacketType::ParameterStatus,
+            BackendMessage::ParseComplete => PacketType::ParseComplete,
+            BackendMessage::PortalSuspended => PacketType::PortalSuspended,
+            BackendMessage::ReadyForQuery(_) => PacketType::ReadyForQuery,
+            BackendMessage::RowDescription(_) => PacketType::RowDescription,
+        }
+    }
+}
+
+fn check_db_type(packet: &Packet) -> Result<(), Error> {
+    if packet.get_db_type() != DatabaseType::PostgresSQL {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Expected a PostgresSQL packet",
+        ));
+    }
+    Ok(())
+}
+
+/// The message body after the type byte and length, checking the length matches
+fn body(packet: &Packet) -> Result<&[u8], Error> {
+    let mut r = Reader::new(&packet.bytes);
+    r.read_u8()?;
+    let length = r.read_i32()?;
+    if length < 4 || length as usize != r.remaining() + 4 {
+        return Err(invalid(format!(
+            "Message length {} does not match {} bytes read",
+            length,
+            packet.bytes.len()
+        )));
+    }
+    Ok(r.read_rest())
+}
+
+fn untagged(body: &[u8]) -> Packet {
+    let mut bytes: Vec<u8> = Vec::with_capacity(4 + body.len());
+    bytes.write_i32::<BigEndian>(4 + body.len() as i32).unwrap();
+    bytes.extend_from_slice(body);
+    Packet::new(DatabaseType::PostgresSQL, bytes)
+}
+
+fn read_i16_array(r: &mut Reader) -> Result<Vec<i16>, Error> {
+    let n = r.read_i16()?;
+    let mut values = Vec::with_capacity(n.max(0) as usize);
+    for _ in 0..n {
+        values.push(r.read_i16()?);
+    }
+    Ok(values)
+}
+
+fn write_i16_array(body: &mut Vec<u8>, values: &[i16]) {
+    body.write_i16::<BigEndian>(values.len() as i16).unwrap();
+    for value in values {
+        body.write_i16::<BigEndian>(*value).unwrap();
+    }
+}
+
+fn write_nullable_bytes(body: &mut Vec<u8>, value: Option<&[u8]>) {
+    match value {
+        Some(value) => {
+            body.write_i32::<BigEndian>(value.len() as i32).unwrap();
+            body.extend_from_slice(value);
+        }
+        None => body.write_i32::<BigEndian>(-1).unwrap(),
+    }
+}
+
+fn read_fields(r: &mut Reader) -> Result<Vec<(u8, String)>, Error> {
+    let mut fields = Vec::new();
+    loop {
+        let code = r.read_u8()?;
+        if code == 0 {
+            break;
+        }
+        let value = String::from_utf8_lossy(r.read_cstr_bytes()?).into_owned();
+        fields.push((code, value));
+    }
+    Ok(fields)
+}
+
+fn write_fields(body: &mut Vec<u8>, fields: &[(u8, String)]) {
+    for (code, value) in fields {
+        body.push(*code);
+        write_cstr(body, value);
+    }
+    body.push(0);
+}
+
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::{collection::vec, option, prelude::*};
+
+    /// Strings that can be sent null-terminated
+    fn cstr() -> impl Strategy<Value = String> {
+        "[^\u{0}]{0,16}"
+    }
+
+    fn nullable_bytes() -> impl Strategy<Value = Vec<Option<Vec<u8>>>> {
+        vec(option::of(vec(any::<u8>(), 0..16)), 0..8)
+    }
+
+    proptest! {
+        #[test]
+        fn frontend_messages_round_trip(
+            name in cstr(),
+            query in cstr(),
+            param_types in vec(any::<u32>(), 0..8),
+            param_formats in vec(0_i16..2, 0..8),
+            params in nullable_bytes(),
+            max_rows in any::<i32>(),
+        ) {
+            let messages = vec![
+                FrontendMessage::Parse {
+                    name: name.clone(),
+                    query: query.clone(),
+                    param_types,
+                },
+                FrontendMessage::Bind {
+                    portal: name.clone(),
+                    statement: name.clone(),
+                    param_formats: param_formats.clone(),
+                    params,
+                    result_formats: param_formats,
+                },
+                FrontendMessage::Execute { portal: name, max_rows },
+                FrontendMessage::Query(query),
+            ];
+            for message in messages {
+                prop_assert_eq!(FrontendMessage::parse(&message.to_packet()).unwrap(), message);
+            }
+        }
+
+        #[test]
+        fn backend_messages_round_trip(
+            name in cstr(),
+            value in cstr(),
+            values in nullable_bytes(),
+            fields in vec((1_u8.., cstr()), 0..4),
+        ) {
+            let messages = vec![
+                BackendMessage::ParameterStatus { name: name.clone(), value },
+                BackendMessage::CommandComplete(name),
+                BackendMessage::DataRow(values),
+                BackendMessage::ErrorResponse(fields),
+            ];
+            for message in messages {
+                prop_assert_eq!(BackendMessage::parse(&message.to_packet()).unwrap(), message);
+            }
+        }
+    }
+
+    #[test]
+    fn direction_decides_decoding() {
+        // An Execute on a portal whose name starts with 'S' looks like an ErrorResponse
+        let execute = FrontendMessage::Execute {
+            portal: "S_1".to_string(),
+            max_rows: 0,
+        };
+        let packet = execute.to_packet();
+        assert_eq!(FrontendMessage::parse(&packet).unwrap(), execute);
+        assert_eq!(
+            BackendMessage::parse(&packet).unwrap().get_packet_type(),
+            PacketType::ErrorResponse
+        );
+
+        let complete = BackendMessage::CommandComplete("INSERT 0 1".to_string());
+        let packet = complete.to_packet();
+        assert_eq!(BackendMessage::parse(&packet).unwrap(), complete);
+        assert!(FrontendMessage::parse(&packet).is_err());
+    }
+
+    #[test]
+    fn frontend_round_trip() {
+        let messages = vec![
+            FrontendMessage::StartupMessage {
+                protocol_version: PROTOCOL_VERSION_3,
+                parameters: vec![
+                    ("user".to_string(), "root".to_string()),
+                    ("database".to_string(), "testdb".to_string()),
+                ],
+            },
+            FrontendMessage::SSLRequest,
+            FrontendMessage::CancelRequest {
+                process_id: 42,
+                secret_key: -7,
+            },
+            FrontendMessage::Parse {
+                name: "s1".to_string(),
+                query: "SELECT $1::int".to_string(),
+                param_types: vec![23],
+            },
+            FrontendMessage::Bind {
+                portal: "".to_string(),
+                statement: "s1".to_string(),
+                param_formats: vec![1],
+                params: vec![Some(vec![0, 0, 0, 1]), None],
+                result_formats: vec![],
+            },
+            FrontendMessage::Describe {
+                target: Target::Portal,
+                name: "".to_string(),
+            },
+            FrontendMessage::Close {
+                target: Target::Statement,
+                name: "s1".to_string(),
+            },
+            FrontendMessage::Query("SELECT 1".to_string()),
+            FrontendMessage::CopyFail("aborted".to_string()),
+            FrontendMessage::Flush,
+            FrontendMessage::Sync,
+            FrontendMessage::Terminate,
+        ];
+        for message in messages {
+            assert_eq!(
+                FrontendMessage::parse(&message.to_packet()).unwrap(),
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn backend_round_trip() {
+        let messages = vec![
+            BackendMessage::Authentication(Authentication::MD5Password { salt: [1, 2, 3, 4] }),
+            BackendMessage::Authentication(Authentication::SASL {
+                mechanisms: vec!["SCRAM-SHA-256".to_string()],
+            }),
+            BackendMessage::ParameterStatus {
+                name: "client_encoding".to_string(),
+                value: "UTF8".to_string(),
+            },
+            BackendMessage::RowDescription(vec![FieldDescription {
+                name: "id".to_string(),
+                table_oid: 16384,
+                column_id: 1,
+                type_oid: 23,
+                type_size: 4,
+                type_modifier: -1,
+                format: 0,
+            }]),
+            BackendMessage::DataRow(vec![Some(b"1".to_vec()), None]),
+            BackendMessage::CopyOutResponse(CopyResponse {
+                format: 0,
+                column_formats: vec![0, 0],
+            }),
+            BackendMessage::ErrorResponse(vec![
+                (b'S', "ERROR".to_string()),
+                (b'C', "42P01".to_string()),
+                (b'M', "relation does not exist".to_string()),
+            ]),
+            BackendMessage::ReadyForQuery(b'I'),
+        ];
+        for message in messages {
+            assert_eq!(
+                BackendMessage::parse(&message.to_packet()).unwrap(),
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn authentication_response_depends_on_request() {
+        let data = b"SCRAM-SHA-256\x00\x00\x00\x00\x03abc";
+        let request = Authentication::SASL {
+            mechanisms: vec!["SCRAM-SHA-256".to_string()],
+        };
+        let response = AuthenticationResponse::parse(data, &request).unwrap();
+        assert_eq!(
+            response,
+            AuthenticationResponse::SASLInitialResponse {
+                mechanism: "SCRAM-SHA-256".to_string(),
+                data: Some(b"abc".to_vec()),
+            }
+        );
+        assert_eq!(
+            response.to_message(),
+            FrontendMessage::AuthenticationResponse(data.to_vec())
+        );
+    }
+}
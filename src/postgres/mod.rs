@@ -0,0 +1,115 @@
+//! PostgresSQL frontend/backend protocol
+//! For reference, see https://www.postgresql.org/docs/12/protocol.html
+
+use byteorder::{BigEndian, ByteOrder};
+use std::io::{Error, ErrorKind};
+
+pub mod charset;
+pub mod copy;
+pub mod decoder;
+pub mod error;
+pub mod message;
+pub mod replication;
+pub mod resultset;
+pub mod startup;
+pub mod statement;
+pub mod value;
+
+/// Cursor over a PostgresSQL message body that reads the protocol's primitive data types
+/// https://www.postgresql.org/docs/12/protocol-message-types.html
+#[derive(Clone, Debug)]
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader { buf, pos: 0 }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, Error> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    pub fn read_i16(&mut self) -> Result<i16, Error> {
+        Ok(BigEndian::read_i16(self.read_bytes(2)?))
+    }
+
+    pub fn read_i32(&mut self) -> Result<i32, Error> {
+        Ok(BigEndian::read_i32(self.read_bytes(4)?))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, Error> {
+        Ok(BigEndian::read_u32(self.read_bytes(4)?))
+    }
+
+    pub fn read_i64(&mut self) -> Result<i64, Error> {
+        Ok(BigEndian::read_i64(self.read_bytes(8)?))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, Error> {
+        Ok(BigEndian::read_u64(self.read_bytes(8)?))
+    }
+
+    pub fn read_bytes(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.remaining() < n {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("Message too short reading {} bytes", n),
+            ));
+        }
+        let bytes = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    pub fn read_rest(&mut self) -> &'a [u8] {
+        let bytes = &self.buf[self.pos..];
+        self.pos = self.buf.len();
+        bytes
+    }
+
+    /// Null-terminated string, the terminator is consumed but not returned
+    pub fn read_cstr_bytes(&mut self) -> Result<&'a [u8], Error> {
+        match self.buf[self.pos..].iter().position(|b| *b == 0) {
+            Some(n) => {
+                let bytes = &self.buf[self.pos..self.pos + n];
+                self.pos += n + 1;
+                Ok(bytes)
+            }
+            None => Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Message too short reading a null-terminated string",
+            )),
+        }
+    }
+
+    pub fn read_cstr(&mut self) -> Result<String, Error> {
+        let bytes = self.read_cstr_bytes()?;
+        String::from_utf8(bytes.to_vec()).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+
+    /// Int32 length followed by that many bytes, where a length of -1 means NULL
+    pub fn read_nullable_bytes(&mut self) -> Result<Option<&'a [u8]>, Error> {
+        let n = self.read_i32()?;
+        if n < 0 {
+            Ok(None)
+        } else {
+            Ok(Some(self.read_bytes(n as usize)?))
+        }
+    }
+}
+
+pub fn write_cstr(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(s.as_bytes());
+    buf.push(0);
+}
@@ -0,0 +1,781 @@
+//! Streaming replication: the messages carried by CopyData once START_REPLICATION switched
+//! the connection to CopyBoth mode, and the changes decoded by the pgoutput plugin of logical
+//! replication
+//! https://www.postgresql.org/docs/12/protocol-replication.html
+//! https://www.postgresql.org/docs/12/protocol-logicalrep-message-formats.html
+
+use byteorder::{BigEndian, WriteBytesExt};
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind},
+};
+
+use super::{
+    message::{BackendMessage, FrontendMessage},
+    value::{Value, BINARY_FORMAT, TEXT_FORMAT},
+    Reader,
+};
+use crate::packet::Packet;
+
+/// Format a WAL position the way the server does, like `0/16B3748`
+pub fn format_lsn(lsn: u64) -> String {
+    format!("{:X}/{:X}", lsn >> 32, lsn & 0xffff_ffff)
+}
+
+/// Parse a WAL position written like `0/16B3748`
+pub fn parse_lsn(s: &str) -> Option<u64> {
+    let mut parts = s.splitn(2, '/');
+    let high = u64::from_str_radix(parts.next()?, 16).ok()?;
+    let low = u64::from_str_radix(parts.next()?, 16).ok()?;
+    if high > 0xffff_ffff || low > 0xffff_ffff {
+        return None;
+    }
+    Some(high << 32 | low)
+}
+
+/// A START_REPLICATION command, sent as a simple Query on a replication connection
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StartReplication {
+    pub slot: Option<String>,
+    pub logical: bool,
+    pub lsn: u64,
+    /// Options passed to the output plugin of a logical slot
+    pub options: Vec<(String, String)>,
+}
+
+impl StartReplication {
+    /// Parse `START_REPLICATION [SLOT name] [PHYSICAL] lsn [TIMELINE tli]` or
+    /// `START_REPLICATION SLOT name LOGICAL lsn [(option 'value', ...)]`. Returns None for
+    /// any other command.
+    pub fn parse(sql: &str) -> Option<StartReplication> {
+        let sql = sql.trim().trim_end_matches(';');
+        let (command, options) = match sql.find('(') {
+            Some(n) => (&sql[..n], Some(sql[n + 1..].trim_end().strip_suffix(')')?)),
+            None => (sql, None),
+        };
+        let mut words = command.split_whitespace();
+        if !words.next()?.eq_ignore_ascii_case("start_replication") {
+            return None;
+        }
+        let mut word = words.next()?;
+        let mut slot = None;
+        if word.eq_ignore_ascii_case("slot") {
+            slot = Some(words.next()?.trim_matches('"').to_string());
+            word = words.next()?;
+        }
+        let logical = word.eq_ignore_ascii_case("logical");
+        if logical || word.eq_ignore_ascii_case("physical") {
+            word = words.next()?;
+        }
+        let lsn = parse_lsn(word)?;
+        let options = options
+            .unwrap_or("")
+            .split(',')
+            .filter_map(|option| {
+                let option = option.trim();
+                let (name, value) = match option.find(char::is_whitespace) {
+                    Some(n) => (&option[..n], option[n..].trim()),
+                    None => (option, ""),
+                };
+                if name.is_empty() {
+                    return None;
+                }
+                Some((
+                    name.trim_matches('"').to_string(),
+                    value.trim_matches('\'').replace("''", "'"),
+                ))
+            })
+            .collect();
+        Some(StartReplication {
+            slot,
+            logical,
+            lsn,
+            options,
+        })
+    }
+
+    pub fn get_option(&self, name: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Options only the pgoutput plugin takes were given
+    pub fn is_pgoutput(&self) -> bool {
+        self.logical && self.get_option("proto_version").is_some()
+    }
+}
+
+/// Messages carried by CopyData during streaming replication. Times are microseconds since
+/// 2000-01-01 00:00:00 UTC.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReplicationMessage {
+    /// WAL data sent by the server, pgoutput messages for logical replication
+    XLogData {
+        start: u64,
+        end: u64,
+        time: i64,
+        data: Vec<u8>,
+    },
+    /// Sent by the server, asking for a status update when `reply` is set
+    PrimaryKeepalive { end: u64, time: i64, reply: bool },
+    /// Positions the client has written, flushed and applied
+    StandbyStatusUpdate {
+        written: u64,
+        flushed: u64,
+        applied: u64,
+        time: i64,
+        reply: bool,
+    },
+    HotStandbyFeedback {
+        time: i64,
+        xmin: u32,
+        epoch: u32,
+        catalog_xmin: u32,
+        catalog_epoch: u32,
+    },
+}
+
+impl ReplicationMessage {
+    pub fn parse(packet: &Packet) -> Result<ReplicationMessage, Error> {
+        if packet.bytes.first() != Some(&b'd') {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Packet is not a CopyData message",
+            ));
+        }
+        let mut r = Reader::new(packet.get_payload()?);
+        let message = match r.read_u8()? {
+            b'w' => ReplicationMessage::XLogData {
+                start: r.read_u64()?,
+                end: r.read_u64()?,
+                time: r.read_i64()?,
+                data: r.read_rest().to_vec(),
+            },
+            b'k' => ReplicationMessage::PrimaryKeepalive {
+                end: r.read_u64()?,
+                time: r.read_i64()?,
+                reply: r.read_u8()? != 0,
+            },
+            b'r' => ReplicationMessage::StandbyStatusUpdate {
+                written: r.read_u64()?,
+                flushed: r.read_u64()?,
+                applied: r.read_u64()?,
+                time: r.read_i64()?,
+                reply: r.read_u8()? != 0,
+            },
+            b'h' => ReplicationMessage::HotStandbyFeedback {
+                time: r.read_i64()?,
+                xmin: r.read_u32()?,
+                epoch: r.read_u32()?,
+                catalog_xmin: r.read_u32()?,
+                catalog_epoch: r.read_u32()?,
+            },
+            b => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Invalid replication message type {:#04x}", b),
+                ))
+            }
+        };
+        Ok(message)
+    }
+
+    /// CopyData carrying the message, from the server or the client depending on its type
+    pub fn to_packet(&self) -> Packet {
+        let mut data: Vec<u8> = Vec::new();
+        match self {
+            ReplicationMessage::XLogData {
+                start,
+                end,
+                time,
+                data: wal,
+            } => {
+                data.push(b'w');
+                data.write_u64::<BigEndian>(*start).unwrap();
+                data.write_u64::<BigEndian>(*end).unwrap();
+                data.write_i64::<BigEndian>(*time).unwrap();
+                data.extend_from_slice(wal);
+            }
+            ReplicationMessage::PrimaryKeepalive { end, time, reply } => {
+                data.push(b'k');
+                data.write_u64::<BigEndian>(*end).unwrap();
+                data.write_i64::<BigEndian>(*time).unwrap();
+                data.push(*reply as u8);
+            }
+            ReplicationMessage::StandbyStatusUpdate {
+                written,
+                flushed,
+                applied,
+                time,
+                reply,
+            } => {
+                data.push(b'r');
+                data.write_u64::<BigEndian>(*written).unwrap();
+                data.write_u64::<BigEndian>(*flushed).unwrap();
+                data.write_u64::<BigEndian>(*applied).unwrap();
+                data.write_i64::<BigEndian>(*time).unwrap();
+                data.push(*reply as u8);
+            }
+            ReplicationMessage::HotStandbyFeedback {
+                time,
+                xmin,
+                epoch,
+                catalog_xmin,
+                catalog_epoch,
+            } => {
+                data.push(b'h');
+                data.write_i64::<BigEndian>(*time).unwrap();
+                data.write_u32::<BigEndian>(*xmin).unwrap();
+                data.write_u32::<BigEndian>(*epoch).unwrap();
+                data.write_u32::<BigEndian>(*catalog_xmin).unwrap();
+                data.write_u32::<BigEndian>(*catalog_epoch).unwrap();
+            }
+        }
+        match self {
+            ReplicationMessage::XLogData { .. } | ReplicationMessage::PrimaryKeepalive { .. } => {
+                BackendMessage::CopyData(data).to_packet()
+            }
+            _ => FrontendMessage::CopyData(data).to_packet(),
+        }
+    }
+}
+
+/// A column of a table, as described by a Relation message
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RelationColumn {
+    /// Part of the replica identity, usually the primary key
+    pub key: bool,
+    pub name: String,
+    pub type_oid: u32,
+    pub type_modifier: i32,
+}
+
+/// A table, described by the server before the first change to it in the stream
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Relation {
+    pub oid: u32,
+    pub namespace: String,
+    pub name: String,
+    /// 'd' default, 'n' nothing, 'f' all columns, 'i' index
+    pub replica_identity: u8,
+    pub columns: Vec<RelationColumn>,
+}
+
+/// A column value of a row sent by pgoutput
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TupleValue {
+    Null,
+    /// A TOASTed value that did not change, and is not sent
+    Unchanged,
+    Text(Vec<u8>),
+    Binary(Vec<u8>),
+}
+
+/// Messages of the pgoutput plugin, carried by XLogData. Times are microseconds since
+/// 2000-01-01 00:00:00 UTC.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LogicalMessage {
+    Begin {
+        final_lsn: u64,
+        time: i64,
+        xid: u32,
+    },
+    Commit {
+        flags: u8,
+        lsn: u64,
+        end_lsn: u64,
+        time: i64,
+    },
+    Origin {
+        lsn: u64,
+        name: String,
+    },
+    Relation(Relation),
+    Type {
+        oid: u32,
+        namespace: String,
+        name: String,
+    },
+    Insert {
+        relation: u32,
+        new: Vec<TupleValue>,
+    },
+    /// `old` holds the replica identity columns, or the whole row with REPLICA IDENTITY
+    /// FULL, when they changed
+    Update {
+        relation: u32,
+        old: Option<Vec<TupleValue>>,
+        new: Vec<TupleValue>,
+    },
+    Delete {
+        relation: u32,
+        old: Vec<TupleValue>,
+    },
+    Truncate {
+        /// 1 for CASCADE, 2 for RESTART IDENTITY
+        options: u8,
+        relations: Vec<u32>,
+    },
+    /// Message written with pg_logical_emit_message
+    Message {
+        transactional: bool,
+        lsn: u64,
+        prefix: String,
+        content: Vec<u8>,
+    },
+}
+
+impl LogicalMessage {
+    pub fn parse(data: &[u8]) -> Result<LogicalMessage, Error> {
+        let mut r = Reader::new(data);
+        let message = match r.read_u8()? {
+            b'B' => LogicalMessage::Begin {
+                final_lsn: r.read_u64()?,
+                time: r.read_i64()?,
+                xid: r.read_u32()?,
+            },
+            b'C' => LogicalMessage::Commit {
+                flags: r.read_u8()?,
+                lsn: r.read_u64()?,
+                end_lsn: r.read_u64()?,
+                time: r.read_i64()?,
+            },
+            b'O' => LogicalMessage::Origin {
+                lsn: r.read_u64()?,
+                name: r.read_cstr()?,
+            },
+            b'R' => {
+                let oid = r.read_u32()?;
+                let namespace = r.read_cstr()?;
+                let name = r.read_cstr()?;
+                let replica_identity = r.read_u8()?;
+                let n = r.read_i16()?;
+                let mut columns = Vec::with_capacity(n.max(0) as usize);
+                for _ in 0..n {
+                    columns.push(RelationColumn {
+                        key: r.read_u8()? & 1 != 0,
+                        name: r.read_cstr()?,
+                        type_oid: r.read_u32()?,
+                        type_modifier: r.read_i32()?,
+                    });
+                }
+                LogicalMessage::Relation(Relation {
+                    oid,
+                    namespace,
+                    name,
+                    replica_identity,
+                    columns,
+                })
+            }
+            b'Y' => LogicalMessage::Type {
+                oid: r.read_u32()?,
+                namespace: r.read_cstr()?,
+                name: r.read_cstr()?,
+            },
+            b'I' => {
+                let relation = r.read_u32()?;
+                expect_tuple(&mut r, b'N')?;
+                LogicalMessage::Insert {
+                    relation,
+                    new: read_tuple(&mut r)?,
+                }
+            }
+            b'U' => {
+                let relation = r.read_u32()?;
+                let mut old = None;
+                let mut kind = r.read_u8()?;
+                if kind == b'K' || kind == b'O' {
+                    old = Some(read_tuple(&mut r)?);
+                    kind = r.read_u8()?;
+                }
+                if kind != b'N' {
+                    return Err(invalid_tuple(kind));
+                }
+                LogicalMessage::Update {
+                    relation,
+                    old,
+                    new: read_tuple(&mut r)?,
+                }
+            }
+            b'D' => {
+                let relation = r.read_u32()?;
+                let kind = r.read_u8()?;
+                if kind != b'K' && kind != b'O' {
+                    return Err(invalid_tuple(kind));
+                }
+                LogicalMessage::Delete {
+                    relation,
+                    old: read_tuple(&mut r)?,
+                }
+            }
+            b'T' => {
+                let n = r.read_i32()?;
+                let options = r.read_u8()?;
+                let mut relations = Vec::with_capacity(n.clamp(0, 1024) as usize);
+                for _ in 0..n {
+                    relations.push(r.read_u32()?);
+                }
+                LogicalMessage::Truncate { options, relations }
+            }
+            b'M' => {
+                let transactional = r.read_u8()? & 1 != 0;
+                let lsn = r.read_u64()?;
+                let prefix = r.read_cstr()?;
+                let n = r.read_i32()?;
+                LogicalMessage::Message {
+                    transactional,
+                    lsn,
+                    prefix,
+                    content: r.read_bytes(n.max(0) as usize)?.to_vec(),
+                }
+            }
+            b => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unsupported pgoutput message type {:#04x}", b),
+                ))
+            }
+        };
+        Ok(message)
+    }
+}
+
+fn invalid_tuple(kind: u8) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("Invalid pgoutput tuple type {:#04x}", kind),
+    )
+}
+
+fn expect_tuple(r: &mut Reader, expected: u8) -> Result<(), Error> {
+    match r.read_u8()? {
+        kind if kind == expected => Ok(()),
+        kind => Err(invalid_tuple(kind)),
+    }
+}
+
+fn read_tuple(r: &mut Reader) -> Result<Vec<TupleValue>, Error> {
+    let n = r.read_i16()?;
+    let mut values = Vec::with_capacity(n.max(0) as usize);
+    for _ in 0..n {
+        let value = match r.read_u8()? {
+            b'n' => TupleValue::Null,
+            b'u' => TupleValue::Unchanged,
+            b't' => {
+                let n = r.read_i32()?;
+                TupleValue::Text(r.read_bytes(n.max(0) as usize)?.to_vec())
+            }
+            b'b' => {
+                let n = r.read_i32()?;
+                TupleValue::Binary(r.read_bytes(n.max(0) as usize)?.to_vec())
+            }
+            b => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Invalid pgoutput column type {:#04x}", b),
+                ))
+            }
+        };
+        values.push(value);
+    }
+    Ok(values)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChangeKind {
+    Insert,
+    Update,
+    Delete,
+}
+
+/// A column of a changed row
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChangeColumn {
+    pub name: String,
+    pub type_oid: u32,
+    /// Part of the replica identity
+    pub key: bool,
+    pub value: TupleValue,
+}
+
+impl ChangeColumn {
+    /// Decode the column value, None for an unchanged TOASTed value
+    pub fn decode(&self) -> Result<Option<Value>, Error> {
+        let value = match &self.value {
+            TupleValue::Null => Value::Null,
+            TupleValue::Unchanged => return Ok(None),
+            TupleValue::Text(bytes) => Value::decode(self.type_oid, TEXT_FORMAT, Some(bytes))?,
+            TupleValue::Binary(bytes) => Value::decode(self.type_oid, BINARY_FORMAT, Some(bytes))?,
+        };
+        Ok(Some(value))
+    }
+}
+
+/// A row inserted, updated or deleted, with the table it belongs to
+#[derive(Clone, Debug, PartialEq)]
+pub struct Change {
+    pub kind: ChangeKind,
+    pub namespace: String,
+    pub table: String,
+    /// Transaction of the change, from the Begin before it
+    pub xid: Option<u32>,
+    /// WAL position of the XLogData carrying the change
+    pub lsn: u64,
+    /// Replica identity columns, or the whole row with REPLICA IDENTITY FULL, of updated and
+    /// deleted rows. Updates only carry them when they changed.
+    pub old: Option<Vec<ChangeColumn>>,
+    /// Inserted row, or updated row
+    pub new: Option<Vec<ChangeColumn>>,
+}
+
+/// State of a replication stream, started by START_REPLICATION
+#[derive(Clone, Debug)]
+pub struct ReplicationStream {
+    start: StartReplication,
+    /// Tables described by the server, by OID
+    relations: HashMap<u32, Relation>,
+    /// Transaction of the last Begin, until its Commit
+    xid: Option<u32>,
+    /// Last WAL position sent by the server
+    wal_end: u64,
+}
+
+impl ReplicationStream {
+    pub fn new(start: StartReplication) -> ReplicationStream {
+        ReplicationStream {
+            wal_end: start.lsn,
+            start,
+            relations: HashMap::new(),
+            xid: None,
+        }
+    }
+
+    /// The START_REPLICATION command that started the stream
+    pub fn get_start(&self) -> &StartReplication {
+        &self.start
+    }
+
+    pub fn get_relation(&self, oid: u32) -> Option<&Relation> {
+        self.relations.get(&oid)
+    }
+
+    /// The transaction being streamed
+    pub fn get_xid(&self) -> Option<u32> {
+        self.xid
+    }
+
+    pub fn get_wal_end(&self) -> u64 {
+        self.wal_end
+    }
+
+    /// Update the stream from a CopyData sent by the server
+    pub fn update(&mut self, packet: &Packet) -> Result<(), Error> {
+        let (end, data) = match ReplicationMessage::parse(packet)? {
+            ReplicationMessage::XLogData { end, data, .. } => (end, data),
+            ReplicationMessage::PrimaryKeepalive { end, .. } => {
+                self.wal_end = self.wal_end.max(end);
+                return Ok(());
+            }
+            _ => return Ok(()),
+        };
+        self.wal_end = self.wal_end.max(end);
+        if !self.start.is_pgoutput() {
+            return Ok(());
+        }
+        match LogicalMessage::parse(&data)? {
+            LogicalMessage::Begin { xid, .. } => self.xid = Some(xid),
+            LogicalMessage::Commit { .. } => self.xid = None,
+            LogicalMessage::Relation(relation) => {
+                self.relations.insert(relation.oid, relation);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// The pgoutput message of an XLogData sent by the server, None for other messages and
+    /// other output plugins
+    pub fn decode_message(&self, packet: &Packet) -> Result<Option<LogicalMessage>, Error> {
+        if !self.start.is_pgoutput() {
+            return Ok(None);
+        }
+        match ReplicationMessage::parse(packet)? {
+            ReplicationMessage::XLogData { data, .. } => Ok(Some(LogicalMessage::parse(&data)?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// The row changed by an XLogData sent by the server, named after the Relation sent
+    /// before it. None for other messages.
+    pub fn decode_change(&self, packet: &Packet) -> Result<Option<Change>, Error> {
+        let lsn = match ReplicationMessage::parse(packet)? {
+            ReplicationMessage::XLogData { start, .. } => start,
+            _ => return Ok(None),
+        };
+        let (kind, relation, old, new) = match self.decode_message(packet)? {
+            Some(LogicalMessage::Insert { relation, new }) => {
+                (ChangeKind::Insert, relation, None, Some(new))
+            }
+            Some(LogicalMessage::Update { relation, old, new }) => {
+                (ChangeKind::Update, relation, old, Some(new))
+            }
+            Some(LogicalMessage::Delete { relation, old }) => {
+                (ChangeKind::Delete, relation, Some(old), None)
+            }
+            _ => return Ok(None),
+        };
+        let relation = self.relations.get(&relation).ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("Change to relation {} sent before its Relation", relation),
+            )
+        })?;
+        let columns = |values: Vec<TupleValue>| -> Result<Vec<ChangeColumn>, Error> {
+            if values.len() != relation.columns.len() {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Row has {} columns, but relation {} has {}",
+                        values.len(),
+                        relation.name,
+                        relation.columns.len()
+                    ),
+                ));
+            }
+            Ok(relation
+                .columns
+                .iter()
+                .zip(values)
+                .map(|(column, value)| ChangeColumn {
+                    name: column.name.clone(),
+                    type_oid: column.type_oid,
+                    key: column.key,
+                    value,
+                })
+                .collect())
+        };
+        Ok(Some(Change {
+            kind,
+            namespace: relation.namespace.clone(),
+            table: relation.name.clone(),
+            xid: self.xid,
+            lsn,
+            old: old.map(columns).transpose()?,
+            new: new.map(columns).transpose()?,
+        }))
+    }
+
+    /// A keepalive sent in place of an XLogData, so the client skips a change but still
+    /// sees the stream move past it
+    pub fn skip(&self, packet: &Packet) -> Result<Packet, Error> {
+        match ReplicationMessage::parse(packet)? {
+            ReplicationMessage::XLogData { end, time, .. } => {
+                Ok(ReplicationMessage::PrimaryKeepalive {
+                    end,
+                    time,
+                    reply: false,
+                }
+                .to_packet())
+            }
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Packet is not an XLogData message",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::postgres::value::INT4_OID;
+
+    fn xlog_data(start: u64, message: &[u8]) -> Packet {
+        ReplicationMessage::XLogData {
+            start,
+            end: start + 0x100,
+            time: 0,
+            data: message.to_vec(),
+        }
+        .to_packet()
+    }
+
+    #[test]
+    fn parses_start_replication() {
+        let start = StartReplication::parse(
+            "START_REPLICATION SLOT \"sub\" LOGICAL 0/16B3748 (proto_version '1', publication_names '\"pub\"')",
+        )
+        .unwrap();
+        assert_eq!(start.slot.as_deref(), Some("sub"));
+        assert!(start.logical);
+        assert_eq!(format_lsn(start.lsn), "0/16B3748");
+        assert_eq!(start.get_option("publication_names"), Some("\"pub\""));
+        assert!(start.is_pgoutput());
+
+        let start = StartReplication::parse("start_replication 1/0 TIMELINE 1").unwrap();
+        assert!(!start.logical);
+        assert_eq!(start.lsn, 1 << 32);
+        assert_eq!(StartReplication::parse("SELECT 1"), None);
+    }
+
+    #[test]
+    fn decodes_pgoutput_changes() {
+        let start = StartReplication::parse(
+            "START_REPLICATION SLOT s LOGICAL 0/0 (proto_version '1', publication_names 'p')",
+        )
+        .unwrap();
+        let mut stream = ReplicationStream::new(start);
+
+        let mut begin = vec![b'B'];
+        begin.extend_from_slice(&[0; 16]);
+        begin.extend_from_slice(&[0, 0, 2, 0]);
+        let mut relation = b"R\x00\x00\x40\x00public\x00users\x00d\x00\x02".to_vec();
+        relation.extend_from_slice(b"\x01id\x00\x00\x00\x00\x17\xff\xff\xff\xff");
+        relation.extend_from_slice(b"\x00name\x00\x00\x00\x00\x19\xff\xff\xff\xff");
+        let insert = b"I\x00\x00\x40\x00N\x00\x02t\x00\x00\x00\x017t\x00\x00\x00\x05alice";
+        let delete = b"D\x00\x00\x40\x00K\x00\x02t\x00\x00\x00\x017n";
+        let messages = [
+            xlog_data(0x10, &begin),
+            xlog_data(0x20, &relation),
+            xlog_data(0x30, insert),
+            xlog_data(0x40, delete),
+        ];
+        for message in messages.iter() {
+            stream.update(message).unwrap();
+        }
+        assert_eq!(stream.get_xid(), Some(512));
+        assert_eq!(stream.get_wal_end(), 0x140);
+        assert_eq!(stream.get_relation(0x4000).unwrap().columns.len(), 2);
+
+        let change = stream.decode_change(&messages[2]).unwrap().unwrap();
+        assert_eq!(change.kind, ChangeKind::Insert);
+        assert_eq!(change.table, "users");
+        assert_eq!(change.lsn, 0x30);
+        let new = change.new.unwrap();
+        assert!(new[0].key);
+        assert_eq!(new[0].type_oid, INT4_OID);
+        assert_eq!(new[0].decode().unwrap(), Some(Value::Int4(7)));
+        assert_eq!(
+            new[1].decode().unwrap(),
+            Some(Value::Text("alice".to_string()))
+        );
+
+        let change = stream.decode_change(&messages[3]).unwrap().unwrap();
+        assert_eq!(change.kind, ChangeKind::Delete);
+        assert!(change.new.is_none());
+        assert_eq!(change.old.unwrap()[1].value, TupleValue::Null);
+        assert_eq!(stream.decode_change(&messages[0]).unwrap(), None);
+
+        let skipped = stream.skip(&messages[2]).unwrap();
+        assert_eq!(
+            ReplicationMessage::parse(&skipped).unwrap(),
+            ReplicationMessage::PrimaryKeepalive {
+                end: 0x130,
+                time: 0,
+                reply: false
+            }
+        );
+    }
+}
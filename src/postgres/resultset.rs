@@ -0,0 +1,226 @@
+//! Decoding DataRow messages using the RowDescription that preceded them, and building
+//! result sets sent by the proxy itself
+
+use std::io::{Error, ErrorKind};
+
+use super::{
+    message::{BackendMessage, FieldDescription},
+    value::{Value, TEXT_FORMAT},
+};
+use crate::packet::Packet;
+
+/// A decoded column of a DataRow
+#[derive(Clone, Debug, PartialEq)]
+pub struct Column {
+    pub name: String,
+    pub type_oid: u32,
+    pub format: i16,
+    pub value: Value,
+}
+
+/// Result set metadata of a connection, needed to make sense of DataRow messages
+#[derive(Clone, Debug, Default)]
+pub struct ResultSet {
+    fields: Option<Vec<FieldDescription>>,
+    /// Result formats requested by the last Bind. A RowDescription answering
+    /// Describe(statement) reports every column as text, since the formats are only known
+    /// once the statement is bound.
+    result_formats: Option<Vec<i16>>,
+}
+
+impl ResultSet {
+    pub fn new() -> ResultSet {
+        ResultSet::default()
+    }
+
+    /// The last RowDescription sent by the server
+    pub fn get_fields(&self) -> Option<&[FieldDescription]> {
+        self.fields.as_deref()
+    }
+
+    pub fn set_fields(&mut self, fields: Vec<FieldDescription>) {
+        self.fields = Some(fields);
+    }
+
+    /// Use the result formats of a Bind, following the same rules as the server: none means
+    /// all text, one applies to every column, otherwise one per column
+    pub fn set_result_formats(&mut self, result_formats: Option<Vec<i16>>) {
+        self.result_formats = result_formats;
+    }
+
+    pub fn get_format(&self, index: usize) -> i16 {
+        match &self.result_formats {
+            Some(formats) if formats.len() == 1 => formats[0],
+            Some(formats) => formats.get(index).copied().unwrap_or(TEXT_FORMAT),
+            None => self
+                .fields
+                .as_ref()
+                .and_then(|fields| fields.get(index))
+                .map(|field| field.format)
+                .unwrap_or(TEXT_FORMAT),
+        }
+    }
+
+    /// Decode a DataRow into its columns
+    pub fn decode_row(&self, packet: &Packet) -> Result<Vec<Column>, Error> {
+        let values = match BackendMessage::parse(packet)? {
+            BackendMessage::DataRow(values) => values,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "Packet is not a DataRow",
+                ))
+            }
+        };
+        let fields = self.fields.as_ref().ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "No RowDescription seen for this DataRow",
+            )
+        })?;
+        if fields.len() != values.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "DataRow has {} columns, but RowDescription has {}",
+                    values.len(),
+                    fields.len()
+                ),
+            ));
+        }
+        fields
+            .iter()
+            .zip(values.iter())
+            .enumerate()
+            .map(|(i, (field, value))| {
+                let format = self.get_format(i);
+                Ok(Column {
+                    name: field.name.clone(),
+                    type_oid: field.type_oid,
+                    format,
+                    value: Value::decode(field.type_oid, format, value.as_deref())?,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Build the response to a simple Query from the proxy: RowDescription, a DataRow per row,
+/// CommandComplete and ReadyForQuery. Values are sent in text format, as the server does for
+/// simple queries.
+///
+/// `transaction_status` is the status from the last ReadyForQuery sent by the server
+/// ('I', 'T' or 'E').
+pub fn simple_query_response(
+    fields: &[FieldDescription],
+    rows: &[Vec<Value>],
+    transaction_status: u8,
+) -> Result<Vec<Packet>, Error> {
+    let fields: Vec<FieldDescription> = fields
+        .iter()
+        .map(|field| FieldDescription {
+            format: TEXT_FORMAT,
+            ..field.clone()
+        })
+        .collect();
+    let mut packets = Vec::with_capacity(rows.len() + 3);
+    for row in rows {
+        if row.len() != fields.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Row has {} values, but there are {} fields",
+                    row.len(),
+                    fields.len()
+                ),
+            ));
+        }
+        let values = fields
+            .iter()
+            .zip(row.iter())
+            .map(|(field, value)| value.encode_text(field.type_oid))
+            .collect();
+        packets.push(BackendMessage::DataRow(values).to_packet());
+    }
+    packets.insert(0, BackendMessage::RowDescription(fields).to_packet());
+    packets.push(BackendMessage::CommandComplete(format!("SELECT {}", rows.len())).to_packet());
+    packets.push(BackendMessage::ReadyForQuery(transaction_status).to_packet());
+    Ok(packets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::PacketType;
+    use crate::postgres::value::{BINARY_FORMAT, INT4_OID, TEXT_OID};
+
+    fn field(name: &str, type_oid: u32) -> FieldDescription {
+        FieldDescription {
+            name: name.to_string(),
+            table_oid: 0,
+            column_id: 0,
+            type_oid,
+            type_size: -1,
+            type_modifier: -1,
+            format: TEXT_FORMAT,
+        }
+    }
+
+    #[test]
+    fn decodes_row_with_bind_formats() {
+        let mut result_set = ResultSet::new();
+        result_set.set_fields(vec![field("id", INT4_OID), field("name", TEXT_OID)]);
+
+        let row = BackendMessage::DataRow(vec![Some(b"7".to_vec()), None]).to_packet();
+        let columns = result_set.decode_row(&row).unwrap();
+        assert_eq!(columns[0].name, "id");
+        assert_eq!(columns[0].value, Value::Int4(7));
+        assert_eq!(columns[1].value, Value::Null);
+
+        result_set.set_result_formats(Some(vec![BINARY_FORMAT]));
+        let row = BackendMessage::DataRow(vec![Some(vec![0, 0, 0, 7]), Some(b"bob".to_vec())])
+            .to_packet();
+        let columns = result_set.decode_row(&row).unwrap();
+        assert_eq!(columns[0].value, Value::Int4(7));
+        assert_eq!(columns[1].format, BINARY_FORMAT);
+        assert_eq!(columns[1].value, Value::Text("bob".to_string()));
+    }
+
+    #[test]
+    fn builds_simple_query_response() {
+        let fields = [
+            FieldDescription::new("id", INT4_OID),
+            FieldDescription::new("name", TEXT_OID),
+        ];
+        let rows = [
+            vec![Value::Int4(1), Value::Text("alice".to_string())],
+            vec![Value::Int4(2), Value::Null],
+        ];
+        let packets = simple_query_response(&fields, &rows, b'I').unwrap();
+        let types: Vec<PacketType> = packets
+            .iter()
+            .map(|p| p.get_packet_type().unwrap())
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                PacketType::RowDescription,
+                PacketType::DataRow,
+                PacketType::DataRow,
+                PacketType::CommandComplete,
+                PacketType::ReadyForQuery,
+            ]
+        );
+
+        let mut result_set = ResultSet::new();
+        result_set.set_fields(fields.to_vec());
+        let columns = result_set.decode_row(&packets[2]).unwrap();
+        assert_eq!(columns[0].value, Value::Int4(2));
+        assert_eq!(columns[1].value, Value::Null);
+        assert_eq!(
+            BackendMessage::parse(&packets[3]).unwrap(),
+            BackendMessage::CommandComplete("SELECT 2".to_string())
+        );
+        assert!(simple_query_response(&fields, &[vec![Value::Int4(3)]], b'I').is_err());
+    }
+}
@@ -0,0 +1,194 @@
+//! StartupMessage sent by the client to open a session, with the user, database and run-time
+//! parameters
+//! https://www.postgresql.org/docs/12/protocol-flow.html#id-1.10.5.7.3
+
+use std::io::{Error, ErrorKind};
+
+use super::message::{FrontendMessage, PROTOCOL_VERSION_3};
+use crate::packet::Packet;
+
+/// Kind of replication connection asked for with the `replication` parameter
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Replication {
+    /// Physical replication, only replication commands are accepted
+    Physical,
+    /// Logical replication on the connected database, SQL is accepted too
+    Logical,
+}
+
+/// A StartupMessage. Parameters keep the order they were sent in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StartupMessage {
+    pub protocol_version: i32,
+    pub parameters: Vec<(String, String)>,
+}
+
+impl StartupMessage {
+    /// A protocol 3.0 startup for a user, which is the only required parameter
+    pub fn new(user: &str) -> StartupMessage {
+        StartupMessage {
+            protocol_version: PROTOCOL_VERSION_3,
+            parameters: vec![("user".to_string(), user.to_string())],
+        }
+    }
+
+    pub fn parse(packet: &Packet) -> Result<StartupMessage, Error> {
+        match FrontendMessage::parse(packet)? {
+            FrontendMessage::StartupMessage {
+                protocol_version,
+                parameters,
+            } => Ok(StartupMessage {
+                protocol_version,
+                parameters,
+            }),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Packet is not a StartupMessage",
+            )),
+        }
+    }
+
+    pub fn to_packet(&self) -> Packet {
+        FrontendMessage::StartupMessage {
+            protocol_version: self.protocol_version,
+            parameters: self.parameters.clone(),
+        }
+        .to_packet()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.parameters
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Set a parameter, replacing its value if it was sent
+    pub fn set(&mut self, name: &str, value: &str) {
+        match self.parameters.iter_mut().find(|(n, _)| n == name) {
+            Some((_, v)) => *v = value.to_string(),
+            None => self.parameters.push((name.to_string(), value.to_string())),
+        }
+    }
+
+    /// Remove a parameter, returning its value
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        let index = self.parameters.iter().position(|(n, _)| n == name)?;
+        Some(self.parameters.remove(index).1)
+    }
+
+    pub fn get_user(&self) -> Option<&str> {
+        self.get("user")
+    }
+
+    /// The database connected to, which defaults to the user name
+    pub fn get_database(&self) -> Option<&str> {
+        self.get("database").or_else(|| self.get_user())
+    }
+
+    pub fn get_application_name(&self) -> Option<&str> {
+        self.get("application_name")
+    }
+
+    pub fn get_client_encoding(&self) -> Option<&str> {
+        self.get("client_encoding")
+    }
+
+    pub fn get_replication(&self) -> Option<Replication> {
+        match self.get("replication")?.to_ascii_lowercase().as_str() {
+            "database" => Some(Replication::Logical),
+            "true" | "on" | "yes" | "1" => Some(Replication::Physical),
+            _ => None,
+        }
+    }
+
+    /// Command-line arguments of the `options` parameter. They are separated by spaces, and
+    /// a backslash escapes the next character.
+    pub fn get_options(&self) -> Vec<String> {
+        let mut options = Vec::new();
+        let mut option = String::new();
+        let mut chars = self.get("options").unwrap_or("").chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => option.extend(chars.next()),
+                _ if c.is_whitespace() => {
+                    if !option.is_empty() {
+                        options.push(std::mem::take(&mut option));
+                    }
+                }
+                _ => option.push(c),
+            }
+        }
+        if !option.is_empty() {
+            options.push(option);
+        }
+        options
+    }
+
+    /// Append a command-line argument to the `options` parameter, escaping it
+    pub fn add_option(&mut self, option: &str) {
+        let mut options = self.get("options").unwrap_or("").to_string();
+        if !options.is_empty() {
+            options.push(' ');
+        }
+        for c in option.chars() {
+            if c == '\\' || c.is_whitespace() {
+                options.push('\\');
+            }
+            options.push(c);
+        }
+        self.set("options", &options);
+    }
+
+    /// Set a run-time parameter for the session with `-c name=value` in `options`, such as
+    /// `statement_timeout`. A later setting of the same name overrides earlier ones.
+    pub fn add_setting(&mut self, name: &str, value: &str) {
+        self.add_option("-c");
+        self.add_option(&format!("{}={}", name, value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::PacketType;
+
+    #[test]
+    fn rewrites_parameters() {
+        let packet = FrontendMessage::StartupMessage {
+            protocol_version: PROTOCOL_VERSION_3,
+            parameters: vec![
+                ("user".to_string(), "alice".to_string()),
+                ("options".to_string(), "-c search_path=a\\ b".to_string()),
+                ("replication".to_string(), "database".to_string()),
+            ],
+        }
+        .to_packet();
+        let mut startup = StartupMessage::parse(&packet).unwrap();
+        assert_eq!(startup.get_user(), Some("alice"));
+        assert_eq!(startup.get_database(), Some("alice"));
+        assert_eq!(startup.get_replication(), Some(Replication::Logical));
+        assert_eq!(startup.get_options(), vec!["-c", "search_path=a b"]);
+
+        startup.set("application_name", "psql 127.0.0.1:5555");
+        startup.set("database", "app");
+        startup.add_setting("statement_timeout", "5s");
+        assert_eq!(startup.remove("replication").as_deref(), Some("database"));
+        let packet = startup.to_packet();
+        assert_eq!(
+            packet.get_packet_type().unwrap(),
+            PacketType::StartupMessage
+        );
+        let sent = StartupMessage::parse(&packet).unwrap();
+        assert_eq!(sent, startup);
+        assert_eq!(sent.get_database(), Some("app"));
+        assert_eq!(
+            sent.get_options(),
+            vec!["-c", "search_path=a b", "-c", "statement_timeout=5s"]
+        );
+        assert_eq!(sent.get_replication(), None);
+
+        let query = FrontendMessage::Query("SELECT 1".to_string()).to_packet();
+        assert!(StartupMessage::parse(&query).is_err());
+    }
+}
@@ -0,0 +1,288 @@
+//! Prepared statements and portals of the extended query protocol, so the SQL text and bound
+//! parameters are known when a portal is executed
+//! https://www.postgresql.org/docs/12/protocol-flow.html#PROTOCOL-FLOW-EXT-QUERY
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{Error, ErrorKind},
+};
+
+use super::{
+    message::{FrontendMessage, Target},
+    value::{Value, TEXT_FORMAT, TEXT_OID},
+};
+
+/// A statement created by a Parse message. The unnamed statement has an empty name.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PreparedStatement {
+    pub name: String,
+    pub query: String,
+    /// Parameter type OIDs given by the client, 0 when left for the server to infer. The
+    /// ones inferred are filled in from the ParameterDescription answering a Describe.
+    pub param_types: Vec<u32>,
+}
+
+/// A parameter value bound to a portal
+#[derive(Clone, Debug, PartialEq)]
+pub struct Parameter {
+    /// 0 when the type was left for the server to infer
+    pub type_oid: u32,
+    /// 0 for text, 1 for binary
+    pub format: i16,
+    /// None for NULL
+    pub value: Option<Vec<u8>>,
+}
+
+impl Parameter {
+    /// Decode the parameter value. Text parameters without a type are decoded as text.
+    pub fn decode(&self) -> Result<Value, Error> {
+        let type_oid = match (self.type_oid, self.format) {
+            (0, TEXT_FORMAT) => TEXT_OID,
+            (type_oid, _) => type_oid,
+        };
+        Value::decode(type_oid, self.format, self.value.as_deref())
+    }
+}
+
+/// A portal created by a Bind message: a statement with its parameter values. The unnamed
+/// portal has an empty name.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Portal {
+    pub name: String,
+    pub statement: String,
+    /// SQL text of the statement when the portal was bound
+    pub query: String,
+    pub params: Vec<Parameter>,
+    pub result_formats: Vec<i16>,
+}
+
+/// Prepared statements and portals of a connection
+#[derive(Clone, Debug, Default)]
+pub struct Statements {
+    statements: HashMap<String, PreparedStatement>,
+    portals: HashMap<String, Portal>,
+    /// Statements described by the client, in the order the server answers them
+    described: VecDeque<String>,
+}
+
+impl Statements {
+    pub fn new() -> Statements {
+        Statements::default()
+    }
+
+    pub fn get_statement(&self, name: &str) -> Option<&PreparedStatement> {
+        self.statements.get(name)
+    }
+
+    pub fn get_portal(&self, name: &str) -> Option<&Portal> {
+        self.portals.get(name)
+    }
+
+    /// Track the statements and portals created or destroyed by a client message
+    pub fn update(&mut self, message: &FrontendMessage) -> Result<(), Error> {
+        match message {
+            FrontendMessage::Parse {
+                name,
+                query,
+                param_types,
+            } => {
+                self.statements.insert(
+                    name.clone(),
+                    PreparedStatement {
+                        name: name.clone(),
+                        query: query.clone(),
+                        param_types: param_types.clone(),
+                    },
+                );
+            }
+            FrontendMessage::Bind {
+                portal,
+                statement,
+                param_formats,
+                params,
+                result_formats,
+            } => {
+                let prepared = self.statements.get(statement).ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::NotFound,
+                        format!("Bind to unknown prepared statement {:?}", statement),
+                    )
+                })?;
+                let params = params
+                    .iter()
+                    .enumerate()
+                    .map(|(i, value)| Parameter {
+                        type_oid: prepared.param_types.get(i).copied().unwrap_or(0),
+                        // Same rules as result formats: none means all text, one applies to
+                        // every parameter, otherwise one per parameter
+                        format: match param_formats.len() {
+                            0 => TEXT_FORMAT,
+                            1 => param_formats[0],
+                            _ => param_formats.get(i).copied().unwrap_or(TEXT_FORMAT),
+                        },
+                        value: value.clone(),
+                    })
+                    .collect();
+                self.portals.insert(
+                    portal.clone(),
+                    Portal {
+                        name: portal.clone(),
+                        statement: statement.clone(),
+                        query: prepared.query.clone(),
+                        params,
+                        result_formats: result_formats.clone(),
+                    },
+                );
+            }
+            FrontendMessage::Close {
+                target: Target::Statement,
+                name,
+            } => {
+                self.statements.remove(name);
+                // Closing a statement also closes the portals bound to it
+                self.portals.retain(|_, portal| &portal.statement != name);
+            }
+            FrontendMessage::Close {
+                target: Target::Portal,
+                name,
+            } => {
+                self.portals.remove(name);
+            }
+            FrontendMessage::Describe {
+                target: Target::Statement,
+                name,
+            } => {
+                self.described.push_back(name.clone());
+            }
+            // A simple query replaces the unnamed statement and portal
+            FrontendMessage::Query(_) => {
+                self.statements.remove("");
+                self.portals.remove("");
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Fill in the parameter types the server inferred for the oldest statement described
+    pub fn describe_parameters(&mut self, param_types: &[u32]) {
+        let name = match self.described.pop_front() {
+            Some(name) => name,
+            None => return,
+        };
+        if let Some(statement) = self.statements.get_mut(&name) {
+            statement.param_types.resize(param_types.len(), 0);
+            for (param_type, inferred) in statement.param_types.iter_mut().zip(param_types) {
+                if *param_type == 0 {
+                    *param_type = *inferred;
+                }
+            }
+        }
+    }
+
+    /// The server skips the rest of the messages up to Sync after an error, and answers
+    /// every Describe before ReadyForQuery: the ones not answered yet never will be
+    pub fn forget_descriptions(&mut self) {
+        self.described.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::postgres::value::{BINARY_FORMAT, INT4_OID};
+
+    #[test]
+    fn tracks_statements_and_portals() {
+        let mut statements = Statements::new();
+        statements
+            .update(&FrontendMessage::Parse {
+                name: "s1".to_string(),
+                query: "SELECT * FROM users WHERE id = $1 AND name = $2".to_string(),
+                param_types: vec![INT4_OID],
+            })
+            .unwrap();
+        statements
+            .update(&FrontendMessage::Bind {
+                portal: String::new(),
+                statement: "s1".to_string(),
+                param_formats: vec![BINARY_FORMAT, TEXT_FORMAT],
+                params: vec![Some(vec![0, 0, 0, 42]), Some(b"bob".to_vec())],
+                result_formats: Vec::new(),
+            })
+            .unwrap();
+
+        let portal = statements.get_portal("").unwrap();
+        assert_eq!(
+            portal.query,
+            "SELECT * FROM users WHERE id = $1 AND name = $2"
+        );
+        let values: Vec<Value> = portal.params.iter().map(|p| p.decode().unwrap()).collect();
+        assert_eq!(
+            values,
+            vec![Value::Int4(42), Value::Text("bob".to_string())]
+        );
+
+        let bind_unknown = FrontendMessage::Bind {
+            portal: String::new(),
+            statement: "s2".to_string(),
+            param_formats: Vec::new(),
+            params: Vec::new(),
+            result_formats: Vec::new(),
+        };
+        assert!(statements.update(&bind_unknown).is_err());
+
+        statements
+            .update(&FrontendMessage::Close {
+                target: Target::Statement,
+                name: "s1".to_string(),
+            })
+            .unwrap();
+        assert!(statements.get_statement("s1").is_none());
+        assert!(statements.get_portal("").is_none());
+    }
+
+    #[test]
+    fn infers_parameter_types_from_descriptions() {
+        let mut statements = Statements::new();
+        statements
+            .update(&FrontendMessage::Parse {
+                name: "s1".to_string(),
+                query: "SELECT * FROM users WHERE id = $1".to_string(),
+                param_types: Vec::new(),
+            })
+            .unwrap();
+        statements
+            .update(&FrontendMessage::Describe {
+                target: Target::Statement,
+                name: "s1".to_string(),
+            })
+            .unwrap();
+        statements.describe_parameters(&[INT4_OID]);
+        statements
+            .update(&FrontendMessage::Bind {
+                portal: String::new(),
+                statement: "s1".to_string(),
+                param_formats: vec![BINARY_FORMAT],
+                params: vec![Some(vec![0, 0, 0, 42])],
+                result_formats: Vec::new(),
+            })
+            .unwrap();
+        let portal = statements.get_portal("").unwrap();
+        assert_eq!(portal.params[0].decode().unwrap(), Value::Int4(42));
+
+        // A description never comes for a Describe after an error
+        statements
+            .update(&FrontendMessage::Describe {
+                target: Target::Statement,
+                name: "s1".to_string(),
+            })
+            .unwrap();
+        statements.forget_descriptions();
+        statements.describe_parameters(&[0]);
+        assert_eq!(
+            statements.get_statement("s1").unwrap().param_types,
+            vec![INT4_OID]
+        );
+    }
+}
@@ -0,0 +1,534 @@
+//! Decoding column values in text and binary format
+//! https://www.postgresql.org/docs/12/datatype.html
+
+use byteorder::{BigEndian, ByteOrder};
+use std::io::{Error, ErrorKind};
+
+// Type OIDs, see pg_type.dat
+pub const BOOL_OID: u32 = 16;
+pub const BYTEA_OID: u32 = 17;
+pub const CHAR_OID: u32 = 18;
+pub const NAME_OID: u32 = 19;
+pub const INT8_OID: u32 = 20;
+pub const INT2_OID: u32 = 21;
+pub const INT4_OID: u32 = 23;
+pub const TEXT_OID: u32 = 25;
+pub const JSON_OID: u32 = 114;
+pub const FLOAT4_OID: u32 = 700;
+pub const FLOAT8_OID: u32 = 701;
+pub const BPCHAR_OID: u32 = 1042;
+pub const VARCHAR_OID: u32 = 1043;
+pub const TIMESTAMP_OID: u32 = 1114;
+pub const TIMESTAMPTZ_OID: u32 = 1184;
+pub const NUMERIC_OID: u32 = 1700;
+pub const UUID_OID: u32 = 2950;
+pub const JSONB_OID: u32 = 3802;
+
+pub const TEXT_FORMAT: i16 = 0;
+pub const BINARY_FORMAT: i16 = 1;
+
+/// Microseconds between 1970-01-01 and the PostgresSQL epoch of 2000-01-01
+const POSTGRES_EPOCH_MICROS: i64 = 946_684_800_000_000;
+
+/// Size of a type as reported in a RowDescription, -1 for variable length types
+pub fn type_size(type_oid: u32) -> i16 {
+    match type_oid {
+        BOOL_OID | CHAR_OID => 1,
+        INT2_OID => 2,
+        INT4_OID | FLOAT4_OID => 4,
+        INT8_OID | FLOAT8_OID | TIMESTAMP_OID | TIMESTAMPTZ_OID => 8,
+        UUID_OID => 16,
+        NAME_OID => 64,
+        _ => -1,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int2(i16),
+    Int4(i32),
+    Int8(i64),
+    Float4(f32),
+    Float8(f64),
+    /// Exact decimal representation
+    Numeric(String),
+    Text(String),
+    Bytea(Vec<u8>),
+    /// Microseconds since 1970-01-01 00:00:00, in UTC for timestamptz.
+    /// 'infinity' and '-infinity' are i64::MAX and i64::MIN.
+    Timestamp(i64),
+    Uuid([u8; 16]),
+    Json(String),
+    /// A type without a decoder, as sent by the server
+    Other(Vec<u8>),
+}
+
+impl Value {
+    /// Decode a column value of type `type_oid` sent in `format` (0 text, 1 binary)
+    pub fn decode(type_oid: u32, format: i16, bytes: Option<&[u8]>) -> Result<Value, Error> {
+        match bytes {
+            None => Ok(Value::Null),
+            Some(bytes) if format == BINARY_FORMAT => Value::decode_binary(type_oid, bytes),
+            Some(bytes) => Value::decode_text(type_oid, bytes),
+        }
+    }
+
+    /// Encode a value in text format for a column of type `type_oid`, None for NULL
+    pub fn encode_text(&self, type_oid: u32) -> Option<Vec<u8>> {
+        let text = match self {
+            Value::Null => return None,
+            Value::Bool(true) => "t".to_string(),
+            Value::Bool(false) => "f".to_string(),
+            Value::Int2(n) => n.to_string(),
+            Value::Int4(n) => n.to_string(),
+            Value::Int8(n) => n.to_string(),
+            Value::Float4(n) => format_float(*n as f64, n.to_string()),
+            Value::Float8(n) => format_float(*n, n.to_string()),
+            Value::Numeric(s) | Value::Text(s) | Value::Json(s) => s.clone(),
+            Value::Bytea(bytes) => {
+                let mut hex = String::with_capacity(2 + 2 * bytes.len());
+                hex.push_str("\\x");
+                for b in bytes {
+                    hex.push_str(&format!("{:02x}", b));
+                }
+                hex
+            }
+            Value::Timestamp(micros) => format_timestamp(*micros, type_oid == TIMESTAMPTZ_OID),
+            Value::Uuid(uuid) => {
+                let hex: String = uuid.iter().map(|b| format!("{:02x}", b)).collect();
+                format!(
+                    "{}-{}-{}-{}-{}",
+                    &hex[0..8],
+                    &hex[8..12],
+                    &hex[12..16],
+                    &hex[16..20],
+                    &hex[20..32]
+                )
+            }
+            Value::Other(bytes) => return Some(bytes.clone()),
+        };
+        Some(text.into_bytes())
+    }
+
+    fn decode_text(type_oid: u32, bytes: &[u8]) -> Result<Value, Error> {
+        let text = || to_str(bytes);
+        let value = match type_oid {
+            BOOL_OID => match bytes {
+                b"t" => Value::Bool(true),
+                b"f" => Value::Bool(false),
+                _ => return Err(invalid("bool")),
+            },
+            INT2_OID => Value::Int2(text()?.parse().map_err(|_| invalid("int2"))?),
+            INT4_OID => Value::Int4(text()?.parse().map_err(|_| invalid("int4"))?),
+            INT8_OID => Value::Int8(text()?.parse().map_err(|_| invalid("int8"))?),
+            FLOAT4_OID => Value::Float4(text()?.parse().map_err(|_| invalid("float4"))?),
+            FLOAT8_OID => Value::Float8(text()?.parse().map_err(|_| invalid("float8"))?),
+            NUMERIC_OID => Value::Numeric(text()?.to_string()),
+            TEXT_OID | VARCHAR_OID | BPCHAR_OID | NAME_OID | CHAR_OID => {
+                Value::Text(text()?.to_string())
+            }
+            BYTEA_OID => Value::Bytea(decode_bytea_text(bytes)?),
+            TIMESTAMP_OID | TIMESTAMPTZ_OID => Value::Timestamp(parse_timestamp(text()?)?),
+            UUID_OID => Value::Uuid(parse_uuid(text()?)?),
+            JSON_OID | JSONB_OID => Value::Json(text()?.to_string()),
+            _ => Value::Other(bytes.to_vec()),
+        };
+        Ok(value)
+    }
+
+    fn decode_binary(type_oid: u32, bytes: &[u8]) -> Result<Value, Error> {
+        let sized = |n: usize, name: &str| {
+            if bytes.len() == n {
+                Ok(bytes)
+            } else {
+                Err(invalid(name))
+            }
+        };
+        let value = match type_oid {
+            BOOL_OID => Value::Bool(sized(1, "bool")?[0] != 0),
+            INT2_OID => Value::Int2(BigEndian::read_i16(sized(2, "int2")?)),
+            INT4_OID => Value::Int4(BigEndian::read_i32(sized(4, "int4")?)),
+            INT8_OID => Value::Int8(BigEndian::read_i64(sized(8, "int8")?)),
+            FLOAT4_OID => Value::Float4(BigEndian::read_f32(sized(4, "float4")?)),
+            FLOAT8_OID => Value::Float8(BigEndian::read_f64(sized(8, "float8")?)),
+            NUMERIC_OID => Value::Numeric(decode_numeric_binary(bytes)?),
+            TEXT_OID | VARCHAR_OID | BPCHAR_OID | NAME_OID | CHAR_OID => {
+                Value::Text(to_str(bytes)?.to_string())
+            }
+            BYTEA_OID => Value::Bytea(bytes.to_vec()),
+            TIMESTAMP_OID | TIMESTAMPTZ_OID => {
+                let micros = BigEndian::read_i64(sized(8, "timestamp")?);
+                Value::Timestamp(match micros {
+                    i64::MAX | i64::MIN => micros,
+                    _ => micros.saturating_add(POSTGRES_EPOCH_MICROS),
+                })
+            }
+            UUID_OID => {
+                let mut uuid = [0; 16];
+                uuid.copy_from_slice(sized(16, "uuid")?);
+                Value::Uuid(uuid)
+            }
+            JSON_OID => Value::Json(to_str(bytes)?.to_string()),
+            // jsonb is prefixed with a version number, which is currently always 1
+            JSONB_OID => match bytes.split_first() {
+                Some((1, json)) => Value::Json(to_str(json)?.to_string()),
+                _ => return Err(invalid("jsonb")),
+            },
+            _ => Value::Other(bytes.to_vec()),
+        };
+        Ok(value)
+    }
+}
+
+fn to_str(bytes: &[u8]) -> Result<&str, Error> {
+    std::str::from_utf8(bytes).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+fn invalid(type_name: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("Invalid {} value", type_name),
+    )
+}
+
+/// bytea in either the hex format ("\x0102") or the older escape format
+fn decode_bytea_text(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    if let Some(hex) = bytes.strip_prefix(b"\\x") {
+        if hex.len() % 2 != 0 {
+            return Err(invalid("bytea"));
+        }
+        return hex
+            .chunks(2)
+            .map(|pair| u8::from_str_radix(to_str(pair)?, 16).map_err(|_| invalid("bytea")))
+            .collect();
+    }
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'\\' {
+            out.push(bytes[i]);
+            i += 1;
+        } else if bytes.get(i + 1) == Some(&b'\\') {
+            out.push(b'\\');
+            i += 2;
+        } else {
+            let octal = bytes.get(i + 1..i + 4).ok_or_else(|| invalid("bytea"))?;
+            out.push(u8::from_str_radix(to_str(octal)?, 8).map_err(|_| invalid("bytea"))?);
+            i += 4;
+        }
+    }
+    Ok(out)
+}
+
+/// Binary numeric: ndigits, weight, sign and display scale, followed by base-10000 digits
+fn decode_numeric_binary(bytes: &[u8]) -> Result<String, Error> {
+    if bytes.len() < 8 {
+        return Err(invalid("numeric"));
+    }
+    let ndigits = BigEndian::read_i16(&bytes[0..2]).max(0) as usize;
+    let weight = BigEndian::read_i16(&bytes[2..4]) as i32;
+    let sign = BigEndian::read_u16(&bytes[4..6]);
+    let dscale = BigEndian::read_u16(&bytes[6..8]) as usize;
+    if bytes.len() != 8 + 2 * ndigits {
+        return Err(invalid("numeric"));
+    }
+    match sign {
+        0xc000 => return Ok("NaN".to_string()),
+        0xd000 => return Ok("Infinity".to_string()),
+        0xf000 => return Ok("-Infinity".to_string()),
+        _ => {}
+    }
+    let digit = |i: i32| -> i16 {
+        if i >= 0 && (i as usize) < ndigits {
+            BigEndian::read_i16(&bytes[8 + 2 * i as usize..])
+        } else {
+            0
+        }
+    };
+
+    let mut s = String::new();
+    if sign == 0x4000 {
+        s.push('-');
+    }
+    if weight < 0 {
+        s.push('0');
+    } else {
+        for i in 0..=weight {
+            if i == 0 {
+                s.push_str(&digit(i).to_string());
+            } else {
+                s.push_str(&format!("{:04}", digit(i)));
+            }
+        }
+    }
+    if dscale > 0 {
+        let mut fraction = String::with_capacity(dscale + 4);
+        let mut i = weight + 1;
+        while fraction.len() < dscale {
+            fraction.push_str(&format!("{:04}", digit(i)));
+            i += 1;
+        }
+        fraction.truncate(dscale);
+        s.push('.');
+        s.push_str(&fraction);
+    }
+    Ok(s)
+}
+
+/// Largest year accepted in a timestamp, beyond what an i64 of microseconds can hold
+const MAX_TIMESTAMP_YEAR: i64 = 300_000;
+
+/// ISO timestamps as sent with DateStyle=ISO, e.g. "2020-01-02 03:04:05.678+01"
+fn parse_timestamp(s: &str) -> Result<i64, Error> {
+    match s {
+        "infinity" => return Ok(i64::MAX),
+        "-infinity" => return Ok(i64::MIN),
+        _ => {}
+    }
+    let err = || invalid("timestamp");
+    let (date, time) = s.split_at(s.find(' ').ok_or_else(err)?);
+    let time = &time[1..];
+    let mut date_parts = date.splitn(3, '-');
+    let mut next = || -> Result<i64, Error> {
+        date_parts
+            .next()
+            .and_then(|p| p.parse().ok())
+            .ok_or_else(err)
+    };
+    let (year, month, day) = (next()?, next()?, next()?);
+
+    // Split off the UTC offset, if any
+    let (time, offset_secs) = match time.find(['+', '-']) {
+        Some(i) => {
+            let sign = if &time[i..=i] == "-" { -1 } else { 1 };
+            let mut secs: i64 = 0;
+            for (n, part) in time[i + 1..].split(':').enumerate() {
+                let v: i64 = part.parse().map_err(|_| err())?;
+                secs = v
+                    .checked_mul(*[3600, 60, 1].get(n).ok_or_else(err)?)
+                    .and_then(|v| secs.checked_add(v))
+                    .ok_or_else(err)?;
+            }
+            (&time[..i], sign * secs)
+        }
+        None => (time, 0),
+    };
+    let mut time_parts = time.splitn(3, ':');
+    let hour: i64 = time_parts
+        .next()
+        .and_then(|p| p.parse().ok())
+        .ok_or_else(err)?;
+    let minute: i64 = time_parts
+        .next()
+        .and_then(|p| p.parse().ok())
+        .ok_or_else(err)?;
+    let seconds = time_parts.next().ok_or_else(err)?;
+    let (whole, fraction) = match seconds.find('.') {
+        Some(i) => (&seconds[..i], &seconds[i + 1..]),
+        None => (seconds, ""),
+    };
+    let second: i64 = whole.parse().map_err(|_| err())?;
+    let mut micros: i64 = 0;
+    for (n, c) in fraction.chars().take(6).enumerate() {
+        micros += c.to_digit(10).ok_or_else(err)? as i64 * 10_i64.pow(5 - n as u32);
+    }
+
+    // Microseconds since 1970 only reach about 292 000 years either way, and the date
+    // arithmetic stays in range below that
+    if year.abs() > MAX_TIMESTAMP_YEAR || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return Err(err());
+    }
+    let days = days_from_civil(year, month, day);
+    let secs = [
+        hour.checked_mul(3600),
+        minute.checked_mul(60),
+        Some(second),
+        offset_secs.checked_neg(),
+    ]
+    .iter()
+    .try_fold(days * 86_400, |secs, part| secs.checked_add((*part)?))
+    .ok_or_else(err)?;
+    secs.checked_mul(1_000_000)
+        .and_then(|whole| whole.checked_add(micros))
+        .ok_or_else(err)
+}
+
+/// Infinities and NaN are spelled the way the server spells them
+fn format_float(n: f64, display: String) -> String {
+    if n.is_nan() {
+        "NaN".to_string()
+    } else if n.is_infinite() && n > 0.0 {
+        "Infinity".to_string()
+    } else if n.is_infinite() {
+        "-Infinity".to_string()
+    } else {
+        display
+    }
+}
+
+/// Format microseconds since 1970-01-01 as an ISO timestamp, in UTC for timestamptz
+fn format_timestamp(micros: i64, with_offset: bool) -> String {
+    match micros {
+        i64::MAX => return "infinity".to_string(),
+        i64::MIN => return "-infinity".to_string(),
+        _ => {}
+    }
+    let secs = micros.div_euclid(1_000_000);
+    let fraction = micros.rem_euclid(1_000_000);
+    let (year, month, day) = civil_from_days(secs.div_euclid(86_400));
+    let time = secs.rem_euclid(86_400);
+    let mut s = format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    );
+    if fraction > 0 {
+        let digits = format!(".{:06}", fraction);
+        s.push_str(digits.trim_end_matches('0'));
+    }
+    if with_offset {
+        s.push_str("+00");
+    }
+    s
+}
+
+/// Days since 1970-01-01 in the proleptic Gregorian calendar
+/// http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Inverse of days_from_civil
+/// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = if z >= 0 { z } else { z - 146_096 } / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn parse_uuid(s: &str) -> Result<[u8; 16], Error> {
+    let hex: Vec<u8> = s.bytes().filter(|b| *b != b'-').collect();
+    if hex.len() != 32 {
+        return Err(invalid("uuid"));
+    }
+    let mut uuid = [0; 16];
+    for (i, pair) in hex.chunks(2).enumerate() {
+        uuid[i] = u8::from_str_radix(to_str(pair)?, 16).map_err(|_| invalid("uuid"))?;
+    }
+    Ok(uuid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_text_values() {
+        let decode = |oid, s: &str| Value::decode(oid, TEXT_FORMAT, Some(s.as_bytes())).unwrap();
+        assert_eq!(decode(BOOL_OID, "t"), Value::Bool(true));
+        assert_eq!(decode(INT8_OID, "-42"), Value::Int8(-42));
+        assert_eq!(decode(FLOAT8_OID, "1.5"), Value::Float8(1.5));
+        assert_eq!(
+            decode(NUMERIC_OID, "12.30"),
+            Value::Numeric("12.30".to_string())
+        );
+        assert_eq!(decode(BYTEA_OID, "\\xdead"), Value::Bytea(vec![0xde, 0xad]));
+        assert_eq!(
+            decode(BYTEA_OID, "a\\000\\\\"),
+            Value::Bytea(b"a\0\\".to_vec())
+        );
+        assert_eq!(
+            decode(TIMESTAMPTZ_OID, "2000-01-01 01:00:00.5+01"),
+            Value::Timestamp(POSTGRES_EPOCH_MICROS + 500_000)
+        );
+        for out_of_range in [
+            "294277-01-01 00:00:00",
+            "9223372036854775807-01-01 00:00:00",
+            "2000-01-01 9223372036854775807:00:00",
+            "2000-01-01 00:00:00+9223372036854775807",
+        ] {
+            let e = Value::decode(TIMESTAMP_OID, TEXT_FORMAT, Some(out_of_range.as_bytes()))
+                .unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::InvalidData, "{}", out_of_range);
+        }
+        assert_eq!(
+            decode(UUID_OID, "a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11"),
+            Value::Uuid([
+                0xa0, 0xee, 0xbc, 0x99, 0x9c, 0x0b, 0x4e, 0xf8, 0xbb, 0x6d, 0x6b, 0xb9, 0xbd, 0x38,
+                0x0a, 0x11
+            ])
+        );
+        assert_eq!(
+            Value::decode(TEXT_OID, TEXT_FORMAT, None).unwrap(),
+            Value::Null
+        );
+    }
+
+    #[test]
+    fn text_encoding_round_trips() {
+        let values = [
+            (BOOL_OID, Value::Bool(false)),
+            (INT4_OID, Value::Int4(-7)),
+            (FLOAT8_OID, Value::Float8(f64::INFINITY)),
+            (BYTEA_OID, Value::Bytea(vec![0, 0xff])),
+            (TIMESTAMP_OID, Value::Timestamp(-1)),
+            (TIMESTAMPTZ_OID, Value::Timestamp(1_582_979_696_250_000)),
+            (UUID_OID, Value::Uuid([7; 16])),
+        ];
+        for (oid, value) in values.iter() {
+            let text = value.encode_text(*oid).unwrap();
+            assert_eq!(
+                &Value::decode(*oid, TEXT_FORMAT, Some(&text)).unwrap(),
+                value
+            );
+        }
+        assert_eq!(
+            Value::Timestamp(1_582_979_696_250_000).encode_text(TIMESTAMPTZ_OID),
+            Some(b"2020-02-29 12:34:56.25+00".to_vec())
+        );
+        assert_eq!(Value::Null.encode_text(TEXT_OID), None);
+    }
+
+    #[test]
+    fn decodes_binary_values() {
+        let decode = |oid, bytes: &[u8]| Value::decode(oid, BINARY_FORMAT, Some(bytes)).unwrap();
+        assert_eq!(decode(INT4_OID, &[0, 0, 1, 0]), Value::Int4(256));
+        assert_eq!(
+            decode(TIMESTAMP_OID, &[0; 8]),
+            Value::Timestamp(POSTGRES_EPOCH_MICROS)
+        );
+        assert_eq!(decode(JSONB_OID, b"\x01{}"), Value::Json("{}".to_string()));
+        // 12345.678 is 1 2345 6780 in base 10000, with weight 1 and scale 3
+        assert_eq!(
+            decode(
+                NUMERIC_OID,
+                &[0, 3, 0, 1, 0, 0, 0, 3, 0, 1, 0x09, 0x29, 0x1a, 0x7c]
+            ),
+            Value::Numeric("12345.678".to_string())
+        );
+        // -0.01 is 100 in base 10000, with weight -1 and scale 2
+        assert_eq!(
+            decode(NUMERIC_OID, &[0, 1, 0xff, 0xff, 0x40, 0, 0, 2, 0, 100]),
+            Value::Numeric("-0.01".to_string())
+        );
+    }
+}
@@ -5,21 +5,80 @@ use futures::{
     select,
     stream::StreamExt,
 };
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
 use tokio::net::{TcpListener, TcpStream};
 
 use crate::{
+    context::Context,
+    mariadb::{compress::CompressionMode, infile::LocalInfilePolicy},
     packet::{DatabaseType, Packet},
     packet_handler::{Direction, PacketHandler},
     pipe::Pipe,
+    transaction::TransactionState,
 };
 
+/// Handle on the contexts of the connections open on a server, for logic that runs next to
+/// the proxy such as draining connections before a failover. Clones share the same list.
+///
+/// Its methods lock each context in turn, so they wait for the packet being handled on every
+/// connection, and must not be called from a `PacketHandler`.
+#[derive(Clone, Debug, Default)]
+pub struct Connections {
+    contexts: Arc<Mutex<Vec<Weak<Mutex<Context>>>>>,
+}
+
+impl Connections {
+    async fn add(&self, context: &Arc<Mutex<Context>>) {
+        let mut contexts = self.contexts.lock().await;
+        contexts.retain(|c| c.strong_count() > 0);
+        contexts.push(Arc::downgrade(context));
+    }
+
+    /// Contexts of the connections still open
+    pub async fn get_contexts(&self) -> Vec<Arc<Mutex<Context>>> {
+        let mut contexts = self.contexts.lock().await;
+        contexts.retain(|c| c.strong_count() > 0);
+        contexts.iter().filter_map(Weak::upgrade).collect()
+    }
+
+    /// Client address and transaction state of each open connection
+    pub async fn get_transactions(&self) -> Vec<(String, TransactionState)> {
+        let mut transactions = Vec::new();
+        for context in self.get_contexts().await {
+            let context = context.lock().await;
+            transactions.push((
+                context.get_client_addr().to_string(),
+                *context.get_transaction(),
+            ));
+        }
+        transactions
+    }
+
+    /// Number of open connections inside a transaction
+    pub async fn count_in_transaction(&self) -> usize {
+        self.get_transactions()
+            .await
+            .iter()
+            .filter(|(_, transaction)| transaction.in_transaction())
+            .count()
+    }
+}
+
+/// Settings applied to the context of each connection
+#[derive(Clone, Debug, Default)]
+struct Settings {
+    compression_mode: CompressionMode,
+    local_infile_policy: LocalInfilePolicy,
+}
+
 #[derive(Debug)]
 pub struct Server {
     db_type: DatabaseType,
     db_addr: String,
+    settings: Settings,
     listener: TcpListener,
     kill_switches: Vec<oneshot::Sender<()>>,
+    connections: Connections,
 }
 
 impl Server {
@@ -27,19 +86,40 @@ impl Server {
         Server {
             db_type,
             db_addr,
+            settings: Settings::default(),
             listener: TcpListener::bind(bind_addr)
                 .await
                 .expect("Unable to bind to bind_addr"),
             kill_switches: Vec::new(),
+            connections: Connections::default(),
         }
     }
 
+    /// Choose which sides of MariaDB connections use the compressed protocol. By default
+    /// both do when the client and server negotiate it.
+    pub fn set_compression_mode(&mut self, compression_mode: CompressionMode) {
+        self.settings.compression_mode = compression_mode;
+    }
+
+    /// Choose what to do with the LOCAL INFILE requests of a MariaDB server. By default they
+    /// are refused.
+    pub fn set_local_infile_policy(&mut self, policy: LocalInfilePolicy) {
+        self.settings.local_infile_policy = policy;
+    }
+
+    /// The connections open on this server. The handle can be kept while the server runs.
+    pub fn get_connections(&self) -> Connections {
+        self.connections.clone()
+    }
+
     async fn create_pipes<T: PacketHandler + Send + Sync + 'static>(
         db_addr: String,
         db_type: DatabaseType,
+        settings: Settings,
         mut client_socket: TcpStream,
         handler_ref: Arc<Mutex<T>>,
         kill_switch_receiver: oneshot::Receiver<()>,
+        connections: Connections,
     ) {
         let client_addr = match client_socket.peer_addr() {
             Ok(addr) => addr.to_string(),
@@ -56,10 +136,16 @@ impl Server {
                 .unwrap_or_else(|_| panic!("Connecting to SQL database ({}) failed", db_addr));
             let (server_reader, server_writer) = server_socket.split();
             let (client_reader, client_writer) = client_socket.split();
+            let mut context = Context::new(client_addr.clone(), db_type);
+            context.set_compression_mode(settings.compression_mode);
+            context.set_local_infile_policy(settings.local_infile_policy);
+            let context = Arc::new(Mutex::new(context));
+            connections.add(&context).await;
             let mut forward_pipe = Pipe::new(
                 client_addr.clone(),
                 db_type,
                 handler_ref.clone(),
+                context.clone(),
                 Direction::Forward,
                 client_reader,
                 server_writer,
@@ -68,6 +154,7 @@ impl Server {
                 client_addr.clone(),
                 db_type,
                 handler_ref.clone(),
+                context.clone(),
                 Direction::Backward,
                 server_reader,
                 client_writer,
@@ -105,6 +192,7 @@ impl Server {
         trace!("Server.run(): enter");
         let db_addr = self.db_addr.clone();
         let db_type = self.db_type;
+        let settings = self.settings.clone();
         let packet_handler = Arc::new(Mutex::new(packet_handler));
         let mut incoming = self.listener.incoming().fuse();
         let mut kill_switch_receiver = kill_switch_receiver.fuse();
@@ -116,11 +204,11 @@ impl Server {
                     trace!("Server.run(): new incoming connection");
                     if let Some(conn) = some_conn {
                         match conn {
-                            Ok(mut client_socket) => {
+                            Ok(client_socket) => {
                                 trace!("Server.run(): got the client_socket");
                                 let (tx, rx) = oneshot::channel();
                                 self.kill_switches.push(tx);
-                                Server::create_pipes(db_addr.clone(), db_type, client_socket, packet_handler.clone(), rx).await;
+                                Server::create_pipes(db_addr.clone(), db_type, settings.clone(), client_socket, packet_handler.clone(), rx, self.connections.clone()).await;
                             },
                             Err(err) => {
                                 // Handle error by printing to STDOUT.
@@ -148,3 +236,54 @@ impl Server {
         info!("Server.run() complete");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::postgres::message::BackendMessage;
+
+    /// A PostgresSQL connection context, inside a transaction or not
+    fn context(client_addr: &str, status: u8) -> Arc<Mutex<Context>> {
+        let mut context = Context::new(client_addr.to_string(), DatabaseType::PostgresSQL);
+        let mut ready = BackendMessage::ReadyForQuery(status).to_packet();
+        context.decode(&Direction::Backward, &mut ready);
+        Arc::new(Mutex::new(context))
+    }
+
+    #[tokio::test]
+    async fn follows_open_connections() {
+        let connections = Connections::default();
+        let idle = context("10.0.0.1:1000", b'I');
+        let active = context("10.0.0.2:1000", b'T');
+        let failed = context("10.0.0.3:1000", b'E');
+        for context in [&idle, &active, &failed] {
+            connections.add(context).await;
+        }
+        assert_eq!(connections.get_contexts().await.len(), 3);
+        let transactions = connections.get_transactions().await;
+        let statuses: Vec<(&str, bool)> = transactions
+            .iter()
+            .map(|(addr, transaction)| (addr.as_str(), transaction.in_transaction()))
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                ("10.0.0.1:1000", false),
+                ("10.0.0.2:1000", true),
+                ("10.0.0.3:1000", true)
+            ]
+        );
+        assert_eq!(connections.count_in_transaction().await, 2);
+
+        // Closed connections drop their context, and are pruned from the shared list
+        drop(active);
+        let clone = connections.clone();
+        assert_eq!(clone.get_contexts().await.len(), 2);
+        assert_eq!(connections.contexts.lock().await.len(), 2);
+        assert_eq!(connections.count_in_transaction().await, 1);
+        drop(failed);
+        drop(idle);
+        assert!(connections.get_transactions().await.is_empty());
+        assert_eq!(connections.count_in_transaction().await, 0);
+    }
+}
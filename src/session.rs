@@ -0,0 +1,766 @@
+//! Session state of a connection: the user, the current database and the session variables,
+//! followed from the packets of both directions so handlers can base decisions on them
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::{
+    mariadb::{
+        self,
+        handshake::{ChangeUser, StatusFlags},
+        response::{OkPacket, SessionTrack},
+    },
+    packet::{DatabaseType, Packet, PacketType},
+    packet_handler::Direction,
+    postgres::{self, message::BackendMessage},
+    sql::{self, Syntax, TokenKind},
+};
+
+/// A change to the session requested by the client, applied once the server accepts it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SessionChange {
+    /// COM_INIT_DB or USE
+    Database(String),
+    /// COM_CHANGE_USER, which also resets the session
+    User {
+        user: String,
+        database: Option<String>,
+    },
+    /// A session variable set, or reset to its default with None
+    Variable(String, Option<String>),
+    /// COM_RESET_CONNECTION, RESET ALL or DISCARD ALL: every session variable goes back to
+    /// its default
+    Reset,
+}
+
+impl SessionChange {
+    /// Session changes made by a single SQL statement: USE, SET and RESET of session
+    /// variables, and DISCARD ALL. Global variables, user variables, transaction settings and
+    /// PostgresSQL's SET LOCAL do not outlive the statement or the transaction, and are
+    /// ignored, as are multi-statement queries.
+    pub fn parse<S: Into<Syntax>>(syntax: S, sql: &str) -> Vec<SessionChange> {
+        let syntax = syntax.into();
+        let mut tokens = tokenize(syntax, sql);
+        while tokens.last().is_some_and(|t| t.is(";")) {
+            tokens.pop();
+        }
+        if tokens.iter().any(|t| t.is(";")) {
+            return Vec::new();
+        }
+        match syntax.db_type {
+            DatabaseType::MariaDB => parse_mariadb(&tokens),
+            DatabaseType::PostgresSQL => parse_postgres(&tokens),
+        }
+    }
+}
+
+/// Session state of a single connection
+#[derive(Clone, Debug, Default)]
+pub struct SessionState {
+    user: Option<String>,
+    database: Option<String>,
+    /// Session variables set by the client, or reported by MariaDB session tracking, by
+    /// lowercase name
+    variables: HashMap<String, String>,
+    /// Run-time parameters reported by the PostgresSQL server with ParameterStatus
+    parameters: HashMap<String, String>,
+    /// Changes of the statements waiting for the server's answer
+    pending: VecDeque<Vec<SessionChange>>,
+}
+
+impl SessionState {
+    pub fn new() -> SessionState {
+        SessionState::default()
+    }
+
+    pub fn get_user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    /// The current database. PostgresSQL connections stay on the database they started with.
+    pub fn get_database(&self) -> Option<&str> {
+        self.database.as_deref()
+    }
+
+    /// A session variable set during the session, or else a run-time parameter reported by
+    /// the PostgresSQL server. None for variables left at their default.
+    pub fn get_variable(&self, name: &str) -> Option<&str> {
+        self.variables
+            .get(&name.to_lowercase())
+            .or_else(|| {
+                self.parameters
+                    .iter()
+                    .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                    .map(|(_, value)| value)
+            })
+            .map(|value| value.as_str())
+    }
+
+    /// Session variables set during the session
+    pub fn get_variables(&self) -> &HashMap<String, String> {
+        &self.variables
+    }
+
+    /// Run-time parameters reported by the PostgresSQL server, like `server_version` or
+    /// `TimeZone`, under the names the server uses
+    pub fn get_parameters(&self) -> &HashMap<String, String> {
+        &self.parameters
+    }
+
+    /// Update the session from a labeled MariaDB packet
+    pub fn update_mariadb(
+        &mut self,
+        direction: &Direction,
+        decoder: &mariadb::decoder::Decoder,
+        packet: &Packet,
+    ) {
+        let packet_type = match packet.get_packet_type() {
+            Ok(packet_type) => packet_type,
+            Err(_) => return,
+        };
+        if *direction == Direction::Backward {
+            match packet_type {
+                PacketType::ComOk => {
+                    for change in self.pending.pop_front().unwrap_or_default() {
+                        self.apply(change);
+                    }
+                    self.track_session(decoder, packet);
+                }
+                PacketType::ComErr => self.pending.clear(),
+                _ => {}
+            }
+            return;
+        }
+        let changes = match packet_type {
+            PacketType::HandshakeResponse => {
+                if let Some(response) = decoder.get_handshake_response() {
+                    self.user = Some(response.username.clone());
+                    self.database = response.database.clone().filter(|db| !db.is_empty());
+                }
+                return;
+            }
+            PacketType::SSLRequest
+            | PacketType::AuthSwitchResponse
+            | PacketType::LocalInfileData => return,
+            PacketType::ComInitDb => match packet.get_payload().map(|p| p.get(1..)) {
+                Ok(Some(name)) => {
+                    let (name, _) = decoder
+                        .get_client_encoding()
+                        .decode_without_bom_handling(name);
+                    vec![SessionChange::Database(name.into_owned())]
+                }
+                _ => Vec::new(),
+            },
+            PacketType::ComQuery => match packet.decode_query(decoder.get_client_encoding()) {
+                Ok(query) => SessionChange::parse(
+                    Syntax::of_session(DatabaseType::MariaDB, self),
+                    &query.text,
+                ),
+                Err(_) => Vec::new(),
+            },
+            PacketType::ComChangeUser => {
+                match ChangeUser::parse(packet, decoder.get_capabilities()) {
+                    Ok(change) => vec![SessionChange::User {
+                        user: change.username,
+                        database: Some(change.database).filter(|db| !db.is_empty()),
+                    }],
+                    Err(e) => {
+                        debug!("Unable to parse COM_CHANGE_USER: {}", e);
+                        Vec::new()
+                    }
+                }
+            }
+            PacketType::ComResetConnection => vec![SessionChange::Reset],
+            _ => Vec::new(),
+        };
+        // MariaDB answers each command before reading the next one
+        self.pending.clear();
+        self.pending.push_back(changes);
+    }
+
+    /// Follow the session state changes of an OK packet, sent with CLIENT_SESSION_TRACK
+    fn track_session(&mut self, decoder: &mariadb::decoder::Decoder, packet: &Packet) {
+        let ok = match OkPacket::parse(packet, decoder.get_capabilities()) {
+            Ok(ok)
+                if ok
+                    .status
+                    .contains(StatusFlags::SERVER_SESSION_STATE_CHANGED) =>
+            {
+                ok
+            }
+            _ => return,
+        };
+        let tracks = match ok.get_session_tracks() {
+            Ok(tracks) => tracks,
+            Err(e) => {
+                debug!("Unable to read session state changes: {}", e);
+                return;
+            }
+        };
+        for track in tracks {
+            match track {
+                SessionTrack::SystemVariable { name, value } => {
+                    self.variables.insert(name.to_lowercase(), value);
+                }
+                SessionTrack::Schema(database) => self.database = Some(database),
+                _ => {}
+            }
+        }
+    }
+
+    /// Update the session from a labeled PostgresSQL message
+    pub fn update_postgres(
+        &mut self,
+        direction: &Direction,
+        decoder: &postgres::decoder::Decoder,
+        packet: &Packet,
+    ) {
+        let packet_type = match packet.get_packet_type() {
+            Ok(packet_type) => packet_type,
+            Err(_) => return,
+        };
+        match (direction, packet_type) {
+            (Direction::Forward, PacketType::StartupMessage) => {
+                if let Some(startup) = decoder.get_startup() {
+                    self.user = startup.get_user().map(|user| user.to_string());
+                    self.database = startup.get_database().map(|db| db.to_string());
+                }
+            }
+            (Direction::Forward, PacketType::Query) => {
+                if let Ok(query) = packet.decode_query(decoder.get_client_encoding()) {
+                    self.push_statement(&query.text);
+                }
+            }
+            (Direction::Forward, PacketType::Execute) => {
+                if let Ok(portal) = decoder.get_execute(packet) {
+                    self.push_statement(&portal.query);
+                }
+            }
+            (Direction::Backward, PacketType::ParameterStatus) => {
+                if let Ok(BackendMessage::ParameterStatus { name, value }) =
+                    BackendMessage::parse(packet)
+                {
+                    self.parameters.insert(name, value);
+                }
+            }
+            (Direction::Backward, PacketType::CommandComplete) => {
+                if let Ok(BackendMessage::CommandComplete(tag)) = BackendMessage::parse(packet) {
+                    if is_session_command(&tag) {
+                        for change in self.pending.pop_front().unwrap_or_default() {
+                            self.apply(change);
+                        }
+                    }
+                }
+            }
+            // Statements not completed by then failed
+            (Direction::Backward, PacketType::ReadyForQuery) => self.pending.clear(),
+            _ => {}
+        }
+    }
+
+    /// Keep the changes of a SET, RESET or DISCARD statement until the server completes it
+    fn push_statement(&mut self, sql: &str) {
+        if is_session_command(sql) {
+            let syntax = Syntax::of_session(DatabaseType::PostgresSQL, self);
+            self.pending.push_back(SessionChange::parse(syntax, sql));
+        }
+    }
+
+    fn apply(&mut self, change: SessionChange) {
+        match change {
+            SessionChange::Database(database) => self.database = Some(database),
+            SessionChange::User { user, database } => {
+                self.user = Some(user);
+                self.database = database;
+                self.variables.clear();
+            }
+            // Variable names are case insensitive, even quoted
+            SessionChange::Variable(name, Some(value)) => {
+                self.variables.insert(name.to_lowercase(), value);
+            }
+            SessionChange::Variable(name, None) => {
+                self.variables.remove(&name.to_lowercase());
+            }
+            SessionChange::Reset => self.variables.clear(),
+        }
+    }
+}
+
+/// A statement, or the tag of its CommandComplete, that may change the session
+fn is_session_command(command: &str) -> bool {
+    let command = command.split_whitespace().next().unwrap_or("");
+    ["set", "reset", "discard"]
+        .iter()
+        .any(|c| command.eq_ignore_ascii_case(c))
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Token {
+    text: String,
+    /// A quoted string or identifier, which is never a keyword
+    quoted: bool,
+}
+
+impl Token {
+    fn is(&self, keyword: &str) -> bool {
+        !self.quoted && self.text.eq_ignore_ascii_case(keyword)
+    }
+
+    /// Names are case insensitive unless quoted
+    fn name(&self) -> String {
+        if self.quoted {
+            self.text.clone()
+        } else {
+            self.text.to_lowercase()
+        }
+    }
+}
+
+/// Split a statement into words, quoted strings and identifiers, and punctuation. Names
+/// such as `@@session.x` or `a.b` are single words, and `:=` is read as `=`.
+fn tokenize(syntax: Syntax, sql: &str) -> Vec<Token> {
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut last_end = None;
+    let mut last_in_name = false;
+    for token in sql::tokenize(syntax, sql) {
+        let in_name = match token.kind {
+            TokenKind::Word | TokenKind::Number | TokenKind::Parameter => true,
+            TokenKind::Symbol => matches!(token.text, "." | "@" | "$"),
+            _ => false,
+        };
+        let adjacent = last_end == Some(token.start);
+        match tokens.last_mut() {
+            Some(last) if adjacent && in_name && last_in_name => last.text.push_str(token.text),
+            Some(last) if adjacent && token.text == "=" && last.text == ":" && !last.quoted => {
+                last.text = "=".to_string()
+            }
+            _ => tokens.push(Token {
+                text: token.value(),
+                quoted: matches!(token.kind, TokenKind::String | TokenKind::Identifier),
+            }),
+        }
+        last_end = Some(token.end());
+        last_in_name = in_name;
+    }
+    tokens
+}
+
+/// The value of a variable, None for DEFAULT. Lists are joined with `, `.
+fn value_text(tokens: &[Token]) -> Option<String> {
+    match tokens {
+        [token] if token.is("default") => None,
+        _ => Some(
+            tokens
+                .split(|t| t.is(","))
+                .map(|item| {
+                    item.iter()
+                        .map(|t| t.text.as_str())
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                })
+                .collect::<Vec<_>>()
+                .join(", "),
+        ),
+    }
+}
+
+/// `USE db`, and `SET [SESSION | LOCAL] name = value, ...` where names may be written
+/// `@@session.name`
+fn parse_mariadb(tokens: &[Token]) -> Vec<SessionChange> {
+    match tokens {
+        [use_, database] if use_.is("use") => {
+            return vec![SessionChange::Database(database.text.clone())]
+        }
+        [set, role, name] if set.is("set") && role.is("role") => {
+            return vec![SessionChange::Variable(
+                "role".to_string(),
+                Some(name.text.clone()).filter(|_| !name.is("none")),
+            )]
+        }
+        [set, ..] if set.is("set") => {}
+        _ => return Vec::new(),
+    }
+    let mut changes = Vec::new();
+    let mut global = false;
+    // Assignments are separated by commas outside parentheses
+    let mut depth = 0;
+    let assignments = tokens[1..].split(|t| {
+        if t.is("(") {
+            depth += 1;
+        } else if t.is(")") {
+            depth -= 1;
+        }
+        depth == 0 && t.is(",")
+    });
+    for assignment in assignments {
+        let mut assignment = assignment;
+        if let Some(scope) = assignment.first() {
+            if scope.is("global") || scope.is("session") || scope.is("local") {
+                global = scope.is("global");
+                assignment = &assignment[1..];
+            }
+        }
+        let (name, value_tokens) = match assignment {
+            [name, eq, value @ ..] if eq.is("=") && !value.is_empty() => (name, value),
+            _ => return Vec::new(),
+        };
+        let mut name = name.name();
+        let mut is_global = global;
+        if let Some(system) = name.strip_prefix("@@") {
+            let (scope, variable) = match system.split_once('.') {
+                Some((scope, variable)) => (Some(scope), variable),
+                None => (None, system),
+            };
+            is_global = scope == Some("global");
+            name = variable.to_string();
+        } else if name.starts_with('@') {
+            // User variable
+            continue;
+        }
+        if !is_global {
+            changes.push(SessionChange::Variable(name, value_text(value_tokens)));
+        }
+    }
+    changes
+}
+
+/// `SET [SESSION] name {TO | =} value`, `RESET name`, `RESET ALL` and `DISCARD ALL`
+fn parse_postgres(tokens: &[Token]) -> Vec<SessionChange> {
+    let (command, rest) = match tokens.split_first() {
+        Some(split) => split,
+        None => return Vec::new(),
+    };
+    if command.is("discard") {
+        return match rest {
+            [all] if all.is("all") => vec![SessionChange::Reset],
+            _ => Vec::new(),
+        };
+    }
+    if command.is("reset") {
+        return match rest {
+            [all] if all.is("all") => vec![SessionChange::Reset],
+            [session, authorization]
+                if session.is("session") && authorization.is("authorization") =>
+            {
+                vec![SessionChange::Variable(
+                    "session_authorization".to_string(),
+                    None,
+                )]
+            }
+            [name] => vec![SessionChange::Variable(name.name(), None)],
+            _ => Vec::new(),
+        };
+    }
+    if !command.is("set") {
+        return Vec::new();
+    }
+    let rest = match rest {
+        [session, authorization, value @ ..]
+            if session.is("session") && authorization.is("authorization") =>
+        {
+            return vec![SessionChange::Variable(
+                "session_authorization".to_string(),
+                value_text(value),
+            )]
+        }
+        [session, rest @ ..] if session.is("session") => rest,
+        rest => rest,
+    };
+    let (name, value_tokens) = match rest {
+        [first, ..] if first.is("local") || first.is("transaction") || first.is("constraints") => {
+            return Vec::new()
+        }
+        [time, zone, value @ ..] if time.is("time") && zone.is("zone") => {
+            ("timezone".to_string(), value)
+        }
+        [schema, value @ ..] if schema.is("schema") => ("search_path".to_string(), value),
+        [names, value @ ..] if names.is("names") => ("client_encoding".to_string(), value),
+        [role, value @ ..] if role.is("role") => ("role".to_string(), value),
+        [name, to, value @ ..] if to.is("to") || to.is("=") => (name.name(), value),
+        _ => return Vec::new(),
+    };
+    if value_tokens.is_empty() {
+        return Vec::new();
+    }
+    // SET TIME ZONE LOCAL and SET ROLE NONE go back to the default
+    let value = match value_tokens {
+        [local] if local.is("local") || local.is("none") => None,
+        _ => value_text(value_tokens),
+    };
+    vec![SessionChange::Variable(name, value)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        context::Context,
+        mariadb::handshake::{CapabilityFlags, HandshakeResponse41, HandshakeV10},
+        mariadb::write_lenenc_bytes,
+        postgres::{message::FrontendMessage, startup::StartupMessage},
+    };
+
+    /// Label a packet and update the session, like a pipe does
+    fn exchange(context: &mut Context, direction: Direction, mut packet: Packet) {
+        context.decode(&direction, &mut packet);
+    }
+
+    fn variable(name: &str, value: Option<&str>) -> SessionChange {
+        SessionChange::Variable(name.to_string(), value.map(|v| v.to_string()))
+    }
+
+    #[test]
+    fn parses_session_statements() {
+        let mariadb = |sql| SessionChange::parse(DatabaseType::MariaDB, sql);
+        assert_eq!(
+            mariadb("USE `app db`;"),
+            vec![SessionChange::Database("app db".to_string())]
+        );
+        assert_eq!(
+            mariadb("SET sql_mode = 'ANSI', @@session.Wait_Timeout := 10, GLOBAL x = 1, @a = 2"),
+            vec![
+                variable("sql_mode", Some("ANSI")),
+                variable("wait_timeout", Some("10")),
+            ]
+        );
+        assert_eq!(
+            mariadb("set @@global.x = 1, time_zone = DEFAULT # comment"),
+            vec![variable("time_zone", None)]
+        );
+        assert_eq!(mariadb("SET NAMES utf8mb4"), vec![]);
+        assert_eq!(mariadb("USE a; USE b"), vec![]);
+
+        let postgres = |sql| SessionChange::parse(DatabaseType::PostgresSQL, sql);
+        assert_eq!(
+            postgres("SET search_path TO app, \"Public\""),
+            vec![variable("search_path", Some("app, Public"))]
+        );
+        assert_eq!(
+            postgres("SET SESSION statement_timeout = '5s'"),
+            vec![variable("statement_timeout", Some("5s"))]
+        );
+        assert_eq!(
+            postgres("set time zone 'UTC'"),
+            vec![variable("timezone", Some("UTC"))]
+        );
+        assert_eq!(postgres("SET LOCAL work_mem = '64MB'"), vec![]);
+        assert_eq!(postgres("RESET work_mem"), vec![variable("work_mem", None)]);
+        assert_eq!(postgres("DISCARD ALL"), vec![SessionChange::Reset]);
+    }
+
+    /// A MariaDB connection of alice to the app database, in a character set
+    fn connect_mariadb(charset: u8) -> Context {
+        let mut context = Context::new("127.0.0.1:1234".to_string(), DatabaseType::MariaDB);
+        let capabilities = CapabilityFlags::CLIENT_PROTOCOL_41
+            | CapabilityFlags::CLIENT_SECURE_CONNECTION
+            | CapabilityFlags::CLIENT_CONNECT_WITH_DB
+            | CapabilityFlags::CLIENT_SESSION_TRACK;
+        let handshake = HandshakeV10 {
+            protocol_version: 10,
+            server_version: "10.5.8-MariaDB".to_string(),
+            connection_id: 1,
+            auth_plugin_data: vec![1; 20],
+            capabilities,
+            charset,
+            status: StatusFlags::SERVER_STATUS_AUTOCOMMIT,
+            auth_plugin_name: None,
+        };
+        let response = HandshakeResponse41 {
+            capabilities,
+            max_packet_size: 0,
+            charset,
+            username: "alice".to_string(),
+            auth_response: vec![2; 20],
+            database: Some("app".to_string()),
+            auth_plugin_name: None,
+            connect_attrs: Vec::new(),
+        };
+        exchange(&mut context, Direction::Backward, handshake.to_packet());
+        exchange(&mut context, Direction::Forward, response.to_packet(1));
+        exchange(&mut context, Direction::Backward, ok(2, &[]));
+        context
+    }
+
+    /// An OK packet with session state changes
+    fn ok(sequence_id: u8, tracks: &[u8]) -> Packet {
+        let mut payload = vec![0x00, 0x00, 0x00, 0x02, 0x40, 0x00, 0x00, 0x00];
+        write_lenenc_bytes(&mut payload, tracks);
+        Packet::new_mariadb(sequence_id, &payload)
+    }
+
+    #[test]
+    fn follows_mariadb_session() {
+        let mut context = connect_mariadb(45);
+        exchange(
+            &mut context,
+            Direction::Forward,
+            Packet::new_mariadb(0, b"\x03SET SESSION sql_mode = 'ANSI'"),
+        );
+        exchange(&mut context, Direction::Backward, ok(1, &[]));
+        exchange(
+            &mut context,
+            Direction::Forward,
+            Packet::new_mariadb(0, b"\x02other"),
+        );
+        exchange(
+            &mut context,
+            Direction::Backward,
+            Packet::new_mariadb(1, b"\xff\x19\x04#42000Unknown database 'other'"),
+        );
+        // The server reports a variable changed by a stored procedure
+        let mut track = Vec::new();
+        write_lenenc_bytes(&mut track, b"time_zone");
+        write_lenenc_bytes(&mut track, b"+02:00");
+        let mut tracks = vec![0x00];
+        write_lenenc_bytes(&mut tracks, &track);
+        exchange(
+            &mut context,
+            Direction::Forward,
+            Packet::new_mariadb(0, b"\x03CALL p()"),
+        );
+        exchange(&mut context, Direction::Backward, ok(1, &tracks));
+        let session = context.get_session();
+        assert_eq!(session.get_user(), Some("alice"));
+        assert_eq!(session.get_database(), Some("app"));
+        assert_eq!(session.get_variable("SQL_MODE"), Some("ANSI"));
+        assert_eq!(session.get_variable("time_zone"), Some("+02:00"));
+
+        exchange(
+            &mut context,
+            Direction::Forward,
+            Packet::new_mariadb(0, b"\x1f"),
+        );
+        exchange(&mut context, Direction::Backward, ok(1, &[]));
+        assert!(context.get_session().get_variables().is_empty());
+        assert_eq!(context.get_session().get_database(), Some("app"));
+    }
+
+    #[test]
+    fn applies_accepted_mariadb_changes() {
+        // Commands sent in turn, each with whether the server accepts it, then the expected
+        // database and variables
+        type Case = (
+            &'static [(&'static [u8], bool)],
+            &'static str,
+            &'static [(&'static str, Option<&'static str>)],
+        );
+        let cases: &[Case] = &[
+            (
+                &[(
+                    b"\x03SET a = 1, b = 'x', @@session.c := 2, @@global.d = 3",
+                    true,
+                )],
+                "app",
+                &[
+                    ("a", Some("1")),
+                    ("b", Some("x")),
+                    ("c", Some("2")),
+                    ("d", None),
+                ],
+            ),
+            (
+                &[
+                    (b"\x03SET SESSION a = 1", true),
+                    (b"\x03SET LOCAL b = 2", true),
+                ],
+                "app",
+                &[("a", Some("1")), ("b", Some("2"))],
+            ),
+            (
+                &[(b"\x03SET `Wait_Timeout` = \"10\", sql_mode = 'it''s'", true)],
+                "app",
+                &[("wait_timeout", Some("10")), ("SQL_MODE", Some("it's"))],
+            ),
+            (&[(b"\x03USE `app db`", true)], "app db", &[]),
+            (&[(b"\x03USE other", false)], "app", &[]),
+            // COM_INIT_DB in the connection's latin1
+            (&[(b"\x02caf\xe9", true)], "caf\u{e9}", &[]),
+            (
+                &[
+                    (b"\x03SET a = 1", true),
+                    (b"\x03SET a = 2, b = 'oops'", false),
+                ],
+                "app",
+                &[("a", Some("1")), ("b", None)],
+            ),
+            // Session variables are not transactional
+            (
+                &[
+                    (b"\x03BEGIN", true),
+                    (b"\x03SET a = 3", true),
+                    (b"\x03ROLLBACK", true),
+                ],
+                "app",
+                &[("a", Some("3"))],
+            ),
+        ];
+        for (commands, database, variables) in cases {
+            let mut context = connect_mariadb(8);
+            for (payload, accepted) in commands.iter() {
+                exchange(
+                    &mut context,
+                    Direction::Forward,
+                    Packet::new_mariadb(0, payload),
+                );
+                let answer = if *accepted {
+                    ok(1, &[])
+                } else {
+                    Packet::new_mariadb(1, b"\xff\x19\x04#42000Refused")
+                };
+                exchange(&mut context, Direction::Backward, answer);
+            }
+            let session = context.get_session();
+            assert_eq!(session.get_database(), Some(*database), "{:?}", commands);
+            for (name, value) in variables.iter() {
+                assert_eq!(session.get_variable(name), *value, "{:?}", commands);
+            }
+        }
+    }
+
+    #[test]
+    fn follows_postgres_session() {
+        let mut context = Context::new("127.0.0.1:1234".to_string(), DatabaseType::PostgresSQL);
+        let query = |sql: &str| FrontendMessage::Query(sql.to_string()).to_packet();
+        let complete = |tag: &str| BackendMessage::CommandComplete(tag.to_string()).to_packet();
+        exchange(
+            &mut context,
+            Direction::Forward,
+            StartupMessage::new("bob").to_packet(),
+        );
+        exchange(
+            &mut context,
+            Direction::Backward,
+            BackendMessage::ParameterStatus {
+                name: "TimeZone".to_string(),
+                value: "UTC".to_string(),
+            }
+            .to_packet(),
+        );
+        exchange(
+            &mut context,
+            Direction::Forward,
+            query("SET work_mem = '64MB'"),
+        );
+        exchange(
+            &mut context,
+            Direction::Forward,
+            query("SET statement_timeout = 'x'"),
+        );
+        exchange(&mut context, Direction::Backward, complete("SET"));
+        exchange(
+            &mut context,
+            Direction::Backward,
+            BackendMessage::ReadyForQuery(b'I').to_packet(),
+        );
+        let session = context.get_session();
+        assert_eq!(session.get_user(), Some("bob"));
+        assert_eq!(session.get_database(), Some("bob"));
+        assert_eq!(session.get_variable("work_mem"), Some("64MB"));
+        assert_eq!(session.get_variable("statement_timeout"), None);
+        assert_eq!(session.get_variable("timezone"), Some("UTC"));
+        assert_eq!(session.get_parameters()["TimeZone"], "UTC");
+
+        exchange(&mut context, Direction::Forward, query("DISCARD ALL"));
+        exchange(&mut context, Direction::Backward, complete("DISCARD ALL"));
+        assert!(context.get_session().get_variables().is_empty());
+    }
+}
@@ -0,0 +1,1065 @@
+//! SQL syntax trees of queries, parsed in the dialect of the proxied database, with helpers
+//! for the kind of a statement, the tables and columns it references, and whether it writes
+
+use sqlparser::{
+    ast::{
+        AssignmentTarget, CopySource, Expr, Ident, ObjectName, ObjectType, Query, SetExpr, Visit,
+        Visitor,
+    },
+    dialect::{Dialect, MySqlDialect, PostgreSqlDialect},
+    parser::Parser,
+};
+use std::{
+    io::{Error, ErrorKind},
+    ops::ControlFlow,
+};
+
+use crate::{packet::DatabaseType, session::SessionState};
+
+pub use sqlparser::ast::Statement;
+
+/// Parse SQL text, which may hold several statements separated by semicolons
+pub fn parse(db_type: DatabaseType, sql: &str) -> Result<Vec<Statement>, Error> {
+    Parser::parse_sql(dialect(db_type).as_ref(), sql)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Invalid SQL: {}", e)))
+}
+
+/// The parser dialect of a database: MySQL for MariaDB, and PostgreSQL
+pub fn dialect(db_type: DatabaseType) -> Box<dyn Dialect> {
+    match db_type {
+        DatabaseType::MariaDB => Box::new(MySqlDialect {}),
+        DatabaseType::PostgresSQL => Box::new(PostgreSqlDialect {}),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum StatementKind {
+    /// A query: SELECT, VALUES, TABLE, or a set operation of them
+    Select,
+    Insert,
+    Update,
+    Delete,
+    Merge,
+    Copy,
+    Create,
+    Alter,
+    Drop,
+    Truncate,
+    /// BEGIN, COMMIT, ROLLBACK, savepoints and SET TRANSACTION
+    Transaction,
+    /// SET, USE, SET ROLE, DISCARD and other changes of the session state
+    Session,
+    /// SHOW and DESCRIBE
+    Show,
+    Explain,
+    /// PREPARE, EXECUTE and DEALLOCATE
+    Prepared,
+    Call,
+    Other,
+}
+
+impl StatementKind {
+    pub fn of(statement: &Statement) -> StatementKind {
+        match statement {
+            Statement::Query(_) => StatementKind::Select,
+            Statement::Insert(_) | Statement::LoadData { .. } => StatementKind::Insert,
+            Statement::Update { .. } => StatementKind::Update,
+            Statement::Delete(_) => StatementKind::Delete,
+            Statement::Merge { .. } => StatementKind::Merge,
+            Statement::Copy { .. } => StatementKind::Copy,
+            Statement::CreateView { .. }
+            | Statement::CreateTable(_)
+            | Statement::CreateVirtualTable { .. }
+            | Statement::CreateIndex(_)
+            | Statement::CreateRole { .. }
+            | Statement::CreateSchema { .. }
+            | Statement::CreateDatabase { .. }
+            | Statement::CreateFunction(_)
+            | Statement::CreateTrigger { .. }
+            | Statement::CreateProcedure { .. }
+            | Statement::CreateSequence { .. }
+            | Statement::CreateType { .. }
+            | Statement::CreateExtension { .. }
+            | Statement::CreatePolicy { .. } => StatementKind::Create,
+            Statement::AlterTable { .. }
+            | Statement::AlterIndex { .. }
+            | Statement::AlterView { .. }
+            | Statement::AlterRole { .. }
+            | Statement::AlterPolicy { .. } => StatementKind::Alter,
+            Statement::Drop { .. }
+            | Statement::DropFunction { .. }
+            | Statement::DropProcedure { .. }
+            | Statement::DropTrigger { .. }
+            | Statement::DropPolicy { .. } => StatementKind::Drop,
+            Statement::Truncate { .. } => StatementKind::Truncate,
+            Statement::StartTransaction { .. }
+            | Statement::SetTransaction { .. }
+            | Statement::Commit { .. }
+            | Statement::Rollback { .. }
+            | Statement::Savepoint { .. }
+            | Statement::ReleaseSavepoint { .. } => StatementKind::Transaction,
+            Statement::SetVariable { .. }
+            | Statement::SetRole { .. }
+            | Statement::SetTimeZone { .. }
+            | Statement::SetNames { .. }
+            | Statement::SetNamesDefault { .. }
+            | Statement::Use(_)
+            | Statement::Discard { .. } => StatementKind::Session,
+            Statement::ShowFunctions { .. }
+            | Statement::ShowVariable { .. }
+            | Statement::ShowStatus { .. }
+            | Statement::ShowVariables { .. }
+            | Statement::ShowCreate { .. }
+            | Statement::ShowColumns { .. }
+            | Statement::ShowDatabases { .. }
+            | Statement::ShowSchemas { .. }
+            | Statement::ShowTables { .. }
+            | Statement::ShowViews { .. }
+            | Statement::ShowCollation { .. }
+            | Statement::ExplainTable { .. } => StatementKind::Show,
+            Statement::Explain { .. } => StatementKind::Explain,
+            Statement::Prepare { .. }
+            | Statement::Execute { .. }
+            | Statement::Deallocate { .. } => StatementKind::Prepared,
+            Statement::Call(_) => StatementKind::Call,
+            _ => StatementKind::Other,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StatementKind::Select => "select",
+            StatementKind::Insert => "insert",
+            StatementKind::Update => "update",
+            StatementKind::Delete => "delete",
+            StatementKind::Merge => "merge",
+            StatementKind::Copy => "copy",
+            StatementKind::Create => "create",
+            StatementKind::Alter => "alter",
+            StatementKind::Drop => "drop",
+            StatementKind::Truncate => "truncate",
+            StatementKind::Transaction => "transaction",
+            StatementKind::Session => "session",
+            StatementKind::Show => "show",
+            StatementKind::Explain => "explain",
+            StatementKind::Prepared => "prepared",
+            StatementKind::Call => "call",
+            StatementKind::Other => "other",
+        }
+    }
+}
+
+/// Whether a statement may modify data or schema. Queries write when they contain a
+/// data-modifying CTE, SELECT INTO or a locking clause. Procedure calls, EXECUTE and
+/// statements of an unknown kind count as writes, while transaction control and session
+/// statements do not.
+pub fn is_write(statement: &Statement) -> bool {
+    match statement {
+        Statement::Query(_) => {
+            let mut writes = WriteFinder;
+            statement.visit(&mut writes).is_break()
+        }
+        Statement::Explain {
+            analyze, statement, ..
+        } => *analyze && is_write(statement),
+        Statement::Prepare { statement, .. } => is_write(statement),
+        Statement::Deallocate { .. } => false,
+        Statement::Copy { to, .. } => !to,
+        _ => !matches!(
+            StatementKind::of(statement),
+            StatementKind::Transaction | StatementKind::Session | StatementKind::Show
+        ),
+    }
+}
+
+/// Whether a statement only reads data: a query, SHOW or EXPLAIN that does not write
+pub fn is_read_only(statement: &Statement) -> bool {
+    matches!(
+        StatementKind::of(statement),
+        StatementKind::Select | StatementKind::Show | StatementKind::Explain
+    ) && !is_write(statement)
+}
+
+/// Tables referenced by a statement, in order of appearance and without duplicates. Names
+/// are unquoted and keep their schema qualification, and CTEs are left out.
+pub fn get_tables(statement: &Statement) -> Vec<String> {
+    let mut finder = TableFinder::default();
+    let _ = statement.visit(&mut finder);
+    let TableFinder { tables, ctes } = finder;
+    tables
+        .into_iter()
+        .filter(|table| !ctes.contains(table))
+        .collect()
+}
+
+/// Columns referenced by a statement, in order of appearance and without duplicates.
+/// Qualified columns keep their qualification, such as `u.name`.
+pub fn get_columns(statement: &Statement) -> Vec<String> {
+    let mut finder = ColumnFinder::default();
+    let _ = statement.visit(&mut finder);
+    finder.columns
+}
+
+/// How the SQL text of a connection is read, which its session can change
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Syntax {
+    pub db_type: DatabaseType,
+    /// Backslashes escape characters in '…' strings, and in MariaDB "…" strings. PostgresSQL
+    /// E'…' strings always allow them.
+    pub backslash_escapes: bool,
+}
+
+impl Syntax {
+    /// The syntax of a new connection: MariaDB allows backslash escapes, and PostgresSQL
+    /// strings conform to the standard
+    pub fn new(db_type: DatabaseType) -> Syntax {
+        Syntax {
+            db_type,
+            backslash_escapes: db_type == DatabaseType::MariaDB,
+        }
+    }
+
+    /// The syntax of a connection whose session may have turned backslash escapes off with
+    /// MariaDB's NO_BACKSLASH_ESCAPES sql_mode, or on with PostgresSQL's
+    /// standard_conforming_strings
+    pub fn of_session(db_type: DatabaseType, session: &SessionState) -> Syntax {
+        let backslash_escapes = match db_type {
+            DatabaseType::MariaDB => !session.get_variable("sql_mode").is_some_and(|mode| {
+                mode.split(',')
+                    .any(|m| m.trim().eq_ignore_ascii_case("no_backslash_escapes"))
+            }),
+            DatabaseType::PostgresSQL => session
+                .get_variable("standard_conforming_strings")
+                .is_some_and(|value| value.eq_ignore_ascii_case("off")),
+        };
+        Syntax {
+            db_type,
+            backslash_escapes,
+        }
+    }
+}
+
+impl From<DatabaseType> for Syntax {
+    fn from(db_type: DatabaseType) -> Syntax {
+        Syntax::new(db_type)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenKind {
+    /// Keyword or unquoted identifier. MariaDB variables such as `@a` and `@@x` are words.
+    Word,
+    /// Quoted identifier, "…" or MariaDB `…`
+    Identifier,
+    /// String constant: '…', dollar quoted, MariaDB "…", or with a prefix such as E'…',
+    /// X'…' or _utf8mb4'…'
+    String,
+    Number,
+    /// Parameter placeholder, `?` or PostgresSQL `$1`
+    Parameter,
+    /// Any other character. Operators are a token per character.
+    Symbol,
+}
+
+/// A token of SQL text. Whitespace and comments are not tokens.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Token<'a> {
+    pub kind: TokenKind,
+    /// The token as written
+    pub text: &'a str,
+    /// Byte offset of the token in the SQL text
+    pub start: usize,
+    /// Backslashes escape characters in this string
+    escapes: bool,
+}
+
+impl Token<'_> {
+    /// Byte offset after the token in the SQL text
+    pub fn end(&self) -> usize {
+        self.start + self.text.len()
+    }
+
+    /// Whether the token is this keyword, in any case
+    pub fn is(&self, keyword: &str) -> bool {
+        self.kind == TokenKind::Word && self.text.eq_ignore_ascii_case(keyword)
+    }
+
+    /// The content of a string constant or quoted identifier, without its quotes and with
+    /// its escapes resolved. The text of other tokens.
+    pub fn value(&self) -> String {
+        match self.kind {
+            TokenKind::Identifier => unquote(&self.text[1..], self.text.as_bytes()[0], false),
+            TokenKind::String if self.text.starts_with('$') => {
+                let tag_len = self.text[1..].find('$').map_or(self.text.len(), |n| n + 2);
+                let (tag, content) = self.text.split_at(tag_len);
+                content.strip_suffix(tag).unwrap_or(content).to_string()
+            }
+            TokenKind::String => match self.text.find(['\'', '"']) {
+                Some(quote) => unquote(
+                    &self.text[quote + 1..],
+                    self.text.as_bytes()[quote],
+                    self.escapes,
+                ),
+                None => self.text.to_string(),
+            },
+            _ => self.text.to_string(),
+        }
+    }
+}
+
+/// Split SQL text into tokens, skipping whitespace and comments. Unterminated quotes and
+/// comments run to the end of the text.
+pub fn tokenize<S: Into<Syntax>>(syntax: S, sql: &str) -> Vec<Token<'_>> {
+    let syntax = syntax.into();
+    let mariadb = syntax.db_type == DatabaseType::MariaDB;
+    let bytes = sql.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = sql[i..].chars().next().unwrap_or_default();
+        let next = bytes.get(i + 1).copied();
+        let mut escapes = false;
+        let (kind, end) = match c {
+            _ if c.is_whitespace() => {
+                i += c.len_utf8();
+                continue;
+            }
+            '-' if next == Some(b'-') => {
+                i = line_end(bytes, i);
+                continue;
+            }
+            '#' if mariadb => {
+                i = line_end(bytes, i);
+                continue;
+            }
+            '/' if next == Some(b'*') => {
+                i = comment_end(bytes, i + 2, !mariadb);
+                continue;
+            }
+            '\'' => {
+                escapes = syntax.backslash_escapes;
+                (TokenKind::String, quote_end(bytes, i + 1, b'\'', escapes))
+            }
+            '"' if mariadb => {
+                escapes = syntax.backslash_escapes;
+                (TokenKind::String, quote_end(bytes, i + 1, b'"', escapes))
+            }
+            '"' => (TokenKind::Identifier, quote_end(bytes, i + 1, b'"', false)),
+            '`' if mariadb => (TokenKind::Identifier, quote_end(bytes, i + 1, b'`', false)),
+            '$' if !mariadb && next.is_some_and(|b| b.is_ascii_digit()) => {
+                let end = (i + 1..bytes.len())
+                    .find(|j| !bytes[*j].is_ascii_digit())
+                    .unwrap_or(bytes.len());
+                (TokenKind::Parameter, end)
+            }
+            '$' if !mariadb => match dollar_quote_end(bytes, i) {
+                Some(end) => (TokenKind::String, end),
+                None => (TokenKind::Symbol, i + 1),
+            },
+            '?' => (TokenKind::Parameter, i + 1),
+            _ if c.is_ascii_digit() || (c == '.' && next.is_some_and(|b| b.is_ascii_digit())) => {
+                (TokenKind::Number, number_end(bytes, i))
+            }
+            _ if is_word_start(c, mariadb) => {
+                let end = sql[i..]
+                    .find(|c| !is_word_char(c, mariadb))
+                    .map_or(bytes.len(), |n| i + n);
+                let word = &sql[i..end];
+                if bytes.get(end) == Some(&b'\'') && is_string_prefix(word) {
+                    // PostgresSQL only treats backslashes as escapes in E'…' strings
+                    escapes = syntax.backslash_escapes || word.eq_ignore_ascii_case("e");
+                    (TokenKind::String, quote_end(bytes, end + 1, b'\'', escapes))
+                } else {
+                    (TokenKind::Word, end)
+                }
+            }
+            _ => (TokenKind::Symbol, i + c.len_utf8()),
+        };
+        tokens.push(Token {
+            kind,
+            text: &sql[i..end],
+            start: i,
+            escapes,
+        });
+        i = end;
+    }
+    tokens
+}
+
+/// Split a batch of statements at the semicolons separating them, ignoring those in quotes,
+/// dollar quotes, comments, parentheses and the BEGIN … END bodies of routines, triggers and
+/// events. Statements are trimmed, and empty ones left out. Unlike `parse`, any text can be
+/// split.
+pub fn split<S: Into<Syntax>>(syntax: S, sql: &str) -> Vec<&str> {
+    let mut statements = Vec::new();
+    let mut start = 0;
+    let mut body = Body::default();
+    for token in tokenize(syntax, sql) {
+        if body.token(&token) {
+            push_statement(&mut statements, &sql[start..token.start]);
+            start = token.end();
+            body = Body::default();
+        }
+    }
+    push_statement(&mut statements, &sql[start..]);
+    statements
+}
+
+/// Where the statement being split is. Semicolons only end the statements of a body once it
+/// starts with BEGIN, so the words before it never count as blocks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum Part {
+    /// Before the first word
+    #[default]
+    Start,
+    /// After CREATE, before the kind of object created
+    Create,
+    /// The name and parameters of a procedure or function
+    RoutineHeader,
+    /// A trigger up to FOR EACH ROW
+    TriggerHeader,
+    /// An event up to DO
+    EventHeader,
+    /// Characteristics and return type of a routine, up to its body
+    Characteristics,
+    /// The BEGIN … END body
+    Block,
+    /// Any other statement, or a body of a single statement
+    Plain,
+}
+
+/// Words allowed between the header of a routine and its BEGIN, other than names
+const CHARACTERISTICS: &[&str] = &[
+    "not",
+    "deterministic",
+    "contains",
+    "sql",
+    "no",
+    "reads",
+    "modifies",
+    "data",
+    "security",
+    "definer",
+    "invoker",
+    "comment",
+    "immutable",
+    "stable",
+    "volatile",
+    "strict",
+    "called",
+    "on",
+    "null",
+    "input",
+    "leakproof",
+    "parallel",
+    "safe",
+    "unsafe",
+    "restricted",
+    "cost",
+    "rows",
+    "window",
+    "external",
+    "unsigned",
+    "signed",
+    "zerofill",
+    "character",
+    "varying",
+    "precision",
+    "with",
+    "without",
+    "time",
+    "zone",
+    "binary",
+];
+
+/// Nesting of the statement being split
+#[derive(Default)]
+struct Body {
+    part: Part,
+    /// Depth of BEGIN and CASE blocks of the body
+    depth: i32,
+    /// An END was read, the next word tells which block it closes
+    end: bool,
+    parens: i32,
+    /// The next word is a name or a type, like the one after RETURNS
+    name: bool,
+    /// The previous token, lowercased
+    previous: String,
+}
+
+impl Body {
+    /// Follow a token. Returns whether it is a semicolon ending the statement.
+    fn token(&mut self, token: &Token) -> bool {
+        let text = token.text.to_ascii_lowercase();
+        let ends = match token.kind {
+            TokenKind::Word => {
+                self.word(&text);
+                false
+            }
+            TokenKind::Symbol => self.symbol(&text),
+            _ => false,
+        };
+        self.previous = text;
+        ends
+    }
+
+    fn symbol(&mut self, symbol: &str) -> bool {
+        match symbol {
+            "(" => self.parens += 1,
+            ")" => {
+                self.parens -= 1;
+                if self.part == Part::RoutineHeader && self.parens == 0 {
+                    self.part = Part::Characteristics;
+                }
+            }
+            // The rest of a qualified name
+            "." if self.part == Part::Characteristics => self.name = true,
+            ";" => return self.ends_statement(),
+            _ => {}
+        }
+        false
+    }
+
+    fn word(&mut self, word: &str) {
+        // Column lists, parameters and types
+        if self.parens > 0 && self.part != Part::Block {
+            return;
+        }
+        match self.part {
+            Part::Start if word == "create" => self.part = Part::Create,
+            Part::Start => self.part = Part::Plain,
+            Part::Create => match word {
+                "procedure" | "function" => self.part = Part::RoutineHeader,
+                "trigger" => self.part = Part::TriggerHeader,
+                "event" => self.part = Part::EventHeader,
+                "table" | "view" | "index" | "database" | "schema" | "sequence" | "type"
+                | "role" | "user" | "server" | "tablespace" | "extension" => {
+                    self.part = Part::Plain
+                }
+                _ => {}
+            },
+            Part::TriggerHeader if word == "row" && self.previous == "each" => {
+                self.part = Part::Characteristics
+            }
+            Part::EventHeader if word == "do" => self.part = Part::Characteristics,
+            Part::Characteristics => {
+                if std::mem::take(&mut self.name) {
+                    return;
+                }
+                match word {
+                    "begin" => {
+                        self.part = Part::Block;
+                        self.depth = 1;
+                    }
+                    "returns" | "language" | "charset" | "collate" | "setof" | "follows"
+                    | "precedes" => self.name = true,
+                    "set" if self.previous == "character" => self.name = true,
+                    _ if CHARACTERISTICS.contains(&word) => {}
+                    _ => self.part = Part::Plain,
+                }
+            }
+            Part::Block => self.block_word(word),
+            _ => {}
+        }
+    }
+
+    fn block_word(&mut self, word: &str) {
+        if std::mem::take(&mut self.end) {
+            match word {
+                // END IF and other blocks that are not counted
+                "if" | "loop" | "while" | "repeat" | "for" => return,
+                "case" => {
+                    self.depth -= 1;
+                    return;
+                }
+                _ => self.depth -= 1,
+            }
+            if self.depth <= 0 {
+                // A label after the END of the body
+                self.part = Part::Plain;
+                return;
+            }
+        }
+        match word {
+            // BEGIN starts a block where a statement starts, and may be a name elsewhere
+            "begin"
+                if matches!(
+                    self.previous.as_str(),
+                    ";" | ":" | "begin" | "then" | "else" | "do" | "loop" | "repeat"
+                ) =>
+            {
+                self.depth += 1
+            }
+            "case" => self.depth += 1,
+            "end" => self.end = true,
+            _ => {}
+        }
+    }
+
+    fn ends_statement(&mut self) -> bool {
+        if std::mem::take(&mut self.end) {
+            self.depth -= 1;
+        }
+        self.depth <= 0 && self.parens <= 0
+    }
+}
+
+fn push_statement<'a>(statements: &mut Vec<&'a str>, statement: &'a str) {
+    let statement = statement.trim();
+    if !statement.is_empty() {
+        statements.push(statement);
+    }
+}
+
+fn is_word_start(c: char, mariadb: bool) -> bool {
+    c.is_alphabetic() || c == '_' || (mariadb && c == '@')
+}
+
+fn is_word_char(c: char, mariadb: bool) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '$' || (mariadb && c == '@')
+}
+
+/// Words that make the string directly after them part of the same constant: E'…', N'…',
+/// X'…', B'…' and charset introducers such as _utf8mb4'…'
+fn is_string_prefix(word: &str) -> bool {
+    ["e", "n", "x", "b"]
+        .iter()
+        .any(|prefix| word.eq_ignore_ascii_case(prefix))
+        || word.starts_with('_')
+}
+
+fn line_end(bytes: &[u8], i: usize) -> usize {
+    (i..bytes.len())
+        .find(|j| bytes[*j] == b'\n')
+        .map_or(bytes.len(), |j| j + 1)
+}
+
+/// End of a `/* … */` comment starting before `i`. PostgresSQL comments nest.
+fn comment_end(bytes: &[u8], mut i: usize, nested: bool) -> usize {
+    let mut depth = 1;
+    while i < bytes.len() {
+        match (bytes[i], bytes.get(i + 1)) {
+            (b'*', Some(b'/')) => {
+                depth -= 1;
+                i += 2;
+                if depth == 0 {
+                    return i;
+                }
+            }
+            (b'/', Some(b'*')) if nested => {
+                depth += 1;
+                i += 2;
+            }
+            _ => i += 1,
+        }
+    }
+    bytes.len()
+}
+
+/// End of a quoted string or identifier starting before `i`. A doubled quote stands for
+/// itself.
+fn quote_end(bytes: &[u8], mut i: usize, quote: u8, escape: bool) -> usize {
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && escape {
+            i += 2;
+        } else if bytes[i] == quote {
+            if bytes.get(i + 1) != Some(&quote) {
+                return i + 1;
+            }
+            i += 2;
+        } else {
+            i += 1;
+        }
+    }
+    bytes.len()
+}
+
+/// Content of a quoted string or identifier after its opening quote
+fn unquote(quoted: &str, quote: u8, escape: bool) -> String {
+    let quote = char::from(quote);
+    let mut content = String::with_capacity(quoted.len());
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' && escape {
+            content.extend(chars.next().map(|escaped| match escaped {
+                '0' => '\0',
+                'b' => '\x08',
+                'f' => '\x0c',
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                'Z' => '\x1a',
+                _ => escaped,
+            }));
+        } else if c != quote {
+            content.push(c);
+        } else if chars.as_str().starts_with(quote) {
+            content.push(quote);
+            chars.next();
+        } else {
+            break;
+        }
+    }
+    content
+}
+
+/// End of a PostgresSQL dollar-quoted string such as `$tag$ … $tag$` starting at `i`, or
+/// None if the `$` does not start one
+fn dollar_quote_end(bytes: &[u8], i: usize) -> Option<usize> {
+    let tag_end = (i + 1..bytes.len()).find(|j| bytes[*j] == b'$')?;
+    let tag = &bytes[i + 1..tag_end];
+    let valid = tag.iter().enumerate().all(|(k, b)| {
+        *b == b'_' || b.is_ascii_alphabetic() || *b >= 0x80 || (k > 0 && b.is_ascii_digit())
+    });
+    if !valid {
+        return None;
+    }
+    let delimiter = &bytes[i..=tag_end];
+    let end = (tag_end + 1..bytes.len())
+        .find(|j| bytes[*j..].starts_with(delimiter))
+        .map_or(bytes.len(), |j| j + delimiter.len());
+    Some(end)
+}
+
+/// End of a number starting at `i`, including hexadecimal ones and exponents
+fn number_end(bytes: &[u8], i: usize) -> usize {
+    let mut last = b' ';
+    let mut end = i;
+    while let Some(b) = bytes.get(end).copied().filter(|b| {
+        b.is_ascii_alphanumeric()
+            || *b == b'.'
+            || *b == b'_'
+            || ((*b == b'-' || *b == b'+') && (last == b'e' || last == b'E'))
+    }) {
+        last = b;
+        end += 1;
+    }
+    end
+}
+
+fn object_name(name: &ObjectName) -> String {
+    idents_name(&name.0)
+}
+
+fn idents_name(idents: &[Ident]) -> String {
+    idents
+        .iter()
+        .map(|ident| ident.value.as_str())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+fn push_unique(names: &mut Vec<String>, name: String) {
+    if !names.contains(&name) {
+        names.push(name);
+    }
+}
+
+/// Stops at the first part of a query that writes
+struct WriteFinder;
+
+impl Visitor for WriteFinder {
+    type Break = ();
+
+    fn pre_visit_query(&mut self, query: &Query) -> ControlFlow<()> {
+        let into = match query.body.as_ref() {
+            SetExpr::Select(select) => select.into.is_some(),
+            _ => false,
+        };
+        if into || !query.locks.is_empty() {
+            return ControlFlow::Break(());
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_statement(&mut self, statement: &Statement) -> ControlFlow<()> {
+        match statement {
+            Statement::Query(_) => ControlFlow::Continue(()),
+            _ if is_write(statement) => ControlFlow::Break(()),
+            _ => ControlFlow::Continue(()),
+        }
+    }
+}
+
+#[derive(Default)]
+struct TableFinder {
+    tables: Vec<String>,
+    ctes: Vec<String>,
+}
+
+impl Visitor for TableFinder {
+    type Break = ();
+
+    fn pre_visit_query(&mut self, query: &Query) -> ControlFlow<()> {
+        for cte in query.with.iter().flat_map(|with| with.cte_tables.iter()) {
+            push_unique(&mut self.ctes, cte.alias.name.value.clone());
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_relation(&mut self, relation: &ObjectName) -> ControlFlow<()> {
+        push_unique(&mut self.tables, object_name(relation));
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_statement(&mut self, statement: &Statement) -> ControlFlow<()> {
+        // Names the visitor does not report as relations
+        match statement {
+            Statement::Drop {
+                object_type: ObjectType::Table,
+                names,
+                ..
+            } => {
+                for name in names {
+                    push_unique(&mut self.tables, object_name(name));
+                }
+            }
+            Statement::Truncate { table_names, .. } => {
+                for target in table_names {
+                    push_unique(&mut self.tables, object_name(&target.name));
+                }
+            }
+            Statement::Copy {
+                source: CopySource::Table { table_name, .. },
+                ..
+            } => push_unique(&mut self.tables, object_name(table_name)),
+            _ => {}
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+#[derive(Default)]
+struct ColumnFinder {
+    columns: Vec<String>,
+}
+
+impl Visitor for ColumnFinder {
+    type Break = ();
+
+    fn pre_visit_expr(&mut self, expr: &Expr) -> ControlFlow<()> {
+        match expr {
+            Expr::Identifier(ident) => push_unique(&mut self.columns, ident.value.clone()),
+            Expr::CompoundIdentifier(idents) => push_unique(&mut self.columns, idents_name(idents)),
+            _ => {}
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_statement(&mut self, statement: &Statement) -> ControlFlow<()> {
+        // Columns written to are not expressions
+        match statement {
+            Statement::Insert(insert) => {
+                for column in &insert.columns {
+                    push_unique(&mut self.columns, column.value.clone());
+                }
+            }
+            Statement::Update { assignments, .. } => {
+                for assignment in assignments {
+                    let names = match &assignment.target {
+                        AssignmentTarget::ColumnName(name) => std::slice::from_ref(name),
+                        AssignmentTarget::Tuple(names) => names.as_slice(),
+                    };
+                    for name in names {
+                        push_unique(&mut self.columns, object_name(name));
+                    }
+                }
+            }
+            Statement::Copy {
+                source: CopySource::Table { columns, .. },
+                ..
+            } => {
+                for column in columns {
+                    push_unique(&mut self.columns, column.value.clone());
+                }
+            }
+            _ => {}
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_one(db_type: DatabaseType, sql: &str) -> Statement {
+        let mut statements = parse(db_type, sql).unwrap();
+        assert_eq!(statements.len(), 1);
+        statements.remove(0)
+    }
+
+    #[test]
+    fn classifies_statements() {
+        let postgres = |sql| parse_one(DatabaseType::PostgresSQL, sql);
+        let select = postgres(
+            "/* report */ WITH recent AS (SELECT * FROM orders WHERE created > now() - interval '1 day')
+             select o.id, c.name from recent o join public.customers c on c.id = o.customer_id",
+        );
+        assert_eq!(StatementKind::of(&select), StatementKind::Select);
+        assert!(is_read_only(&select));
+        assert_eq!(get_tables(&select), vec!["orders", "public.customers"]);
+        assert_eq!(
+            get_columns(&select),
+            vec!["created", "o.id", "c.name", "c.id", "o.customer_id"]
+        );
+
+        let locking = postgres("SELECT id FROM jobs LIMIT 1 FOR UPDATE SKIP LOCKED");
+        assert!(is_write(&locking));
+        let writable_cte = postgres(
+            "WITH moved AS (UPDATE jobs SET done = true RETURNING id) SELECT count(*) FROM moved",
+        );
+        assert_eq!(StatementKind::of(&writable_cte), StatementKind::Select);
+        assert!(is_write(&writable_cte));
+
+        let explain = postgres("EXPLAIN ANALYZE DELETE FROM jobs WHERE done");
+        assert_eq!(StatementKind::of(&explain), StatementKind::Explain);
+        assert!(is_write(&explain));
+        assert!(!is_write(&postgres("EXPLAIN DELETE FROM jobs")));
+
+        let begin = postgres("BEGIN");
+        assert_eq!(StatementKind::of(&begin), StatementKind::Transaction);
+        assert!(!is_write(&begin) && !is_read_only(&begin));
+        assert!(is_write(&postgres("COPY jobs FROM '/tmp/jobs.csv'")));
+        assert!(!is_write(&postgres("COPY jobs (id) TO STDOUT")));
+        assert!(parse(DatabaseType::PostgresSQL, "SELEC 1").is_err());
+    }
+
+    #[test]
+    fn splits_batches() {
+        assert_eq!(
+            split(
+                DatabaseType::MariaDB,
+                "SELECT 'a;b', \"c;\\\"d\", `e;f` FROM t; -- x;y\n# z;\nDROP TABLE users;; /* ; */ "
+            ),
+            vec!["SELECT 'a;b', \"c;\\\"d\", `e;f` FROM t", "-- x;y\n# z;\nDROP TABLE users", "/* ; */"]
+        );
+        assert_eq!(
+            split(
+                DatabaseType::MariaDB,
+                "CREATE PROCEDURE p() BEGIN IF x THEN SELECT 1; END IF; SELECT CASE WHEN y THEN 1 END; END; CALL p()"
+            ),
+            vec![
+                "CREATE PROCEDURE p() BEGIN IF x THEN SELECT 1; END IF; SELECT CASE WHEN y THEN 1 END; END",
+                "CALL p()"
+            ]
+        );
+        assert_eq!(
+            split(
+                DatabaseType::PostgresSQL,
+                "SELECT E'\\';', 'x\\'; SELECT \"a;\"\"b\" FROM t$1; \
+                 CREATE FUNCTION f() RETURNS int AS $fn$ SELECT 1; $$ $fn$ LANGUAGE sql; \
+                 /* a /* nested; */ ; */ BEGIN; SELECT $1"
+            ),
+            vec![
+                "SELECT E'\\';', 'x\\'",
+                "SELECT \"a;\"\"b\" FROM t$1",
+                "CREATE FUNCTION f() RETURNS int AS $fn$ SELECT 1; $$ $fn$ LANGUAGE sql",
+                "/* a /* nested; */ ; */ BEGIN",
+                "SELECT $1",
+            ]
+        );
+        assert_eq!(
+            split(
+                DatabaseType::PostgresSQL,
+                "CREATE FUNCTION g() RETURNS int LANGUAGE sql BEGIN ATOMIC SELECT 1; SELECT 2; END"
+            )
+            .len(),
+            1
+        );
+        assert!(split(DatabaseType::PostgresSQL, " ; ").is_empty());
+
+        // BEGIN and CASE only open blocks in the body of a routine, trigger or event
+        for sql in [
+            "CREATE TABLE t (begin int); DROP TABLE users",
+            "CREATE VIEW v AS SELECT 1 AS x, 2 AS begin; DROP TABLE users",
+            "CREATE FUNCTION f(begin INT) RETURNS INT RETURN begin; DROP TABLE users",
+            "SELECT case FROM t; DROP TABLE users",
+        ] {
+            assert_eq!(split(DatabaseType::MariaDB, sql).len(), 2, "{}", sql);
+        }
+        assert_eq!(
+            split(
+                DatabaseType::MariaDB,
+                "CREATE DEFINER=`root`@`%` TRIGGER t BEFORE INSERT ON items FOR EACH ROW \
+                 BEGIN SET NEW.begin = 1; l: BEGIN END l; END; DROP TABLE users"
+            )
+            .len(),
+            2
+        );
+
+        let sql = "SELECT 'a\\'; DROP TABLE users; SELECT '";
+        assert_eq!(split(DatabaseType::MariaDB, sql).len(), 1);
+        let no_backslash_escapes = Syntax {
+            db_type: DatabaseType::MariaDB,
+            backslash_escapes: false,
+        };
+        assert_eq!(
+            split(no_backslash_escapes, sql),
+            vec!["SELECT 'a\\'", "DROP TABLE users", "SELECT '"]
+        );
+    }
+
+    #[test]
+    fn tokenizes() {
+        let tokens = tokenize(
+            DatabaseType::PostgresSQL,
+            "SELECT E'it''s\\n', $q$a'b$q$, \"Co\"\"l\", x::int, $2 -- c\n/* d */>= 1.5e-3",
+        );
+        let kinds: Vec<_> = tokens.iter().map(|t| t.kind).collect();
+        use TokenKind::*;
+        assert_eq!(
+            kinds,
+            vec![
+                Word, String, Symbol, String, Symbol, Identifier, Symbol, Word, Symbol, Symbol,
+                Word, Symbol, Parameter, Symbol, Symbol, Number
+            ]
+        );
+        let values: Vec<_> = tokens.iter().map(Token::value).collect();
+        assert_eq!(values[1], "it's\n");
+        assert_eq!(values[3], "a'b");
+        assert_eq!(values[5], "Co\"l");
+        assert_eq!(tokens[15].text, "1.5e-3");
+        assert!(tokens[0].is("select"));
+
+        let tokens = tokenize(
+            DatabaseType::MariaDB,
+            "SET @@session.x = _utf8mb4'a\\'b', `t``1` # e",
+        );
+        let values: Vec<_> = tokens.iter().map(Token::value).collect();
+        assert_eq!(
+            values,
+            vec!["SET", "@@session", ".", "x", "=", "a'b", ",", "t`1"]
+        );
+    }
+
+    #[test]
+    fn finds_mariadb_tables_and_columns() {
+        let mariadb = |sql| parse_one(DatabaseType::MariaDB, sql);
+        let update =
+            mariadb("UPDATE `shop`.`items` SET price = price * 2, `stock` = 0 WHERE id IN (1, 2)");
+        assert_eq!(StatementKind::of(&update), StatementKind::Update);
+        assert!(is_write(&update));
+        assert_eq!(get_tables(&update), vec!["shop.items"]);
+        assert_eq!(get_columns(&update), vec!["price", "stock", "id"]);
+
+        let insert = mariadb("INSERT INTO items (id, name) SELECT id, label FROM staging");
+        assert_eq!(StatementKind::of(&insert), StatementKind::Insert);
+        assert_eq!(get_tables(&insert), vec!["items", "staging"]);
+        assert_eq!(get_columns(&insert), vec!["id", "name", "label"]);
+
+        let show = mariadb("SHOW TABLES");
+        assert_eq!(StatementKind::of(&show), StatementKind::Show);
+        assert!(is_read_only(&show));
+        let set = mariadb("SET autocommit = 0");
+        assert_eq!(StatementKind::of(&set), StatementKind::Session);
+        let drop = mariadb("DROP TABLE IF EXISTS old_items");
+        assert_eq!(get_tables(&drop), vec!["old_items"]);
+        assert_eq!(
+            parse(DatabaseType::MariaDB, "SELECT 1; SELECT 2")
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+}
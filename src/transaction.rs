@@ -0,0 +1,224 @@
+//! Transaction state of a connection, followed from the server's responses so handlers and
+//! the server know whether the client is inside a transaction
+
+use crate::{
+    mariadb::{
+        self,
+        handshake::{HandshakeV10, StatusFlags},
+        response::{EofPacket, OkPacket},
+    },
+    packet::{Packet, PacketType},
+    postgres::message::BackendMessage,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransactionStatus {
+    /// Not in a transaction block
+    Idle,
+    InTransaction,
+    /// PostgresSQL only: a statement of the transaction failed, and every statement is
+    /// rejected until it ends
+    Failed,
+}
+
+/// Transaction state of a single connection, as last reported by the server
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TransactionState {
+    status: TransactionStatus,
+    /// Statements outside a transaction block are committed at once. PostgresSQL always
+    /// autocommits, MariaDB does unless autocommit is off.
+    autocommit: bool,
+    /// MariaDB only: the transaction was started with START TRANSACTION READ ONLY
+    read_only: bool,
+}
+
+impl Default for TransactionState {
+    fn default() -> TransactionState {
+        TransactionState::new()
+    }
+}
+
+impl TransactionState {
+    pub fn new() -> TransactionState {
+        TransactionState {
+            status: TransactionStatus::Idle,
+            autocommit: true,
+            read_only: false,
+        }
+    }
+
+    pub fn get_status(&self) -> TransactionStatus {
+        self.status
+    }
+
+    /// Whether the client is inside a transaction, failed or not. With autocommit off,
+    /// MariaDB starts one implicitly with the first statement.
+    pub fn in_transaction(&self) -> bool {
+        self.status != TransactionStatus::Idle
+    }
+
+    pub fn is_failed(&self) -> bool {
+        self.status == TransactionStatus::Failed
+    }
+
+    pub fn is_autocommit(&self) -> bool {
+        self.autocommit
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Update the state from a labeled MariaDB packet sent by the server. The server status
+    /// flags come with the handshake, and every OK and EOF packet.
+    pub fn update_mariadb(&mut self, decoder: &mariadb::decoder::Decoder, packet: &Packet) {
+        let status = match packet.get_packet_type() {
+            Ok(PacketType::Handshake) => HandshakeV10::parse(packet).map(|h| h.status),
+            Ok(PacketType::ComOk) => {
+                OkPacket::parse(packet, decoder.get_capabilities()).map(|ok| ok.status)
+            }
+            Ok(PacketType::ComEof) => EofPacket::parse(packet).map(|eof| eof.status),
+            _ => return,
+        };
+        let status = match status {
+            Ok(status) => status,
+            Err(e) => {
+                debug!("Unable to read MariaDB server status: {}", e);
+                return;
+            }
+        };
+        self.status = if status.contains(StatusFlags::SERVER_STATUS_IN_TRANS) {
+            TransactionStatus::InTransaction
+        } else {
+            TransactionStatus::Idle
+        };
+        self.autocommit = status.contains(StatusFlags::SERVER_STATUS_AUTOCOMMIT);
+        self.read_only = status.contains(StatusFlags::SERVER_STATUS_IN_TRANS_READONLY);
+    }
+
+    /// Update the state from a labeled PostgresSQL message sent by the server. ReadyForQuery
+    /// carries the transaction status.
+    pub fn update_postgres(&mut self, packet: &Packet) {
+        if packet.get_packet_type().ok() != Some(PacketType::ReadyForQuery) {
+            return;
+        }
+        self.status = match BackendMessage::parse(packet) {
+            Ok(BackendMessage::ReadyForQuery(b'I')) => TransactionStatus::Idle,
+            Ok(BackendMessage::ReadyForQuery(b'T')) => TransactionStatus::InTransaction,
+            Ok(BackendMessage::ReadyForQuery(b'E')) => TransactionStatus::Failed,
+            Ok(message) => {
+                debug!("Unknown transaction status in {:?}", message);
+                return;
+            }
+            Err(e) => {
+                debug!("Unable to read ReadyForQuery: {}", e);
+                return;
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        context::Context, mariadb::handshake::CapabilityFlags, packet::DatabaseType,
+        packet_handler::Direction,
+    };
+
+    fn exchange(context: &mut Context, direction: Direction, mut packet: Packet) {
+        context.decode(&direction, &mut packet);
+    }
+
+    #[test]
+    fn follows_mariadb_transactions() {
+        let mut context = Context::new("127.0.0.1:1234".to_string(), DatabaseType::MariaDB);
+        let handshake = HandshakeV10 {
+            protocol_version: 10,
+            server_version: "10.5.8-MariaDB".to_string(),
+            connection_id: 1,
+            auth_plugin_data: vec![1; 20],
+            capabilities: CapabilityFlags::CLIENT_PROTOCOL_41
+                | CapabilityFlags::CLIENT_SECURE_CONNECTION,
+            charset: 45,
+            status: StatusFlags::empty(),
+            auth_plugin_name: None,
+        };
+        exchange(&mut context, Direction::Backward, handshake.to_packet());
+        assert!(!context.get_transaction().is_autocommit());
+
+        let ok = |status: StatusFlags| OkPacket {
+            header: 0x00,
+            affected_rows: 0,
+            last_insert_id: 0,
+            status,
+            warnings: 0,
+            info: Vec::new(),
+            session_state_changes: Vec::new(),
+        };
+        let capabilities = CapabilityFlags::CLIENT_PROTOCOL_41;
+        let query = |sql: &str| {
+            let mut payload = vec![0x03];
+            payload.extend_from_slice(sql.as_bytes());
+            Packet::new_mariadb(0, &payload)
+        };
+        exchange(&mut context, Direction::Forward, query("START TRANSACTION"));
+        let in_trans = StatusFlags::SERVER_STATUS_AUTOCOMMIT | StatusFlags::SERVER_STATUS_IN_TRANS;
+        exchange(
+            &mut context,
+            Direction::Backward,
+            ok(in_trans).to_packet(1, capabilities),
+        );
+        let transaction = context.get_transaction();
+        assert_eq!(transaction.get_status(), TransactionStatus::InTransaction);
+        assert!(transaction.is_autocommit());
+
+        exchange(&mut context, Direction::Forward, query("COMMIT"));
+        let committed = ok(StatusFlags::SERVER_STATUS_AUTOCOMMIT);
+        exchange(
+            &mut context,
+            Direction::Backward,
+            committed.to_packet(1, capabilities),
+        );
+        assert!(!context.get_transaction().in_transaction());
+
+        exchange(
+            &mut context,
+            Direction::Forward,
+            query("SET autocommit = 0"),
+        );
+        exchange(
+            &mut context,
+            Direction::Backward,
+            ok(StatusFlags::empty()).to_packet(1, capabilities),
+        );
+        assert!(!context.get_transaction().is_autocommit());
+        // COM_SET_OPTION is answered with an EOF packet, which carries the status too
+        let set_option = Packet::new_mariadb(0, &[0x1b, 0x01, 0x00]);
+        exchange(&mut context, Direction::Forward, set_option);
+        let eof = EofPacket {
+            warnings: 0,
+            status: StatusFlags::SERVER_STATUS_AUTOCOMMIT,
+        };
+        exchange(&mut context, Direction::Backward, eof.to_packet(1));
+        assert!(context.get_transaction().is_autocommit());
+    }
+
+    #[test]
+    fn follows_postgres_transactions() {
+        let mut context = Context::new("127.0.0.1:1234".to_string(), DatabaseType::PostgresSQL);
+        let mut ready = |status: u8| {
+            exchange(
+                &mut context,
+                Direction::Backward,
+                BackendMessage::ReadyForQuery(status).to_packet(),
+            );
+            *context.get_transaction()
+        };
+        assert_eq!(ready(b'T').get_status(), TransactionStatus::InTransaction);
+        let failed = ready(b'E');
+        assert!(failed.in_transaction() && failed.is_failed());
+        assert_eq!(ready(b'I').get_status(), TransactionStatus::Idle);
+        assert!(ready(b'I').is_autocommit());
+    }
+}
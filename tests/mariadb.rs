@@ -8,8 +8,9 @@ use std::{error::Error, sync::Once};
 use tokio;
 
 use sql_proxy::{
+    context::Context,
     packet::{DatabaseType, Packet},
-    packet_handler::PacketHandler,
+    packet_handler::{Action, PacketHandler},
 };
 
 static INIT: Once = Once::new();
@@ -18,22 +19,22 @@ struct PassthroughHandler {}
 
 #[async_trait::async_trait]
 impl PacketHandler for PassthroughHandler {
-    async fn handle_request(&mut self, p: &Packet) -> Packet {
+    async fn handle_request(&mut self, _context: &Context, p: &Packet) -> Action {
         debug!(
             "c=>s: {:?} packet: {} bytes",
             p.get_packet_type(),
             p.get_size()
         );
-        p.clone()
+        Action::Forward(p.clone())
     }
 
-    async fn handle_response(&mut self, p: &Packet) -> Packet {
+    async fn handle_response(&mut self, _context: &Context, p: &Packet) -> Action {
         debug!(
             "c<=s: {:?} packet: {} bytes",
             p.get_packet_type(),
             p.get_size()
         );
-        p.clone()
+        Action::Forward(p.clone())
     }
 }
 